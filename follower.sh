@@ -3,7 +3,14 @@ set -e
 
 IP="$1"
 PPS="$2"
-METHOD="$3"
-DURATION=$((20000/20000*1800+1200))
+MASTER="$3"
+SECRET="$4"
+NAME="${5:-$IP}"
 
-RUST_LOG=INFO target/release/anytrace --ip $IP --pps $PPS --duration $DURATION --method $METHOD > result$METHOD.csv
+if [ -z "$SECRET" ]; then
+    echo "Usage: $0 IP PPS MASTER SECRET [NAME]" >&2
+    exit 1
+fi
+
+# The method is announced by the master, which stops the followers when it finishes
+RUST_LOG=INFO target/release/anytrace --ip $IP --pps $PPS --join $MASTER --secret "$SECRET" --name "$NAME" > result-$NAME.csv
@@ -3,5 +3,13 @@
 IP="$1"
 PPS="$2"
 METHOD="$3"
+SECRET="$4"
+FOLLOWERS="${5:-0}"
+CONTROL="${6:-0.0.0.0:7777}"
 
-RUST_LOG=INFO target/release/anytrace --ip $IP --pps $PPS --method $METHOD --hitlist hitlist$METHOD.txt --master > result$METHOD.csv
+if [ -z "$SECRET" ]; then
+    echo "Usage: $0 IP PPS METHOD SECRET [FOLLOWERS] [CONTROL]" >&2
+    exit 1
+fi
+
+RUST_LOG=INFO target/release/anytrace --ip $IP --pps $PPS --method $METHOD --secret "$SECRET" --hitlist hitlist$METHOD.txt --master --control $CONTROL --followers $FOLLOWERS > result$METHOD.csv
@@ -4,4 +4,9 @@ extern crate log;
 
 mod ping;
 
-pub use ping::{PingHandler, PingHandlerBuilder, PingMethod, Responce, IcmpResponce};
+pub use ping::{PingHandler, PingHandlerBuilder, PingMethod, ProbeKey, Responce, IcmpResponce,
+               PAYLOAD_LENGTH, TCP_PORT, UDP_PORT};
+pub use ping::{IcmpExtensions, InterfaceInformation, MplsLabel};
+pub use ping::{MemoryNetwork, MemoryTransport, RawTransport, SimulatedTransport, Topology,
+               Transport, TransportReader, TransportWriter};
+pub use ping::{KernelTimestamp, PendingTimestamps};
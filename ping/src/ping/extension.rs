@@ -0,0 +1,399 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Length of the original datagram assumed for the routers that append the extensions
+/// without setting the length field (RFC 4884, section 5).
+const COMPATIBILITY_LENGTH: usize = 128;
+
+/// Class of the MPLS label stack objects (RFC 4950)
+const CLASS_MPLS: u8 = 1;
+
+/// Class of the interface information objects (RFC 5837)
+const CLASS_INTERFACE: u8 = 2;
+
+/// Extension objects appended to an ICMP or ICMPv6 error (RFC 4884).
+///
+/// Only the MPLS label stack (RFC 4950) and the interface information (RFC 5837) objects
+/// are decoded, ignoring the rest.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IcmpExtensions {
+    pub mpls: Vec<MplsLabel>,
+    pub interfaces: Vec<InterfaceInformation>,
+}
+
+/// Entry of the MPLS label stack of the packet that generated the error
+#[derive(Debug, Clone, PartialEq)]
+pub struct MplsLabel {
+    pub label: u32,
+    pub traffic_class: u8,
+    pub bottom: bool,
+    pub ttl: u8,
+}
+
+/// Information of an interface of the router that generated the error.
+///
+/// The role is 0 for the incoming interface, 1 for the sub-IP component of the incoming
+/// interface, 2 for the outgoing interface and 3 for the IP next hop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceInformation {
+    pub role: u8,
+    pub index: Option<u32>,
+    pub address: Option<IpAddr>,
+    pub name: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+impl IcmpExtensions {
+    /// Parse the extensions of the given ICMP or ICMPv6 error, including its header.
+    ///
+    /// Messages without a valid extension structure return no extensions.
+    pub fn parse(icmp: &[u8], ipv6: bool) -> IcmpExtensions {
+        let mut extensions = IcmpExtensions::default();
+        if icmp.len() < 8 {
+            return extensions;
+        }
+        // The length is stored in 32 bits words for ICMP, and 64 bits words for ICMPv6
+        let length = match ipv6 {
+            true => icmp[4] as usize * 8,
+            false => icmp[5] as usize * 4,
+        };
+        let compatibility = length == 0;
+        let start = 8 + if compatibility { COMPATIBILITY_LENGTH } else { length };
+        let structure = match icmp.get(start..) {
+            Some(structure) if structure.len() >= 4 => structure,
+            _ => return extensions,
+        };
+        if structure[0] >> 4 != 2 {
+            return extensions;
+        }
+        // The checksum is optional, but needed to detect the extensions without length
+        let check = get_u16(structure, 2);
+        if (compatibility || check != 0) && !valid_checksum(structure) {
+            return extensions;
+        }
+
+        let mut position = 4;
+        while position + 4 <= structure.len() {
+            let length = get_u16(structure, position) as usize;
+            if length < 4 || position + length > structure.len() {
+                break;
+            }
+            let (class, ctype) = (structure[position + 2], structure[position + 3]);
+            let data = &structure[position + 4..position + length];
+            match class {
+                CLASS_MPLS if ctype == 1 => {
+                    extensions.mpls.extend(data.chunks(4).filter(|entry| entry.len() == 4).map(|entry| {
+                        let entry = get_u32(entry, 0);
+                        MplsLabel {
+                            label: entry >> 12,
+                            traffic_class: (entry >> 9) as u8 & 0x07,
+                            bottom: entry & 0x100 != 0,
+                            ttl: entry as u8,
+                        }
+                    }));
+                }
+                CLASS_INTERFACE => {
+                    if let Some(interface) = InterfaceInformation::parse(ctype, data) {
+                        extensions.interfaces.push(interface);
+                    }
+                }
+                _ => {}
+            }
+            position += length;
+        }
+        return extensions;
+    }
+
+    /// Check if no extension was received
+    pub fn is_empty(&self) -> bool {
+        return self.mpls.is_empty() && self.interfaces.is_empty();
+    }
+}
+
+impl InterfaceInformation {
+    /// Parse the interface information object, where the c-type indicates the role and the
+    /// sub-objects included, in the order: ifIndex, IP address, name and MTU.
+    fn parse(ctype: u8, data: &[u8]) -> Option<InterfaceInformation> {
+        let mut interface = InterfaceInformation {
+            role: ctype >> 6,
+            index: None,
+            address: None,
+            name: None,
+            mtu: None,
+        };
+        let mut position = 0;
+        if ctype & 0x08 != 0 {
+            data.get(position..position + 4)?;
+            interface.index = Some(get_u32(data, position));
+            position += 4;
+        }
+        if ctype & 0x04 != 0 {
+            data.get(position..position + 4)?;
+            let address = match get_u16(data, position) {
+                1 => {
+                    let octets = data.get(position + 4..position + 8)?;
+                    position += 8;
+                    IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+                }
+                2 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(data.get(position + 4..position + 20)?);
+                    position += 20;
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return None,
+            };
+            interface.address = Some(address);
+        }
+        if ctype & 0x02 != 0 {
+            // The length of the name sub-object includes the length byte
+            let length = *data.get(position)? as usize;
+            let name = data.get(position + 1..position + length.max(1))?;
+            let name = String::from_utf8_lossy(name);
+            interface.name = Some(name.trim_end_matches('\0').to_string());
+            position += length.max(1);
+        }
+        if ctype & 0x01 != 0 {
+            data.get(position..position + 4)?;
+            interface.mtu = Some(get_u32(data, position));
+        }
+        return Some(interface);
+    }
+}
+
+/// Format the extensions as space separated tokens: `mpls=label/tc/s/ttl` for each label
+/// of the stack, and `if=role/index/address/name/mtu` for each interface, leaving empty
+/// the fields not received.
+impl fmt::Display for IcmpExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tokens = Vec::new();
+        for label in &self.mpls {
+            tokens.push(format!(
+                "mpls={}/{}/{}/{}",
+                label.label, label.traffic_class, label.bottom as u8, label.ttl
+            ));
+        }
+        for interface in &self.interfaces {
+            let role = match interface.role {
+                0 => "incoming",
+                1 => "sub-ip",
+                2 => "outgoing",
+                _ => "next-hop",
+            };
+            // The name is sanitized, as it's written as part of a CSV field
+            let name = interface.name.as_ref().map(|name| {
+                name.chars()
+                    .map(|c| if c.is_whitespace() || c == ',' || c == '/' { '_' } else { c })
+                    .collect::<String>()
+            });
+            tokens.push(format!(
+                "if={}/{}/{}/{}/{}",
+                role,
+                optional(&interface.index),
+                optional(&interface.address),
+                optional(&name),
+                optional(&interface.mtu)
+            ));
+        }
+        return write!(f, "{}", tokens.join(" "));
+    }
+}
+
+/// Format the value, or an empty string if it's missing
+fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+    return match *value {
+        Some(ref value) => value.to_string(),
+        None => String::new(),
+    };
+}
+
+/// Check the internet checksum of the extension structure
+fn valid_checksum(data: &[u8]) -> bool {
+    let mut sum: u32 = 0;
+    for word in data.chunks(2) {
+        let high = (word[0] as u32) << 8;
+        let low = if word.len() > 1 { word[1] as u32 } else { 0 };
+        sum += high | low;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    return sum == 0xFFFF;
+}
+
+fn get_u16(buffer: &[u8], position: usize) -> u16 {
+    return (buffer[position] as u16) << 8 | buffer[position + 1] as u16;
+}
+
+fn get_u32(buffer: &[u8], position: usize) -> u32 {
+    return (get_u16(buffer, position) as u32) << 16 | get_u16(buffer, position + 2) as u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an ICMP or ICMPv6 error quoting a datagram of the given length, stored on its
+    /// length field, followed by the extension objects
+    fn message(ipv6: bool, length: usize, objects: &[u8]) -> Vec<u8> {
+        let mut message = vec![11, 0, 0, 0, 0, 0, 0, 0];
+        match ipv6 {
+            true => message[4] = (length / 8) as u8,
+            false => message[5] = (length / 4) as u8,
+        }
+        message.extend(vec![0x45; length]);
+        message.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        message.extend_from_slice(objects);
+        let structure = 8 + length;
+        let checksum = checksum(&message[structure..]);
+        message[structure + 2] = (checksum >> 8) as u8;
+        message[structure + 3] = checksum as u8;
+        return message;
+    }
+
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum: u32 = 0;
+        for word in data.chunks(2) {
+            sum += (word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        return !sum as u16;
+    }
+
+    /// MPLS label stack of RFC 4950, with the labels 16002 (TC 0, TTL 1) and 24001
+    /// (TC 5, bottom of the stack, TTL 255)
+    const MPLS_STACK: [u8; 12] = [0x00, 0x0c, 0x01, 0x01, 0x03, 0xe8, 0x20, 0x01, 0x05, 0xdc, 0x1b, 0xff];
+
+    fn mpls_stack() -> Vec<MplsLabel> {
+        return vec![
+            MplsLabel {
+                label: 16002,
+                traffic_class: 0,
+                bottom: false,
+                ttl: 1,
+            },
+            MplsLabel {
+                label: 24001,
+                traffic_class: 5,
+                bottom: true,
+                ttl: 255,
+            },
+        ];
+    }
+
+    #[test]
+    fn mpls_label_stack() {
+        let extensions = IcmpExtensions::parse(&message(false, 128, &MPLS_STACK), false);
+        assert_eq!(extensions.mpls, mpls_stack());
+        assert!(extensions.interfaces.is_empty());
+        assert_eq!(extensions.to_string(), "mpls=16002/0/0/1 mpls=24001/5/1/255");
+
+        // The original datagram can be longer than the compatibility length
+        let extensions = IcmpExtensions::parse(&message(true, 256, &MPLS_STACK), true);
+        assert_eq!(extensions.mpls, mpls_stack());
+    }
+
+    #[test]
+    fn interface_information() {
+        let objects = vec![
+            // Incoming interface with its ifIndex, IPv4 address, name and MTU
+            0x00, 0x20, 0x02, 0x0f,
+            0x00, 0x00, 0x00, 0x07,
+            0x00, 0x01, 0x00, 0x00, 192, 0, 2, 1,
+            0x0c, b'g', b'e', b'-', b'0', b'/', b'0', b'/', b'1', 0, 0, 0,
+            0x00, 0x00, 0x05, 0xdc,
+            // Outgoing interface with its IPv6 address
+            0x00, 0x18, 0x02, 0x84,
+            0x00, 0x02, 0x00, 0x00, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        ];
+        let extensions = IcmpExtensions::parse(&message(true, 128, &objects), true);
+        assert!(extensions.mpls.is_empty());
+        assert_eq!(extensions.interfaces, vec![
+            InterfaceInformation {
+                role: 0,
+                index: Some(7),
+                address: Some("192.0.2.1".parse().unwrap()),
+                name: Some("ge-0/0/1".to_string()),
+                mtu: Some(1500),
+            },
+            InterfaceInformation {
+                role: 2,
+                index: None,
+                address: Some("2001:db8::1".parse().unwrap()),
+                name: None,
+                mtu: None,
+            },
+        ]);
+        assert_eq!(extensions.to_string(), "if=incoming/7/192.0.2.1/ge-0_0_1/1500 if=outgoing//2001:db8::1//");
+    }
+
+    #[test]
+    fn compatibility_length() {
+        // Without length, the extensions are only accepted after 128 bytes with a valid checksum
+        let mut icmp = message(false, COMPATIBILITY_LENGTH, &MPLS_STACK);
+        icmp[5] = 0;
+        assert_eq!(IcmpExtensions::parse(&icmp, false).mpls, mpls_stack());
+        icmp[8 + 128 + 2] = 0;
+        icmp[8 + 128 + 3] = 0;
+        assert!(IcmpExtensions::parse(&icmp, false).is_empty());
+        let mut icmp = message(true, COMPATIBILITY_LENGTH, &MPLS_STACK);
+        icmp[4] = 0;
+        assert_eq!(IcmpExtensions::parse(&icmp, true).mpls, mpls_stack());
+        // The extensions after a shorter datagram are not found without its length
+        let mut icmp = message(false, 64, &MPLS_STACK);
+        icmp[5] = 0;
+        assert!(IcmpExtensions::parse(&icmp, false).is_empty());
+    }
+
+    #[test]
+    fn checksum_of_the_structure() {
+        let mut icmp = message(false, 128, &MPLS_STACK);
+        icmp[8 + 128 + 3] ^= 0x01;
+        assert!(IcmpExtensions::parse(&icmp, false).is_empty());
+        // The checksum is optional when the length is given
+        icmp[8 + 128 + 2] = 0;
+        icmp[8 + 128 + 3] = 0;
+        assert_eq!(IcmpExtensions::parse(&icmp, false).mpls, mpls_stack());
+        // Other versions are ignored
+        let mut icmp = message(false, 128, &MPLS_STACK);
+        icmp[8 + 128] = 0x10;
+        assert!(IcmpExtensions::parse(&icmp, false).is_empty());
+    }
+
+    #[test]
+    fn truncated_objects() {
+        // The objects before a truncated object are kept
+        let mut objects = MPLS_STACK.to_vec();
+        objects.extend_from_slice(&[0x00, 0x10, 0x01, 0x01, 0x03, 0xe8, 0x21, 0x01]);
+        assert_eq!(IcmpExtensions::parse(&message(false, 128, &objects), false).mpls, mpls_stack());
+
+        // Objects shorter than their header stop the parsing
+        let mut objects = vec![0x00, 0x02, 0x01, 0x01];
+        objects.extend_from_slice(&MPLS_STACK);
+        assert!(IcmpExtensions::parse(&message(false, 128, &objects), false).is_empty());
+
+        // Interface objects missing their sub-objects are ignored
+        let objects = [
+            0x00, 0x08, 0x02, 0x08, 0x00, 0x00, 0x00, 0x07,
+            0x00, 0x08, 0x02, 0x0c, 0x00, 0x00, 0x00, 0x07,
+            0x00, 0x0c, 0x02, 0x06, 0x00, 0x01, 0x00, 0x00, 192, 0, 2, 1,
+            0x00, 0x0c, 0x02, 0x04, 0x00, 0x03, 0x00, 0x00, 192, 0, 2, 1,
+        ];
+        let extensions = IcmpExtensions::parse(&message(false, 128, &objects), false);
+        assert_eq!(extensions.interfaces, vec![InterfaceInformation {
+            role: 0,
+            index: Some(7),
+            address: None,
+            name: None,
+            mtu: None,
+        }]);
+
+        // Structures shorter than their header and messages shorter than their length
+        let icmp = message(false, 128, &MPLS_STACK);
+        assert!(IcmpExtensions::parse(&icmp[..8 + 128 + 3], false).is_empty());
+        assert!(IcmpExtensions::parse(&icmp[..100], false).is_empty());
+        assert!(IcmpExtensions::parse(&icmp[..4], false).is_empty());
+    }
+}
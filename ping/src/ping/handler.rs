@@ -1,41 +1,97 @@
 use ping::PingMethod;
+use ping::key::ProbeKey;
 use ping::reader::PingReader;
+use ping::transport::{RawTransport, Transport};
 use ping::writer::PingWriter;
 
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::transport::TransportChannelType::Layer3;
-use pnet::transport::transport_channel;
+use pnet::packet::tcp::{Tcp, TcpFlags};
+
+/// Maximum age of the replies accepted by `verify_signature`, in milliseconds
+const REPLAY_WINDOW_MS: u64 = 60_000;
 
 pub struct PingHandler {
     pub reader: PingReader,
     pub writer: PingWriter,
+    key: ProbeKey,
+    instance: u16,
 }
 
 impl PingHandler {
-    /// Construct a new PingHandler.
+    /// Construct a new PingHandler using raw sockets.
     ///
     /// This will write and read the received packets asynchronously.
     /// The writting will be limited to `rate_limit` packet per second.
-    pub fn new(localip: Ipv4Addr, method: PingMethod, rate_limit: u32) -> PingHandler {
-        let (reader, writer) = Self::generate_transport(localip, method, rate_limit);
+    /// The probes are authenticated with a random key.
+    pub fn new(localip: IpAddr, method: PingMethod, rate_limit: u32) -> PingHandler {
+        let key = ProbeKey::random();
+        let instance = Self::default_instance(&key, &method);
+        return Self::with_transport(localip, method, rate_limit, key, instance, false, &mut RawTransport);
+    }
+
+    /// Construct a new PingHandler sending and receiving the packets with the given transport,
+    /// authenticating the probes with the key.
+    ///
+    /// Only the replies to the probes marked with the instance are received, so many
+    /// handlers can be used on the same address. On `paris` mode, the probes sharing the
+    /// identifier keep the same flow (see `PingWriter::new`).
+    pub fn with_transport(
+        localip: IpAddr,
+        method: PingMethod,
+        rate_limit: u32,
+        key: ProbeKey,
+        instance: u16,
+        paris: bool,
+        transport: &mut dyn Transport,
+    ) -> PingHandler {
+        let (reader, writer) =
+            Self::generate_transport(localip, method, rate_limit, key, instance, paris, transport);
         return PingHandler {
             reader: reader,
             writer: writer,
+            key: key,
+            instance: instance,
         };
     }
 
-    /// Construct the PingReader and PingWriter using the given local IPv4 Address.
+    /// Get the instance derived from the key and the method, so the nodes sharing the key
+    /// use the same instance, while the measurements of different methods are isolated.
+    pub fn default_instance(key: &ProbeKey, method: &PingMethod) -> u16 {
+        let method = match *method {
+            PingMethod::ICMP => 0,
+            PingMethod::UDP => 1,
+            PingMethod::TcpSyn => 2,
+            PingMethod::TcpAck => 3,
+        };
+        let instance = key.mac(&[b"instance", &[method]]) as u16;
+        // Zero is not used, as the kernel replaces the IPv4 identification when it's zero
+        return instance.max(1);
+    }
+
+    /// Get the key used to authenticate the probes
+    pub fn key(&self) -> ProbeKey {
+        return self.key;
+    }
+
+    /// Get the instance used to mark the probes
+    pub fn instance(&self) -> u16 {
+        return self.instance;
+    }
+
+    /// Construct the PingReader and PingWriter using the given local IP Address.
     fn generate_transport(
-        local: Ipv4Addr,
+        local: IpAddr,
         method: PingMethod,
         rate_limit: u32,
+        key: ProbeKey,
+        instance: u16,
+        paris: bool,
+        transport: &mut dyn Transport,
     ) -> (PingReader, PingWriter) {
-        // We use Icmp as transport for Icmp and Udp, as it only filter the received packets
-        let protocol = Layer3(IpNextHeaderProtocols::Icmp);
-        let (tx, rx) = match transport_channel(4096, protocol) {
-            Ok((tx, rx)) => (tx, rx),
+        let (tx, receivers) = match transport.open(local, &method) {
+            Ok((tx, receivers)) => (tx, receivers),
             Err(e) => panic!(
                 "An error occurred when creating the transport channel, check if the program is running as root or have the CAP_NET_RAW capabilities (sudo setcap CAP_NET_RAW+ep target/release/anytrace):
                             {}",
@@ -43,36 +99,69 @@ impl PingHandler {
             ),
         };
 
-        let reader = PingReader::new(rx, local);
+        let reader = PingReader::new(receivers, local, instance);
         let reader_writer = reader.writer();
         return (
             reader,
-            PingWriter::new(tx, local, method, rate_limit, reader_writer),
+            PingWriter::new(tx, local, method, rate_limit, key, instance, paris, reader_writer),
         );
     }
 
-    pub fn verify_signature(payload: &[u8]) -> Result<(), &str> {
+    /// Verify that the payload belongs to a probe sent to the target with our key,
+    /// rejecting the replies older than the replay window.
+    pub fn verify_signature(&self, payload: &[u8], target: IpAddr) -> Result<(), &'static str> {
         // The packet should be 14 bytes long
         if payload.len() < 14 {
             return Err("Payload is not of length 14");
         }
 
-        // Check the payload key
-        if payload[12..14] != *PingWriter::get_payload_key() {
-            return Err("Payload key invalid");
+        // Check the payload MAC
+        if payload[12..14] != PingWriter::get_payload_mac(&self.key, target, payload) {
+            return Err("Payload MAC invalid");
+        }
+
+        // Check that the reply is recent, with a second of margin for the clock differences
+        let sent = Self::get_packet_timestamp_ms(payload)?;
+        let now = Self::time_from_epoch_ms();
+        if sent > now + 1000 || now.saturating_sub(sent) > REPLAY_WINDOW_MS {
+            return Err("Payload outside of the replay window");
         }
         return Ok(());
     }
 
     /// Check the packet payload and get the timestamp
-    pub fn get_packet_timestamp_ms(payload: &[u8]) -> Result<u64, &str> {
+    pub fn get_packet_timestamp_ms(payload: &[u8]) -> Result<u64, &'static str> {
         // The packet should be 10 bytes long
         if payload.len() < 14 {
             return Err("Payload is not of length 14");
         }
 
         // Get the timestamp from the payload and convert it from Big Endian
-        return Ok(u64::from_be(Self::array_to_u64(&payload[..8])));
+        return Ok(u64::from_be(Self::array_to_u64(&payload[4..12])));
+    }
+
+    /// Get the identifier and sequence encoded in the TCP probe that generated the given reply.
+    ///
+    /// A SYN probe is answered with an acknowledgement of its sequence number plus one,
+    /// while an ACK probe is answered with a RST using its acknowledgement number as sequence.
+    pub fn get_tcp_id_seq(tcp: &Tcp) -> (u16, u16) {
+        let sequence = if tcp.flags & TcpFlags::ACK != 0 {
+            tcp.acknowledgement.wrapping_sub(1)
+        } else {
+            tcp.sequence
+        };
+        return ((sequence >> 16) as u16, sequence as u16);
+    }
+
+    /// Get the current time in milliseconds
+    fn time_from_epoch_ms() -> u64 {
+        let start = SystemTime::now();
+        let since_the_epoch = start
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        let in_ms =
+            since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_nanos() as u64 / 1_000_000;
+        return in_ms;
     }
 
     fn array_to_u64(data: &[u8]) -> u64 {
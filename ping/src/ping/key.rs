@@ -0,0 +1,181 @@
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Secret key used to authenticate the probes and their replies.
+///
+/// The key is used to compute truncated SipHash-2-4 MACs, so only the processes sharing
+/// the secret can generate probes whose replies are accepted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl ProbeKey {
+    /// Create a key from its two 64 bits halves
+    pub fn new(k0: u64, k1: u64) -> ProbeKey {
+        return ProbeKey { k0: k0, k1: k1 };
+    }
+
+    /// Get the two 64 bits halves of the key, to store it and create it again with `new`
+    pub fn halves(&self) -> (u64, u64) {
+        return (self.k0, self.k1);
+    }
+
+    /// Derive the key from a secret shared between the master and the followers
+    pub fn from_secret(secret: &str) -> ProbeKey {
+        let base = ProbeKey::new(0x616e797472616365, 0x70726f62656b6579);
+        let k0 = base.mac(&[secret.as_bytes(), &[0]]);
+        let k1 = base.mac(&[secret.as_bytes(), &[1]]);
+        return ProbeKey::new(k0, k1);
+    }
+
+    /// Create a random key, only valid for the current process
+    pub fn random() -> ProbeKey {
+        let mut bytes = [0u8; 16];
+        let random = File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes));
+        if let Err(_) = random {
+            // Fallback to the current time, which is not secret but still unique per run
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards");
+            return ProbeKey::from_secret(&format!("{:?}", now));
+        }
+        let mut k0 = 0;
+        let mut k1 = 0;
+        for i in 0..8 {
+            k0 = k0 << 8 | bytes[i] as u64;
+            k1 = k1 << 8 | bytes[i + 8] as u64;
+        }
+        return ProbeKey::new(k0, k1);
+    }
+
+    /// Calculate the MAC of the concatenation of the given parts
+    pub fn mac(&self, parts: &[&[u8]]) -> u64 {
+        let mut hasher = SipHasher::new(self.k0, self.k1);
+        for part in parts {
+            hasher.write(part);
+        }
+        return hasher.finish();
+    }
+
+    /// Calculate the MAC of the fields of a probe sent to the target
+    pub fn mac_probe(&self, target: IpAddr, fields: &[u8]) -> u64 {
+        return match target {
+            IpAddr::V4(target) => self.mac(&[&target.octets(), fields]),
+            IpAddr::V6(target) => self.mac(&[&target.octets(), fields]),
+        };
+    }
+}
+
+/// SipHash-2-4 implementation, as the hasher of the standard library is deprecated
+struct SipHasher {
+    state: [u64; 4],
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl SipHasher {
+    fn new(k0: u64, k1: u64) -> SipHasher {
+        return SipHasher {
+            state: [
+                k0 ^ 0x736f6d6570736575,
+                k1 ^ 0x646f72616e646f6d,
+                k0 ^ 0x6c7967656e657261,
+                k1 ^ 0x7465646279746573,
+            ],
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        };
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for byte in data {
+            self.tail |= (*byte as u64) << (8 * self.ntail);
+            self.ntail += 1;
+            if self.ntail == 8 {
+                let word = self.tail;
+                self.compress(word, 2);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+        self.length += data.len();
+    }
+
+    fn finish(mut self) -> u64 {
+        let word = self.tail | ((self.length as u64 & 0xFF) << 56);
+        self.compress(word, 2);
+        self.state[2] ^= 0xFF;
+        self.rounds(4);
+        return self.state[0] ^ self.state[1] ^ self.state[2] ^ self.state[3];
+    }
+
+    fn compress(&mut self, word: u64, rounds: usize) {
+        self.state[3] ^= word;
+        self.rounds(rounds);
+        self.state[0] ^= word;
+    }
+
+    fn rounds(&mut self, rounds: usize) {
+        let v = &mut self.state;
+        for _ in 0..rounds {
+            v[0] = v[0].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(13);
+            v[1] ^= v[0];
+            v[0] = v[0].rotate_left(32);
+            v[2] = v[2].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(16);
+            v[3] ^= v[2];
+            v[0] = v[0].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(21);
+            v[3] ^= v[0];
+            v[2] = v[2].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(17);
+            v[1] ^= v[2];
+            v[2] = v[2].rotate_left(32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reference vectors of the SipHash paper (appendix A and vectors.h), with the key
+    /// 00 01 .. 0f and the messages 00 01 .. of the given lengths
+    const VECTORS: [(usize, u64); 9] = [
+        (0, 0x726fdb47dd0e0e31),
+        (1, 0x74f839c593dc67fd),
+        (2, 0x0d6c8009d9a94f5a),
+        (3, 0x85676696d7fb7e2d),
+        (7, 0xab0200f58b01d137),
+        (8, 0x93f5f5799a932462),
+        (15, 0xa129ca6149be45e5),
+        (16, 0x3f2acc7f57c29bdb),
+        (63, 0x958a324ceb064572),
+    ];
+
+    #[test]
+    fn siphash_reference_vectors() {
+        let key = ProbeKey::new(0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        let message: Vec<u8> = (0..64).collect();
+        for &(length, expected) in VECTORS.iter() {
+            assert_eq!(key.mac(&[&message[..length]]), expected, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn mac_of_the_concatenation() {
+        let key = ProbeKey::from_secret("secret");
+        let message: Vec<u8> = (0..20).collect();
+        let whole = key.mac(&[&message]);
+        assert_eq!(key.mac(&[&message[..3], &message[3..11], &[], &message[11..]]), whole);
+        assert_ne!(ProbeKey::from_secret("other").mac(&[&message]), whole);
+        assert_eq!(key, ProbeKey::new(key.halves().0, key.halves().1));
+    }
+}
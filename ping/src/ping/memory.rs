@@ -0,0 +1,108 @@
+use ping::PingMethod;
+use ping::timestamp::KernelTimestamp;
+use ping::transport::{Transport, TransportReader, TransportWriter};
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc;
+
+/// Transport keeping the packets in memory, without using the network.
+///
+/// The packets sent by the PingWriter are delivered to the paired `MemoryNetwork`,
+/// which can inject the packets that the PingReader will receive.
+pub struct MemoryTransport {
+    sent: Option<mpsc::Sender<Vec<u8>>>,
+    received: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+/// Side of the memory transport that acts as the network.
+pub struct MemoryNetwork {
+    sent: mpsc::Receiver<Vec<u8>>,
+    received: mpsc::Sender<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    /// Create a new memory transport and the network connected to it.
+    pub fn new() -> (MemoryTransport, MemoryNetwork) {
+        let (sent_tx, sent_rx) = mpsc::channel();
+        let (received_tx, received_rx) = mpsc::channel();
+        let transport = MemoryTransport {
+            sent: Some(sent_tx),
+            received: Some(received_rx),
+        };
+        let network = MemoryNetwork {
+            sent: sent_rx,
+            received: received_tx,
+        };
+        return (transport, network);
+    }
+}
+
+impl Transport for MemoryTransport {
+    /// Open the transport. It can only be opened once, as the network is connected to a single reader.
+    fn open(
+        &mut self,
+        _local: IpAddr,
+        _method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)> {
+        return match (self.sent.take(), self.received.take()) {
+            (Some(sent), Some(received)) => Ok((
+                Box::new(MemoryWriter(sent)),
+                vec![Box::new(MemoryReader(received))],
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "The memory transport was already opened",
+            )),
+        };
+    }
+}
+
+impl MemoryNetwork {
+    /// Get the channel with the IP packets sent by the writer
+    pub fn sent(&self) -> &mpsc::Receiver<Vec<u8>> {
+        return &self.sent;
+    }
+
+    /// Deliver the IP packet to the reader, returning error if the reader was closed.
+    pub fn inject(&self, packet: Vec<u8>) -> Result<(), ()> {
+        if let Err(_) = self.received.send(packet) {
+            return Err(());
+        }
+        return Ok(());
+    }
+
+    /// Get a clone of the sender used to deliver packets to the reader.
+    pub fn injector(&self) -> mpsc::Sender<Vec<u8>> {
+        return self.received.clone();
+    }
+}
+
+struct MemoryWriter(mpsc::Sender<Vec<u8>>);
+
+impl TransportWriter for MemoryWriter {
+    fn send_to(&mut self, packet: &[u8], _target: IpAddr) -> io::Result<usize> {
+        if let Err(_) = self.0.send(packet.to_vec()) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The memory network was closed",
+            ));
+        }
+        return Ok(packet.len());
+    }
+}
+
+/// Reader of the packets delivered through a channel, also used by the SimulatedTransport.
+pub(crate) struct MemoryReader(pub(crate) mpsc::Receiver<Vec<u8>>);
+
+impl TransportReader for MemoryReader {
+    fn recv(&mut self) -> io::Result<(Vec<u8>, KernelTimestamp)> {
+        return match self.0.recv() {
+            Ok(packet) => Ok((packet, KernelTimestamp::default())),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The memory network was closed",
+            )),
+        };
+    }
+}
@@ -1,22 +1,83 @@
+mod extension;
 mod handler;
+mod key;
+mod memory;
+mod pacer;
 mod reader;
+mod simulator;
+mod socket4;
+mod socket6;
+mod timestamp;
+mod topology;
+mod transport;
 mod writer;
 
+pub use self::extension::{IcmpExtensions, InterfaceInformation, MplsLabel};
 pub use self::handler::PingHandler;
+pub use self::key::ProbeKey;
+pub use self::memory::{MemoryNetwork, MemoryTransport};
 pub use self::reader::{IcmpResponce, Responce};
+pub use self::simulator::SimulatedTransport;
+pub use self::timestamp::KernelTimestamp;
+pub use self::topology::Topology;
+pub use self::transport::{PendingTimestamps, RawTransport, Transport, TransportReader, TransportWriter};
+pub use self::writer::{PAYLOAD_LENGTH, TCP_PORT, UDP_PORT};
 
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum PingMethod {
     ICMP,
     UDP,
+    TcpSyn,
+    TcpAck,
+}
+
+impl PingMethod {
+    /// Check if the method sends TCP packets, which require listening to the TCP replies.
+    pub fn is_tcp(&self) -> bool {
+        return *self == PingMethod::TcpSyn || *self == PingMethod::TcpAck;
+    }
+}
+
+/// Format the method with the names used on the command line: ICMP, UDP, TCP-SYN and TCP-ACK
+impl fmt::Display for PingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            PingMethod::ICMP => "ICMP",
+            PingMethod::UDP => "UDP",
+            PingMethod::TcpSyn => "TCP-SYN",
+            PingMethod::TcpAck => "TCP-ACK",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+/// Parse the method from the names used on the command line, ignoring the case
+impl FromStr for PingMethod {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<PingMethod, ()> {
+        return match name.to_uppercase().as_ref() {
+            "ICMP" => Ok(PingMethod::ICMP),
+            "UDP" => Ok(PingMethod::UDP),
+            "TCP-SYN" => Ok(PingMethod::TcpSyn),
+            "TCP-ACK" => Ok(PingMethod::TcpAck),
+            _ => Err(()),
+        };
+    }
 }
 
 pub struct PingHandlerBuilder {
-    localip: Option<Ipv4Addr>,
+    localip: Option<IpAddr>,
     method: Option<PingMethod>,
     rate_limit: Option<u32>,
+    key: Option<ProbeKey>,
+    instance: Option<u16>,
+    paris: bool,
+    transport: Option<Box<dyn Transport>>,
 }
 
 impl PingHandlerBuilder {
@@ -26,12 +87,16 @@ impl PingHandlerBuilder {
             localip: None,
             method: None,
             rate_limit: None,
+            key: None,
+            instance: None,
+            paris: false,
+            transport: None,
         };
     }
 
-    /// Set the local IP address to listen.
+    /// Set the local IP address to listen, either IPv4 or IPv6.
     pub fn localip(mut self, localip: &str) -> Self {
-        let local: Ipv4Addr = localip.parse().unwrap();
+        let local: IpAddr = localip.parse().unwrap();
         self.localip = Some(local);
         return self;
     }
@@ -48,12 +113,48 @@ impl PingHandlerBuilder {
         return self;
     }
 
+    /// Set the key used to authenticate the probes. A random key is used by default.
+    pub fn key(mut self, key: ProbeKey) -> Self {
+        self.key = Some(key);
+        return self;
+    }
+
+    /// Set the instance used to mark the probes, ignoring the replies to other instances.
+    ///
+    /// By default it is derived from the key and the method.
+    pub fn instance(mut self, instance: u16) -> Self {
+        self.instance = Some(instance);
+        return self;
+    }
+
+    /// Keep the flow identifier constant for the probes sharing the identifier, as in
+    /// Paris traceroute, so they follow the same path through the load balancers.
+    pub fn paris(mut self, paris: bool) -> Self {
+        self.paris = paris;
+        return self;
+    }
+
+    /// Set the transport used to send and receive the packets. Raw sockets are used by default.
+    pub fn transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        return self;
+    }
+
     /// Build the PingHandler
     pub fn build(self) -> PingHandler {
-        return PingHandler::new(
+        let mut transport = self.transport.unwrap_or(Box::new(RawTransport));
+        let method = self.method.unwrap();
+        let key = self.key.unwrap_or_else(ProbeKey::random);
+        let instance = self.instance
+            .unwrap_or_else(|| PingHandler::default_instance(&key, &method));
+        return PingHandler::with_transport(
             self.localip.unwrap(),
-            self.method.unwrap(),
+            method,
             self.rate_limit.unwrap_or(100_000),
+            key,
+            instance,
+            self.paris,
+            &mut *transport,
         );
     }
 }
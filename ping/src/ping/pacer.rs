@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket used to pace the packets at a fixed rate.
+///
+/// Packets exceeding the rate are delayed until the bucket has room for them, instead
+/// of being dropped. The bucket holds up to 10 ms of packets, smoothing the bursts while
+/// avoiding a wakeup for every packet on high rates.
+pub(crate) struct Pacer {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    /// Create a new pacer for the given rate of packets per second
+    pub fn new(rate: u32) -> Pacer {
+        let rate = rate.max(1) as f64;
+        let capacity = (rate / 100.0).max(1.0);
+        return Pacer {
+            rate: rate,
+            capacity: capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        };
+    }
+
+    /// Block until a packet can be sent, consuming it from the bucket.
+    pub fn wait(&mut self) {
+        loop {
+            self.refill();
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return;
+            }
+            let missing = (1.0 - self.tokens) / self.rate;
+            thread::sleep(Duration::new(
+                missing as u64,
+                (missing.fract() * 1e9) as u32,
+            ));
+        }
+    }
+
+    /// Add the tokens generated since the last refill, up to the capacity
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_of_the_bucket() {
+        let mut pacer = Pacer::new(1000);
+        assert_eq!(pacer.capacity, 10.0);
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert!(pacer.tokens < 1.0);
+
+        // The bucket refills up to its capacity
+        thread::sleep(Duration::from_millis(50));
+        pacer.refill();
+        assert_eq!(pacer.tokens, 10.0);
+
+        // Low rates still hold a packet
+        let pacer = Pacer::new(0);
+        assert_eq!((pacer.rate, pacer.capacity), (1.0, 1.0));
+    }
+
+    #[test]
+    fn rate_of_the_packets() {
+        let mut pacer = Pacer::new(1000);
+        let start = Instant::now();
+        // The 10 packets of the bucket are sent at once, and the next 100 every millisecond
+        for _ in 0..110 {
+            pacer.wait();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(99), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+}
@@ -7,40 +7,63 @@ use pnet::packet::icmp::destination_unreachable::{DestinationUnreachable,
 use pnet::packet::icmp::echo_reply::{EchoReply, EchoReplyPacket};
 use pnet::packet::icmp::time_exceeded::{TimeExceeded, TimeExceededPacket};
 use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet::packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
-use pnet::transport::{TransportReceiver, ipv4_packet_iter};
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::{Tcp, TcpFlags, TcpPacket};
 
-use std::net::Ipv4Addr;
+use std::io;
+use std::net::IpAddr;
+
+use ping::extension::IcmpExtensions;
+use ping::timestamp::KernelTimestamp;
+use ping::transport::TransportReader;
 
-use std::sync::Arc;
-use std::sync::Mutex;
 use std::sync::mpsc;
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub struct PingReader {
     reader: mpsc::Receiver<IcmpResponce>,
     writer: mpsc::Sender<IcmpResponce>,
 }
 
+/// Received packet.
+///
+/// ICMPv6 messages share the format of their ICMP counterparts, so they are stored
+/// using the same structures.
 pub enum Responce {
     Echo(EchoReply),
     Timeout(TimeExceeded),
     Unreachable(DestinationUnreachable),
-    LocalSendedEcho(Ipv4Addr),
+    Tcp(Tcp),
+    /// Probe sent to the target, with its identifier and sequence
+    LocalSendedEcho(IpAddr, u16, u16),
 }
 
+/// Received packet, with the source and TTL of its IP header.
+///
+/// The extensions are only received on Time Exceeded and Destination Unreachable messages.
 pub struct IcmpResponce {
-    pub source: Ipv4Addr,
+    pub source: IpAddr,
     pub ttl: u8,
     pub icmp: Responce,
+    pub extensions: IcmpExtensions,
+    /// Length of the ICMP or TCP message, without the IP header
+    pub size: u16,
+    /// Time since the epoch when the packet was received, or sent for the local packets
     pub time_ms: u64,
+    /// Monotonic time when the packet was received, or sent, used to measure the round trip time
+    pub time: Instant,
+    /// Kernel timestamps of the packet, preferred to measure the round trip time when present
+    pub timestamp: KernelTimestamp,
 }
 
 impl PingReader {
-    pub fn new(tx: TransportReceiver, local: Ipv4Addr) -> PingReader {
-        let (sender, receiver) = Self::run(local, tx);
+    /// Construct a new PingReader, only accepting the replies to the probes of the given instance.
+    pub fn new(tx: Vec<Box<dyn TransportReader>>, local: IpAddr, instance: u16) -> PingReader {
+        let (sender, receiver) = Self::run(local, instance, tx);
         return PingReader {
             reader: receiver,
             writer: sender,
@@ -57,39 +80,120 @@ impl PingReader {
         return self.writer.clone();
     }
 
-    /// Create a new thread and channel to receive requests asynchronously.
-    fn run(local: Ipv4Addr, reader: TransportReceiver) -> (mpsc::Sender<IcmpResponce>, mpsc::Receiver<IcmpResponce>) {
+    /// Create a new thread for each transport and a channel to receive requests asynchronously.
+    fn run(local: IpAddr, instance: u16, readers: Vec<Box<dyn TransportReader>>) -> (mpsc::Sender<IcmpResponce>, mpsc::Receiver<IcmpResponce>) {
         let (sender, receiver) = mpsc::channel::<IcmpResponce>();
-        let reader = Arc::new(Mutex::new(reader));
-        let sender_thread = sender.clone();
-        thread::spawn(move || {
-            let mut reader = reader.lock().unwrap();
-            let mut iter = ipv4_packet_iter(&mut reader);
-            loop {
-                let packet = iter.next();
-                if let Ok((packet, _)) = packet {
-                    if let Err(_) = Self::process_ipv4(&packet, local, &sender_thread) {
-                        // Channel is closed, exit
+        for mut reader in readers {
+            let sender_thread = sender.clone();
+            thread::spawn(move || loop {
+                match reader.recv() {
+                    Ok((packet, timestamp)) => {
+                        if let Err(_) = Self::process_packet(&packet, local, instance, &sender_thread, timestamp) {
+                            // Channel is closed, exit
+                            return;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                        // Transport is closed, exit
                         return;
                     }
+                    Err(_) => {}
                 }
-            }
-        });
+            });
+        }
 
         return (sender, receiver);
     }
 
-    /// Parse the IPv4 packet, only continuing if the ICMP protocol was used.
+    /// Parse the IP packet, only continuing if it was sent from or to the local address.
+    fn process_packet(
+        packet: &[u8],
+        local: IpAddr,
+        instance: u16,
+        sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
+    ) -> Result<(), ()> {
+        match packet.first() {
+            Some(version) if version >> 4 == 6 => {
+                if let Some(packet) = Ipv6Packet::new(packet) {
+                    return Self::process_ipv6(&packet, local, instance, sender, timestamp);
+                }
+            }
+            _ => {
+                if let Some(packet) = Ipv4Packet::new(packet) {
+                    return Self::process_ipv4(&packet, local, instance, sender, timestamp);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Parse the IPv4 packet, only continuing if the ICMP or TCP protocol was used.
     fn process_ipv4(
         packet: &Ipv4Packet,
-        local: Ipv4Addr,
+        local: IpAddr,
+        instance: u16,
         sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
     ) -> Result<(), ()> {
-        if packet.get_source() != local && packet.get_destination() != local {
+        let source = IpAddr::V4(packet.get_source());
+        let destination = IpAddr::V4(packet.get_destination());
+        if source != local && destination != local {
             return Ok(());
         }
         if packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
-            return Self::process_icmp4(packet.payload(), &packet, local, sender);
+            return Self::process_icmp4(packet.payload(), source, packet.get_ttl(), instance, sender, timestamp);
+        } else if packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp {
+            if destination != local {
+                return Ok(());
+            }
+            return Self::process_tcp(packet.payload(), source, packet.get_ttl(), instance, sender, timestamp);
+        }
+        return Ok(());
+    }
+
+    /// Parse the IPv6 packet, only continuing if the ICMPv6 or TCP protocol was used.
+    fn process_ipv6(
+        packet: &Ipv6Packet,
+        local: IpAddr,
+        instance: u16,
+        sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
+    ) -> Result<(), ()> {
+        let source = IpAddr::V6(packet.get_source());
+        if IpAddr::V6(packet.get_destination()) != local {
+            return Ok(());
+        }
+        if packet.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+            return Self::process_icmp6(packet.payload(), source, packet.get_hop_limit(), instance, sender, timestamp);
+        } else if packet.get_next_header() == IpNextHeaderProtocols::Tcp {
+            return Self::process_tcp(packet.payload(), source, packet.get_hop_limit(), instance, sender, timestamp);
+        }
+        return Ok(());
+    }
+
+    /// Parse the TCP packet and send the RST and SYN-ACK replies to the channel.
+    ///
+    /// Both replies mean that the probe reached the destination host.
+    /// The probes are sent from the port of the instance, so only the replies to that port are accepted.
+    fn process_tcp(
+        packet: &[u8],
+        source: IpAddr,
+        ttl: u8,
+        instance: u16,
+        sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
+    ) -> Result<(), ()> {
+        if let Some(tcp) = TcpPacket::new(packet) {
+            if tcp.get_destination() != instance {
+                return Ok(());
+            }
+            let flags = tcp.get_flags();
+            let synack = TcpFlags::SYN | TcpFlags::ACK;
+            if flags & TcpFlags::RST != 0 || flags & synack == synack {
+                let icmp = Responce::Tcp(tcp.from_packet());
+                return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, IcmpExtensions::default());
+            }
         }
         return Ok(());
     }
@@ -97,53 +201,36 @@ impl PingReader {
     /// Parse the ICMP packet and send EchoReply to the channel.
     fn process_icmp4(
         packet: &[u8],
-        header: &Ipv4Packet,
-        _local: Ipv4Addr,
+        source: IpAddr,
+        ttl: u8,
+        instance: u16,
         sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
     ) -> Result<(), ()> {
+        if !Self::verify_instance(packet, instance) {
+            return Ok(());
+        }
         let icmp_packet = IcmpPacket::new(packet);
         if let Some(icmp) = icmp_packet {
             match icmp.get_icmp_type() {
                 IcmpTypes::EchoReply => {
                     if let Some(icmp) = EchoReplyPacket::new(&packet) {
-                        let responce = IcmpResponce {
-                            source: Ipv4Addr::from(header.get_source()),
-                            ttl: header.get_ttl(),
-                            icmp: Responce::Echo(icmp.from_packet()),
-                            time_ms: Self::time_from_epoch_ms(),
-                        };
-                        if let Err(_) = sender.send(responce) {
-                            // Return error if the channel is closed.
-                            return Err(());
-                        }
+                        let icmp = Responce::Echo(icmp.from_packet());
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, IcmpExtensions::default());
                     }
                 }
                 IcmpTypes::TimeExceeded => {
                     if let Some(icmp) = TimeExceededPacket::new(&packet) {
-                        let responce = IcmpResponce {
-                            source: Ipv4Addr::from(header.get_source()),
-                            ttl: header.get_ttl(),
-                            icmp: Responce::Timeout(icmp.from_packet()),
-                            time_ms: Self::time_from_epoch_ms(),
-                        };
-                        if let Err(_) = sender.send(responce) {
-                            // Return error if the channel is closed.
-                            return Err(());
-                        }
+                        let icmp = Responce::Timeout(icmp.from_packet());
+                        let extensions = IcmpExtensions::parse(packet, false);
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, extensions);
                     }
                 }
                 IcmpTypes::DestinationUnreachable => {
                     if let Some(icmp) = DestinationUnreachablePacket::new(&packet) {
-                        let responce = IcmpResponce {
-                            source: Ipv4Addr::from(header.get_source()),
-                            ttl: header.get_ttl(),
-                            icmp: Responce::Unreachable(icmp.from_packet()),
-                            time_ms: Self::time_from_epoch_ms(),
-                        };
-                        if let Err(_) = sender.send(responce) {
-                            // Return error if the channel is closed.
-                            return Err(());
-                        }
+                        let icmp = Responce::Unreachable(icmp.from_packet());
+                        let extensions = IcmpExtensions::parse(packet, false);
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, extensions);
                     }
                 }
                 IcmpTypes::EchoRequest => {
@@ -155,6 +242,126 @@ impl PingReader {
         return Ok(());
     }
 
+    /// Parse the ICMPv6 packet and send EchoReply to the channel.
+    ///
+    /// The packets are parsed as their ICMP counterparts, as they share the same format.
+    fn process_icmp6(
+        packet: &[u8],
+        source: IpAddr,
+        ttl: u8,
+        instance: u16,
+        sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
+    ) -> Result<(), ()> {
+        if !Self::verify_instance(packet, instance) {
+            return Ok(());
+        }
+        let icmp_packet = Icmpv6Packet::new(packet);
+        if let Some(icmp) = icmp_packet {
+            match icmp.get_icmpv6_type() {
+                Icmpv6Types::EchoReply => {
+                    if let Some(icmp) = EchoReplyPacket::new(&packet) {
+                        let icmp = Responce::Echo(icmp.from_packet());
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, IcmpExtensions::default());
+                    }
+                }
+                Icmpv6Types::TimeExceeded => {
+                    if let Some(icmp) = TimeExceededPacket::new(&packet) {
+                        let icmp = Responce::Timeout(icmp.from_packet());
+                        let extensions = IcmpExtensions::parse(packet, true);
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, extensions);
+                    }
+                }
+                Icmpv6Types::DestinationUnreachable => {
+                    if let Some(icmp) = DestinationUnreachablePacket::new(&packet) {
+                        let icmp = Responce::Unreachable(icmp.from_packet());
+                        let extensions = IcmpExtensions::parse(packet, true);
+                        return Self::send_responce(sender, timestamp, source, ttl, packet.len(), icmp, extensions);
+                    }
+                }
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+
+    /// Check that the ICMP or ICMPv6 message was generated by a probe of the instance.
+    ///
+    /// Echo replies carry the instance in their payload, while errors quote the probe, checked
+    /// by `verify_quote`. Other messages are accepted, as they are not replies to our probes.
+    fn verify_instance(packet: &[u8], instance: u16) -> bool {
+        return match packet.first() {
+            Some(&0) | Some(&129) => Self::get_u16(packet, 8 + 14) == Some(instance),
+            Some(&3) | Some(&11) if packet.get(8).map(|v| v >> 4) == Some(4) => {
+                // IPv4 Time Exceeded or Destination Unreachable
+                Self::verify_quote(&packet[8..], instance)
+            }
+            Some(&1) | Some(&3) if packet.get(8).map(|v| v >> 4) == Some(6) => {
+                // ICMPv6 Destination Unreachable or Time Exceeded
+                Self::verify_quote(&packet[8..], instance)
+            }
+            Some(&3) | Some(&11) | Some(&1) => false,
+            _ => true,
+        };
+    }
+
+    /// Check that the quoted probe was sent by the instance.
+    ///
+    /// TCP probes carry the instance in their source port, and ICMP and UDP probes in their
+    /// payload, as both survive the NATs rewriting the identification of the IPv4 header.
+    /// The identification is only checked for IPv4 errors quoting 8 bytes of the probe, whose
+    /// payload is missing, or zeroed when the quote is padded for the extensions (RFC 4884).
+    fn verify_quote(quote: &[u8], instance: u16) -> bool {
+        let ipv4 = quote[0] >> 4 == 4;
+        let (header, protocol) = match ipv4 {
+            true => ((quote[0] & 0xf) as usize * 4, quote.get(9)),
+            false => (40, quote.get(6)),
+        };
+        let payload = header + 8;
+        // The timestamp of the payload is never zero on the probes
+        let quoted = quote.len() >= payload + 16 && quote[payload + 4..payload + 12].iter().any(|&byte| byte != 0);
+        return match protocol {
+            Some(&6) => Self::get_u16(quote, header) == Some(instance),
+            Some(&1) | Some(&17) | Some(&58) if quoted => Self::get_u16(quote, payload + 14) == Some(instance),
+            Some(&1) | Some(&17) if ipv4 => Self::get_u16(quote, 4) == Some(instance),
+            _ => false,
+        };
+    }
+
+    /// Read the big endian u16 at the position of the buffer
+    fn get_u16(buffer: &[u8], position: usize) -> Option<u16> {
+        if buffer.len() < position + 2 {
+            return None;
+        }
+        return Some((buffer[position] as u16) << 8 | buffer[position + 1] as u16);
+    }
+
+    /// Send the responce to the channel, returning error if the channel is closed.
+    fn send_responce(
+        sender: &mpsc::Sender<IcmpResponce>,
+        timestamp: KernelTimestamp,
+        source: IpAddr,
+        ttl: u8,
+        size: usize,
+        icmp: Responce,
+        extensions: IcmpExtensions,
+    ) -> Result<(), ()> {
+        let responce = IcmpResponce {
+            source: source,
+            ttl: ttl,
+            icmp: icmp,
+            extensions: extensions,
+            size: size.min(u16::max_value() as usize) as u16,
+            time_ms: Self::time_from_epoch_ms(),
+            time: Instant::now(),
+            timestamp: timestamp,
+        };
+        if let Err(_) = sender.send(responce) {
+            return Err(());
+        }
+        return Ok(());
+    }
+
     /// Get the current time in milliseconds
     fn time_from_epoch_ms() -> u64 {
         let start = SystemTime::now();
@@ -166,3 +373,106 @@ impl PingReader {
         return in_ms;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCE: u16 = 0x1234;
+
+    /// Payload of a probe of the instance, with its identifier, sequence, timestamp and MAC
+    fn payload(instance: u16) -> Vec<u8> {
+        let mut payload = vec![0xab, 0xcd, 0x00, 0x05, 0, 0, 1, 0x5e, 0x9e, 0x2f, 0x00, 0x00, 0x77, 0x88];
+        payload.extend_from_slice(&[(instance >> 8) as u8, instance as u8, 0, 0]);
+        return payload;
+    }
+
+    /// Quote of an IPv4 probe of the protocol with the identification, followed by the
+    /// transport header and the given payload
+    fn ipv4_quote(protocol: u8, identification: u16, payload: &[u8]) -> Vec<u8> {
+        let mut quote = vec![0x45, 0, 0, 46, (identification >> 8) as u8, identification as u8, 0x40, 0, 1, protocol, 0, 0];
+        quote.extend_from_slice(&[198, 51, 100, 1, 192, 0, 2, 1]);
+        quote.extend_from_slice(&[8, 0, 0, 0, 0xab, 0xcd, 0x00, 0x05]);
+        quote.extend_from_slice(payload);
+        return quote;
+    }
+
+    /// Quote of an IPv6 probe of the protocol, followed by the transport header and the payload
+    fn ipv6_quote(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut quote = vec![0x60, 0, 0, 0, 0, 26, protocol, 1];
+        quote.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        quote.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        quote.extend_from_slice(&[128, 0, 0, 0, 0xab, 0xcd, 0x00, 0x05]);
+        quote.extend_from_slice(payload);
+        return quote;
+    }
+
+    fn error(kind: u8, quote: &[u8]) -> Vec<u8> {
+        let mut packet = vec![kind, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(quote);
+        return packet;
+    }
+
+    #[test]
+    fn instance_of_the_echo_replies() {
+        for &kind in &[0, 129] {
+            let mut reply = vec![kind, 0, 0, 0, 0xab, 0xcd, 0x00, 0x05];
+            reply.extend_from_slice(&payload(INSTANCE));
+            assert!(PingReader::verify_instance(&reply, INSTANCE));
+            assert!(!PingReader::verify_instance(&reply, INSTANCE + 1));
+            assert!(!PingReader::verify_instance(&reply[..20], INSTANCE));
+        }
+        // Other messages are not replies to the probes
+        assert!(PingReader::verify_instance(&[8, 0, 0, 0], INSTANCE));
+    }
+
+    #[test]
+    fn instance_of_the_quoted_payload() {
+        // The payload is checked even if a NAT rewrote the identification
+        for &protocol in &[1, 17] {
+            let quote = ipv4_quote(protocol, 0x9999, &payload(INSTANCE));
+            assert!(PingReader::verify_instance(&error(11, &quote), INSTANCE));
+            assert!(PingReader::verify_instance(&error(3, &quote), INSTANCE));
+            let quote = ipv4_quote(protocol, INSTANCE, &payload(0x9999));
+            assert!(!PingReader::verify_instance(&error(11, &quote), INSTANCE));
+        }
+        for &kind in &[1, 3] {
+            let quote = ipv6_quote(58, &payload(INSTANCE));
+            assert!(PingReader::verify_instance(&error(kind, &quote), INSTANCE));
+            let quote = ipv6_quote(17, &payload(0x9999));
+            assert!(!PingReader::verify_instance(&error(kind, &quote), INSTANCE));
+        }
+    }
+
+    #[test]
+    fn instance_of_the_short_quotes() {
+        // IPv4 errors quoting 8 bytes of the probe are checked with the identification
+        let quote = ipv4_quote(1, INSTANCE, &[]);
+        assert_eq!(quote.len(), 28);
+        assert!(PingReader::verify_instance(&error(11, &quote), INSTANCE));
+        assert!(!PingReader::verify_instance(&error(11, &ipv4_quote(17, 0x9999, &[])), INSTANCE));
+        // As the quotes padded with zeros for the extensions
+        let quote = ipv4_quote(17, INSTANCE, &[0; 100]);
+        assert!(PingReader::verify_instance(&error(3, &quote), INSTANCE));
+        assert!(!PingReader::verify_instance(&error(3, &ipv4_quote(17, 0x9999, &[0; 100])), INSTANCE));
+        // IPv6 has no identification, so the payload is required
+        assert!(!PingReader::verify_instance(&error(3, &ipv6_quote(58, &[])), INSTANCE));
+        assert!(!PingReader::verify_instance(&error(3, &ipv6_quote(58, &[0; 100])), INSTANCE));
+        // Errors without a quote of a known protocol are rejected
+        assert!(!PingReader::verify_instance(&error(11, &[]), INSTANCE));
+        assert!(!PingReader::verify_instance(&error(11, &ipv4_quote(47, INSTANCE, &[])), INSTANCE));
+    }
+
+    #[test]
+    fn instance_of_the_tcp_quotes() {
+        let mut quote = ipv4_quote(6, 0x9999, &[]);
+        quote[20..22].clone_from_slice(&[(INSTANCE >> 8) as u8, INSTANCE as u8]);
+        assert!(PingReader::verify_instance(&error(11, &quote), INSTANCE));
+        quote[21] ^= 1;
+        assert!(!PingReader::verify_instance(&error(11, &quote), INSTANCE));
+
+        let mut quote = ipv6_quote(6, &[]);
+        quote[40..42].clone_from_slice(&[(INSTANCE >> 8) as u8, INSTANCE as u8]);
+        assert!(PingReader::verify_instance(&error(3, &quote), INSTANCE));
+    }
+}
@@ -0,0 +1,697 @@
+use ping::PingMethod;
+use ping::memory::MemoryReader;
+use ping::topology::{Balance, Topology};
+use ping::transport::{Transport, TransportReader, TransportWriter};
+use ping::writer::PingWriter;
+
+use pnet::packet::ip::IpNextHeaderProtocol;
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+
+/// Transport answering the probes with a simulated network, described by a `Topology`.
+///
+/// The probes are sent from the router of the local site. Every router on the path
+/// decrements the TTL, answering with a Time Exceeded when it expires, and the destination
+/// answers with an Echo Reply, a Port Unreachable or a TCP RST. The routers prohibiting the
+/// packets answer with a Destination Unreachable instead of forwarding them. The replies are only received
+/// when the local site is the closest one to the router sending them.
+pub struct SimulatedTransport {
+    topology: Arc<Topology>,
+    site: usize,
+}
+
+impl SimulatedTransport {
+    /// Create a new simulated transport for the given site of the topology.
+    ///
+    /// The first site of the topology is used if none is given.
+    pub fn new(topology: Topology, site: Option<&str>) -> Result<SimulatedTransport, String> {
+        let site = match site {
+            Some(name) => match topology.get_site(name) {
+                Some(site) => site,
+                None => return Err(format!("Unknown site {}", name)),
+            },
+            None => 0,
+        };
+        return Ok(SimulatedTransport {
+            topology: Arc::new(topology),
+            site: site,
+        });
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn open(
+        &mut self,
+        _local: IpAddr,
+        _method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)> {
+        let (delivery, queue) = mpsc::channel();
+        let (received_tx, received_rx) = mpsc::channel();
+        thread::spawn(move || Self::deliver(queue, received_tx));
+
+        let writer = SimulatedWriter {
+            topology: self.topology.clone(),
+            site: self.site,
+            random: self.topology.seed.max(1),
+            buckets: HashMap::new(),
+            delivery: delivery,
+        };
+        return Ok((Box::new(writer), vec![Box::new(MemoryReader(received_rx))]));
+    }
+}
+
+impl SimulatedTransport {
+    /// Deliver the replies to the reader once their delay is elapsed.
+    ///
+    /// The thread ends when the writer is closed and every pending reply was delivered.
+    fn deliver(queue: mpsc::Receiver<(Instant, Vec<u8>)>, received: mpsc::Sender<Vec<u8>>) {
+        let mut pending: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>> = BinaryHeap::new();
+        let mut order: u64 = 0;
+        let mut closed = false;
+        loop {
+            let now = Instant::now();
+            while pending
+                .peek()
+                .map(|&Reverse((time, _, _))| time <= now)
+                .unwrap_or(false)
+            {
+                let Reverse((_, _, packet)) = pending.pop().unwrap();
+                if let Err(_) = received.send(packet) {
+                    return;
+                }
+            }
+
+            let next = pending.peek().map(|&Reverse((time, _, _))| time);
+            let reply = match (next, closed) {
+                (None, true) => return,
+                (Some(time), true) => {
+                    thread::sleep(time.duration_since(now));
+                    continue;
+                }
+                (Some(time), false) => queue.recv_timeout(time.duration_since(now)),
+                (None, false) => queue
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match reply {
+                Ok((time, packet)) => {
+                    order += 1;
+                    pending.push(Reverse((time, order, packet)));
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => closed = true,
+            }
+        }
+    }
+}
+
+/// Fields of the probe used by the simulation
+struct Probe<'a> {
+    packet: &'a [u8],
+    header: usize,
+    source: IpAddr,
+    destination: IpAddr,
+    ttl: u8,
+    protocol: u8,
+}
+
+impl<'a> Probe<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Probe<'a>> {
+        match packet.first() {
+            Some(version) if version >> 4 == 4 && packet.len() >= 20 => {
+                let header = (packet[0] & 0x0F) as usize * 4;
+                if header < 20 || packet.len() < header {
+                    return None;
+                }
+                let mut source = [0u8; 4];
+                let mut destination = [0u8; 4];
+                source.clone_from_slice(&packet[12..16]);
+                destination.clone_from_slice(&packet[16..20]);
+                return Some(Probe {
+                    packet: packet,
+                    header: header,
+                    source: IpAddr::V4(Ipv4Addr::from(source)),
+                    destination: IpAddr::V4(Ipv4Addr::from(destination)),
+                    ttl: packet[8],
+                    protocol: packet[9],
+                });
+            }
+            Some(version) if version >> 4 == 6 && packet.len() >= 40 => {
+                let mut source = [0u8; 16];
+                let mut destination = [0u8; 16];
+                source.clone_from_slice(&packet[8..24]);
+                destination.clone_from_slice(&packet[24..40]);
+                return Some(Probe {
+                    packet: packet,
+                    header: 40,
+                    source: IpAddr::V6(Ipv6Addr::from(source)),
+                    destination: IpAddr::V6(Ipv6Addr::from(destination)),
+                    ttl: packet[7],
+                    protocol: packet[6],
+                });
+            }
+            _ => return None,
+        }
+    }
+
+    fn payload(&self) -> &'a [u8] {
+        return &self.packet[self.header..];
+    }
+
+    fn is_ipv6(&self) -> bool {
+        return self.header == 40 && self.packet[0] >> 4 == 6;
+    }
+
+    /// Get the identifier of the flow used by the load balancers
+    fn flow(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let payload = self.payload();
+        let ports = &payload[..payload.len().min(4)];
+        let addresses = if self.is_ipv6() {
+            &self.packet[8..40]
+        } else {
+            &self.packet[12..20]
+        };
+        for byte in addresses.iter().chain(ports).chain(&[self.protocol]) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        return hash;
+    }
+
+    /// Get a copy of the probe as received with the given TTL, limited to the length
+    fn quote(&self, ttl: u8, length: usize) -> Vec<u8> {
+        let mut quote = self.packet.to_vec();
+        if self.is_ipv6() {
+            quote[7] = ttl;
+        } else {
+            quote[8] = ttl;
+            quote[10] = 0;
+            quote[11] = 0;
+            let check = checksum(&quote[..self.header], 0);
+            quote[10] = (check >> 8) as u8;
+            quote[11] = check as u8;
+        }
+        quote.truncate(length);
+        return quote;
+    }
+}
+
+/// Node answering a probe
+struct Responder {
+    address: IpAddr,
+    router: usize,
+    hops: u32,
+    ittl: u8,
+}
+
+struct SimulatedWriter {
+    topology: Arc<Topology>,
+    site: usize,
+    random: u64,
+    buckets: HashMap<usize, (f64, Instant)>,
+    delivery: mpsc::Sender<(Instant, Vec<u8>)>,
+}
+
+impl TransportWriter for SimulatedWriter {
+    fn send_to(&mut self, packet: &[u8], _target: IpAddr) -> io::Result<usize> {
+        if let Some((delay, reply)) = self.simulate(packet) {
+            let time = Instant::now() + Duration::from_millis(delay);
+            if let Err(_) = self.delivery.send((time, reply)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "The simulated network was closed",
+                ));
+            }
+        }
+        return Ok(packet.len());
+    }
+}
+
+impl SimulatedWriter {
+    /// Forward the probe through the topology, returning the delay in milliseconds
+    /// and the reply received by the local site, if any.
+    fn simulate(&mut self, packet: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let topology = self.topology.clone();
+        let probe = Probe::parse(packet)?;
+        let target_router = topology.find_router(probe.destination);
+        let network = topology.find_network(probe.destination);
+        let gateway = match (target_router, network) {
+            (Some(router), _) => router,
+            (None, Some(network)) => network.router,
+            (None, None) => return None,
+        };
+
+        let mut current = topology.sites[self.site].1;
+        let mut ttl = probe.ttl;
+        let mut delay = 0;
+        loop {
+            let router = &topology.routers[current];
+            if target_router == Some(current) {
+                // The probe is addressed to the router itself
+                if router.filtered || !self.allow(current) {
+                    return None;
+                }
+                let responder = Responder {
+                    address: router.address,
+                    router: current,
+                    hops: 0,
+                    ittl: router.ittl,
+                };
+                let reply = self.reply_destination(&probe, ttl, &responder)?;
+                return self.send_back(responder, delay, reply);
+            }
+
+            if router.decrement > 0 && ttl <= router.decrement {
+                if router.filtered || !self.allow(current) {
+                    return None;
+                }
+                let responder = Responder {
+                    address: router.address,
+                    router: current,
+                    hops: 0,
+                    ittl: router.ittl,
+                };
+                // ICMPv6 errors quote as much of the probe as possible (RFC 4443)
+                let length = if probe.is_ipv6() { 1232 } else { router.quote.max(probe.header + 8) };
+                let quote = probe.quote(ttl, length);
+                let reply = build_error(&probe, responder.address, ErrorKind::TimeExceeded, quote, router.label);
+                return self.send_back(responder, delay, reply);
+            }
+            ttl -= router.decrement;
+
+            if router.prohibit {
+                if router.filtered || !self.allow(current) {
+                    return None;
+                }
+                let responder = Responder {
+                    address: router.address,
+                    router: current,
+                    hops: 0,
+                    ittl: router.ittl,
+                };
+                let length = if probe.is_ipv6() { 1232 } else { router.quote.max(probe.header + 8) };
+                let quote = probe.quote(ttl, length);
+                let reply = build_error(&probe, responder.address, ErrorKind::Prohibited, quote, None);
+                return self.send_back(responder, delay, reply);
+            }
+
+            if current == gateway {
+                let network = network?;
+                if network.silent {
+                    return None;
+                }
+                let responder = Responder {
+                    address: probe.destination,
+                    router: current,
+                    hops: 1,
+                    ittl: network.ittl,
+                };
+                let reply = self.reply_destination(&probe, ttl, &responder)?;
+                return self.send_back(responder, delay, reply);
+            }
+
+            let next_hops = topology.next_hops(current, gateway);
+            let next = match (next_hops.len(), &router.balance) {
+                (0, _) => return None,
+                (1, _) | (_, &Balance::None) => 0,
+                (count, &Balance::Flow) => (probe.flow() % count as u64) as usize,
+                (count, &Balance::Packet) => (self.next_random() % count as u64) as usize,
+            };
+            let (next, link) = next_hops[next];
+            if self.lost(link.loss) {
+                return None;
+            }
+            delay += link.delay_ms;
+            current = next;
+        }
+    }
+
+    /// Build the reply of the destination of the probe
+    fn reply_destination(&self, probe: &Probe, ttl: u8, responder: &Responder) -> Option<Vec<u8>> {
+        let payload = probe.payload();
+        return match probe.protocol {
+            ICMP | ICMPV6 => {
+                let request = if probe.is_ipv6() { 128 } else { 8 };
+                if payload.len() < 8 || payload[0] != request {
+                    return None;
+                }
+                let mut icmp = payload.to_vec();
+                icmp[0] = if probe.is_ipv6() { 129 } else { 0 };
+                Some(build_packet(probe, responder.address, probe.protocol, icmp))
+            }
+            UDP => {
+                let length = if probe.is_ipv6() { 1232 } else { 548 };
+                let quote = probe.quote(ttl, length);
+                Some(build_error(probe, responder.address, ErrorKind::PortUnreachable, quote, None))
+            }
+            TCP => {
+                if payload.len() < 20 {
+                    return None;
+                }
+                let mut tcp = vec![0u8; 20];
+                tcp[0..2].clone_from_slice(&payload[2..4]);
+                tcp[2..4].clone_from_slice(&payload[0..2]);
+                let sequence = read_u32(&payload[4..8]);
+                let acknowledgement = read_u32(&payload[8..12]);
+                if payload[13] & 0x02 != 0 {
+                    // SYN probe, answered with RST-ACK
+                    write_u32(&mut tcp[8..12], sequence.wrapping_add(1));
+                    tcp[13] = 0x14;
+                } else {
+                    // ACK probe, answered with RST
+                    write_u32(&mut tcp[4..8], acknowledgement);
+                    tcp[13] = 0x04;
+                }
+                tcp[12] = 5 << 4;
+                Some(build_packet(probe, responder.address, TCP, tcp))
+            }
+            _ => None,
+        };
+    }
+
+    /// Forward the reply from the responder to its closest site, returning it
+    /// only if that site is the local one and there is a path back to it.
+    fn send_back(&mut self, responder: Responder, delay: u64, mut reply: Vec<u8>) -> Option<(u64, Vec<u8>)> {
+        let topology = self.topology.clone();
+        if topology.catchment(responder.router) != Some(self.site) {
+            return None;
+        }
+        let site = topology.sites[self.site].1;
+        let mut current = responder.router;
+        let mut delay = delay;
+        while current != site {
+            let (next, link) = *topology.next_hops(current, site).first()?;
+            if self.lost(link.loss) {
+                return None;
+            }
+            delay += link.delay_ms;
+            current = next;
+        }
+
+        let hops = responder.hops + topology.distance(responder.router, site);
+        if hops >= responder.ittl as u32 {
+            return None;
+        }
+        let ttl = responder.ittl - hops as u8;
+        if reply[0] >> 4 == 6 {
+            reply[7] = ttl;
+        } else {
+            reply[8] = ttl;
+            reply[10] = 0;
+            reply[11] = 0;
+            let check = checksum(&reply[..20], 0);
+            reply[10] = (check >> 8) as u8;
+            reply[11] = check as u8;
+        }
+        return Some((delay, reply));
+    }
+
+    /// Check the ICMP rate limit of the router, consuming a packet of its budget
+    fn allow(&mut self, router: usize) -> bool {
+        let limit = match self.topology.routers[router].ratelimit {
+            Some(limit) => limit as f64,
+            None => return true,
+        };
+        let now = Instant::now();
+        let bucket = self.buckets.entry(router).or_insert((limit, now));
+        let elapsed = now.duration_since(bucket.1);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        bucket.0 = (bucket.0 + elapsed * limit).min(limit);
+        bucket.1 = now;
+        if bucket.0 < 1.0 {
+            return false;
+        }
+        bucket.0 -= 1.0;
+        return true;
+    }
+
+    /// Check if a packet is lost with the given probability
+    fn lost(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        let value = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+        return value < probability;
+    }
+
+    /// Get the next value of the xorshift generator, deterministic for a given seed
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        return self.random.wrapping_mul(0x2545F4914F6CDD1D);
+    }
+}
+
+enum ErrorKind {
+    TimeExceeded,
+    PortUnreachable,
+    Prohibited,
+}
+
+/// Build the ICMP error sent to the source of the probe, quoting the given bytes.
+///
+/// If the probe expired with a MPLS label, the label stack is appended as an extension
+/// (RFC 4950), padding the quote to 128 bytes and setting its length (RFC 4884).
+fn build_error(probe: &Probe, source: IpAddr, kind: ErrorKind, mut quote: Vec<u8>, label: Option<u32>) -> Vec<u8> {
+    let (kind, code) = match (kind, probe.is_ipv6()) {
+        (ErrorKind::TimeExceeded, false) => (11, 0),
+        (ErrorKind::TimeExceeded, true) => (3, 0),
+        (ErrorKind::PortUnreachable, false) => (3, 3),
+        (ErrorKind::PortUnreachable, true) => (1, 4),
+        (ErrorKind::Prohibited, false) => (3, 13),
+        (ErrorKind::Prohibited, true) => (1, 1),
+    };
+    let mut icmp = vec![kind, code, 0, 0, 0, 0, 0, 0];
+    if let Some(label) = label {
+        quote.resize(128, 0);
+        // The length is stored in 64 bits words on ICMPv6, and 32 bits words on ICMP
+        match probe.is_ipv6() {
+            true => icmp[4] = 128 / 8,
+            false => icmp[5] = 128 / 4,
+        }
+        quote.extend_from_slice(&mpls_extension(label));
+    }
+    icmp.extend_from_slice(&quote);
+    let protocol = if probe.is_ipv6() { ICMPV6 } else { ICMP };
+    return build_packet(probe, source, protocol, icmp);
+}
+
+/// Build the extension structure with the label stack of a packet expiring with the label
+fn mpls_extension(label: u32) -> Vec<u8> {
+    // [version 2, checksum], followed by the object [length, class 1, c-type 1, entry]
+    let mut extension = vec![0x20, 0, 0, 0, 0, 8, 1, 1, 0, 0, 0, 0];
+    // The entry is [label(20), traffic class(3), bottom of stack(1), ttl(8)]
+    write_u32(&mut extension[8..], label << 12 | 1 << 8 | 1);
+    let check = checksum(&extension, 0);
+    extension[2] = (check >> 8) as u8;
+    extension[3] = check as u8;
+    return extension;
+}
+
+/// Build the IP packet sent to the source of the probe, setting the checksum of the payload.
+///
+/// The TTL is set when the packet is delivered.
+fn build_packet(probe: &Probe, source: IpAddr, protocol: u8, mut payload: Vec<u8>) -> Vec<u8> {
+    let offset = match protocol {
+        TCP => 16,
+        _ => 2,
+    };
+    payload[offset] = 0;
+    payload[offset + 1] = 0;
+    let pseudo = if protocol == ICMP {
+        0
+    } else {
+        pseudo_header(source, probe.source, protocol, payload.len())
+    };
+    let check = checksum(&payload, pseudo);
+    payload[offset] = (check >> 8) as u8;
+    payload[offset + 1] = check as u8;
+
+    let mut packet = vec![0u8; if probe.is_ipv6() { 40 } else { 20 }];
+    packet.extend_from_slice(&payload);
+    let protocol = IpNextHeaderProtocol::new(protocol);
+    match (source, probe.source) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            PingWriter::format_ipv4(&mut packet, protocol, source, destination, 0, 0)
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            PingWriter::format_ipv6(&mut packet, protocol, source, destination, 0)
+        }
+        _ => {}
+    }
+    return packet;
+}
+
+/// Get the sum of the pseudo header used by the checksums of TCP, UDP and ICMPv6
+fn pseudo_header(source: IpAddr, destination: IpAddr, protocol: u8, length: usize) -> u32 {
+    let mut bytes = Vec::new();
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            bytes.extend_from_slice(&source.octets());
+            bytes.extend_from_slice(&destination.octets());
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            bytes.extend_from_slice(&source.octets());
+            bytes.extend_from_slice(&destination.octets());
+        }
+        _ => {}
+    }
+    let mut sum = sum_words(&bytes);
+    sum += protocol as u32;
+    sum += (length >> 16) as u32 + (length & 0xFFFF) as u32;
+    return sum;
+}
+
+/// Sum the 16 bits words of the data
+fn sum_words(data: &[u8]) -> u32 {
+    let mut sum: u32 = 0;
+    for word in data.chunks(2) {
+        let high = (word[0] as u32) << 8;
+        let low = if word.len() > 1 { word[1] as u32 } else { 0 };
+        sum = sum.wrapping_add(high | low);
+    }
+    return sum;
+}
+
+/// Calculate the internet checksum of the data, adding the initial sum
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = sum_words(data).wrapping_add(initial);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    return !(sum as u16);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    return (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32;
+}
+
+fn write_u32(bytes: &mut [u8], value: u32) {
+    bytes[0] = (value >> 24) as u8;
+    bytes[1] = (value >> 16) as u8;
+    bytes[2] = (value >> 8) as u8;
+    bytes[3] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPOLOGY: &str = "
+        router gw      10.0.0.1
+        router mid     10.0.1.1
+        router hidden  10.0.2.1  filtered
+        router edge    10.0.3.1
+        router scl-gw  10.1.0.1
+        router island  10.9.0.1
+        router fw      10.0.4.1  prohibit
+        link gw      mid     delay=2
+        link mid     hidden  delay=3
+        link hidden  edge    delay=4
+        link edge    scl-gw  delay=1
+        link mid     fw      delay=1
+        site ams gw
+        site scl scl-gw
+        network 198.51.100.0/24 mid ittl=128
+        network 203.0.113.0/24 edge
+        network 192.0.2.0/24 island
+        network 100.64.0.0/24 fw
+    ";
+
+    fn writer(site: &str) -> SimulatedWriter {
+        let topology = Topology::parse(TOPOLOGY).unwrap();
+        let (delivery, _) = mpsc::channel();
+        return SimulatedWriter {
+            site: topology.get_site(site).unwrap(),
+            topology: Arc::new(topology),
+            random: 1,
+            buckets: HashMap::new(),
+            delivery: delivery,
+        };
+    }
+
+    /// Build an ICMP echo request to the destination
+    fn probe(destination: [u8; 4], ttl: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 44, 0, 0, 0, 0, ttl, ICMP, 0, 0, 192, 0, 2, 100];
+        packet.extend_from_slice(&destination);
+        packet.extend_from_slice(&[8, 0, 0, 0, 0x12, 0x34, 0, 1]);
+        packet.extend_from_slice(&[1; 16]);
+        return packet;
+    }
+
+    /// Get the source, TTL and ICMP type of the reply
+    fn reply(reply: &[u8]) -> (Ipv4Addr, u8, u8) {
+        return (Ipv4Addr::new(reply[12], reply[13], reply[14], reply[15]), reply[8], reply[20]);
+    }
+
+    #[test]
+    fn replies_of_the_path() {
+        let mut ams = writer("ams");
+        let (delay, echo) = ams.simulate(&probe([198, 51, 100, 1], 64)).unwrap();
+        assert_eq!(delay, 4);
+        assert_eq!(reply(&echo), (Ipv4Addr::new(198, 51, 100, 1), 126, 0));
+
+        let (delay, error) = ams.simulate(&probe([198, 51, 100, 1], 1)).unwrap();
+        assert_eq!(delay, 0);
+        assert_eq!(reply(&error), (Ipv4Addr::new(10, 0, 0, 1), 255, 11));
+        // The error quotes the header and the first 8 bytes of the probe
+        assert_eq!(&error[28 + 20..], &probe([198, 51, 100, 1], 1)[20..28]);
+    }
+
+    #[test]
+    fn replies_of_a_firewall() {
+        let mut ams = writer("ams");
+        let (_, prohibited) = ams.simulate(&probe([100, 64, 0, 1], 64)).unwrap();
+        assert_eq!(reply(&prohibited), (Ipv4Addr::new(10, 0, 4, 1), 253, 3));
+        assert_eq!(prohibited[21], 13);
+        let (_, error) = ams.simulate(&probe([100, 64, 0, 1], 3)).unwrap();
+        assert_eq!(reply(&error), (Ipv4Addr::new(10, 0, 4, 1), 253, 11));
+
+        let truth = ams.topology.ground_truth("ams", "100.64.0.1".parse().unwrap()).unwrap();
+        let firewall = Some("10.0.4.1".parse().unwrap());
+        assert_eq!(truth.len(), 3);
+        assert_eq!(truth[2], firewall);
+    }
+
+    #[test]
+    fn replies_lost() {
+        let mut ams = writer("ams");
+        // Filtered router
+        assert!(ams.simulate(&probe([203, 0, 113, 1], 3)).is_none());
+        // Replies delivered to the closest site
+        assert!(ams.simulate(&probe([203, 0, 113, 1], 64)).is_none());
+        assert!(writer("scl").simulate(&probe([203, 0, 113, 1], 64)).is_some());
+        // Destinations without path from the site
+        assert!(ams.simulate(&probe([192, 0, 2, 1], 64)).is_none());
+        assert!(ams.simulate(&probe([192, 0, 2, 1], 1)).is_some());
+    }
+
+    #[test]
+    fn replies_follow_the_ground_truth() {
+        let mut ams = writer("ams");
+        let destination = "203.0.113.1".parse().unwrap();
+        let truth = ams.topology.ground_truth("scl", destination).unwrap();
+        let mut scl = writer("scl");
+        for (index, hop) in truth.iter().enumerate() {
+            let source = scl.simulate(&probe([203, 0, 113, 1], index as u8 + 1))
+                .map(|(_, packet)| IpAddr::V4(reply(&packet).0));
+            assert_eq!(source, *hop);
+        }
+        assert_eq!(truth.len(), 3);
+        assert!(ams.simulate(&probe([10, 0, 2, 1], 64)).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+extern crate libc;
+
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use ping::timestamp;
+use ping::timestamp::{KernelTimestamp, SentTimestamps};
+use ping::transport::PendingTimestamps;
+
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+/// Raw IPv4 socket for a single protocol, sending the packets with their IP header.
+///
+/// The received packets include their IP header, and are only the ones of the protocol.
+pub struct Socket4 {
+    fd: libc::c_int,
+    timestamps: Mutex<SentTimestamps>,
+}
+
+impl Socket4 {
+    /// Open a new raw socket for the given protocol.
+    pub fn new(protocol: IpNextHeaderProtocol) -> io::Result<Socket4> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET,
+                libc::SOCK_RAW,
+                protocol.0 as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket4 {
+            fd: fd,
+            timestamps: Mutex::new(SentTimestamps::new()),
+        };
+
+        let enabled: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_HDRINCL,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Err(e) = timestamp::enable(fd) {
+            warn!("Kernel timestamps not available, using the time of the process: {}", e);
+        }
+        return Ok(socket);
+    }
+
+    /// Send the IPv4 packet to the destination of its header.
+    pub fn send_to(&self, packet: &[u8]) -> io::Result<usize> {
+        let destination = match Ipv4Packet::new(packet) {
+            Some(packet) => packet.get_destination(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid IPv4 packet",
+                ))
+            }
+        };
+        let address = Self::to_sockaddr(destination);
+        let sent = unsafe {
+            libc::sendto(
+                self.fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &address as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(sent as usize);
+    }
+
+    /// Get the kernel transmit timestamp of the IPv4 packet just sent, which is pending if
+    /// it's not available yet.
+    pub fn sent_timestamp(&self, packet: &[u8]) -> KernelTimestamp {
+        return self.timestamps.lock().unwrap().lookup(self.fd, packet);
+    }
+
+    /// Receive the next packet in the buffer, with its IP header.
+    ///
+    /// Return the length of the packet and the kernel timestamps of its reception.
+    pub fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, KernelTimestamp)> {
+        return timestamp::recv(self.fd, buffer);
+    }
+
+    fn to_sockaddr(address: Ipv4Addr) -> libc::sockaddr_in {
+        let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+        // The octets are already in network order
+        sockaddr.sin_addr.s_addr = u32::from_ne_bytes(address.octets());
+        return sockaddr;
+    }
+}
+
+impl PendingTimestamps for Socket4 {
+    fn pending_timestamp(&self, id: u32) -> Option<KernelTimestamp> {
+        return self.timestamps.lock().unwrap().pending(self.fd, id);
+    }
+}
+
+impl Drop for Socket4 {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
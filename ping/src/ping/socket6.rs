@@ -0,0 +1,231 @@
+extern crate libc;
+
+use pnet::packet::Packet;
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv6::Ipv6Packet;
+
+use ping::timestamp;
+use ping::timestamp::{KernelTimestamp, SentTimestamps};
+use ping::transport::PendingTimestamps;
+
+use std::io;
+use std::mem;
+use std::net::Ipv6Addr;
+use std::ptr;
+use std::sync::Mutex;
+
+// Linux socket options, defined here as they are not available in every version of libc
+const IPV6_UNICAST_HOPS: libc::c_int = 16;
+const IPV6_RECVHOPLIMIT: libc::c_int = 51;
+const IPV6_HOPLIMIT: libc::c_int = 52;
+
+/// Raw IPv6 socket for a single protocol.
+///
+/// The kernel does not allow writing the IPv6 header on raw sockets, neither it includes it
+/// on the received packets, so the hop limit and the addresses are set and received
+/// using the socket options and control messages.
+pub struct Socket6 {
+    fd: libc::c_int,
+    protocol: IpNextHeaderProtocol,
+    timestamps: Mutex<SentTimestamps>,
+}
+
+impl Socket6 {
+    /// Open a new raw socket for the given protocol, using the local address as source.
+    pub fn new(protocol: IpNextHeaderProtocol, local: Ipv6Addr) -> io::Result<Socket6> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET6,
+                libc::SOCK_RAW,
+                protocol.0 as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket6 {
+            fd: fd,
+            protocol: protocol,
+            timestamps: Mutex::new(SentTimestamps::new()),
+        };
+
+        let address = Self::to_sockaddr(local);
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        socket.set_option(IPV6_RECVHOPLIMIT, 1)?;
+        if let Err(e) = timestamp::enable(fd) {
+            warn!("Kernel timestamps not available, using the time of the process: {}", e);
+        }
+        return Ok(socket);
+    }
+
+    /// Get the protocol of the socket
+    pub fn protocol(&self) -> IpNextHeaderProtocol {
+        return self.protocol;
+    }
+
+    /// Send the IPv6 packet, using the destination and hop limit of its header.
+    ///
+    /// The source address of the packet is ignored, using the address of the socket.
+    pub fn send_to(&self, packet: &[u8]) -> io::Result<usize> {
+        let packet = match Ipv6Packet::new(packet) {
+            Some(packet) => packet,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid IPv6 packet",
+                ))
+            }
+        };
+        self.set_option(IPV6_UNICAST_HOPS, packet.get_hop_limit() as libc::c_int)?;
+
+        let address = Self::to_sockaddr(packet.get_destination());
+        let payload = packet.payload();
+        let sent = unsafe {
+            libc::sendto(
+                self.fd,
+                payload.as_ptr() as *const libc::c_void,
+                payload.len(),
+                0,
+                &address as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(sent as usize);
+    }
+
+    /// Get the kernel transmit timestamp of the IPv6 packet just sent, which is pending if
+    /// it's not available yet.
+    pub fn sent_timestamp(&self, packet: &[u8]) -> KernelTimestamp {
+        return match Ipv6Packet::new(packet) {
+            Some(packet) => self.timestamps.lock().unwrap().lookup(self.fd, packet.payload()),
+            None => KernelTimestamp::default(),
+        };
+    }
+
+    /// Receive the payload of the next packet in the buffer.
+    ///
+    /// Return the source address, the hop limit of the packet, the length of the payload and
+    /// the kernel timestamps of its reception.
+    pub fn recv(&self, buffer: &mut [u8]) -> io::Result<(Ipv6Addr, u8, usize, KernelTimestamp)> {
+        let mut address: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        let mut control = [0u8; 256];
+        let mut iov = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_name = &mut address as *mut libc::sockaddr_in6 as *mut libc::c_void;
+        message.msg_namelen = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+
+        let length = unsafe { libc::recvmsg(self.fd, &mut message, 0) };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let control = &control[..message.msg_controllen as usize];
+        let mut hop_limit = 0;
+        let mut received = KernelTimestamp::default();
+        for (level, kind, data) in ControlMessages::new(control) {
+            if level == libc::IPPROTO_IPV6 && kind == IPV6_HOPLIMIT && data.len() >= 4 {
+                let value = unsafe { ptr::read_unaligned(data.as_ptr() as *const libc::c_int) };
+                hop_limit = value as u8;
+            } else if let Some(timestamp) = KernelTimestamp::from_control(level, kind, data) {
+                received = timestamp;
+            }
+        }
+        let source = Ipv6Addr::from(address.sin6_addr.s6_addr);
+        return Ok((source, hop_limit, length as usize, received));
+    }
+
+    /// Set an integer option of the IPv6 level.
+    fn set_option(&self, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::IPPROTO_IPV6,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    fn to_sockaddr(address: Ipv6Addr) -> libc::sockaddr_in6 {
+        let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sockaddr.sin6_addr.s6_addr = address.octets();
+        return sockaddr;
+    }
+}
+
+impl PendingTimestamps for Socket6 {
+    fn pending_timestamp(&self, id: u32) -> Option<KernelTimestamp> {
+        return self.timestamps.lock().unwrap().pending(self.fd, id);
+    }
+}
+
+impl Drop for Socket6 {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Iterator over the (level, type, data) of the control messages received with recvmsg.
+pub(crate) struct ControlMessages<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> ControlMessages<'a> {
+    pub(crate) fn new(buffer: &'a [u8]) -> ControlMessages<'a> {
+        return ControlMessages { buffer: buffer };
+    }
+
+    /// Align the length to the size of the control message fields
+    fn align(length: usize) -> usize {
+        let align = mem::size_of::<usize>();
+        return (length + align - 1) & !(align - 1);
+    }
+}
+
+impl<'a> Iterator for ControlMessages<'a> {
+    type Item = (libc::c_int, libc::c_int, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header_length = mem::size_of::<libc::cmsghdr>();
+        if self.buffer.len() < header_length {
+            return None;
+        }
+        let header =
+            unsafe { ptr::read_unaligned(self.buffer.as_ptr() as *const libc::cmsghdr) };
+        let length = header.cmsg_len as usize;
+        if length < header_length || length > self.buffer.len() {
+            return None;
+        }
+        let data = &self.buffer[Self::align(header_length)..length];
+        let next = Self::align(length).min(self.buffer.len());
+        self.buffer = &self.buffer[next..];
+        return Some((header.cmsg_level, header.cmsg_type, data));
+    }
+}
@@ -0,0 +1,374 @@
+extern crate libc;
+
+use ping::socket6::ControlMessages;
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+// Linux socket options, defined here as they are not available in every version of libc
+const SO_TIMESTAMPING: libc::c_int = 37;
+const SCM_TIMESTAMPING: libc::c_int = SO_TIMESTAMPING;
+const SOF_TIMESTAMPING_TX_HARDWARE: libc::c_int = 1 << 0;
+const SOF_TIMESTAMPING_TX_SOFTWARE: libc::c_int = 1 << 1;
+const SOF_TIMESTAMPING_RX_HARDWARE: libc::c_int = 1 << 2;
+const SOF_TIMESTAMPING_RX_SOFTWARE: libc::c_int = 1 << 3;
+const SOF_TIMESTAMPING_SOFTWARE: libc::c_int = 1 << 4;
+const SOF_TIMESTAMPING_RAW_HARDWARE: libc::c_int = 1 << 6;
+const SOF_TIMESTAMPING_OPT_ID: libc::c_int = 1 << 7;
+const IP_RECVERR: libc::c_int = 11;
+const IPV6_RECVERR: libc::c_int = 25;
+const SO_EE_ORIGIN_TIMESTAMPING: u8 = 4;
+
+/// Time the transmit timestamps are kept waiting to be paired with the reply of their packet
+const TIMESTAMP_AGE: Duration = Duration::from_secs(10);
+
+/// Timestamps taken by the kernel when a packet was sent or received.
+///
+/// The software timestamps are the time since the epoch, while the hardware timestamps use
+/// the clock of the network interface, so only timestamps of the same kind can be compared.
+/// Hardware timestamps are only present if they were enabled on the interface, for example
+/// with `hwstamp_ctl`.
+///
+/// The transmit timestamps can be given by the kernel after their packet was sent, as the
+/// hardware ones always are. Those are `pending`, and can be read later from the transport
+/// with `PingWriter::sent_timestamp`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KernelTimestamp {
+    pub software: Option<Duration>,
+    pub hardware: Option<Duration>,
+    /// Identifier of the transmit timestamp of the packet, if it was not available yet
+    pub pending: Option<u32>,
+}
+
+impl KernelTimestamp {
+    /// Get the time elapsed since the earlier timestamp, using the hardware timestamps if
+    /// both packets have them, or the software ones otherwise.
+    pub fn elapsed_since(&self, earlier: &KernelTimestamp) -> Option<Duration> {
+        if let (Some(now), Some(earlier)) = (self.hardware, earlier.hardware) {
+            return now.checked_sub(earlier);
+        }
+        if let (Some(now), Some(earlier)) = (self.software, earlier.software) {
+            return now.checked_sub(earlier);
+        }
+        return None;
+    }
+
+    /// Check if the kernel gave no timestamp
+    pub fn is_empty(&self) -> bool {
+        return self.software.is_none() && self.hardware.is_none();
+    }
+
+    /// Read the timestamps of a control message, if it has them
+    pub(crate) fn from_control(level: libc::c_int, kind: libc::c_int, data: &[u8]) -> Option<KernelTimestamp> {
+        let size = mem::size_of::<libc::timespec>();
+        if level != libc::SOL_SOCKET || kind != SCM_TIMESTAMPING || data.len() < size * 3 {
+            return None;
+        }
+        // The software timestamp is the first, and the raw hardware timestamp the third one
+        let read = |index: usize| {
+            let time = unsafe { ptr::read_unaligned(data[index * size..].as_ptr() as *const libc::timespec) };
+            match time.tv_sec == 0 && time.tv_nsec == 0 {
+                true => None,
+                false => Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32)),
+            }
+        };
+        return Some(KernelTimestamp {
+            software: read(0),
+            hardware: read(2),
+            pending: None,
+        });
+    }
+}
+
+/// Enable the software and hardware timestamps of the packets sent and received by the socket.
+///
+/// The transmit timestamps are identified by the number of packets sent on the socket before
+/// them, counting from zero when they are enabled.
+pub(crate) fn enable(fd: libc::c_int) -> io::Result<()> {
+    let flags = SOF_TIMESTAMPING_TX_HARDWARE | SOF_TIMESTAMPING_TX_SOFTWARE
+        | SOF_TIMESTAMPING_RX_HARDWARE | SOF_TIMESTAMPING_RX_SOFTWARE
+        | SOF_TIMESTAMPING_SOFTWARE | SOF_TIMESTAMPING_RAW_HARDWARE
+        | SOF_TIMESTAMPING_OPT_ID;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_TIMESTAMPING,
+            &flags as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+/// Packet looped back in the error queue of a socket with its transmit timestamp.
+///
+/// The content is only kept while no sent packet was identified with it.
+struct LoopedPacket {
+    data: Vec<u8>,
+    timestamp: KernelTimestamp,
+    received: Instant,
+}
+
+impl LoopedPacket {
+    fn contains(&self, sent: &[u8]) -> bool {
+        return !sent.is_empty() && self.data.windows(sent.len()).any(|window| window == sent);
+    }
+}
+
+/// Transmit timestamps of the packets sent on a socket, read from its error queue.
+///
+/// The kernel loops back the sent packets with their timestamps, including the headers of
+/// lower layers, and the identifier of the packet (the number of packets sent before it).
+/// Each packet sent is identified when it's sent, by the count of packets sent, or by its
+/// content if the kernel and our count disagree, for example when a send failed after being
+/// counted or the kernel doesn't identify the timestamps.
+///
+/// The timestamps given after their packet was sent are kept by identifier, until they are
+/// read when the reply of the packet is processed, or they are older than `TIMESTAMP_AGE`.
+pub(crate) struct SentTimestamps {
+    next_id: u32,
+    looped: HashMap<u32, LoopedPacket>,
+}
+
+impl SentTimestamps {
+    pub(crate) fn new() -> SentTimestamps {
+        return SentTimestamps {
+            next_id: 0,
+            looped: HashMap::new(),
+        };
+    }
+
+    /// Identify the packet just sent on the socket, getting its transmit timestamp without
+    /// blocking.
+    ///
+    /// If the timestamp is not available yet, it's pending with the identifier of the packet.
+    pub(crate) fn lookup(&mut self, fd: libc::c_int, sent: &[u8]) -> KernelTimestamp {
+        self.drain(fd);
+        let id = self.next_id;
+        let found = match self.looped.get(&id) {
+            Some(looped) if looped.contains(sent) => Some(id),
+            _ => self.looped
+                .iter()
+                .find(|&(_, looped)| looped.contains(sent))
+                .map(|(&key, _)| key),
+        };
+        let id = found.unwrap_or(id);
+        self.next_id = id.wrapping_add(1);
+
+        // The content of the packets sent before is not looked up anymore
+        let next_id = self.next_id;
+        for (_, looped) in self.looped.iter_mut().filter(|&(&key, _)| Self::before(key, next_id)) {
+            looped.data = Vec::new();
+        }
+        return match self.looped.remove(&id) {
+            Some(looped) => looped.timestamp,
+            None => KernelTimestamp {
+                pending: Some(id),
+                ..KernelTimestamp::default()
+            },
+        };
+    }
+
+    /// Get the transmit timestamp of the packet sent with the identifier, if the kernel already
+    /// gave it, without blocking. The timestamp is removed once it's read.
+    pub(crate) fn pending(&mut self, fd: libc::c_int, id: u32) -> Option<KernelTimestamp> {
+        self.drain(fd);
+        return self.looped.remove(&id).map(|looped| looped.timestamp);
+    }
+
+    /// Check if the identifier is of a packet sent before the other one, on a wrapping count
+    fn before(id: u32, other: u32) -> bool {
+        return (id.wrapping_sub(other) as i32) < 0;
+    }
+
+    /// Read every packet of the error queue of the socket, without blocking, and forget the
+    /// timestamps older than `TIMESTAMP_AGE`.
+    fn drain(&mut self, fd: libc::c_int) {
+        self.looped.retain(|_, looped| looped.received.elapsed() < TIMESTAMP_AGE);
+        let mut buffer = [0u8; 2048];
+        let mut control = [0u8; 256];
+        loop {
+            let mut iov = libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            };
+            let mut message: libc::msghdr = unsafe { mem::zeroed() };
+            message.msg_iov = &mut iov;
+            message.msg_iovlen = 1;
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            message.msg_controllen = control.len() as _;
+
+            let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+            let length = unsafe { libc::recvmsg(fd, &mut message, flags) };
+            if length < 0 {
+                return;
+            }
+
+            let mut timestamp = None;
+            let mut id = None;
+            for (level, kind, data) in ControlMessages::new(&control[..message.msg_controllen as usize]) {
+                if let Some(sent) = KernelTimestamp::from_control(level, kind, data) {
+                    timestamp = Some(sent);
+                } else if let Some(key) = Self::timestamp_id(level, kind, data) {
+                    id = Some(key);
+                }
+            }
+            if let Some(timestamp) = timestamp {
+                // Without identifier, only the last packet read is kept
+                let id = id.unwrap_or(self.next_id);
+                let data = match Self::before(id, self.next_id) {
+                    true => Vec::new(),
+                    false => buffer[..length as usize].to_vec(),
+                };
+                let looped = LoopedPacket {
+                    data: data,
+                    timestamp: timestamp,
+                    received: Instant::now(),
+                };
+                self.looped.insert(id, looped);
+            }
+        }
+    }
+
+    /// Read the identifier of a timestamp in the extended error of its control message.
+    fn timestamp_id(level: libc::c_int, kind: libc::c_int, data: &[u8]) -> Option<u32> {
+        let recverr = (level == libc::IPPROTO_IP && kind == IP_RECVERR)
+            || (level == libc::IPPROTO_IPV6 && kind == IPV6_RECVERR);
+        // struct sock_extended_err: errno (u32), origin, type, code, pad (u8), info, data (u32)
+        if !recverr || data.len() < 16 || data[4] != SO_EE_ORIGIN_TIMESTAMPING {
+            return None;
+        }
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&data[12..16]);
+        return Some(u32::from_ne_bytes(id));
+    }
+}
+
+/// Receive the next packet of the socket in the buffer, with its kernel timestamps.
+///
+/// Return the length of the packet, which includes the IP header on IPv4 raw sockets.
+pub(crate) fn recv(fd: libc::c_int, buffer: &mut [u8]) -> io::Result<(usize, KernelTimestamp)> {
+    let mut control = [0u8; 256];
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control.len() as _;
+
+    let length = unsafe { libc::recvmsg(fd, &mut message, 0) };
+    if length < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut timestamp = KernelTimestamp::default();
+    for (level, kind, data) in ControlMessages::new(&control[..message.msg_controllen as usize]) {
+        if let Some(received) = KernelTimestamp::from_control(level, kind, data) {
+            timestamp = received;
+        }
+    }
+    return Ok((length as usize, timestamp));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Open a UDP socket sending to itself on the loopback, with timestamps enabled.
+    fn loopback_socket() -> libc::c_int {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        assert!(fd >= 0);
+        let mut address: libc::sockaddr_in = unsafe { mem::zeroed() };
+        address.sin_family = libc::AF_INET as libc::sa_family_t;
+        address.sin_addr.s_addr = u32::from_ne_bytes([127, 0, 0, 1]);
+        let size = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        let pointer = &mut address as *mut libc::sockaddr_in as *mut libc::sockaddr;
+        let mut length = size;
+        unsafe {
+            assert_eq!(libc::bind(fd, pointer, size), 0);
+            assert_eq!(libc::getsockname(fd, pointer, &mut length), 0);
+            assert_eq!(libc::connect(fd, pointer, size), 0);
+        }
+        enable(fd).unwrap();
+        return fd;
+    }
+
+    fn send(fd: libc::c_int, payload: &[u8]) {
+        let sent = unsafe { libc::send(fd, payload.as_ptr() as *const libc::c_void, payload.len(), 0) };
+        assert_eq!(sent, payload.len() as isize);
+    }
+
+    #[test]
+    fn lookup_each_packet_sent() {
+        let fd = loopback_socket();
+        let mut timestamps = SentTimestamps::new();
+        for index in 0..20u8 {
+            let payload = [index; 16];
+            send(fd, &payload);
+            let timestamp = timestamps.lookup(fd, &payload);
+            assert!(timestamp.software.is_some());
+            assert_eq!(timestamp.pending, None);
+        }
+        assert!(timestamps.looped.is_empty());
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn lookup_after_missed_packets() {
+        let fd = loopback_socket();
+        let mut timestamps = SentTimestamps::new();
+        // The timestamps of the first packets are never looked up
+        send(fd, &[1; 16]);
+        send(fd, &[2; 16]);
+        send(fd, &[3; 16]);
+        assert!(timestamps.lookup(fd, &[3; 16]).software.is_some());
+
+        send(fd, &[4; 16]);
+        assert!(timestamps.lookup(fd, &[4; 16]).software.is_some());
+        assert_eq!(timestamps.next_id, 4);
+        assert_eq!(timestamps.lookup(fd, &[5; 16]).pending, Some(4));
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn pending_after_later_packets() {
+        let fd = loopback_socket();
+        let mut timestamps = SentTimestamps::new();
+        // The timestamp of the first packet is only given after the second one is sent,
+        // as the hardware timestamps are
+        let first = timestamps.lookup(fd, &[1; 16]);
+        assert_eq!(first, KernelTimestamp { pending: Some(0), ..KernelTimestamp::default() });
+        send(fd, &[1; 16]);
+        send(fd, &[2; 16]);
+        assert!(timestamps.lookup(fd, &[2; 16]).software.is_some());
+        assert_eq!(timestamps.next_id, 2);
+
+        // The late timestamp is kept until it's read, without its content
+        assert!(timestamps.looped[&0].data.is_empty());
+        assert!(timestamps.pending(fd, 0).unwrap().software.is_some());
+        assert!(timestamps.pending(fd, 0).is_none());
+        assert!(timestamps.looped.is_empty());
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn pending_expire() {
+        let fd = loopback_socket();
+        let mut timestamps = SentTimestamps::new();
+        assert_eq!(timestamps.lookup(fd, &[1; 16]).pending, Some(0));
+        send(fd, &[1; 16]);
+        timestamps.drain(fd);
+        timestamps.looped.get_mut(&0).unwrap().received -= TIMESTAMP_AGE;
+        assert!(timestamps.pending(fd, 0).is_none());
+        unsafe { libc::close(fd) };
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+
+/// Description of a simulated network, used by the SimulatedTransport.
+///
+/// The topology is loaded from a text file, with one element per line:
+///
+/// ```text
+/// # Comments start with '#'
+/// seed 42
+/// router <name> <address> [filtered] [prohibit] [balance[=flow|packet]] [decrement=1] [ratelimit=pps] [ittl=255] [quote=28] [label=N]
+/// link <router> <router> [delay=ms] [loss=probability]
+/// site <name> <router>
+/// network <prefix> <router> [ittl=64] [silent]
+/// ```
+///
+/// * `filtered` routers forward the packets but never answer.
+/// * `prohibit` routers drop the packets they should forward, answering with a Destination
+///   Unreachable (administratively prohibited), as the firewalls in the middle of a path.
+/// * `balance` routers distribute the packets among every shortest next hop, using the flow
+///   identifier (addresses, protocol and the first 4 bytes of the transport header) or
+///   a random choice per packet.
+/// * `decrement` is the value subtracted from the TTL, where 0 hides the router (as in MPLS tunnels).
+/// * `ratelimit` is the maximum of ICMP packets per second generated by the router.
+/// * `ittl` is the initial TTL of the packets generated by the router or the hosts.
+/// * `quote` is the amount of bytes of the original packet quoted in the ICMP errors, as ICMPv6
+///   errors always quote the complete packet.
+/// * `label` is the MPLS label of the packets expiring on the router, added to the ICMP errors
+///   as an extension (RFC 4950).
+/// * `silent` networks receive the packets but never answer.
+///
+/// Sites are the anycast nodes that share the local address. Every reply is delivered
+/// to the site closest (in hops) to the router sending it.
+pub struct Topology {
+    pub(crate) routers: Vec<Router>,
+    pub(crate) links: Vec<Vec<(usize, Link)>>,
+    pub(crate) networks: Vec<Network>,
+    pub(crate) sites: Vec<(String, usize)>,
+    pub(crate) seed: u64,
+    names: HashMap<String, usize>,
+    distances: Vec<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Balance {
+    None,
+    Flow,
+    Packet,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Router {
+    pub name: String,
+    pub address: IpAddr,
+    pub filtered: bool,
+    pub prohibit: bool,
+    pub balance: Balance,
+    pub decrement: u8,
+    pub ratelimit: Option<u32>,
+    pub ittl: u8,
+    pub quote: usize,
+    pub label: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Link {
+    pub delay_ms: u64,
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Network {
+    pub address: IpAddr,
+    pub prefix: u8,
+    pub router: usize,
+    pub ittl: u8,
+    pub silent: bool,
+}
+
+/// Distance used for unreachable routers
+const UNREACHABLE: u32 = u32::max_value();
+
+impl Topology {
+    /// Load the topology from the given file
+    pub fn load(path: &str) -> Result<Topology, String> {
+        let mut content = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+            return Err(format!("Unable to read {}: {}", path, e));
+        }
+        return Self::parse(&content);
+    }
+
+    /// Parse the topology description
+    pub fn parse(content: &str) -> Result<Topology, String> {
+        let mut topology = Topology {
+            routers: Vec::new(),
+            links: Vec::new(),
+            networks: Vec::new(),
+            sites: Vec::new(),
+            seed: 1,
+            names: HashMap::new(),
+            distances: Vec::new(),
+        };
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let result = match fields[0] {
+                "seed" => topology.parse_seed(&fields[1..]),
+                "router" => topology.parse_router(&fields[1..]),
+                "link" => topology.parse_link(&fields[1..]),
+                "site" => topology.parse_site(&fields[1..]),
+                "network" => topology.parse_network(&fields[1..]),
+                other => Err(format!("Unknown element '{}'", other)),
+            };
+            if let Err(e) = result {
+                return Err(format!("Line {}: {}", number + 1, e));
+            }
+        }
+
+        if topology.sites.is_empty() {
+            return Err("The topology must have at least one site".to_string());
+        }
+        topology.distances = (0..topology.routers.len())
+            .map(|router| topology.bfs(router))
+            .collect();
+        return Ok(topology);
+    }
+
+    fn parse_seed(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() != 1 {
+            return Err("Expected: seed <number>".to_string());
+        }
+        self.seed = parse_value(fields[0])?;
+        return Ok(());
+    }
+
+    fn parse_router(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() < 2 {
+            return Err("Expected: router <name> <address> [options]".to_string());
+        }
+        if self.names.contains_key(fields[0]) {
+            return Err(format!("Duplicated router {}", fields[0]));
+        }
+        let mut router = Router {
+            name: fields[0].to_string(),
+            address: parse_value(fields[1])?,
+            filtered: false,
+            prohibit: false,
+            balance: Balance::None,
+            decrement: 1,
+            ratelimit: None,
+            ittl: 255,
+            quote: 28,
+            label: None,
+        };
+        for option in &fields[2..] {
+            let (key, value) = split_option(option);
+            match (key, value) {
+                ("filtered", None) => router.filtered = true,
+                ("prohibit", None) => router.prohibit = true,
+                ("balance", None) | ("balance", Some("flow")) => router.balance = Balance::Flow,
+                ("balance", Some("packet")) => router.balance = Balance::Packet,
+                ("decrement", Some(value)) => router.decrement = parse_value(value)?,
+                ("ratelimit", Some(value)) => router.ratelimit = Some(parse_value(value)?),
+                ("ittl", Some(value)) => router.ittl = parse_value(value)?,
+                ("quote", Some(value)) => router.quote = parse_value(value)?,
+                ("label", Some(value)) => router.label = Some(parse_value(value)?),
+                _ => return Err(format!("Unknown router option '{}'", option)),
+            }
+        }
+        self.names.insert(router.name.clone(), self.routers.len());
+        self.routers.push(router);
+        self.links.push(Vec::new());
+        return Ok(());
+    }
+
+    fn parse_link(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() < 2 {
+            return Err("Expected: link <router> <router> [options]".to_string());
+        }
+        let first = self.get_router(fields[0])?;
+        let second = self.get_router(fields[1])?;
+        let mut link = Link {
+            delay_ms: 1,
+            loss: 0.0,
+        };
+        for option in &fields[2..] {
+            match split_option(option) {
+                ("delay", Some(value)) => link.delay_ms = parse_value(value)?,
+                ("loss", Some(value)) => link.loss = parse_value(value)?,
+                _ => return Err(format!("Unknown link option '{}'", option)),
+            }
+        }
+        self.links[first].push((second, link.clone()));
+        self.links[second].push((first, link));
+        return Ok(());
+    }
+
+    fn parse_site(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() != 2 {
+            return Err("Expected: site <name> <router>".to_string());
+        }
+        let router = self.get_router(fields[1])?;
+        self.sites.push((fields[0].to_string(), router));
+        return Ok(());
+    }
+
+    fn parse_network(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() < 2 {
+            return Err("Expected: network <prefix> <router> [options]".to_string());
+        }
+        let mut prefix = fields[0].split('/');
+        let address: IpAddr = parse_value(prefix.next().unwrap())?;
+        let maximum = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let length = match prefix.next() {
+            Some(length) => parse_value(length)?,
+            None => maximum,
+        };
+        if length > maximum {
+            return Err(format!("Invalid prefix length /{} for {}", length, address));
+        }
+        let mut network = Network {
+            address: address,
+            prefix: length,
+            router: self.get_router(fields[1])?,
+            ittl: 64,
+            silent: false,
+        };
+        for option in &fields[2..] {
+            match split_option(option) {
+                ("ittl", Some(value)) => network.ittl = parse_value(value)?,
+                ("silent", None) => network.silent = true,
+                _ => return Err(format!("Unknown network option '{}'", option)),
+            }
+        }
+        self.networks.push(network);
+        return Ok(());
+    }
+
+    fn get_router(&self, name: &str) -> Result<usize, String> {
+        return match self.names.get(name) {
+            Some(router) => Ok(*router),
+            None => Err(format!("Unknown router {}", name)),
+        };
+    }
+
+    /// Get the addresses answering the probes sent from the site to the destination, by TTL
+    /// starting from 1, as the ground truth of the traces.
+    ///
+    /// The filtered routers and silent networks are None, and the routers with a decrement
+    /// of 0 are skipped. The path ends at the first router prohibiting the packets. The load balancers use their first next hop, as without balancing.
+    /// Return None if the destination is unknown or not reachable from the site.
+    pub fn ground_truth(&self, site: &str, destination: IpAddr) -> Option<Vec<Option<IpAddr>>> {
+        let target_router = self.find_router(destination);
+        let network = self.find_network(destination);
+        let gateway = target_router.or(network.map(|network| network.router))?;
+        let mut current = self.sites[self.get_site(site)?].1;
+        let mut hops = Vec::new();
+        loop {
+            let router = &self.routers[current];
+            let answer = if router.filtered { None } else { Some(router.address) };
+            if target_router == Some(current) {
+                hops.push(answer);
+                return Some(hops);
+            }
+            for _ in 0..router.decrement {
+                hops.push(answer);
+            }
+            if router.prohibit {
+                return Some(hops);
+            }
+            if current == gateway {
+                let network = network?;
+                hops.push(if network.silent { None } else { Some(destination) });
+                return Some(hops);
+            }
+            current = self.next_hops(current, gateway).first()?.0;
+        }
+    }
+
+    /// Get the index of the site with the given name
+    pub(crate) fn get_site(&self, name: &str) -> Option<usize> {
+        return self.sites.iter().position(|&(ref site, _)| site == name);
+    }
+
+    /// Get the router that owns the given address
+    pub(crate) fn find_router(&self, address: IpAddr) -> Option<usize> {
+        return self.routers.iter().position(|router| router.address == address);
+    }
+
+    /// Get the most specific network containing the given address
+    pub(crate) fn find_network(&self, address: IpAddr) -> Option<&Network> {
+        return self.networks
+            .iter()
+            .filter(|network| contains(network.address, network.prefix, address))
+            .max_by_key(|network| network.prefix);
+    }
+
+    /// Get the distance in hops between both routers
+    pub(crate) fn distance(&self, from: usize, to: usize) -> u32 {
+        return self.distances[from][to];
+    }
+
+    /// Get the site closest to the given router, which receives the packets sent to the anycast address
+    pub(crate) fn catchment(&self, router: usize) -> Option<usize> {
+        return (0..self.sites.len())
+            .filter(|site| self.distance(router, self.sites[*site].1) != UNREACHABLE)
+            .min_by_key(|site| self.distance(router, self.sites[*site].1));
+    }
+
+    /// Get every neighbour of the router that is in a shortest path to the destination
+    pub(crate) fn next_hops(&self, router: usize, destination: usize) -> Vec<(usize, &Link)> {
+        let distance = self.distance(router, destination);
+        return self.links[router]
+            .iter()
+            .filter(|&&(next, _)| distance != UNREACHABLE && self.distance(next, destination) + 1 == distance)
+            .map(|&(next, ref link)| (next, link))
+            .collect();
+    }
+
+    /// Calculate the distance from the router to every other router
+    fn bfs(&self, start: usize) -> Vec<u32> {
+        let mut distances = vec![UNREACHABLE; self.routers.len()];
+        let mut queue = VecDeque::new();
+        distances[start] = 0;
+        queue.push_back(start);
+        while let Some(router) = queue.pop_front() {
+            for &(next, _) in &self.links[router] {
+                if distances[next] == UNREACHABLE {
+                    distances[next] = distances[router] + 1;
+                    queue.push_back(next);
+                }
+            }
+        }
+        return distances;
+    }
+}
+
+/// Check if the address is inside the network
+fn contains(network: IpAddr, prefix: u8, address: IpAddr) -> bool {
+    return match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) => {
+            let mask = (!0u32).checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(address) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(address)) => {
+            let mask = (!0u128).checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    };
+}
+
+/// Split an option of the form key=value
+fn split_option(option: &str) -> (&str, Option<&str>) {
+    let mut parts = option.splitn(2, '=');
+    return (parts.next().unwrap(), parts.next());
+}
+
+fn parse_value<T: ::std::str::FromStr>(value: &str) -> Result<T, String> {
+    return value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}'", value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPOLOGY: &str = "
+        router gw      10.0.0.1
+        router lb      10.0.1.1  balance
+        router a       10.0.2.1
+        router b       10.0.3.1  filtered
+        router tunnel  10.0.4.1  decrement=0
+        router edge    10.0.5.1  decrement=2
+        router island  10.9.0.1
+        link gw lb
+        link lb a
+        link lb b
+        link a tunnel
+        link b tunnel
+        link tunnel edge
+        site ams gw
+        network 198.51.100.0/24 edge
+        network 198.51.100.128/25 edge silent
+        network 203.0.113.0/24 island
+    ";
+
+    fn ip(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    #[test]
+    fn ground_truth_of_the_paths() {
+        let topology = Topology::parse(TOPOLOGY).unwrap();
+        let hop = |address: &str| Some(ip(address));
+        assert_eq!(
+            topology.ground_truth("ams", ip("198.51.100.1")).unwrap(),
+            vec![hop("10.0.0.1"), hop("10.0.1.1"), hop("10.0.2.1"), hop("10.0.5.1"), hop("10.0.5.1"), hop("198.51.100.1")]
+        );
+        assert_eq!(
+            topology.ground_truth("ams", ip("198.51.100.129")).unwrap().last(),
+            Some(&None)
+        );
+        assert_eq!(
+            topology.ground_truth("ams", ip("10.0.5.1")).unwrap(),
+            vec![hop("10.0.0.1"), hop("10.0.1.1"), hop("10.0.2.1"), hop("10.0.5.1")]
+        );
+        assert_eq!(topology.ground_truth("ams", ip("203.0.113.1")), None);
+        assert_eq!(topology.ground_truth("ams", ip("192.0.2.1")), None);
+        assert_eq!(topology.ground_truth("scl", ip("198.51.100.1")), None);
+    }
+
+    #[test]
+    fn invalid_topologies() {
+        let error = Topology::parse("router a 10.0.0.1\nlink a b\nsite s a").err().unwrap();
+        assert_eq!(error, "Line 2: Unknown router b");
+        assert!(Topology::parse("router a 10.0.0.1 unknown\nsite s a").is_err());
+        assert!(Topology::parse("router a 10.0.0.1").is_err());
+
+        let error = Topology::parse("router a 10.0.0.1\nsite s a\nnetwork 10.0.0.0/40 a").err().unwrap();
+        assert_eq!(error, "Line 3: Invalid prefix length /40 for 10.0.0.0");
+        assert!(Topology::parse("router a 10.0.0.1\nsite s a\nnetwork 2001:db8::/129 a").is_err());
+        assert!(Topology::parse("router a 10.0.0.1\nsite s a\nnetwork 2001:db8::/128 a").is_ok());
+        assert!(Topology::parse("router a 10.0.0.1\nsite s a\nnetwork 0.0.0.0/0 a").is_ok());
+    }
+}
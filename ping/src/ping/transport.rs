@@ -0,0 +1,267 @@
+use ping::PingMethod;
+use ping::socket4::Socket4;
+use ping::socket6::Socket6;
+use ping::timestamp::KernelTimestamp;
+use ping::writer::PingWriter;
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// Backend used by the PingHandler to send and receive the packets.
+pub trait Transport {
+    /// Open the writer and readers used to send and receive the packets of the method,
+    /// using the local address as source.
+    fn open(
+        &mut self,
+        local: IpAddr,
+        method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)>;
+}
+
+/// Sending half of a transport.
+pub trait TransportWriter: Send {
+    /// Send the complete IP packet to the target.
+    fn send_to(&mut self, packet: &[u8], target: IpAddr) -> io::Result<usize>;
+
+    /// Get the kernel timestamps of the last packet sent, if the transport supports them,
+    /// which are pending if they were not available yet when it was sent.
+    fn sent_timestamp(&mut self) -> KernelTimestamp {
+        return KernelTimestamp::default();
+    }
+
+    /// Get the source of the pending transmit timestamps, if the transport gives them.
+    fn pending_timestamps(&self) -> Option<Arc<dyn PendingTimestamps>> {
+        return None;
+    }
+}
+
+/// Transmit timestamps given by the kernel after their packet was sent, as the hardware ones.
+pub trait PendingTimestamps: Send + Sync {
+    /// Get the transmit timestamp identified by `KernelTimestamp::pending`, if it's available now.
+    ///
+    /// The timestamps are only kept for a few seconds, and can only be read once.
+    fn pending_timestamp(&self, id: u32) -> Option<KernelTimestamp>;
+}
+
+/// Receiving half of a transport.
+pub trait TransportReader: Send {
+    /// Block until a new packet is received, returning it as a complete IP packet, with the
+    /// kernel timestamps of its reception if the transport supports them.
+    ///
+    /// An error of kind `BrokenPipe` means that the transport was closed and no more
+    /// packets will be received.
+    fn recv(&mut self) -> io::Result<(Vec<u8>, KernelTimestamp)>;
+}
+
+/// Transport using raw sockets, requiring root or the CAP_NET_RAW capability.
+pub struct RawTransport;
+
+impl Transport for RawTransport {
+    fn open(
+        &mut self,
+        local: IpAddr,
+        method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)> {
+        return match local {
+            IpAddr::V4(local) => Self::open_ipv4(local, method),
+            IpAddr::V6(local) => Self::open_ipv6(local, method),
+        };
+    }
+}
+
+impl RawTransport {
+    /// Open the IPv4 sockets used to send and receive the packets.
+    fn open_ipv4(
+        _local: Ipv4Addr,
+        method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)> {
+        // We use Icmp as transport for every method, as it only filter the received packets
+        let socket = Arc::new(Socket4::new(IpNextHeaderProtocols::Icmp)?);
+        let mut receivers: Vec<Box<dyn TransportReader>> =
+            vec![Box::new(RawReader4::new(socket.clone()))];
+
+        // TCP probes are answered with TCP packets (RST or SYN-ACK), that are only
+        // delivered to a socket listening to the TCP protocol
+        if method.is_tcp() {
+            let tcp = Socket4::new(IpNextHeaderProtocols::Tcp)?;
+            receivers.push(Box::new(RawReader4::new(Arc::new(tcp))));
+        }
+        return Ok((Box::new(RawWriter4::new(socket)), receivers));
+    }
+
+    /// Open the IPv6 sockets used to send and receive the packets.
+    ///
+    /// As the IPv6 header can't be written, packets must be sent using a socket of their protocol.
+    fn open_ipv6(
+        local: Ipv6Addr,
+        method: &PingMethod,
+    ) -> io::Result<(Box<dyn TransportWriter>, Vec<Box<dyn TransportReader>>)> {
+        let protocol = match *method {
+            PingMethod::ICMP => IpNextHeaderProtocols::Icmpv6,
+            PingMethod::UDP => IpNextHeaderProtocols::Udp,
+            PingMethod::TcpSyn | PingMethod::TcpAck => IpNextHeaderProtocols::Tcp,
+        };
+        let tx = Socket6::new(protocol, local)?;
+        let mut receivers: Vec<Box<dyn TransportReader>> = vec![
+            Box::new(RawReader6::new(
+                Socket6::new(IpNextHeaderProtocols::Icmpv6, local)?,
+                local,
+            )),
+        ];
+        if method.is_tcp() {
+            receivers.push(Box::new(RawReader6::new(
+                Socket6::new(IpNextHeaderProtocols::Tcp, local)?,
+                local,
+            )));
+        }
+        return Ok((Box::new(RawWriter6::new(tx)), receivers));
+    }
+}
+
+/// Writer of a raw IPv4 socket, which shares the socket with the ICMP reader.
+struct RawWriter4 {
+    socket: Arc<Socket4>,
+    timestamp: KernelTimestamp,
+}
+
+impl RawWriter4 {
+    fn new(socket: Arc<Socket4>) -> RawWriter4 {
+        return RawWriter4 {
+            socket: socket,
+            timestamp: KernelTimestamp::default(),
+        };
+    }
+}
+
+impl TransportWriter for RawWriter4 {
+    fn send_to(&mut self, packet: &[u8], _target: IpAddr) -> io::Result<usize> {
+        let sent = self.socket.send_to(packet)?;
+        self.timestamp = self.socket.sent_timestamp(packet);
+        return Ok(sent);
+    }
+
+    fn sent_timestamp(&mut self) -> KernelTimestamp {
+        return self.timestamp;
+    }
+
+    fn pending_timestamps(&self) -> Option<Arc<dyn PendingTimestamps>> {
+        return Some(self.socket.clone());
+    }
+}
+
+/// Reader of a raw IPv4 socket, receiving the packets with their IP header.
+struct RawReader4 {
+    socket: Arc<Socket4>,
+    buffer: Vec<u8>,
+}
+
+impl RawReader4 {
+    fn new(socket: Arc<Socket4>) -> RawReader4 {
+        return RawReader4 {
+            socket: socket,
+            buffer: vec![0; 4096],
+        };
+    }
+}
+
+impl TransportReader for RawReader4 {
+    fn recv(&mut self) -> io::Result<(Vec<u8>, KernelTimestamp)> {
+        let (length, received) = self.socket.recv(&mut self.buffer)?;
+        return Ok((self.buffer[..length].to_vec(), received));
+    }
+}
+
+struct RawWriter6 {
+    socket: Arc<Socket6>,
+    timestamp: KernelTimestamp,
+}
+
+impl RawWriter6 {
+    fn new(socket: Socket6) -> RawWriter6 {
+        return RawWriter6 {
+            socket: Arc::new(socket),
+            timestamp: KernelTimestamp::default(),
+        };
+    }
+}
+
+impl TransportWriter for RawWriter6 {
+    fn send_to(&mut self, packet: &[u8], _target: IpAddr) -> io::Result<usize> {
+        let sent = self.socket.send_to(packet)?;
+        self.timestamp = self.socket.sent_timestamp(packet);
+        return Ok(sent);
+    }
+
+    fn sent_timestamp(&mut self) -> KernelTimestamp {
+        return self.timestamp;
+    }
+
+    fn pending_timestamps(&self) -> Option<Arc<dyn PendingTimestamps>> {
+        return Some(self.socket.clone());
+    }
+}
+
+/// Reader of a raw IPv6 socket, rebuilding the IPv6 header that is removed by the kernel.
+struct RawReader6 {
+    socket: Socket6,
+    local: Ipv6Addr,
+    buffer: Vec<u8>,
+}
+
+impl RawReader6 {
+    fn new(socket: Socket6, local: Ipv6Addr) -> RawReader6 {
+        return RawReader6 {
+            socket: socket,
+            local: local,
+            buffer: vec![0; 4096],
+        };
+    }
+}
+
+impl TransportReader for RawReader6 {
+    fn recv(&mut self) -> io::Result<(Vec<u8>, KernelTimestamp)> {
+        let (source, hop_limit, length, received) = self.socket.recv(&mut self.buffer)?;
+        let packet = ipv6_packet(&self.buffer[..length], self.socket.protocol(), source, self.local, hop_limit);
+        return Ok((packet, received));
+    }
+}
+
+/// Add the IPv6 header to the payload received from the source
+fn ipv6_packet(
+    payload: &[u8],
+    protocol: IpNextHeaderProtocol,
+    source: Ipv6Addr,
+    local: Ipv6Addr,
+    hop_limit: u8,
+) -> Vec<u8> {
+    let mut packet = vec![0; 40 + payload.len()];
+    packet[40..].clone_from_slice(payload);
+    PingWriter::format_ipv6(&mut packet, protocol, source, local, hop_limit);
+    return packet;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::Packet;
+    use pnet::packet::ipv6::Ipv6Packet;
+
+    #[test]
+    fn rebuild_the_ipv6_header() {
+        let (source, local) = ("2001:db8::1".parse().unwrap(), "2001:db8:1::2".parse().unwrap());
+        let payload = [3, 0, 0xf4, 0xff, 0, 0, 0, 0, 0x60];
+        let packet = ipv6_packet(&payload, IpNextHeaderProtocols::Icmpv6, source, local, 61);
+        assert_eq!(packet.len(), 49);
+        let ipv6 = Ipv6Packet::new(&packet).unwrap();
+        assert_eq!(ipv6.get_version(), 6);
+        assert_eq!((ipv6.get_traffic_class(), ipv6.get_flow_label()), (0, 0));
+        assert_eq!(ipv6.get_payload_length(), 9);
+        assert_eq!(ipv6.get_next_header(), IpNextHeaderProtocols::Icmpv6);
+        assert_eq!(ipv6.get_hop_limit(), 61);
+        assert_eq!((ipv6.get_source(), ipv6.get_destination()), (source, local));
+        assert_eq!(ipv6.payload(), &payload);
+    }
+}
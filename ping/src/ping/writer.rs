@@ -1,35 +1,76 @@
 extern crate pnet;
-extern crate ratelimit_meter;
 
-use pnet::packet::icmp::{checksum, echo_request, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::Packet;
+use pnet::packet::icmp::{checksum, echo_request, IcmpType, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::icmpv6;
+use pnet::packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4;
-use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::tcp;
+use pnet::packet::tcp::{MutableTcpPacket, TcpFlags};
+use pnet::packet::udp;
 use pnet::packet::udp::MutableUdpPacket;
-use pnet::transport::TransportSender;
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use ping::PingMethod;
+use ping::extension::IcmpExtensions;
+use ping::key::ProbeKey;
+use ping::pacer::Pacer;
+use ping::timestamp::KernelTimestamp;
+use ping::transport::{PendingTimestamps, TransportWriter};
 
-use std::cell::RefCell;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::sync::mpsc;
 use std::thread;
 
 use ::{Responce, IcmpResponce};
 
+/// Destination port used by the TCP probes.
+pub const TCP_PORT: u16 = 80;
+
+/// Port used by the generic UDP probes, and as destination of the UDP probes on Paris mode.
+pub const UDP_PORT: u16 = 33434;
+
+/// Length of the payload of the probes, without the padding of the UDP probes on Paris mode
+pub const PAYLOAD_LENGTH: usize = 18;
+
+/// Offset of the checksum compensation in the payload of the probes
+const COMPENSATION_OFFSET: usize = 16;
+
+/// Minimum amount of requests that can be queued on the writer
+const MIN_QUEUE_SIZE: usize = 1024;
+
 pub struct PingWriter {
-    writer: mpsc::Sender<PingRequest>,
+    writer: mpsc::SyncSender<PingRequest>,
     method: PingMethod,
-    item_count: RefCell<u64>,
+    stats: Arc<WriterStats>,
+    capacity: usize,
+    timestamps: Option<Arc<dyn PendingTimestamps>>,
+}
+
+/// Values used to mark the probes as sent by this writer
+pub(crate) struct Signature {
+    key: ProbeKey,
+    instance: u16,
+    paris: bool,
+}
+
+/// Count of the requests processed by the writer
+struct WriterStats {
+    queued: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
 }
 
 struct PingRequest {
-    target: Ipv4Addr,
+    target: IpAddr,
     ttl: u8,
     identifier: u16,
     sequence: u16,
@@ -38,38 +79,102 @@ struct PingRequest {
 }
 
 impl PingWriter {
-    /// Construct a new PingWriter. The writer will use the local ip as the source of the IPv4 packets.
+    /// Construct a new PingWriter. The writer will use the local ip as the source of the IP packets.
     ///
     /// This function will spawn a thread that process any received request asynchronously, sending the packet with a frequency of `rate_limit`.
+    /// The requests are stored on a bounded queue, holding up to a second of packets.
+    ///
+    /// On `paris` mode the flow identifier of the probes (the ICMP checksum or the UDP ports)
+    /// only depends on the ports of the request, so the load balancers send every probe
+    /// of a trace through the same path.
     pub fn new(
-        tx: TransportSender,
-        local: Ipv4Addr,
+        tx: Box<dyn TransportWriter>,
+        local: IpAddr,
         method: PingMethod,
         rate_limit: u32,
+        key: ProbeKey,
+        instance: u16,
+        paris: bool,
         loopback: mpsc::Sender<IcmpResponce>
     ) -> PingWriter {
+        let stats = Arc::new(WriterStats {
+            queued: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let capacity = (rate_limit as usize).max(MIN_QUEUE_SIZE);
+        let signature = Signature {
+            key: key,
+            instance: instance,
+            paris: paris,
+        };
+        let timestamps = tx.pending_timestamps();
         return PingWriter {
-            writer: Self::run(tx, local, method.clone(), rate_limit, loopback),
+            writer: Self::run(tx, local, method.clone(), rate_limit, capacity, signature, loopback, stats.clone()),
             method: method,
-            item_count: RefCell::new(0),
+            stats: stats,
+            capacity: capacity,
+            timestamps: timestamps,
+        };
+    }
+
+    /// Complete the transmit timestamp of a packet sent, if it was pending when the packet
+    /// was sent and the kernel gave it since then.
+    ///
+    /// Pending timestamps are only kept for a few seconds, so they are read when the reply
+    /// of the packet is processed. Return the timestamp unchanged otherwise.
+    pub fn sent_timestamp(&self, timestamp: KernelTimestamp) -> KernelTimestamp {
+        return match (timestamp.pending, &self.timestamps) {
+            (Some(id), &Some(ref timestamps)) => timestamps.pending_timestamp(id).unwrap_or(timestamp),
+            _ => timestamp,
         };
     }
 
-    /// Return the count of the sended packets
+    /// Return the count of the packets sent by the transport
     pub fn sended_packets(&self) -> u64 {
-        return *self.item_count.borrow_mut();
+        return self.stats.sent.load(Ordering::Relaxed);
+    }
+
+    /// Return the count of the packets that failed to be sent
+    pub fn dropped_packets(&self) -> u64 {
+        return self.stats.dropped.load(Ordering::Relaxed);
+    }
+
+    /// Return the count of the requests queued since the writer started. The requests are
+    /// sent in order, so the n-th request was processed once the sent and dropped packets reach n.
+    pub fn queued_packets(&self) -> u64 {
+        return self.stats.queued.load(Ordering::Relaxed);
     }
 
-    /// Send a generic Echo request to the ipv4 target asynchronously.
+    /// Return the count of the requests waiting to be sent
+    pub fn queue_depth(&self) -> usize {
+        let done = self.sended_packets() + self.dropped_packets();
+        return self.stats.queued.load(Ordering::Relaxed).saturating_sub(done) as usize;
+    }
+
+    /// Return the maximum of requests that can wait to be sent.
+    ///
+    /// When the queue is full, the requests block until the writer sends the queued packets,
+    /// so the callers check the depth of the queue before queueing requests that can wait.
+    pub fn queue_capacity(&self) -> usize {
+        return self.capacity;
+    }
+
+    /// Send a generic Echo request to the target asynchronously.
     ///
     /// If sending an ICMP packet, it will have a default identification and sequence of 1.
-    /// The payload will contain the timestamp in milliseconds, followed by the character 'mt'.
-    pub fn send(&self, target: Ipv4Addr) {
-        self.send_complete(target, 33434, 33434, 64, 1, 1);
+    /// If sending a TCP packet, it will be sent to the port `TCP_PORT`.
+    /// The payload will contain the timestamp in milliseconds, followed by the MAC of the probe.
+    pub fn send(&self, target: IpAddr) {
+        if self.method.is_tcp() {
+            self.send_complete(target, UDP_PORT, TCP_PORT, 64, 1, 1);
+        } else {
+            self.send_complete(target, UDP_PORT, UDP_PORT, 64, 1, 1);
+        }
     }
 
     /// Send an ICMP request with the given parameters
-    pub fn send_icmp(&self, target: Ipv4Addr, ttl: u8, identifier: u16, sequence: u16) {
+    pub fn send_icmp(&self, target: IpAddr, ttl: u8, identifier: u16, sequence: u16) {
         assert_eq!(
             self.method,
             PingMethod::ICMP,
@@ -79,7 +184,10 @@ impl PingWriter {
     }
 
     /// Send an UDP request with the given parameters
-    pub fn send_udp(&self, target: Ipv4Addr, ttl: u8, src_port: u16, dst_port: u16) {
+    ///
+    /// On Paris mode the UDP checksum is set to the identifier of `send_complete` instead,
+    /// which is always 0 when using this function, sent as 0xFFFF on IPv6.
+    pub fn send_udp(&self, target: IpAddr, ttl: u8, src_port: u16, dst_port: u16) {
         assert_eq!(
             self.method,
             PingMethod::UDP,
@@ -88,18 +196,45 @@ impl PingWriter {
         self.send_complete(target, src_port, dst_port, ttl, 0, 0);
     }
 
-    /// Send the Echo request to the ipv4 target asynchronously with the given parameters.
+    /// Send a TCP request with the given parameters
     ///
-    /// The payload will contain the timestamp in milliseconds, followed by the character 'mt'.
+    /// The identifier and sequence are encoded in the TCP sequence number of the packet.
+    /// The source port is replaced by the instance of the writer, used to match the replies.
+    pub fn send_tcp(
+        &self,
+        target: IpAddr,
+        ttl: u8,
+        src_port: u16,
+        dst_port: u16,
+        identifier: u16,
+        sequence: u16,
+    ) {
+        assert!(
+            self.method.is_tcp(),
+            "Calling send_tcp is not allowed when the method is not PingMethod::TcpSyn or PingMethod::TcpAck"
+        );
+        self.send_complete(target, src_port, dst_port, ttl, identifier, sequence);
+    }
+
+    /// Send the Echo request to the target asynchronously with the given parameters.
+    ///
+    /// The payload will contain the timestamp in milliseconds, followed by the MAC of the probe.
+    /// On Paris mode, the checksum of the ICMP probes is set to the source port, so the flow only
+    /// depends on the ports as on UDP. The UDP probes keep their ports, setting their checksum to
+    /// the identifier and padding their payload with the low byte of the sequence, so both can
+    /// change without changing the flow.
+    /// Block while the queue of the writer is full.
     pub fn send_complete(
         &self,
-        target: Ipv4Addr,
+        target: IpAddr,
         src_port: u16,
         dst_port: u16,
         ttl: u8,
         identifier: u16,
         sequence: u16,
     ) {
+        // Count the request before queueing it, so it's never sent before being counted
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
         self.writer
             .send(PingRequest {
                 target: target,
@@ -108,45 +243,42 @@ impl PingWriter {
                 sequence: sequence,
                 src_port: src_port,
                 dst_port: dst_port,
-            })  
+            })
             .unwrap();
-        let mut count = self.item_count.borrow_mut();
-        *count = *count + 1;
     }
 
     /// Create a new thread and a channel to receive requests asynchronously.
-    /// 
+    ///
     /// Use process_icmp or process_udp depending on the selected method.
+    /// Requests exceeding the rate limit are delayed, never dropped.
     fn run(
-        tx: TransportSender,
-        local: Ipv4Addr,
+        tx: Box<dyn TransportWriter>,
+        local: IpAddr,
         method: PingMethod,
         rate_limit: u32,
+        capacity: usize,
+        signature: Signature,
         loopback: mpsc::Sender<IcmpResponce>,
-    ) -> mpsc::Sender<PingRequest> {
+        stats: Arc<WriterStats>,
+    ) -> mpsc::SyncSender<PingRequest> {
         let tx = Arc::new(Mutex::new(tx));
-        let (sender, receiver) = mpsc::channel::<PingRequest>();
+        let (sender, receiver) = mpsc::sync_channel::<PingRequest>(capacity);
         let process = match method {
             PingMethod::ICMP => Self::process_icmp,
             PingMethod::UDP => Self::process_udp,
+            PingMethod::TcpSyn => Self::process_tcp_syn,
+            PingMethod::TcpAck => Self::process_tcp_ack,
         };
-        use ping::writer::ratelimit_meter::Decider;
-        use std::time::Duration;
         thread::spawn(move || {
-            let mut ratelimit = ratelimit_meter::LeakyBucket::new(rate_limit, Duration::from_secs(1)).unwrap();
+            let mut pacer = Pacer::new(rate_limit);
 
             let mut sender = tx.lock().unwrap();
             while let Ok(request) = receiver.recv() {
-                match ratelimit.check() {
-                    Ok(()) => { 
-                        process(&mut sender, local, &request, &loopback);
-                    }
-                    Err(_) => {
-                        // Wait for 100 millis to fill the bucket with more than one item,
-                        // preveting wakeups of one packet
-                        thread::sleep(Duration::from_millis(100));
-                    }
-                }
+                pacer.wait();
+                match process(&mut sender, local, &signature, &request, &loopback) {
+                    Ok(()) => stats.sent.fetch_add(1, Ordering::Relaxed),
+                    Err(()) => stats.dropped.fetch_add(1, Ordering::Relaxed),
+                };
             }
         });
 
@@ -154,89 +286,135 @@ impl PingWriter {
     }
 
     /// Send a UDP packet with the given parameters
-    fn process_udp(tx: &mut TransportSender, src: Ipv4Addr, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) {
-        // Buffer is [20 ipv4, 8 UDP, 14 Payload]
-        let mut buffer = [0; 20 + 8 + 14];
-        Self::format_udp(&mut buffer[20..], request);
-        Self::format_ipv4(
-            &mut buffer,
-            IpNextHeaderProtocols::Udp,
-            src,
-            request.target,
-            request.ttl,
-        );
-        match tx.send_to(
-            Ipv4Packet::new(&buffer).unwrap(),
-            IpAddr::V4(request.target),
-        ) {
-            Ok(_) => {
-                // send the packet to the loopback to store the send_time
-                let _ = loopback.send(IcmpResponce {
-                    source: src,
-                    ttl: request.ttl,
-                    icmp: Responce::LocalSendedEcho(request.target),
-                    time_ms: Self::time_from_epoch_ms(),
-                });
-            }
-            Err(e) => error!("failed to send packet to {}: {}", request.target, e),
-        };
+    fn process_udp(tx: &mut Box<dyn TransportWriter>, src: IpAddr, signature: &Signature, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) -> Result<(), ()> {
+        // Buffer is [20 ipv4 or 40 ipv6, 8 UDP, 18 Payload], padded on Paris mode
+        let header = Self::header_length(src);
+        let padding = if signature.paris { request.sequence as u8 as usize } else { 0 };
+        let mut buffer = vec![0; header + 8 + PAYLOAD_LENGTH + padding];
+        Self::format_udp(&mut buffer[header..], signature, request, src);
+        return Self::send_packet(tx, &mut buffer, IpNextHeaderProtocols::Udp, src, signature.instance, request, loopback);
     }
 
     /// Format the buffer as a UDP packet.
-    fn format_udp(buffer: &mut [u8], request: &PingRequest) {
+    ///
+    /// The checksum is only set for IPv6 packets, where it is mandatory, and on Paris mode,
+    /// where it stores the identifier of the request.
+    fn format_udp(buffer: &mut [u8], signature: &Signature, request: &PingRequest, src: IpAddr) {
+        Self::set_payload(&mut buffer[8..], signature, request);
+        let length = buffer.len() as u16;
         {
             let mut udp = MutableUdpPacket::new(buffer).unwrap();
             udp.set_source(request.src_port);
             udp.set_destination(request.dst_port);
-            udp.set_length(8+14);
+            udp.set_length(length);
             udp.set_checksum(0);
+            let check = match (src, request.target) {
+                (IpAddr::V4(src), IpAddr::V4(target)) if signature.paris => {
+                    udp::ipv4_checksum(&udp.to_immutable(), &src, &target)
+                }
+                (IpAddr::V6(src), IpAddr::V6(target)) => {
+                    match udp::ipv6_checksum(&udp.to_immutable(), &src, &target) {
+                        0 => 0xffff,
+                        check => check,
+                    }
+                }
+                _ => 0,
+            };
+            udp.set_checksum(check);
+        }
+        if signature.paris {
+            // A zero checksum is not allowed on IPv6, so the identifier 0 is sent as 0xFFFF,
+            // the same value in one's complement
+            let identifier = match (request.identifier, src) {
+                (0, IpAddr::V6(_)) => 0xffff,
+                (identifier, _) => identifier,
+            };
+            Self::compensate_checksum(buffer, 6, 8 + COMPENSATION_OFFSET, identifier);
         }
-        Self::set_payload(&mut buffer[8..], request.identifier, request.sequence);
+    }
+
+    /// Send a TCP SYN packet with the given parameters
+    fn process_tcp_syn(tx: &mut Box<dyn TransportWriter>, src: IpAddr, signature: &Signature, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) -> Result<(), ()> {
+        return Self::process_tcp(tx, src, signature, request, loopback, true);
+    }
+
+    /// Send a TCP ACK packet with the given parameters
+    fn process_tcp_ack(tx: &mut Box<dyn TransportWriter>, src: IpAddr, signature: &Signature, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) -> Result<(), ()> {
+        return Self::process_tcp(tx, src, signature, request, loopback, false);
+    }
+
+    /// Send a TCP packet, with the SYN flag if `syn` is set or the ACK flag otherwise
+    fn process_tcp(tx: &mut Box<dyn TransportWriter>, src: IpAddr, signature: &Signature, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>, syn: bool) -> Result<(), ()> {
+        // Buffer is [20 ipv4 or 40 ipv6, 20 TCP], as some middleboxes drop SYN packets with payload
+        let header = Self::header_length(src);
+        let mut buffer = vec![0; header + 20];
+        Self::format_tcp(&mut buffer[header..], signature.instance, request, src, syn);
+        return Self::send_packet(tx, &mut buffer, IpNextHeaderProtocols::Tcp, src, signature.instance, request, loopback);
+    }
+
+    /// Format the buffer as a TCP packet.
+    ///
+    /// The identifier and sequence are stored in the TCP sequence number (and acknowledgement
+    /// number for ACK packets), as it is quoted in the ICMP errors and echoed in the RST replies.
+    /// The source port is set to the instance, as it is the only field echoed in the replies.
+    fn format_tcp(buffer: &mut [u8], instance: u16, request: &PingRequest, src: IpAddr, syn: bool) {
+        let sequence = (request.identifier as u32) << 16 | request.sequence as u32;
+        let mut tcp = MutableTcpPacket::new(buffer).unwrap();
+        tcp.set_source(instance);
+        tcp.set_destination(request.dst_port);
+        tcp.set_sequence(sequence);
+        if syn {
+            tcp.set_flags(TcpFlags::SYN);
+        } else {
+            tcp.set_flags(TcpFlags::ACK);
+            tcp.set_acknowledgement(sequence);
+        }
+        tcp.set_data_offset(5);
+        tcp.set_window(1024);
+        let check = match (src, request.target) {
+            (IpAddr::V4(src), IpAddr::V4(target)) => {
+                tcp::ipv4_checksum(&tcp.to_immutable(), &src, &target)
+            }
+            (IpAddr::V6(src), IpAddr::V6(target)) => {
+                tcp::ipv6_checksum(&tcp.to_immutable(), &src, &target)
+            }
+            _ => 0,
+        };
+        tcp.set_checksum(check);
     }
 
     /// Send a ICMP packet with the given parameters
-    fn process_icmp(tx: &mut TransportSender, src: Ipv4Addr, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) {
-        // Buffer is [20 ipv4, 8 ICMP, 14 Payload]
-        let mut buffer = [0; 20 + 8 + 14];
-
-        Self::format_icmp(&mut buffer[20..], request.identifier, request.sequence);
-        Self::format_ipv4(
-            &mut buffer,
-            IpNextHeaderProtocols::Icmp,
-            src,
-            request.target,
-            request.ttl,
-        );
+    fn process_icmp(tx: &mut Box<dyn TransportWriter>, src: IpAddr, signature: &Signature, request: &PingRequest, loopback: &mpsc::Sender<IcmpResponce>) -> Result<(), ()> {
+        // Buffer is [20 ipv4 or 40 ipv6, 8 ICMP, 18 Payload]
+        let header = Self::header_length(src);
+        let mut buffer = vec![0; header + 8 + PAYLOAD_LENGTH];
+        Self::format_icmp(&mut buffer[header..], signature, request);
 
-        match tx.send_to(
-            Ipv4Packet::new(&buffer).unwrap(),
-            IpAddr::V4(request.target),
-        ) {
-            Ok(_) => {
-                // send the packet to the loopback to store the send_time
-                let _ = loopback.send(IcmpResponce {
-                    source: src,
-                    ttl: request.ttl,
-                    icmp: Responce::LocalSendedEcho(request.target),
-                    time_ms: Self::time_from_epoch_ms(),
-                });
+        let protocol = match (src, request.target) {
+            (IpAddr::V6(src), IpAddr::V6(target)) => {
+                Self::format_icmpv6(&mut buffer[header..], src, target);
+                IpNextHeaderProtocols::Icmpv6
             }
-            Err(e) => error!("failed to send packet to {}: {}", request.target, e),
+            _ => IpNextHeaderProtocols::Icmp,
         };
+        if signature.paris {
+            Self::compensate_checksum(&mut buffer[header..], 2, 8 + COMPENSATION_OFFSET, request.src_port);
+        }
+        return Self::send_packet(tx, &mut buffer, protocol, src, signature.instance, request, loopback);
     }
 
     /// Format the buffer as a ICMP packet.
     ///
-    /// The payload of the packet will be the u64 timestamp, followed by the characters 'mt'.
-    fn format_icmp(buffer: &mut [u8], identifier: u16, sequence: u16) {
-        let mut payload = [0u8; 14];
-        Self::set_payload(&mut payload, identifier, sequence);
+    /// The payload of the packet will be the u64 timestamp, followed by the MAC of the probe.
+    fn format_icmp(buffer: &mut [u8], signature: &Signature, request: &PingRequest) {
+        let mut payload = [0u8; PAYLOAD_LENGTH];
+        Self::set_payload(&mut payload, signature, request);
         {
             let mut icmp = echo_request::MutableEchoRequestPacket::new(buffer).unwrap();
             icmp.set_icmp_type(IcmpTypes::EchoRequest);
             icmp.set_icmp_code(echo_request::IcmpCodes::NoCode);
-            icmp.set_identifier(identifier);
-            icmp.set_sequence_number(sequence);
+            icmp.set_identifier(request.identifier);
+            icmp.set_sequence_number(request.sequence);
             icmp.set_payload(&payload);
         }
         {
@@ -246,19 +424,105 @@ impl PingWriter {
         }
     }
 
+    /// Transform the ICMP packet in the buffer to an ICMPv6 Echo request.
+    ///
+    /// Both Echo requests share the same format, only changing the type and the checksum,
+    /// which includes the IPv6 pseudo header.
+    fn format_icmpv6(buffer: &mut [u8], src: Ipv6Addr, target: Ipv6Addr) {
+        let mut icmp = MutableIcmpPacket::new(buffer).unwrap();
+        icmp.set_icmp_type(IcmpType::new(Icmpv6Types::EchoRequest.0));
+        icmp.set_checksum(0);
+        let check = icmpv6::checksum(&Icmpv6Packet::new(icmp.packet()).unwrap(), &src, &target);
+        icmp.set_checksum(check);
+    }
+
+    /// Replace the valid checksum of the transport header in the buffer by the given value,
+    /// updating the compensation field so the packet is still valid.
+    ///
+    /// Both the checksum and the compensation must be aligned to 16 bits.
+    fn compensate_checksum(buffer: &mut [u8], checksum: usize, compensation: usize, value: u16) {
+        let current = (buffer[checksum] as u16) << 8 | buffer[checksum + 1] as u16;
+        // The sum of the packet must not change, so compensation + value == current
+        let mut sum = current as u32 + !value as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+        let word = sum as u16;
+        buffer[checksum..checksum + 2].clone_from_slice(&[(value >> 8) as u8, value as u8]);
+        buffer[compensation..compensation + 2].clone_from_slice(&[(word >> 8) as u8, word as u8]);
+    }
+
+    /// Format the IP header of the buffer and send it to the target.
+    ///
+    /// The instance is stored in the identification of IPv4 packets, as it is quoted by the ICMP errors.
+    /// The packet is sent to the loopback to store the send time.
+    /// Return error if the packet could not be sent.
+    fn send_packet(
+        tx: &mut Box<dyn TransportWriter>,
+        buffer: &mut [u8],
+        protocol: IpNextHeaderProtocol,
+        src: IpAddr,
+        instance: u16,
+        request: &PingRequest,
+        loopback: &mpsc::Sender<IcmpResponce>,
+    ) -> Result<(), ()> {
+        match (src, request.target) {
+            (IpAddr::V4(src), IpAddr::V4(target)) => {
+                Self::format_ipv4(buffer, protocol, src, target, request.ttl, instance)
+            }
+            (IpAddr::V6(src), IpAddr::V6(target)) => {
+                Self::format_ipv6(buffer, protocol, src, target, request.ttl)
+            }
+            _ => {
+                error!("failed to send packet to {}: address family differs from {}", request.target, src);
+                return Err(());
+            }
+        }
+
+        // The time is taken before sending, as the reply can be received before send_to returns
+        let (time, time_ms) = (Instant::now(), Self::time_from_epoch_ms());
+        match tx.send_to(&buffer, request.target) {
+            Ok(_) => {
+                // send the packet to the loopback to store the send_time
+                let _ = loopback.send(IcmpResponce {
+                    source: src,
+                    ttl: request.ttl,
+                    icmp: Responce::LocalSendedEcho(request.target, request.identifier, request.sequence),
+                    extensions: IcmpExtensions::default(),
+                    size: (buffer.len() - Self::header_length(src)) as u16,
+                    time_ms: time_ms,
+                    time: time,
+                    timestamp: tx.sent_timestamp(),
+                });
+                return Ok(());
+            }
+            Err(e) => {
+                error!("failed to send packet to {}: {}", request.target, e);
+                return Err(());
+            }
+        };
+    }
+
+    /// Get the length of the IP header used to send packets from the given address
+    fn header_length(src: IpAddr) -> usize {
+        return match src {
+            IpAddr::V4(_) => 20,
+            IpAddr::V6(_) => 40,
+        };
+    }
+
     /// Format the buffer IPv4 Header
-    fn format_ipv4(
+    pub(crate) fn format_ipv4(
         buffer: &mut [u8],
         protocol: IpNextHeaderProtocol,
         src: Ipv4Addr,
         target: Ipv4Addr,
         ttl: u8,
+        identification: u16,
     ) {
         let length = buffer.len() as u16;
         let mut ipv4 = MutableIpv4Packet::new(buffer).unwrap();
         ipv4.set_version(4);
         ipv4.set_header_length(5);
-        ipv4.set_identification(1323); // Not used by the responce
+        ipv4.set_identification(identification);
         ipv4.set_flags(2);
         ipv4.set_header_length(5);
         ipv4.set_total_length(length);
@@ -270,6 +534,26 @@ impl PingWriter {
         ipv4.set_checksum(checksum);
     }
 
+    /// Format the buffer IPv6 Header
+    ///
+    /// The header is not sent by the raw sockets, but used to get the destination and hop limit.
+    pub(crate) fn format_ipv6(
+        buffer: &mut [u8],
+        protocol: IpNextHeaderProtocol,
+        src: Ipv6Addr,
+        target: Ipv6Addr,
+        ttl: u8,
+    ) {
+        let length = buffer.len() as u16 - 40;
+        let mut ipv6 = MutableIpv6Packet::new(buffer).unwrap();
+        ipv6.set_version(6);
+        ipv6.set_payload_length(length);
+        ipv6.set_next_header(protocol);
+        ipv6.set_hop_limit(ttl);
+        ipv6.set_source(src);
+        ipv6.set_destination(target);
+    }
+
     /// Get the current time in milliseconds
     fn time_from_epoch_ms() -> u64 {
         let start = SystemTime::now();
@@ -295,24 +579,234 @@ impl PingWriter {
         ];
     }
 
-    /// Set the payload of the packet, with a minimun of 14 bytes of payload.
-    /// The format used is [id(2), seq(2), timestamp(8), key(2)].
-    fn set_payload(payload: &mut [u8], identifier: u16, sequence: u16) {
-        if payload.len() < 14 {
+    /// Set the payload of the packet, with a minimun of 18 bytes of payload.
+    /// The format used is [id(2), seq(2), timestamp(8), mac(2), instance(2), compensation(2)],
+    /// where the MAC authenticates the target and the id, seq and timestamp, and the
+    /// compensation is used to set the checksum on Paris mode.
+    fn set_payload(payload: &mut [u8], signature: &Signature, request: &PingRequest) {
+        if payload.len() < PAYLOAD_LENGTH {
             panic!("Payload buffer of incorrect length {}", payload.len());
         }
+        let (identifier, sequence) = (request.identifier, request.sequence);
         payload[0..4].clone_from_slice(&[
             (identifier >> 8) as u8, identifier as u8,
             (sequence >> 8) as u8, sequence as u8
         ]);
         payload[4..12].clone_from_slice(&Self::u64_to_array(Self::time_from_epoch_ms().to_be()));
-        payload[12..14].clone_from_slice(Self::get_payload_key());
+        let mac = Self::get_payload_mac(&signature.key, request.target, &payload[..12]);
+        payload[12..14].clone_from_slice(&mac);
+        payload[14..16].clone_from_slice(&[(signature.instance >> 8) as u8, signature.instance as u8]);
+    }
+
+    /// Get the MAC of the first 12 bytes of the payload of a probe sent to the target
+    pub(crate) fn get_payload_mac(key: &ProbeKey, target: IpAddr, payload: &[u8]) -> [u8; 2] {
+        let mac = key.mac_probe(target, &payload[..12]);
+        return [(mac >> 8) as u8, mac as u8];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ping::handler::PingHandler;
+    use pnet::packet::FromPacket;
+    use pnet::packet::tcp::TcpPacket;
+    use std::io;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    fn request(target: &str, identifier: u16, sequence: u16) -> PingRequest {
+        return PingRequest {
+            target: target.parse().unwrap(),
+            ttl: 5,
+            identifier: identifier,
+            sequence: sequence,
+            src_port: UDP_PORT,
+            dst_port: TCP_PORT,
+        };
+    }
+
+    /// Build a TCP reply with the given flags, sequence and acknowledgement numbers
+    fn tcp_reply(flags: u8, sequence: u32, acknowledgement: u32) -> Vec<u8> {
+        let mut buffer = vec![0; 20];
+        {
+            let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+            tcp.set_flags(flags.into());
+            tcp.set_sequence(sequence);
+            tcp.set_acknowledgement(acknowledgement);
+            tcp.set_data_offset(5);
+        }
+        return buffer;
+    }
+
+    /// Transport sending each packet once it's allowed by the gate, or at once after the
+    /// gate is dropped
+    struct GatedTransport {
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl TransportWriter for GatedTransport {
+        fn send_to(&mut self, packet: &[u8], _target: IpAddr) -> io::Result<usize> {
+            let _ = self.gate.recv();
+            return Ok(packet.len());
+        }
+    }
+
+    fn signature(paris: bool) -> Signature {
+        return Signature {
+            key: ProbeKey::new(1, 2),
+            instance: 7,
+            paris: paris,
+        };
+    }
+
+    /// Check the checksum of the transport packet, including the pseudo header of the
+    /// addresses unless it's ICMP over IPv4
+    fn valid_checksum(src: IpAddr, target: IpAddr, protocol: u8, packet: &[u8]) -> bool {
+        let mut data = Vec::new();
+        match (src, target) {
+            (IpAddr::V4(src), IpAddr::V4(target)) if protocol != 1 => {
+                data.extend_from_slice(&src.octets());
+                data.extend_from_slice(&target.octets());
+                data.extend_from_slice(&[0, protocol, (packet.len() >> 8) as u8, packet.len() as u8]);
+            }
+            (IpAddr::V6(src), IpAddr::V6(target)) => {
+                data.extend_from_slice(&src.octets());
+                data.extend_from_slice(&target.octets());
+                data.extend_from_slice(&[0, 0, (packet.len() >> 8) as u8, packet.len() as u8, 0, 0, 0, protocol]);
+            }
+            _ => {}
+        }
+        data.extend_from_slice(packet);
+        let mut sum: u32 = 0;
+        for word in data.chunks(2) {
+            sum += (word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        return sum == 0xffff;
+    }
+
+    #[test]
+    fn compensated_checksums() {
+        let addresses = [("198.51.100.1", "192.0.2.1"), ("2001:db8::1", "2001:db8:1::1")];
+        for &(src, target) in &addresses {
+            let src: IpAddr = src.parse().unwrap();
+            for &identifier in &[0, 1, 0x1234, 0xfffe, 0xffff] {
+                // The UDP checksum stores the identifier, and the padding the low byte of the sequence
+                let mut probe = request(target, identifier, 0x0305);
+                probe.src_port = UDP_PORT + 3;
+                probe.dst_port = UDP_PORT;
+                let mut udp = vec![0; 8 + PAYLOAD_LENGTH + 5];
+                PingWriter::format_udp(&mut udp, &signature(true), &probe, src);
+                assert!(valid_checksum(src, probe.target, 17, &udp), "{} {}", src, identifier);
+                let checksum = (udp[6] as u16) << 8 | udp[7] as u16;
+                match (identifier, src) {
+                    (0, IpAddr::V6(_)) => assert_eq!(checksum, 0xffff),
+                    _ => assert_eq!(checksum, identifier),
+                }
+
+                // The ICMP checksum stores the source port
+                probe.src_port = identifier;
+                let mut icmp = vec![0; 8 + PAYLOAD_LENGTH];
+                PingWriter::format_icmp(&mut icmp, &signature(true), &probe);
+                let protocol = match (src, probe.target) {
+                    (IpAddr::V6(src), IpAddr::V6(target)) => {
+                        PingWriter::format_icmpv6(&mut icmp, src, target);
+                        58
+                    }
+                    _ => 1,
+                };
+                PingWriter::compensate_checksum(&mut icmp, 2, 8 + COMPENSATION_OFFSET, identifier);
+                assert!(valid_checksum(src, probe.target, protocol, &icmp), "{} {}", src, identifier);
+                assert_eq!((icmp[2] as u16) << 8 | icmp[3] as u16, identifier);
+            }
+        }
+
+        // The checksum of the UDP probes is always set on IPv6
+        let (src, probe) = ("2001:db8::1".parse().unwrap(), request("2001:db8:1::1", 1, 1));
+        let mut udp = vec![0; 8 + PAYLOAD_LENGTH];
+        PingWriter::format_udp(&mut udp, &signature(false), &probe, src);
+        assert!(udp[6] != 0 || udp[7] != 0);
+        assert!(valid_checksum(src, probe.target, 17, &udp));
+    }
+
+    #[test]
+    fn bounded_queue() {
+        let (gate, receiver) = mpsc::channel();
+        let (loopback, _replies) = mpsc::channel();
+        let transport = Box::new(GatedTransport { gate: receiver });
+        let local = "198.51.100.1".parse().unwrap();
+        let writer = Arc::new(PingWriter::new(transport, local, PingMethod::ICMP, 1000, ProbeKey::new(1, 2), 1, false, loopback));
+        let capacity = writer.queue_capacity();
+        assert_eq!(capacity, MIN_QUEUE_SIZE);
+        assert_eq!(writer.queue_depth(), 0);
+
+        // The first request is taken by the writer, blocked sending it, and the rest fill the queue
+        let target = "192.0.2.1".parse().unwrap();
+        for sequence in 0..capacity as u16 + 1 {
+            writer.send_icmp(target, 64, 1, sequence);
+        }
+        assert_eq!(writer.queue_depth(), capacity + 1);
+        assert_eq!(writer.queued_packets(), capacity as u64 + 1);
+
+        // Another request blocks until the writer sends the queued packets
+        let queued = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let (writer, queued) = (writer.clone(), queued.clone());
+            thread::spawn(move || {
+                writer.send_icmp(target, 64, 2, 0);
+                queued.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!queued.load(Ordering::SeqCst));
+        assert_eq!(writer.queue_depth(), capacity + 2);
+        assert_eq!(writer.sended_packets(), 0);
+
+        drop(gate);
+        sender.join().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while writer.queue_depth() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(writer.queue_depth(), 0);
+        assert_eq!(writer.sended_packets(), capacity as u64 + 2);
+        assert_eq!(writer.dropped_packets(), 0);
     }
 
+    #[test]
+    fn tcp_identifier_and_sequence() {
+        let (src, target) = ("198.51.100.1".parse().unwrap(), "192.0.2.1".parse().unwrap());
+        let request = request("192.0.2.1", 0xabcd, 0x1234);
+        let mut syn = vec![0; 20];
+        PingWriter::format_tcp(&mut syn, 7, &request, IpAddr::V4(src), true);
+        let probe = TcpPacket::new(&syn).unwrap();
+        assert_eq!((probe.get_source(), probe.get_destination()), (7, TCP_PORT));
+        assert_eq!(probe.get_sequence(), 0xabcd_1234);
+        assert_eq!(probe.get_acknowledgement(), 0);
+        assert_eq!(probe.get_flags() as u8, TcpFlags::SYN as u8);
+        assert_eq!(probe.get_checksum(), tcp::ipv4_checksum(&probe, &src, &target));
 
+        let mut ack = vec![0; 20];
+        PingWriter::format_tcp(&mut ack, 7, &request, IpAddr::V4(src), false);
+        let probe = TcpPacket::new(&ack).unwrap();
+        assert_eq!((probe.get_sequence(), probe.get_acknowledgement()), (0xabcd_1234, 0xabcd_1234));
+        assert_eq!(probe.get_flags() as u8, TcpFlags::ACK as u8);
+        assert_eq!(probe.get_checksum(), tcp::ipv4_checksum(&probe, &src, &target));
 
-    /// Get the current key of the packets
-    pub fn get_payload_key() -> &'static [u8; 2] {
-        return b"mt";
+        // A SYN is acknowledged with its sequence plus one, by a SYN-ACK or a RST-ACK
+        let synack = (TcpFlags::SYN | TcpFlags::ACK) as u8;
+        let reply = tcp_reply(synack, 42, 0xabcd_1235);
+        assert_eq!(PingHandler::get_tcp_id_seq(&TcpPacket::new(&reply).unwrap().from_packet()), (0xabcd, 0x1234));
+        let reply = tcp_reply((TcpFlags::RST | TcpFlags::ACK) as u8, 0, 0xabcd_1235);
+        assert_eq!(PingHandler::get_tcp_id_seq(&TcpPacket::new(&reply).unwrap().from_packet()), (0xabcd, 0x1234));
+        // An ACK is answered by a RST with its acknowledgement as sequence
+        let reply = tcp_reply(TcpFlags::RST as u8, 0xabcd_1234, 0);
+        assert_eq!(PingHandler::get_tcp_id_seq(&TcpPacket::new(&reply).unwrap().from_packet()), (0xabcd, 0x1234));
+        // The acknowledgement wraps around for the last sequence
+        let reply = tcp_reply(synack, 0, 0);
+        assert_eq!(PingHandler::get_tcp_id_seq(&TcpPacket::new(&reply).unwrap().from_packet()), (0xffff, 0xffff));
     }
 }
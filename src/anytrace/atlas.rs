@@ -0,0 +1,250 @@
+use super::ping::PingMethod;
+use super::sink::{json_string, HopRecord, ResultSink, TraceRecord};
+
+use std::io::Write;
+use std::net::IpAddr;
+
+/// Firmware version written on the results, so the parsers use the current traceroute schema
+const ATLAS_FIRMWARE: u32 = 5000;
+
+/// Write the traces as RIPE Atlas traceroute results, one JSON object per line.
+///
+/// Each trace is a result with a `result` array of the hops probed, up to the first one
+/// answered by the target, where the probes without replies are written as `{"x": "*"}`.
+/// The hops that were not probed are missing, as the traces start at the estimated distance
+/// of the target and stop at the first router already seen. Only the finished traces are
+/// written, ignoring the individual records of the probes and replies.
+///
+/// The `fw` field is always 5000, the firmware of the current schema, as the results don't
+/// come from an Atlas probe. The `size` of the result is the length of the probes without
+/// their IP header, or 0 if the trace has no probe sent by this node.
+pub struct AtlasSink<W: Write> {
+    writer: W,
+    msm_id: u32,
+    prb_id: u32,
+}
+
+impl<W: Write> AtlasSink<W> {
+    /// Create a sink writing to the given writer, identifying the results with the measurement
+    /// and probe identifiers.
+    pub fn new(writer: W, msm_id: u32, prb_id: u32) -> AtlasSink<W> {
+        return AtlasSink {
+            writer: writer,
+            msm_id: msm_id,
+            prb_id: prb_id,
+        };
+    }
+}
+
+impl<W: Write> ResultSink for AtlasSink<W> {
+    fn write(&mut self, _record: &HopRecord) {}
+
+    fn write_trace(&mut self, trace: &TraceRecord) {
+        // Stop at the first hop reaching the target, as the upper hops only repeat it
+        let last = trace.hops.iter()
+            .filter(|hop| hop.router == trace.target)
+            .map(|hop| hop.ttl)
+            .min()
+            .or(trace.hops.iter().map(|hop| hop.ttl).max())
+            .unwrap_or(0);
+        let mut ttls: Vec<u8> = trace.probed.iter().cloned()
+            .chain(trace.hops.iter().map(|hop| hop.ttl))
+            .filter(|ttl| *ttl <= last)
+            .collect();
+        ttls.sort();
+        ttls.dedup();
+        let mut hops = Vec::new();
+        for ttl in ttls {
+            let packets = trace.hops.iter()
+                .filter(|hop| hop.ttl == ttl)
+                .map(packet_json)
+                .collect::<Vec<_>>();
+            let packets = match packets.is_empty() {
+                true => "{\"x\": \"*\"}".to_string(),
+                false => packets.join(", "),
+            };
+            hops.push(format!("{{\"hop\": {}, \"result\": [{}]}}", ttl, packets));
+        }
+
+        let start_ms = trace.hops.iter().filter_map(|hop| hop.sent_ms).min()
+            .or(trace.hops.iter().filter_map(|hop| hop.received_ms).min())
+            .unwrap_or(0);
+        let end_ms = trace.hops.iter().filter_map(|hop| hop.received_ms).max().unwrap_or(start_ms);
+        let proto = match trace.method {
+            PingMethod::ICMP => "ICMP",
+            PingMethod::UDP => "UDP",
+            PingMethod::TcpSyn | PingMethod::TcpAck => "TCP",
+        };
+        let af = if trace.target.is_ipv4() { 4 } else { 6 };
+        let line = format!(
+            "{{\"af\": {}, \"dst_addr\": {}, \"dst_name\": {}, \"endtime\": {}, \"from\": {}, \"fw\": {}, \"msm_id\": {}, \"msm_name\": \"Traceroute\", \"prb_id\": {}, \"proto\": \"{}\", \"result\": [{}], \"size\": {}, \"src_addr\": {}, \"timestamp\": {}, \"type\": \"traceroute\"}}",
+            af,
+            json_string(&trace.target),
+            json_string(&trace.target),
+            end_ms / 1000,
+            json_string(&trace.source),
+            ATLAS_FIRMWARE,
+            self.msm_id,
+            self.prb_id,
+            proto,
+            hops.join(", "),
+            trace.probe_size.unwrap_or(0),
+            json_string(&trace.source),
+            start_ms / 1000
+        );
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            error!("Unable to write the result: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Unable to flush the results: {}", e);
+        }
+    }
+}
+
+/// Format the reply as a packet of the result of a hop
+fn packet_json(hop: &HopRecord) -> String {
+    let mut fields = vec![format!("\"from\": {}", json_string(&hop.router))];
+    if let Some(error) = unreachable_error(hop.router, hop.icmp_type, hop.icmp_code) {
+        fields.push(format!("\"err\": {}", error));
+    }
+    if let Some(ref quote) = hop.quote {
+        // The TTL of the packet triggering the error is only written if it's not 1, as Atlas does
+        if quote.ttl != 1 {
+            fields.push(format!("\"ittl\": {}", quote.ttl));
+        }
+    }
+    if let Some(rtt) = hop.rtt_us {
+        fields.push(format!("\"rtt\": {:.3}", rtt as f64 / 1000.0));
+    }
+    fields.push(format!("\"size\": {}", hop.size));
+    if let Some(ttl) = hop.reply_ttl {
+        fields.push(format!("\"ttl\": {}", ttl));
+    }
+    if !hop.extensions.mpls.is_empty() {
+        let labels = hop.extensions.mpls.iter().map(|label| {
+            format!(
+                "{{\"exp\": {}, \"label\": {}, \"s\": {}, \"ttl\": {}}}",
+                label.traffic_class, label.label, label.bottom as u8, label.ttl
+            )
+        });
+        fields.push(format!(
+            "\"icmpext\": {{\"version\": 2, \"rfc4884\": 1, \"obj\": [{{\"class\": 1, \"type\": 1, \"mpls\": [{}]}}]}}",
+            labels.collect::<Vec<_>>().join(", ")
+        ));
+    }
+    return format!("{{{}}}", fields.join(", "));
+}
+
+/// Get the error written by Atlas for a Destination Unreachable message, using a letter
+/// for the known codes and the code otherwise. Port unreachable is the expected reply of
+/// the UDP probes reaching the target, so it's not an error.
+fn unreachable_error(router: IpAddr, icmp_type: Option<u8>, icmp_code: Option<u8>) -> Option<String> {
+    let code = icmp_code?;
+    let letter = match (router.is_ipv4(), icmp_type?) {
+        (true, 3) => match code {
+            0 => "N",
+            1 => "H",
+            2 => "P",
+            3 => return None,
+            13 => "A",
+            _ => return Some(code.to_string()),
+        },
+        (false, 1) => match code {
+            0 => "N",
+            1 => "A",
+            3 => "H",
+            4 => return None,
+            _ => return Some(code.to_string()),
+        },
+        _ => return None,
+    };
+    return Some(format!("\"{}\"", letter));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::helper::QuotedHeader;
+    use super::super::ping::{IcmpExtensions, MplsLabel};
+
+    fn reply(ttl: u8, router: &str, icmp_type: u8, icmp_code: u8) -> HopRecord {
+        return HopRecord {
+            target: "192.0.2.1".parse().unwrap(),
+            router: router.parse().unwrap(),
+            ttl: ttl,
+            reply_ttl: Some(250),
+            size: 36,
+            sent_ms: Some(1_500_000_000_000),
+            received_ms: Some(1_500_000_001_000),
+            rtt_us: Some(12_345),
+            icmp_type: Some(icmp_type),
+            icmp_code: Some(icmp_code),
+            method: PingMethod::ICMP,
+            quote: None,
+            extensions: IcmpExtensions::default(),
+        };
+    }
+
+    fn trace(probed: Vec<u8>, hops: Vec<HopRecord>) -> TraceRecord {
+        return TraceRecord {
+            source: "198.51.100.1".parse().unwrap(),
+            target: "192.0.2.1".parse().unwrap(),
+            method: PingMethod::ICMP,
+            paris: false,
+            probe_size: Some(26),
+            probed: probed,
+            hops: hops,
+        };
+    }
+
+    fn write(trace: &TraceRecord) -> String {
+        let mut sink = AtlasSink::new(Vec::new(), 7, 9);
+        sink.write_trace(trace);
+        return String::from_utf8(sink.writer).unwrap();
+    }
+
+    #[test]
+    fn only_the_hops_probed() {
+        let mut router = reply(4, "10.0.0.4", 11, 0);
+        router.quote = Some(QuotedHeader {
+            ttl: 2,
+            tos: 0,
+            identification: 1,
+            length: 46,
+            checksum: Some(0),
+        });
+        router.extensions.mpls.push(MplsLabel {
+            label: 16002,
+            traffic_class: 0,
+            bottom: true,
+            ttl: 1,
+        });
+        let mut target = reply(6, "192.0.2.1", 0, 0);
+        target.rtt_us = None;
+        let trace = trace(vec![4, 5, 6, 7], vec![router, target, reply(7, "192.0.2.1", 0, 0)]);
+        assert_eq!(
+            write(&trace),
+            "{\"af\": 4, \"dst_addr\": \"192.0.2.1\", \"dst_name\": \"192.0.2.1\", \"endtime\": 1500000001, \
+             \"from\": \"198.51.100.1\", \"fw\": 5000, \"msm_id\": 7, \"msm_name\": \"Traceroute\", \"prb_id\": 9, \
+             \"proto\": \"ICMP\", \"result\": [\
+             {\"hop\": 4, \"result\": [{\"from\": \"10.0.0.4\", \"ittl\": 2, \"rtt\": 12.345, \"size\": 36, \"ttl\": 250, \
+             \"icmpext\": {\"version\": 2, \"rfc4884\": 1, \"obj\": [{\"class\": 1, \"type\": 1, \"mpls\": [{\"exp\": 0, \"label\": 16002, \"s\": 1, \"ttl\": 1}]}]}}]}, \
+             {\"hop\": 5, \"result\": [{\"x\": \"*\"}]}, \
+             {\"hop\": 6, \"result\": [{\"from\": \"192.0.2.1\", \"size\": 36, \"ttl\": 250}]}], \
+             \"size\": 26, \"src_addr\": \"198.51.100.1\", \"timestamp\": 1500000000, \"type\": \"traceroute\"}\n"
+        );
+    }
+
+    #[test]
+    fn errors_of_the_hops() {
+        let trace = trace(vec![], vec![reply(2, "10.0.0.2", 3, 13), reply(3, "192.0.2.1", 3, 3)]);
+        let line = write(&trace);
+        assert!(line.contains("\"result\": [{\"hop\": 2, \"result\": [{\"from\": \"10.0.0.2\", \"err\": \"A\", "));
+        assert!(line.contains("{\"hop\": 3, \"result\": [{\"from\": \"192.0.2.1\", \"rtt\""));
+        assert_eq!(unreachable_error("2001:db8::1".parse().unwrap(), Some(1), Some(4)), None);
+        assert_eq!(unreachable_error("2001:db8::1".parse().unwrap(), Some(1), Some(5)), Some("5".to_string()));
+    }
+}
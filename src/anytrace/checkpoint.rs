@@ -0,0 +1,530 @@
+use super::helper::QuotedHeader;
+use super::ping::{IcmpExtensions, InterfaceInformation, KernelTimestamp, MplsLabel, PingMethod, ProbeKey};
+use super::seen::{put_address, put_u32, read_address, read_u32, SeenNetworks};
+use super::sink::HopRecord;
+use super::{Trace, TraceConfiguration};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Instant;
+
+/// Magic number starting the checkpoints, with the version of the format
+const CHECKPOINT_MAGIC: &[u8; 8] = b"ANYCKPT\x01";
+
+/// Longest string read, as the names of the methods and interfaces are short
+const MAX_STRING_LENGTH: u32 = 255;
+
+/// State of a measurement saved periodically, used to resume it after a crash or a reboot.
+///
+/// It holds the amount of lines read from the hitlist, the key authenticating the probes,
+/// the traces in progress with the replies of their hops, the networks caught on the catchment
+/// mode and the networks and routers seen. The traces are resumed from the hop being probed,
+/// but the times the probes were sent are lost, so the replies to the probes sent before the
+/// checkpoint have no round trip time. The file is only readable by its owner, as it holds the key.
+pub struct Checkpoint {
+    pub(crate) hitlist_offset: u64,
+    pub(crate) key: ProbeKey,
+    pub(crate) traces: Vec<((IpAddr, u8), TraceConfiguration)>,
+    pub(crate) caught: HashSet<(IpAddr, u8)>,
+    pub(crate) seen: SeenNetworks,
+}
+
+impl Checkpoint {
+    /// Load the checkpoint from the given file
+    pub fn load(path: &str) -> io::Result<Checkpoint> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid checkpoint"));
+        }
+        let hitlist_offset = read_u64(&mut reader)?;
+        let key = ProbeKey::new(read_u64(&mut reader)?, read_u64(&mut reader)?);
+        let mut traces = Vec::new();
+        for _ in 0..read_u32(&mut reader)? {
+            traces.push(read_trace(&mut reader)?);
+        }
+        let mut caught = HashSet::new();
+        for _ in 0..read_u32(&mut reader)? {
+            caught.insert((read_address(&mut reader)?, read_u8(&mut reader)?));
+        }
+        let seen = SeenNetworks::import(&mut reader)?;
+        return Ok(Checkpoint {
+            hitlist_offset: hitlist_offset,
+            key: key,
+            traces: traces,
+            caught: caught,
+            seen: seen,
+        });
+    }
+
+    /// Get the amount of lines of the hitlist already processed
+    pub fn hitlist_offset(&self) -> u64 {
+        return self.hitlist_offset;
+    }
+
+    /// Get the amount of traces in progress
+    pub fn traces(&self) -> usize {
+        return self.traces.len();
+    }
+}
+
+/// Save the state of the measurement to the given file. The checkpoint is written to a
+/// temporary file first, replacing the previous one only once it's complete.
+///
+/// The hitlist offset must not count the targets whose probes are still queued, so they are
+/// probed when resuming.
+pub(crate) fn save(
+    path: &str,
+    hitlist_offset: u64,
+    key: &ProbeKey,
+    traces: &HashMap<(IpAddr, u8), TraceConfiguration>,
+    caught: &HashSet<(IpAddr, u8)>,
+    seen: &SeenNetworks,
+) -> io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    {
+        // The file is created again, so a previous file or link can't change its permissions
+        let _ = fs::remove_file(&temporary);
+        let file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&temporary)?;
+        let mut writer = BufWriter::new(file);
+        let (k0, k1) = key.halves();
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(CHECKPOINT_MAGIC);
+        put_u64(&mut buffer, hitlist_offset);
+        put_u64(&mut buffer, k0);
+        put_u64(&mut buffer, k1);
+        put_u32(&mut buffer, traces.len() as u32);
+        for (network, trace) in traces {
+            put_trace(&mut buffer, network, trace);
+        }
+        put_u32(&mut buffer, caught.len() as u32);
+        for &(network, length) in caught {
+            put_address(&mut buffer, network);
+            buffer.push(length);
+        }
+        writer.write_all(&buffer)?;
+        seen.export(&mut writer)?;
+        writer.flush()?;
+    }
+    return fs::rename(&temporary, path);
+}
+
+/// Encode the trace in progress, with its network, the state of each hop and its replies
+fn put_trace(buffer: &mut Vec<u8>, network: &(IpAddr, u8), trace: &TraceConfiguration) {
+    put_address(buffer, network.0);
+    buffer.push(network.1);
+    put_address(buffer, trace.source);
+    buffer.push(trace.max_hop);
+    buffer.push(trace.current_ttl);
+    match trace.probe_size {
+        Some(size) => buffer.extend_from_slice(&[1, (size >> 8) as u8, size as u8]),
+        None => buffer.extend_from_slice(&[0, 0, 0]),
+    }
+    for (hop, interfaces) in trace.traces.iter().zip(trace.interfaces.iter()) {
+        match hop {
+            Some(hop) => {
+                buffer.push(1);
+                put_address(buffer, hop.router);
+                buffer.push(hop.hops);
+                buffer.push(hop.done as u8);
+                put_option(buffer, hop.sent_ms);
+                match hop.pending {
+                    Some((ref reply, _)) => {
+                        buffer.push(1);
+                        put_record(buffer, reply);
+                    }
+                    None => buffer.push(0),
+                }
+            }
+            None => buffer.push(0),
+        }
+        put_u32(buffer, interfaces.len() as u32);
+        for interface in interfaces {
+            put_address(buffer, *interface);
+        }
+    }
+    put_u32(buffer, trace.replies.len() as u32);
+    for reply in &trace.replies {
+        put_record(buffer, reply);
+    }
+}
+
+/// Decode a trace encoded by `put_trace`
+fn read_trace<R: Read>(reader: &mut R) -> io::Result<((IpAddr, u8), TraceConfiguration)> {
+    let network = (read_address(reader)?, read_u8(reader)?);
+    let source = read_address(reader)?;
+    let max_hop = read_u8(reader)?;
+    let mut trace = TraceConfiguration::new(source, max_hop);
+    trace.current_ttl = read_u8(reader)?;
+    let mut size = [0u8; 3];
+    reader.read_exact(&mut size)?;
+    if size[0] != 0 {
+        trace.probe_size = Some((size[1] as u16) << 8 | size[2] as u16);
+    }
+    for index in 0..max_hop as usize {
+        if read_u8(reader)? != 0 {
+            let router = read_address(reader)?;
+            let hops = read_u8(reader)?;
+            let done = read_u8(reader)? != 0;
+            let sent_ms = read_option(reader)?;
+            // The time the pending reply was received is lost, as the time of its probe
+            let pending = match read_u8(reader)? {
+                0 => None,
+                _ => Some((read_record(reader)?, (Instant::now(), KernelTimestamp::default()))),
+            };
+            trace.traces[index] = Some(Trace {
+                router: router,
+                hops: hops,
+                done: done,
+                sent_ms: sent_ms,
+                sent: None,
+                flow_sent: HashMap::new(),
+                pending: pending,
+            });
+        }
+        for _ in 0..read_u32(reader)? {
+            trace.interfaces[index].push(read_address(reader)?);
+        }
+    }
+    for _ in 0..read_u32(reader)? {
+        trace.replies.push(read_record(reader)?);
+    }
+    return Ok((network, trace));
+}
+
+/// Encode the record of a reply, with its quoted header and extensions
+fn put_record(buffer: &mut Vec<u8>, record: &HopRecord) {
+    put_address(buffer, record.target);
+    put_address(buffer, record.router);
+    buffer.push(record.ttl);
+    put_option(buffer, record.reply_ttl.map(|ttl| ttl as u64));
+    buffer.extend_from_slice(&[(record.size >> 8) as u8, record.size as u8]);
+    put_option(buffer, record.sent_ms);
+    put_option(buffer, record.received_ms);
+    put_option(buffer, record.rtt_us);
+    put_option(buffer, record.icmp_type.map(|kind| kind as u64));
+    put_option(buffer, record.icmp_code.map(|code| code as u64));
+    put_string(buffer, &record.method.to_string());
+    match record.quote {
+        Some(ref quote) => {
+            buffer.extend_from_slice(&[1, quote.ttl, quote.tos]);
+            put_u32(buffer, quote.identification);
+            buffer.extend_from_slice(&[(quote.length >> 8) as u8, quote.length as u8]);
+            put_option(buffer, quote.checksum.map(|checksum| checksum as u64));
+        }
+        None => buffer.push(0),
+    }
+    put_u32(buffer, record.extensions.mpls.len() as u32);
+    for label in &record.extensions.mpls {
+        put_u32(buffer, label.label);
+        buffer.extend_from_slice(&[label.traffic_class, label.bottom as u8, label.ttl]);
+    }
+    put_u32(buffer, record.extensions.interfaces.len() as u32);
+    for interface in &record.extensions.interfaces {
+        buffer.push(interface.role);
+        put_option(buffer, interface.index.map(|index| index as u64));
+        match interface.address {
+            Some(address) => {
+                buffer.push(1);
+                put_address(buffer, address);
+            }
+            None => buffer.push(0),
+        }
+        match interface.name {
+            Some(ref name) => {
+                buffer.push(1);
+                put_string(buffer, name);
+            }
+            None => buffer.push(0),
+        }
+        put_option(buffer, interface.mtu.map(|mtu| mtu as u64));
+    }
+}
+
+/// Decode a record encoded by `put_record`
+fn read_record<R: Read>(reader: &mut R) -> io::Result<HopRecord> {
+    let target = read_address(reader)?;
+    let router = read_address(reader)?;
+    let ttl = read_u8(reader)?;
+    let reply_ttl = read_option(reader)?.map(|ttl| ttl as u8);
+    let size = (read_u8(reader)? as u16) << 8 | read_u8(reader)? as u16;
+    let sent_ms = read_option(reader)?;
+    let received_ms = read_option(reader)?;
+    let rtt_us = read_option(reader)?;
+    let icmp_type = read_option(reader)?.map(|kind| kind as u8);
+    let icmp_code = read_option(reader)?.map(|code| code as u8);
+    let method: PingMethod = read_string(reader)?
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid method"))?;
+    let quote = match read_u8(reader)? {
+        0 => None,
+        _ => Some(QuotedHeader {
+            ttl: read_u8(reader)?,
+            tos: read_u8(reader)?,
+            identification: read_u32(reader)?,
+            length: (read_u8(reader)? as u16) << 8 | read_u8(reader)? as u16,
+            checksum: read_option(reader)?.map(|checksum| checksum as u16),
+        }),
+    };
+    let mut extensions = IcmpExtensions::default();
+    for _ in 0..read_u32(reader)? {
+        extensions.mpls.push(MplsLabel {
+            label: read_u32(reader)?,
+            traffic_class: read_u8(reader)?,
+            bottom: read_u8(reader)? != 0,
+            ttl: read_u8(reader)?,
+        });
+    }
+    for _ in 0..read_u32(reader)? {
+        let role = read_u8(reader)?;
+        let index = read_option(reader)?.map(|index| index as u32);
+        let address = match read_u8(reader)? {
+            0 => None,
+            _ => Some(read_address(reader)?),
+        };
+        let name = match read_u8(reader)? {
+            0 => None,
+            _ => Some(read_string(reader)?),
+        };
+        extensions.interfaces.push(InterfaceInformation {
+            role: role,
+            index: index,
+            address: address,
+            name: name,
+            mtu: read_option(reader)?.map(|mtu| mtu as u32),
+        });
+    }
+    return Ok(HopRecord {
+        target: target,
+        router: router,
+        ttl: ttl,
+        reply_ttl: reply_ttl,
+        size: size,
+        sent_ms: sent_ms,
+        received_ms: received_ms,
+        rtt_us: rtt_us,
+        icmp_type: icmp_type,
+        icmp_code: icmp_code,
+        method: method,
+        quote: quote,
+        extensions: extensions,
+    });
+}
+
+/// Write the optional value preceded by a byte telling if it's present
+fn put_option(buffer: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            buffer.push(1);
+            put_u64(buffer, value);
+        }
+        None => buffer.push(0),
+    }
+}
+
+fn read_option<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    return match read_u8(reader)? {
+        0 => Ok(None),
+        _ => Ok(Some(read_u64(reader)?)),
+    };
+}
+
+/// Write the string preceded by its length
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    put_u32(buffer, value.len() as u32);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = read_u32(reader)?;
+    if length > MAX_STRING_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "String too long"));
+    }
+    let mut value = vec![0u8; length as usize];
+    reader.read_exact(&mut value)?;
+    return String::from_utf8(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid string"));
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    put_u32(buffer, (value >> 32) as u32);
+    put_u32(buffer, value as u32);
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let high = read_u32(reader)? as u64;
+    return Ok(high << 32 | read_u32(reader)? as u64);
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buffer = [0u8; 1];
+    reader.read_exact(&mut buffer)?;
+    return Ok(buffer[0]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+
+    fn reply(target: &str, router: &str, ttl: u8) -> HopRecord {
+        return HopRecord {
+            target: target.parse().unwrap(),
+            router: router.parse().unwrap(),
+            ttl: ttl,
+            reply_ttl: Some(250),
+            size: 56,
+            sent_ms: Some(1_500_000_000_000),
+            received_ms: Some(1_500_000_000_012),
+            rtt_us: Some(12_345),
+            icmp_type: Some(11),
+            icmp_code: Some(0),
+            method: PingMethod::UDP,
+            quote: Some(QuotedHeader {
+                ttl: 1,
+                tos: 0x20,
+                identification: 0x1234_5678,
+                length: 46,
+                checksum: Some(0xbeef),
+            }),
+            extensions: IcmpExtensions::default(),
+        };
+    }
+
+    fn hop(router: &str, hops: u8, pending: Option<HopRecord>) -> Trace {
+        return Trace {
+            router: router.parse().unwrap(),
+            hops: hops,
+            done: pending.is_none(),
+            sent_ms: Some(1_500_000_000_000),
+            sent: Some((Instant::now(), KernelTimestamp::default())),
+            flow_sent: HashMap::new(),
+            pending: pending.map(|reply| (reply, (Instant::now(), KernelTimestamp::default()))),
+        };
+    }
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("anytrace-{}-{}", name, std::process::id()));
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut router = reply("192.0.2.1", "10.0.0.2", 2);
+        router.extensions.mpls.push(MplsLabel {
+            label: 16002,
+            traffic_class: 5,
+            bottom: true,
+            ttl: 1,
+        });
+        router.extensions.interfaces.push(InterfaceInformation {
+            role: 0,
+            index: Some(7),
+            address: Some("10.0.0.2".parse().unwrap()),
+            name: Some("xe-0/0/1".to_string()),
+            mtu: Some(9000),
+        });
+        router.extensions.interfaces.push(InterfaceInformation {
+            role: 2,
+            index: None,
+            address: None,
+            name: None,
+            mtu: None,
+        });
+        let mut pending = reply("192.0.2.1", "10.0.0.1", 1);
+        pending.quote = None;
+        pending.rtt_us = None;
+        pending.method = PingMethod::TcpAck;
+
+        let mut trace = TraceConfiguration::new("198.51.100.1".parse().unwrap(), 4);
+        trace.current_ttl = 1;
+        trace.probe_size = Some(26);
+        trace.traces[0] = Some(hop("10.0.0.1", 1, Some(pending.clone())));
+        trace.traces[1] = Some(hop("10.0.0.2", 2, None));
+        trace.interfaces[1] = vec!["10.0.0.2".parse().unwrap(), "10.0.1.2".parse().unwrap()];
+        trace.replies.push(router.clone());
+
+        let mut unprobed = TraceConfiguration::new("2001:db8::1".parse().unwrap(), 3);
+        let mut target = reply("2001:db8:1::1", "2001:db8:1::1", 3);
+        target.icmp_type = Some(129);
+        target.quote.as_mut().unwrap().checksum = None;
+        unprobed.replies.push(target.clone());
+
+        let network = ("192.0.2.0".parse().unwrap(), 24);
+        let network6 = ("2001:db8:1::".parse().unwrap(), 48);
+        let mut traces = HashMap::new();
+        traces.insert(network, trace);
+        traces.insert(network6, unprobed);
+        let mut caught = HashSet::new();
+        caught.insert(("203.0.113.0".parse().unwrap(), 24));
+        caught.insert(("2001:db8:2::".parse().unwrap(), 48));
+        let mut seen = SeenNetworks::new();
+        seen.insert_network("198.51.100.0".parse().unwrap(), 24);
+        seen.insert_router("10.0.0.1".parse().unwrap());
+
+        let path = path("save-and-load");
+        save(&path, 42, &ProbeKey::new(1, 2), &traces, &caught, &seen).unwrap();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(checkpoint.hitlist_offset(), 42);
+        assert_eq!(checkpoint.key, ProbeKey::new(1, 2));
+        assert_eq!(checkpoint.caught, caught);
+        assert!(checkpoint.seen.contains_network("198.51.100.7".parse().unwrap(), 24));
+        assert!(checkpoint.seen.contains_router("10.0.0.1".parse().unwrap()));
+        assert_eq!(checkpoint.traces(), 2);
+
+        let loaded = &checkpoint.traces.iter().find(|&&(key, _)| key == network).unwrap().1;
+        assert_eq!(loaded.source, "198.51.100.1".parse::<IpAddr>().unwrap());
+        assert_eq!((loaded.max_hop, loaded.current_ttl, loaded.probe_size), (4, 1, Some(26)));
+        assert_eq!(loaded.interfaces[1], traces[&network].interfaces[1]);
+        assert_eq!(format!("{:?}", loaded.replies), format!("{:?}", vec![router]));
+        let first = loaded.traces[0].as_ref().unwrap();
+        assert_eq!((first.router, first.hops, first.done), ("10.0.0.1".parse().unwrap(), 1, false));
+        assert_eq!(first.sent_ms, Some(1_500_000_000_000));
+        // The times the probes were sent are lost
+        assert!(first.sent.is_none());
+        assert_eq!(format!("{:?}", first.pending.as_ref().unwrap().0), format!("{:?}", pending));
+        let second = loaded.traces[1].as_ref().unwrap();
+        assert!(second.done && second.pending.is_none());
+        assert!(loaded.traces[2].is_none() && loaded.traces[3].is_none());
+
+        let loaded = &checkpoint.traces.iter().find(|&&(key, _)| key == network6).unwrap().1;
+        assert_eq!((loaded.max_hop, loaded.current_ttl, loaded.probe_size), (3, 3, None));
+        assert!(loaded.traces.iter().all(|hop| hop.is_none()));
+        assert_eq!(format!("{:?}", loaded.replies), format!("{:?}", vec![target]));
+    }
+
+    #[test]
+    fn invalid_checkpoints() {
+        let path = path("invalid");
+        fs::write(&path, b"ANYSEEN\x01").unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+        // Truncated after the key
+        let mut buffer = CHECKPOINT_MAGIC.to_vec();
+        buffer.extend_from_slice(&[0; 24]);
+        fs::write(&path, &buffer).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn long_strings() {
+        let mut buffer = Vec::new();
+        put_string(&mut buffer, "xe-0/0/1");
+        assert_eq!(read_string(&mut Cursor::new(buffer)).unwrap(), "xe-0/0/1");
+        // The length is checked before the string is allocated
+        let error = read_string(&mut Cursor::new(vec![0xff, 0xff, 0xff, 0xff])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let mut buffer = Vec::new();
+        put_u32(&mut buffer, MAX_STRING_LENGTH + 1);
+        buffer.resize(buffer.len() + MAX_STRING_LENGTH as usize + 1, b'x');
+        assert!(read_string(&mut Cursor::new(buffer)).is_err());
+    }
+}
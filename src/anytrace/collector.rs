@@ -0,0 +1,620 @@
+use super::helper::{auth_nonce, auth_proof, time_from_epoch_ms};
+use super::ping::ProbeKey;
+use super::sink::{hop_json, json_string, trace_json, HopRecord, ResultSink, TraceRecord};
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Kinds of the frames sent between the nodes and the collector
+const FRAME_HELLO: u8 = 1;
+const FRAME_RECORD: u8 = 2;
+const FRAME_ACK: u8 = 3;
+const FRAME_CHALLENGE: u8 = 4;
+const FRAME_WELCOME: u8 = 5;
+
+/// Maximum length of a frame, as longer frames can only come from a corrupted stream
+const MAX_FRAME: usize = 16 << 20;
+
+/// Maximum of records kept while they are not acknowledged by the collector, dropping the oldest ones
+const MAX_BUFFERED: usize = 1 << 20;
+
+/// Interval between the attempts to connect to the collector
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum time waiting for the collector to acknowledge every record when the sink is flushed
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum time to wait for the other side of the connection to authenticate
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum of records written by the collector before acknowledging them, when a node
+/// sends them faster than they are written
+const ACK_INTERVAL: u64 = 256;
+
+enum Message {
+    Record(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Send the records to a collector over TCP, which merges the results of every node.
+///
+/// The records are sent as JSON objects on frames of `length, kind, payload`, numbered
+/// within the session of the sink. They are kept until the collector acknowledges them, and
+/// sent again after reconnecting if the connection is lost, so the collector skips the
+/// records it already has. The records are sent from a background thread, so a slow or
+/// unreachable collector doesn't stop the measurement.
+///
+/// When connecting, the node and the collector prove to each other that they have the key,
+/// with a MAC of the nonces of both sides and the site name:
+///
+/// ```text
+/// collector -> node: CHALLENGE <nonce>
+/// node -> collector: HELLO <session> <nonce> <proof> <site>
+/// collector -> node: WELCOME <proof>
+/// collector -> node: ACK <last record of the session received>
+/// ```
+pub struct CollectorSink {
+    sender: mpsc::Sender<Message>,
+    traces: bool,
+}
+
+impl CollectorSink {
+    /// Create a sink sending the records to the collector on the given address, identifying
+    /// them with the site name and authenticating with the key. The finished traces are also
+    /// sent if `traces` is set.
+    pub fn new(address: &str, site: &str, key: ProbeKey, traces: bool) -> CollectorSink {
+        let (sender, receiver) = mpsc::channel();
+        let uploader = Uploader {
+            address: address.to_string(),
+            site: site.to_string(),
+            key: key,
+            session: time_from_epoch_ms() << 16 ^ process::id() as u64,
+            buffer: VecDeque::new(),
+            next: 1,
+            sent: 0,
+            dropped: 0,
+            connection: None,
+            last_attempt: None,
+            flushes: Vec::new(),
+        };
+        thread::spawn(move || uploader.run(receiver));
+        return CollectorSink {
+            sender: sender,
+            traces: traces,
+        };
+    }
+}
+
+impl ResultSink for CollectorSink {
+    fn write(&mut self, record: &HopRecord) {
+        let _ = self.sender.send(Message::Record(hop_json(record)));
+    }
+
+    fn write_trace(&mut self, trace: &TraceRecord) {
+        if self.traces {
+            let _ = self.sender.send(Message::Record(trace_json(trace)));
+        }
+    }
+
+    fn flush(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        if let Err(_) = self.sender.send(Message::Flush(sender)) {
+            return;
+        }
+        if let Err(_) = receiver.recv_timeout(FLUSH_TIMEOUT) {
+            error!("Timeout waiting for the collector to receive the results");
+        }
+    }
+}
+
+/// Connection to the collector, whose acknowledgements are read on another thread
+struct Connection {
+    writer: BufWriter<TcpStream>,
+    acked: Arc<Mutex<u64>>,
+    broken: Arc<AtomicBool>,
+}
+
+/// Background task of the CollectorSink, sending the records buffered
+struct Uploader {
+    address: String,
+    site: String,
+    key: ProbeKey,
+    session: u64,
+    /// Records not acknowledged yet, with their sequence numbers
+    buffer: VecDeque<(u64, String)>,
+    next: u64,
+    /// Last record sent on the current connection
+    sent: u64,
+    dropped: u64,
+    connection: Option<Connection>,
+    last_attempt: Option<Instant>,
+    flushes: Vec<mpsc::Sender<()>>,
+}
+
+impl Uploader {
+    fn run(mut self, receiver: mpsc::Receiver<Message>) {
+        let mut closed = false;
+        loop {
+            if closed {
+                // The sink was dropped, keep sending the records left until they are acknowledged
+                if self.buffer.is_empty() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100));
+            } else {
+                match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok(message) => {
+                        self.receive(message);
+                        while let Ok(message) = receiver.try_recv() {
+                            self.receive(message);
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => closed = true,
+                }
+            }
+
+            if self.connection.is_none() {
+                self.connect();
+            }
+            self.acknowledge();
+            self.send();
+            if self.buffer.is_empty() {
+                for flush in self.flushes.drain(..) {
+                    let _ = flush.send(());
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, message: Message) {
+        match message {
+            Message::Record(record) => {
+                self.buffer.push_back((self.next, record));
+                self.next += 1;
+                if self.buffer.len() > MAX_BUFFERED {
+                    self.buffer.pop_front();
+                    if self.dropped == 0 {
+                        error!("Too many results waiting for the collector, dropping the oldest ones");
+                    }
+                    self.dropped += 1;
+                }
+            }
+            Message::Flush(sender) => self.flushes.push(sender),
+        }
+    }
+
+    /// Connect to the collector, if the last attempt was long enough ago
+    fn connect(&mut self) {
+        if self.last_attempt.map_or(false, |last| last.elapsed() < RECONNECT_INTERVAL) {
+            return;
+        }
+        self.last_attempt = Some(Instant::now());
+        let (mut reader, writer) = match self.handshake() {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Unable to connect to the collector {}: {}", self.address, e);
+                return;
+            }
+        };
+
+        let acked = Arc::new(Mutex::new(0));
+        let broken = Arc::new(AtomicBool::new(false));
+        let (acked_reader, broken_reader) = (acked.clone(), broken.clone());
+        thread::spawn(move || loop {
+            match read_frame(&mut reader) {
+                Ok((FRAME_ACK, ref payload)) if payload.len() == 8 => {
+                    *acked_reader.lock().unwrap() = read_be64(payload);
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    broken_reader.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+        });
+        info!("Connected to the collector {}", self.address);
+        // Every record not acknowledged is sent again, and skipped by the collector if it already has it
+        self.sent = 0;
+        self.connection = Some(Connection {
+            writer: writer,
+            acked: acked,
+            broken: broken,
+        });
+    }
+
+    /// Connect to the collector and authenticate both sides, returning the halves of the stream
+    fn handshake(&self) -> io::Result<(TcpStream, BufWriter<TcpStream>)> {
+        let mut reader = TcpStream::connect(self.address.as_str())?;
+        reader.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut writer = BufWriter::new(reader.try_clone()?);
+        let challenge = match read_frame(&mut reader)? {
+            (FRAME_CHALLENGE, ref payload) if payload.len() == 8 => read_be64(payload),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected CHALLENGE")),
+        };
+        let nonce = auth_nonce();
+        let mut hello = be64(self.session).to_vec();
+        hello.extend_from_slice(&be64(nonce));
+        hello.extend_from_slice(&be64(auth_proof(&self.key, "node", &[challenge, self.session], &self.site)));
+        hello.extend_from_slice(self.site.as_bytes());
+        write_frame(&mut writer, FRAME_HELLO, &hello)?;
+        writer.flush()?;
+
+        // The collector closes the connection of the nodes failing the authentication
+        let expected = auth_proof(&self.key, "collector", &[challenge, nonce], &self.site);
+        match read_frame(&mut reader) {
+            Ok((FRAME_WELCOME, ref payload)) if payload.len() == 8 && read_be64(payload) == expected => {}
+            _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The collector rejected the secret")),
+        }
+        reader.set_read_timeout(None)?;
+        return Ok((reader, writer));
+    }
+
+    /// Remove the records acknowledged by the collector
+    fn acknowledge(&mut self) {
+        if let Some(ref connection) = self.connection {
+            let acked = *connection.acked.lock().unwrap();
+            while self.buffer.front().map_or(false, |&(sequence, _)| sequence <= acked) {
+                self.buffer.pop_front();
+            }
+        }
+    }
+
+    /// Send the records not sent yet on the current connection
+    fn send(&mut self) {
+        let mut failed = false;
+        if let Some(ref mut connection) = self.connection {
+            let mut result = Ok(());
+            let sent = self.sent;
+            for &(sequence, ref record) in self.buffer.iter().filter(|&&(sequence, _)| sequence > sent) {
+                let mut payload = be64(sequence).to_vec();
+                payload.extend_from_slice(record.as_bytes());
+                result = write_frame(&mut connection.writer, FRAME_RECORD, &payload);
+                if result.is_err() {
+                    break;
+                }
+                self.sent = sequence;
+            }
+            if let Err(e) = result.and_then(|_| connection.writer.flush()) {
+                warn!("Lost the connection with the collector: {}", e);
+                failed = true;
+            } else if connection.broken.load(Ordering::SeqCst) {
+                warn!("Lost the connection with the collector");
+                failed = true;
+            }
+        }
+        if failed {
+            self.connection = None;
+        }
+    }
+}
+
+/// Collector receiving the records of every node of the measurement, merged on a single
+/// dataset as JSON lines tagged with the site of the node.
+///
+/// Each session of a node is tracked with the last record received, so the records sent
+/// again after a reconnection are only written once. Only the nodes with the key are accepted.
+pub struct Collector {
+    listener: TcpListener,
+    key: ProbeKey,
+}
+
+impl Collector {
+    /// Listen for the nodes authenticating with the key on the given address
+    pub fn bind<A: ToSocketAddrs>(address: A, key: ProbeKey) -> io::Result<Collector> {
+        return Ok(Collector {
+            listener: TcpListener::bind(address)?,
+            key: key,
+        });
+    }
+
+    /// Get the address where the collector is listening
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        return self.listener.local_addr();
+    }
+
+    /// Receive the records of the nodes forever, writing them to the writer
+    pub fn run<W: Write + Send + 'static>(self, writer: W) {
+        let writer = Arc::new(Mutex::new(writer));
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
+        let key = self.key;
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (writer, sessions) = (writer.clone(), sessions.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = Collector::handle(stream, key, writer, sessions) {
+                            debug!("Connection with a node closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Unable to accept a node: {}", e),
+            }
+        }
+    }
+
+    /// Authenticate a node and receive its records, acknowledging them once they are written
+    fn handle<W: Write>(
+        mut stream: TcpStream,
+        key: ProbeKey,
+        writer: Arc<Mutex<W>>,
+        sessions: Arc<Mutex<HashMap<(String, u64), u64>>>,
+    ) -> io::Result<()> {
+        let address = stream.peer_addr()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let challenge = auth_nonce();
+        write_frame(&mut stream, FRAME_CHALLENGE, &be64(challenge))?;
+        let (session, nonce, proof) = match read_frame(&mut reader)? {
+            (FRAME_HELLO, ref payload) if payload.len() > 24 => {
+                let site = String::from_utf8_lossy(&payload[24..]).to_string();
+                ((site, read_be64(payload)), read_be64(&payload[8..]), read_be64(&payload[16..]))
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected HELLO")),
+        };
+        if proof != auth_proof(&key, "node", &[challenge, session.1], &session.0) {
+            warn!("Node {} from {} doesn't have the secret of the collector", session.0, address);
+            return Ok(());
+        }
+        write_frame(&mut stream, FRAME_WELCOME, &be64(auth_proof(&key, "collector", &[challenge, nonce], &session.0)))?;
+        stream.set_read_timeout(None)?;
+        let mut acked = *sessions.lock().unwrap().get(&session).unwrap_or(&0);
+        write_frame(&mut stream, FRAME_ACK, &be64(acked))?;
+        info!("Node {} connected from {}", session.0, address);
+
+        let site = json_string(&session.0);
+        loop {
+            let (kind, payload) = match read_frame(&mut reader) {
+                Ok(frame) => frame,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    info!("Node {} disconnected", session.0);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if kind != FRAME_RECORD || payload.len() < 9 {
+                continue;
+            }
+            let sequence = read_be64(&payload);
+            let last = {
+                let mut sessions = sessions.lock().unwrap();
+                let last = sessions.entry(session.clone()).or_insert(0);
+                if sequence > *last {
+                    // Add the site as the first field of the object
+                    let record = String::from_utf8_lossy(&payload[8..]);
+                    let line = format!("{{\"site\": {}, {}", site, record.trim_start_matches('{'));
+                    if let Err(e) = writeln!(writer.lock().unwrap(), "{}", line) {
+                        error!("Unable to write the result: {}", e);
+                        return Err(e);
+                    }
+                    *last = sequence;
+                }
+                *last
+            };
+            // Acknowledge the records once every frame received was written, or regularly
+            // if the node keeps sending them
+            if reader.buffer().is_empty() || last >= acked + ACK_INTERVAL {
+                writer.lock().unwrap().flush()?;
+                write_frame(&mut stream, FRAME_ACK, &be64(last))?;
+                acked = last;
+            }
+        }
+    }
+}
+
+/// Write the frame, preceded by its length and kind
+fn write_frame<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let length = payload.len() as u32 + 1;
+    writer.write_all(&[(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8, kind])?;
+    return writer.write_all(payload);
+}
+
+/// Read the next frame, returning its kind and payload
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    let length = (header[0] as usize) << 24 | (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+    if length == 0 || length > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid frame length"));
+    }
+    let mut payload = vec![0; length - 1];
+    reader.read_exact(&mut payload)?;
+    return Ok((header[4], payload));
+}
+
+fn be64(value: u64) -> [u8; 8] {
+    let mut buffer = [0u8; 8];
+    for i in 0..8 {
+        buffer[i] = (value >> (56 - i * 8)) as u8;
+    }
+    return buffer;
+}
+
+fn read_be64(buffer: &[u8]) -> u64 {
+    return buffer[..8].iter().fold(0, |value, byte| value << 8 | *byte as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ping::{IcmpExtensions, PingMethod};
+
+    use std::net::IpAddr;
+
+    /// Output of the collector shared with the test
+    #[derive(Clone)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Output {
+        fn lines(&self) -> Vec<String> {
+            let buffer = self.0.lock().unwrap();
+            return String::from_utf8_lossy(&buffer).lines().map(|line| line.to_string()).collect();
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            return self.0.lock().unwrap().write(buffer);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn collector() -> (SocketAddr, Output) {
+        let collector = Collector::bind("127.0.0.1:0", ProbeKey::from_secret("secret")).unwrap();
+        let address = collector.local_addr().unwrap();
+        let output = Output(Arc::new(Mutex::new(Vec::new())));
+        let writer = output.clone();
+        thread::spawn(move || collector.run(writer));
+        return (address, output);
+    }
+
+    /// Connect to the collector as a node, returning the stream and the first acknowledgement
+    fn connect(address: SocketAddr, key: &ProbeKey, session: u64, site: &str) -> io::Result<(TcpStream, u64)> {
+        let mut stream = TcpStream::connect(address)?;
+        let challenge = match read_frame(&mut stream)? {
+            (FRAME_CHALLENGE, payload) => read_be64(&payload),
+            _ => panic!("Expected CHALLENGE"),
+        };
+        let mut hello = be64(session).to_vec();
+        hello.extend_from_slice(&be64(1));
+        hello.extend_from_slice(&be64(auth_proof(key, "node", &[challenge, session], site)));
+        hello.extend_from_slice(site.as_bytes());
+        write_frame(&mut stream, FRAME_HELLO, &hello)?;
+        match read_frame(&mut stream)? {
+            (FRAME_WELCOME, ref payload) => {
+                assert_eq!(read_be64(payload), auth_proof(key, "collector", &[challenge, 1], site))
+            }
+            _ => panic!("Expected WELCOME"),
+        }
+        return match read_frame(&mut stream)? {
+            (FRAME_ACK, payload) => Ok((stream, read_be64(&payload))),
+            _ => panic!("Expected ACK"),
+        };
+    }
+
+    fn send_records(stream: &mut TcpStream, sequences: &[u64]) {
+        let mut frames = Vec::new();
+        for &sequence in sequences {
+            let mut payload = be64(sequence).to_vec();
+            payload.extend_from_slice(format!("{{\"sequence\": {}}}", sequence).as_bytes());
+            write_frame(&mut frames, FRAME_RECORD, &payload).unwrap();
+        }
+        stream.write_all(&frames).unwrap();
+    }
+
+    /// Read the acknowledgements until the given record, returning them
+    fn read_acks(stream: &mut TcpStream, last: u64) -> Vec<u64> {
+        let mut acks = Vec::new();
+        while acks.last() != Some(&last) {
+            match read_frame(stream).unwrap() {
+                (FRAME_ACK, payload) => acks.push(read_be64(&payload)),
+                _ => panic!("Expected ACK"),
+            }
+        }
+        return acks;
+    }
+
+    #[test]
+    fn records_of_the_sink() {
+        let (address, output) = collector();
+        let key = ProbeKey::from_secret("secret");
+        let mut sink = CollectorSink::new(&address.to_string(), "ams", key, false);
+        let target: IpAddr = "192.0.2.1".parse().unwrap();
+        for ttl in 1..4 {
+            sink.write(&HopRecord {
+                target: target,
+                router: target,
+                ttl: ttl,
+                reply_ttl: Some(60),
+                size: 8,
+                sent_ms: None,
+                received_ms: None,
+                rtt_us: None,
+                icmp_type: Some(0),
+                icmp_code: Some(0),
+                method: PingMethod::ICMP,
+                quote: None,
+                extensions: IcmpExtensions::default(),
+            });
+        }
+        sink.flush();
+        let lines = output.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.starts_with("{\"site\": \"ams\", ")));
+    }
+
+    #[test]
+    fn records_sent_again_after_reconnecting() {
+        let (address, output) = collector();
+        let key = ProbeKey::from_secret("secret");
+        let (mut stream, acked) = connect(address, &key, 7, "ams").unwrap();
+        assert_eq!(acked, 0);
+        send_records(&mut stream, &[1, 2, 3]);
+        read_acks(&mut stream, 3);
+        drop(stream);
+
+        // The node sends again the records it has, and the collector skips the ones written
+        let (mut stream, acked) = connect(address, &key, 7, "ams").unwrap();
+        assert_eq!(acked, 3);
+        send_records(&mut stream, &[2, 3, 4, 5]);
+        read_acks(&mut stream, 5);
+
+        // Another session of the same site is not mixed with the first one
+        let (mut other, acked) = connect(address, &key, 8, "ams").unwrap();
+        assert_eq!(acked, 0);
+        send_records(&mut other, &[1]);
+        read_acks(&mut other, 1);
+
+        let lines = output.lines();
+        let expected: Vec<String> = [1, 2, 3, 4, 5, 1].iter()
+            .map(|sequence| format!("{{\"site\": \"ams\", \"sequence\": {}}}", sequence))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn acknowledge_while_receiving() {
+        let (address, _) = collector();
+        let key = ProbeKey::from_secret("secret");
+        let (mut stream, _) = connect(address, &key, 1, "ams").unwrap();
+        let total = ACK_INTERVAL * 8;
+        let sequences: Vec<u64> = (1..total + 1).collect();
+        send_records(&mut stream, &sequences);
+        let acks = read_acks(&mut stream, total);
+        let mut previous = 0;
+        for ack in acks {
+            assert!(ack > previous && ack - previous <= ACK_INTERVAL);
+            previous = ack;
+        }
+    }
+
+    #[test]
+    fn node_with_other_secret() {
+        let (address, output) = collector();
+        let key = ProbeKey::from_secret("guess");
+        let mut stream = TcpStream::connect(address).unwrap();
+        let challenge = match read_frame(&mut stream).unwrap() {
+            (FRAME_CHALLENGE, payload) => read_be64(&payload),
+            _ => panic!("Expected CHALLENGE"),
+        };
+        let mut hello = be64(1).to_vec();
+        hello.extend_from_slice(&be64(1));
+        hello.extend_from_slice(&be64(auth_proof(&key, "node", &[challenge, 1], "ams")));
+        hello.extend_from_slice(b"ams");
+        write_frame(&mut stream, FRAME_HELLO, &hello).unwrap();
+        send_records(&mut stream, &[1]);
+        assert!(read_frame(&mut stream).is_err());
+        assert!(output.lines().is_empty());
+    }
+}
@@ -0,0 +1,590 @@
+use super::helper::{auth_nonce, auth_proof};
+use super::ping::{PingMethod, ProbeKey};
+
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval between the checks of the state of the followers while the master waits for them
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum time to wait for the other side of the connection to authenticate
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum time blocked writing a message to a follower
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Parameters of the measurement announced by the master to the followers.
+///
+/// The control protocol is made of text lines sent over TCP:
+///
+/// ```text
+/// master -> follower: CHALLENGE <nonce>
+/// follower -> master: HELLO <name> <nonce> <proof>
+/// master -> follower: WELCOME <proof>
+/// master -> follower: START <start_ms> <method> <instance|-> <hitlist_size|-> <trace|catchment>
+/// follower -> master: PROGRESS <sent> <traces> <finished>
+/// master -> follower: STOP
+/// follower -> master: DONE <sent> <traces> <finished>
+/// ```
+///
+/// The key of the probes is never sent: every node derives it from the secret, and proves it
+/// with a MAC of the nonces of both sides, so the nodes with another secret are rejected.
+/// The followers joining after the start receive the announcement when they register,
+/// and the STOP too if the master already finished.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    /// Time in milliseconds since the epoch when every node starts the measurement
+    pub start_ms: u64,
+    pub method: PingMethod,
+    /// Instance marking the probes, if it's not derived from the key and the method
+    pub instance: Option<u16>,
+    /// Amount of targets on the hitlist of the master, if it's known
+    pub hitlist_size: Option<u64>,
+    /// If only the catchment is measured, without tracing the networks
+    pub catchment: bool,
+}
+
+impl Announcement {
+    fn to_line(&self) -> String {
+        let optional = |value: Option<u64>| value.map_or("-".to_string(), |value| value.to_string());
+        return format!(
+            "START {} {} {} {} {}",
+            self.start_ms,
+            self.method,
+            optional(self.instance.map(|instance| instance as u64)),
+            optional(self.hitlist_size),
+            if self.catchment { "catchment" } else { "trace" }
+        );
+    }
+
+    fn parse(fields: &[&str]) -> Result<Announcement, ()> {
+        if fields.len() != 5 {
+            return Err(());
+        }
+        return Ok(Announcement {
+            start_ms: fields[0].parse().map_err(|_| ())?,
+            method: fields[1].parse()?,
+            instance: match fields[2] {
+                "-" => None,
+                instance => Some(instance.parse().map_err(|_| ())?),
+            },
+            hitlist_size: match fields[3] {
+                "-" => None,
+                size => Some(size.parse().map_err(|_| ())?),
+            },
+            catchment: match fields[4] {
+                "trace" => false,
+                "catchment" => true,
+                _ => return Err(()),
+            },
+        });
+    }
+}
+
+fn parse_hex(field: Option<&&str>) -> io::Result<u64> {
+    return field
+        .and_then(|field| u64::from_str_radix(field, 16).ok())
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid authentication"));
+}
+
+/// Read the next line of the connection, failing if it's closed
+fn read_line<I: Iterator<Item = io::Result<String>>>(lines: &mut I) -> io::Result<String> {
+    return match lines.next() {
+        Some(line) => line,
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")),
+    };
+}
+
+/// Progress of the measurement of a node
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    /// Packets sent by the node
+    pub sent: u64,
+    /// Traces in progress
+    pub traces: u64,
+    /// Traces finished, or networks caught on the catchment mode
+    pub finished: u64,
+}
+
+impl Progress {
+    fn parse(fields: &[&str]) -> Result<Progress, ()> {
+        if fields.len() != 3 {
+            return Err(());
+        }
+        return Ok(Progress {
+            sent: fields[0].parse().map_err(|_| ())?,
+            traces: fields[1].parse().map_err(|_| ())?,
+            finished: fields[2].parse().map_err(|_| ())?,
+        });
+    }
+}
+
+/// State of a follower registered on the master
+#[derive(Debug, Clone)]
+pub struct FollowerStatus {
+    pub name: String,
+    pub address: SocketAddr,
+    /// Last progress reported by the follower
+    pub progress: Progress,
+    /// If the follower finished its measurement
+    pub done: bool,
+    /// If the connection with the follower is still open
+    pub connected: bool,
+}
+
+struct Follower {
+    status: FollowerStatus,
+    stream: TcpStream,
+}
+
+struct ServerState {
+    followers: Vec<Follower>,
+    announcement: Option<String>,
+    stopped: bool,
+}
+
+/// Control server of the master, which announces the measurement to the followers and
+/// tells them to stop when the probes of the master have drained.
+pub struct ControlServer {
+    address: SocketAddr,
+    key: ProbeKey,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl ControlServer {
+    /// Listen for the followers on the given address, registering them on the background
+    /// if they authenticate with the key.
+    pub fn bind<A: ToSocketAddrs>(address: A, key: ProbeKey) -> io::Result<ControlServer> {
+        let listener = TcpListener::bind(address)?;
+        let server = ControlServer {
+            address: listener.local_addr()?,
+            key: key,
+            state: Arc::new(Mutex::new(ServerState {
+                followers: Vec::new(),
+                announcement: None,
+                stopped: false,
+            })),
+        };
+        let state = server.state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = ControlServer::handle(stream, key, state) {
+                                warn!("Error on the connection with a follower: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Unable to accept a follower: {}", e),
+                }
+            }
+        });
+        info!("Waiting for followers on {}", server.address);
+        return Ok(server);
+    }
+
+    /// Get the address where the server is listening
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.address;
+    }
+
+    /// Get the key the followers authenticate with
+    pub fn key(&self) -> ProbeKey {
+        return self.key;
+    }
+
+    /// Authenticate the follower connected on the stream, register it and read its reports
+    /// until it disconnects
+    fn handle(mut stream: TcpStream, key: ProbeKey, state: Arc<Mutex<ServerState>>) -> io::Result<()> {
+        let address = stream.peer_addr()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut lines = BufReader::new(stream.try_clone()?).lines();
+
+        let challenge = auth_nonce();
+        writeln!(stream, "CHALLENGE {:016x}", challenge)?;
+        let line = read_line(&mut lines)?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 || fields[0] != "HELLO" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected HELLO"));
+        }
+        let name = fields[1].to_string();
+        let response = parse_hex(fields.get(2))?;
+        if parse_hex(fields.get(3))? != auth_proof(&key, "follower", &[challenge], &name) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} from {} doesn't have the secret of the master", name, address),
+            ));
+        }
+        writeln!(stream, "WELCOME {:016x}", auth_proof(&key, "master", &[challenge, response], &name))?;
+        stream.set_read_timeout(None)?;
+
+        // The messages already sent to the others are written after releasing the lock
+        let (index, announcement, stopped) = {
+            let mut state = state.lock().unwrap();
+            state.followers.push(Follower {
+                status: FollowerStatus {
+                    name: name.clone(),
+                    address: address,
+                    progress: Progress::default(),
+                    done: false,
+                    connected: true,
+                },
+                stream: stream.try_clone()?,
+            });
+            (state.followers.len() - 1, state.announcement.clone(), state.stopped)
+        };
+        info!("Follower {} registered from {}", name, address);
+        if let Some(announcement) = announcement {
+            writeln!(stream, "{}", announcement)?;
+        }
+        if stopped {
+            writeln!(stream, "STOP")?;
+        }
+
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mut state = state.lock().unwrap();
+            let status = &mut state.followers[index].status;
+            match fields.get(0) {
+                Some(&"PROGRESS") => status.progress = Progress::parse(&fields[1..]).unwrap_or(status.progress),
+                Some(&"DONE") => {
+                    status.progress = Progress::parse(&fields[1..]).unwrap_or(status.progress);
+                    status.done = true;
+                    info!("Follower {} finished", status.name);
+                }
+                _ => debug!("Unknown message from follower {}: {}", status.name, line),
+            }
+        }
+        let mut state = state.lock().unwrap();
+        let status = &mut state.followers[index].status;
+        status.connected = false;
+        if !status.done {
+            warn!("Follower {} disconnected before finishing", status.name);
+        }
+        return Ok(());
+    }
+
+    /// Wait until the given amount of followers are registered
+    pub fn wait_followers(&self, count: usize) {
+        let mut last_log = Instant::now();
+        loop {
+            let registered = self.state.lock().unwrap().followers.len();
+            if registered >= count {
+                return;
+            }
+            if last_log.elapsed() >= Duration::from_secs(10) {
+                last_log = Instant::now();
+                info!("Waiting for followers ({}/{})", registered, count);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Announce the measurement to the followers registered, and to the ones registering later
+    pub fn start(&self, announcement: &Announcement) {
+        let line = announcement.to_line();
+        let followers = {
+            let mut state = self.state.lock().unwrap();
+            state.announcement = Some(line.clone());
+            Self::connected(&state)
+        };
+        for (name, mut stream) in followers {
+            if let Err(e) = writeln!(stream, "{}", line) {
+                warn!("Unable to announce the start to {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Tell the followers to stop, as the probes of the master have drained
+    pub fn stop(&self) {
+        let followers = {
+            let mut state = self.state.lock().unwrap();
+            state.stopped = true;
+            Self::connected(&state)
+        };
+        for (name, mut stream) in followers {
+            if let Err(e) = writeln!(stream, "STOP") {
+                warn!("Unable to stop {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Get the names and the streams of the followers still connected, to write to them
+    /// without holding the lock
+    fn connected(state: &ServerState) -> Vec<(String, TcpStream)> {
+        return state.followers.iter()
+            .filter(|follower| follower.status.connected)
+            .filter_map(|follower| match follower.stream.try_clone() {
+                Ok(stream) => Some((follower.status.name.clone(), stream)),
+                Err(_) => None,
+            })
+            .collect();
+    }
+
+    /// Wait until every follower connected has finished, or the timeout expires.
+    /// Return if every follower finished.
+    pub fn wait_done(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let pending = self.state.lock().unwrap().followers.iter()
+                .any(|follower| follower.status.connected && !follower.status.done);
+            if !pending {
+                return true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        return false;
+    }
+
+    /// Get the state of the followers registered
+    pub fn followers(&self) -> Vec<FollowerStatus> {
+        let state = self.state.lock().unwrap();
+        return state.followers.iter().map(|follower| follower.status.clone()).collect();
+    }
+}
+
+/// Connection of a follower to the control server of the master
+pub struct ControlClient {
+    stream: TcpStream,
+    key: ProbeKey,
+    announcement: Announcement,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ControlClient {
+    /// Register on the master with the given name, authenticating both sides with the key,
+    /// and wait until the measurement is announced.
+    ///
+    /// The follower is stopped when the master tells it, or if the connection is closed.
+    pub fn join<A: ToSocketAddrs>(address: A, name: &str, key: ProbeKey) -> io::Result<ControlClient> {
+        let mut stream = TcpStream::connect(address)?;
+        let name = name.replace(char::is_whitespace, "_");
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut lines = BufReader::new(stream.try_clone()?).lines();
+
+        let line = read_line(&mut lines)?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 || fields[0] != "CHALLENGE" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected CHALLENGE"));
+        }
+        let challenge = parse_hex(fields.get(1))?;
+        let response = auth_nonce();
+        writeln!(
+            stream,
+            "HELLO {} {:016x} {:016x}",
+            name,
+            response,
+            auth_proof(&key, "follower", &[challenge], &name)
+        )?;
+        // The master closes the connection of the followers failing the authentication
+        let line = read_line(&mut lines).map_err(|_| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "The master rejected the secret")
+        })?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let expected = auth_proof(&key, "master", &[challenge, response], &name);
+        if fields.len() != 2 || fields[0] != "WELCOME" || parse_hex(fields.get(1))? != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "The master doesn't have the same secret",
+            ));
+        }
+        stream.set_read_timeout(None)?;
+
+        let announcement = loop {
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The master closed the connection")),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(0) == Some(&"START") {
+                match Announcement::parse(&fields[1..]) {
+                    Ok(announcement) => break announcement,
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid announcement")),
+                }
+            }
+        };
+        info!("Joined the measurement of the master, starting at {} ms", announcement.start_ms);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = stopped.clone();
+        thread::spawn(move || {
+            for line in lines {
+                match line {
+                    Ok(ref line) if line.trim() == "STOP" => {
+                        info!("The master stopped the measurement");
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(_) => {
+                        warn!("Lost the connection with the master, stopping");
+                        break;
+                    }
+                }
+            }
+            flag.store(true, Ordering::SeqCst);
+        });
+
+        return Ok(ControlClient {
+            stream: stream,
+            key: key,
+            announcement: announcement,
+            stopped: stopped,
+        });
+    }
+
+    /// Get the measurement announced by the master
+    pub fn announcement(&self) -> &Announcement {
+        return &self.announcement;
+    }
+
+    /// Get the key of the probes, derived from the secret shared with the master
+    pub fn key(&self) -> ProbeKey {
+        return self.key;
+    }
+
+    /// Check if the master told the follower to stop
+    pub fn stopped(&self) -> bool {
+        return self.stopped.load(Ordering::SeqCst);
+    }
+
+    /// Report the progress of the follower to the master
+    pub fn report(&mut self, progress: &Progress) {
+        self.send("PROGRESS", progress);
+    }
+
+    /// Tell the master the follower finished, with its final progress
+    pub fn done(&mut self, progress: &Progress) {
+        self.send("DONE", progress);
+    }
+
+    fn send(&mut self, kind: &str, progress: &Progress) {
+        let result = writeln!(
+            self.stream,
+            "{} {} {} {}",
+            kind, progress.sent, progress.traces, progress.finished
+        );
+        if let Err(e) = result {
+            debug!("Unable to report to the master: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement() -> Announcement {
+        return Announcement {
+            start_ms: 1000,
+            method: PingMethod::UDP,
+            instance: Some(42),
+            hitlist_size: None,
+            catchment: false,
+        };
+    }
+
+    /// Wait until the condition is true, failing after a few seconds
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timeout");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn join(server: &ControlServer, name: &str, secret: &str) -> thread::JoinHandle<io::Result<ControlClient>> {
+        let address = server.local_addr();
+        let name = name.to_string();
+        let key = ProbeKey::from_secret(secret);
+        return thread::spawn(move || ControlClient::join(address, &name, key));
+    }
+
+    #[test]
+    fn announcement_without_key() {
+        let line = announcement().to_line();
+        assert_eq!(line, "START 1000 UDP 42 - trace");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = Announcement::parse(&fields[1..]).unwrap();
+        assert_eq!(parsed.instance, Some(42));
+        assert_eq!(parsed.method, PingMethod::UDP);
+    }
+
+    #[test]
+    fn measurement_with_late_follower() {
+        let server = ControlServer::bind("127.0.0.1:0", ProbeKey::from_secret("secret")).unwrap();
+        let early = join(&server, "early site", "secret");
+        server.wait_followers(1);
+        server.start(&announcement());
+        let mut early = early.join().unwrap().unwrap();
+        assert_eq!(early.announcement().start_ms, 1000);
+        assert_eq!(early.key(), server.key());
+        assert!(!early.stopped());
+
+        let progress = Progress {
+            sent: 10,
+            traces: 2,
+            finished: 1,
+        };
+        early.report(&progress);
+        wait_until(|| server.followers()[0].progress == progress);
+        assert_eq!(server.followers()[0].name, "early_site");
+
+        server.stop();
+        wait_until(|| early.stopped());
+
+        // A follower joining after the stop is announced the measurement and stopped
+        let mut late = join(&server, "late", "secret").join().unwrap().unwrap();
+        assert_eq!(late.announcement().instance, Some(42));
+        wait_until(|| late.stopped());
+        assert!(!server.wait_done(Duration::from_millis(10)));
+
+        early.done(&progress);
+        late.done(&Progress::default());
+        assert!(server.wait_done(Duration::from_secs(5)));
+        let followers = server.followers();
+        assert_eq!(followers.len(), 2);
+        assert!(followers.iter().all(|follower| follower.done));
+    }
+
+    #[test]
+    fn follower_with_other_secret() {
+        let server = ControlServer::bind("127.0.0.1:0", ProbeKey::from_secret("secret")).unwrap();
+        server.start(&announcement());
+        let error = join(&server, "intruder", "guess").join().unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(server.followers().is_empty());
+    }
+
+    #[test]
+    fn master_with_other_secret() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let key = ProbeKey::from_secret("secret");
+        let follower = thread::spawn(move || ControlClient::join(address, "site", key));
+
+        // A fake master can't prove the key, even replaying the proof of the follower
+        let (mut stream, _) = listener.accept().unwrap();
+        writeln!(stream, "CHALLENGE {:016x}", 1).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let hello = lines.next().unwrap().unwrap();
+        let fields: Vec<&str> = hello.split_whitespace().collect();
+        assert_eq!(fields.len(), 4);
+        writeln!(stream, "WELCOME {}", fields[3]).unwrap();
+        writeln!(stream, "{}", announcement().to_line()).unwrap();
+
+        let error = follower.join().unwrap().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
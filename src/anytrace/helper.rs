@@ -1,45 +1,136 @@
 extern crate ping;
 extern crate pnet;
 
-use self::ping::IcmpResponce;
+use self::ping::{IcmpResponce, ProbeKey, PAYLOAD_LENGTH, UDP_PORT};
 use self::pnet::packet::FromPacket;
 use self::pnet::packet::Packet;
 use self::pnet::packet::icmp::echo_request::EchoRequestPacket;
-use self::pnet::packet::ip::IpNextHeaderProtocols::{Icmp, Udp};
+use self::pnet::packet::ip::IpNextHeaderProtocols::{Icmp, Tcp, Udp};
+use self::pnet::packet::ip::IpNextHeaderProtocols::Icmpv6;
 use self::pnet::packet::ipv4::Ipv4Packet;
+use self::pnet::packet::ipv6::Ipv6Packet;
 use self::pnet::packet::udp::UdpPacket;
-use std::net::Ipv4Addr;
-use std::ops::BitXor;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Encode the ip address and the ttl into the id and sequence number, applying the given key
-/// Return the tuple (identifier, sequence_number)
-pub fn encode_id_seq_key(address: u32, ttl: u8, key: u16) -> (u16, u16) {
-    let (id, seq) = encode_id_seq(address, ttl);
-    return (id.bitxor(key), seq.bitxor(key));
+/// Fields of the IP header of a probe, as quoted in an ICMP error.
+///
+/// Comparing them with the sent values reveals the middleboxes rewriting the headers, and
+/// the quoted TTL the tunnels resetting or keeping the TTL of the probes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotedHeader {
+    /// TTL, or hop limit on IPv6
+    pub ttl: u8,
+    /// Type of service, or traffic class on IPv6, with the DSCP and ECN bits
+    pub tos: u8,
+    /// Identification, or flow label on IPv6
+    pub identification: u32,
+    /// Total length, or payload length on IPv6
+    pub length: u16,
+    /// Header checksum, only present on IPv4
+    pub checksum: Option<u16>,
 }
 
-/// Decode the id and seq to obtain the original (netowrk, ttl) tuple, applying the given key
-pub fn decode_id_seq_key(id: u16, seq: u16, key: u16) -> (u32, u8) {
-    return decode_id_seq(id.bitxor(key), seq.bitxor(key));
+impl QuotedHeader {
+    /// Read the fields of the quoted IPv4 header
+    fn ipv4(data: &[u8]) -> QuotedHeader {
+        return QuotedHeader {
+            ttl: data[8],
+            tos: data[1],
+            identification: read_u16(data, 4) as u32,
+            length: read_u16(data, 2),
+            checksum: Some(read_u16(data, 10)),
+        };
+    }
+
+    /// Read the fields of the quoted IPv6 header
+    fn ipv6(data: &[u8]) -> QuotedHeader {
+        return QuotedHeader {
+            ttl: data[7],
+            tos: data[0] << 4 | data[1] >> 4,
+            identification: (data[1] as u32 & 0x0F) << 16 | read_u16(data, 2) as u32,
+            length: read_u16(data, 4),
+            checksum: None,
+        };
+    }
 }
 
-/// Decode the id and seq to obtain the original (netowrk, ttl) tuple.
-pub fn decode_id_seq(id: u16, seq: u16) -> (u32, u8) {
-    return ((id as u32) << 16 | (seq & 0xFF00) as u32, seq as u8);
+/// Format the header as `quote=ttl/tos/identification/length/checksum`, leaving the
+/// checksum empty on IPv6.
+impl fmt::Display for QuotedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let checksum = match self.checksum {
+            Some(checksum) => checksum.to_string(),
+            None => String::new(),
+        };
+        return write!(
+            f,
+            "quote={}/{}/{}/{}/{}",
+            self.ttl, self.tos, self.identification, self.length, checksum
+        );
+    }
 }
-/// Encode the ip address and the ttl into the id and sequence number
+
+/// Length in milliseconds of the epochs authenticated by the probes. The replies are accepted
+/// during the epoch of their probe and the next one, so they can't be replayed after a minute.
+const MAC_EPOCH_MS: u64 = 30_000;
+
+/// Encode the ttl and flow of a probe sent to the target at the given time into the id and
+/// sequence number, authenticated with the given key.
+///
+/// The sequence number stores the flow and the ttl, while the whole identifier is a MAC of the
+/// target, the sequence and the epoch of the time, so every hop has its own 16 bits MAC.
 /// Return the tuple (identifier, sequence_number)
-pub fn encode_id_seq(address: u32, ttl: u8) -> (u16, u16) {
-    let ip = u32::from(address) & 0xFFFFFF00;
-    let identifier: u16 = (ip >> 16) as u16;
-    let sequence: u16 = (ip as u16) | (ttl as u16);
-    return (identifier, sequence);
+pub fn encode_id_seq_key(target: IpAddr, ttl: u8, flow: u8, time_ms: u64, key: &ProbeKey) -> (u16, u16) {
+    let sequence = (flow as u16) << 8 | ttl as u16;
+    return (probe_mac(target, sequence, time_ms / MAC_EPOCH_MS, key), sequence);
+}
+
+/// Decode the ttl and flow from the id and seq of a probe sent to the target, returning
+/// error if they were not generated with the given key, or if the probe was not sent on the
+/// epoch of the given time or the previous one. The next epoch is also accepted, as the
+/// clocks of the nodes can differ.
+pub fn decode_id_seq_key(
+    target: IpAddr,
+    id: u16,
+    seq: u16,
+    time_ms: u64,
+    key: &ProbeKey,
+) -> Result<(u8, u8), ()> {
+    let epoch = time_ms / MAC_EPOCH_MS;
+    for epoch in [epoch, epoch.saturating_sub(1), epoch + 1].iter() {
+        if probe_mac(target, seq, *epoch, key) == id {
+            return Ok((seq as u8, (seq >> 8) as u8));
+        }
+    }
+    return Err(());
 }
 
-pub fn verify_packet_network(source: Ipv4Addr, network: u32) -> bool {
-    let source = get_ip_mask(source);
-    return source == network;
+/// Get the MAC of the sequence of a probe sent to the target on the epoch.
+///
+/// Zero is replaced by 0xFFFF, as the UDP probes store the identifier in the source port,
+/// or on Paris mode in the checksum, where zero is sent as 0xFFFF on IPv6.
+fn probe_mac(target: IpAddr, sequence: u16, epoch: u64, key: &ProbeKey) -> u16 {
+    let mut fields = [(sequence >> 8) as u8, sequence as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+    for i in 0..8 {
+        fields[2 + i] = (epoch >> (56 - 8 * i)) as u8;
+    }
+    return match key.mac_probe(target, &fields) as u16 {
+        0 => 0xffff,
+        mac => mac,
+    };
+}
+
+/// Get the amount of probes needed to discard, with the given confidence, that a hop
+/// with the given count of known interfaces has another one, as the stopping rule of MDA.
+pub fn mda_probes(interfaces: usize, confidence: f64) -> u16 {
+    if interfaces == 0 {
+        return 1;
+    }
+    let known = interfaces as f64;
+    let probes = (((1.0 - confidence) / (known + 1.0)).ln() / (known / (known + 1.0)).ln()).ceil();
+    return probes.min(u16::max_value() as f64) as u16;
 }
 
 /// Calculate the aproximate distance in hops to the given packet
@@ -53,22 +144,54 @@ pub fn get_max_ttl(packet: &IcmpResponce) -> u8 {
     unreachable!();
 }
 
-/// Get the inner icmp information from a timeout packet.
-/// Return the source address and the icmp echo request.
-pub fn parse_icmp(data: &Vec<u8>) -> Result<(Ipv4Addr, u16, u16), ()> {
+/// Get the inner icmp information from a timeout packet, which can quote an IPv4 or IPv6 packet.
+/// Return the source address and the icmp echo request, with the fields of the quoted IP header.
+///
+/// On `paris` mode the id of the UDP probes is read from the checksum, and the sequence from
+/// the source port and the length, instead of the ports.
+pub fn parse_icmp(data: &Vec<u8>, paris: bool) -> Result<(IpAddr, u16, u16, QuotedHeader), ()> {
+    match data.first() {
+        Some(version) if version >> 4 == 6 => return parse_ipv6(data, paris),
+        _ => {}
+    }
     if let Some(ipv4) = Ipv4Packet::new(data) {
-        if ipv4.get_next_level_protocol() == Icmp {
-            return process_icmp(ipv4.payload(), ipv4.get_destination());
+        let destination = IpAddr::V4(ipv4.get_destination());
+        let probe = if ipv4.get_next_level_protocol() == Icmp {
+            process_icmp(ipv4.payload(), destination)
         } else if ipv4.get_next_level_protocol() == Udp {
-            return process_udp(ipv4.payload(), ipv4.get_destination());
+            process_udp(ipv4.payload(), destination, paris)
+        } else if ipv4.get_next_level_protocol() == Tcp {
+            process_tcp(ipv4.payload(), destination)
         } else {
-        }
+            Err(())
+        };
+        return probe.map(|(destination, id, seq)| (destination, id, seq, QuotedHeader::ipv4(data)));
+    }
+    return Err(());
+}
+
+/// Get the inner information of a quoted IPv6 packet.
+///
+/// Extension headers are not supported, as they are never added to the sent packets.
+fn parse_ipv6(data: &[u8], paris: bool) -> Result<(IpAddr, u16, u16, QuotedHeader), ()> {
+    if let Some(ipv6) = Ipv6Packet::new(data) {
+        let destination = IpAddr::V6(ipv6.get_destination());
+        let probe = if ipv6.get_next_header() == Icmpv6 {
+            process_icmp(ipv6.payload(), destination)
+        } else if ipv6.get_next_header() == Udp {
+            process_udp(ipv6.payload(), destination, paris)
+        } else if ipv6.get_next_header() == Tcp {
+            process_tcp(ipv6.payload(), destination)
+        } else {
+            Err(())
+        };
+        return probe.map(|(destination, id, seq)| (destination, id, seq, QuotedHeader::ipv6(data)));
     }
     return Err(());
 }
 
-/// Return the Ipv4Addr, Identifier and Sequence Number
-fn process_icmp(payload: &[u8], destination: Ipv4Addr) -> Result<(Ipv4Addr, u16, u16), ()> {
+/// Return the IpAddr, Identifier and Sequence Number
+fn process_icmp(payload: &[u8], destination: IpAddr) -> Result<(IpAddr, u16, u16), ()> {
     if let Some(icmp) = EchoRequestPacket::new(payload) {
         let icmp = icmp.from_packet();
 
@@ -78,20 +201,64 @@ fn process_icmp(payload: &[u8], destination: Ipv4Addr) -> Result<(Ipv4Addr, u16,
     return Err(());
 }
 
-fn process_udp(payload: &[u8], destination: Ipv4Addr) -> Result<(Ipv4Addr, u16, u16), ()> {
+/// Return the IpAddr, Identifier and Sequence Number stored in the UDP header.
+///
+/// The id is stored in the source port and the seq in the destination port above `UDP_PORT`.
+/// On Paris mode the ports only store the flow, so the id is stored in the checksum and
+/// the ttl in the length of the payload.
+fn process_udp(payload: &[u8], destination: IpAddr, paris: bool) -> Result<(IpAddr, u16, u16), ()> {
     if let Some(udp) = UdpPacket::new(payload) {
-        let _payload = udp.payload();
-        // Use the stored id and seq on the source/destination port
-        let id = udp.get_source();
-        let seq = udp.get_destination();
-        return Ok((destination, id, seq));
+        if paris {
+            let flow = udp.get_source().wrapping_sub(UDP_PORT);
+            let ttl = udp.get_length().wrapping_sub(8 + PAYLOAD_LENGTH as u16);
+            if flow > 0xff || ttl > 0xff {
+                return Err(());
+            }
+            return Ok((destination, udp.get_checksum(), flow << 8 | ttl));
+        }
+        return Ok((destination, udp.get_source(), udp.get_destination().wrapping_sub(UDP_PORT)));
     }
     return Err(());
 }
 
-/// Get the /24 mask of the given ip address as an u32
-pub fn get_ip_mask(address: Ipv4Addr) -> u32 {
-    return u32::from(address) & 0xFFFFFF00;
+/// Return the IpAddr, Identifier and Sequence Number stored in the TCP sequence number.
+///
+/// Only the first 8 bytes of the TCP header are guaranteed to be quoted, so the header is
+/// parsed directly instead of using a TcpPacket.
+fn process_tcp(payload: &[u8], destination: IpAddr) -> Result<(IpAddr, u16, u16), ()> {
+    if payload.len() < 8 {
+        return Err(());
+    }
+    let id = (payload[4] as u16) << 8 | payload[5] as u16;
+    let seq = (payload[6] as u16) << 8 | payload[7] as u16;
+    return Ok((destination, id, seq));
+}
+
+/// Read the big endian u16 at the position of the buffer
+fn read_u16(buffer: &[u8], position: usize) -> u16 {
+    return (buffer[position] as u16) << 8 | buffer[position + 1] as u16;
+}
+
+/// Get the network of the given ip address, using the first `prefix` bits
+pub fn get_ip_mask(address: IpAddr, prefix: u8) -> IpAddr {
+    return match address {
+        IpAddr::V4(address) => {
+            let mask = (!0u32).checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = (!0u128).checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    };
+}
+
+/// Get the unspecified address of the same family of the given address
+pub fn get_unspecified(address: IpAddr) -> IpAddr {
+    return match address {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
 }
 
 /// Get the current time in milliseconds
@@ -104,3 +271,159 @@ pub fn time_from_epoch_ms() -> u64 {
         since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_nanos() as u64 / 1_000_000;
     return in_ms;
 }
+
+/// Proof of a node that it has the key, as the MAC of its role, the nonces of the connection
+/// and its name
+pub fn auth_proof(key: &ProbeKey, role: &str, nonces: &[u64], name: &str) -> u64 {
+    let mut parts: Vec<&[u8]> = vec![role.as_bytes()];
+    let bytes: Vec<[u8; 8]> = nonces.iter().map(|nonce| nonce.to_be_bytes()).collect();
+    parts.extend(bytes.iter().map(|nonce| &nonce[..]));
+    parts.push(name.as_bytes());
+    return key.mac(&parts);
+}
+
+/// Random value used once to authenticate a connection
+pub fn auth_nonce() -> u64 {
+    return ProbeKey::random().halves().0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use self::ping::Responce;
+    use std::time::Instant;
+
+    fn reply_with_ttl(ttl: u8) -> IcmpResponce {
+        return IcmpResponce {
+            source: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            ttl: ttl,
+            icmp: Responce::LocalSendedEcho(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 0, 0),
+            extensions: Default::default(),
+            size: 0,
+            time_ms: 0,
+            time: Instant::now(),
+            timestamp: Default::default(),
+        };
+    }
+
+    #[test]
+    fn max_ttl_from_the_common_initial_ttls() {
+        assert_eq!(get_max_ttl(&reply_with_ttl(64)), 2);
+        assert_eq!(get_max_ttl(&reply_with_ttl(60)), 6);
+        assert_eq!(get_max_ttl(&reply_with_ttl(65)), 65);
+        assert_eq!(get_max_ttl(&reply_with_ttl(120)), 10);
+        assert_eq!(get_max_ttl(&reply_with_ttl(250)), 7);
+        assert_eq!(get_max_ttl(&reply_with_ttl(255)), 2);
+    }
+
+    /// Quote of an IPv4 probe of the protocol, with the ToS 0xb8, identification 0x1234,
+    /// TTL 1 and checksum 0xbeef, followed by the transport header
+    fn ipv4_quote(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut quote = vec![0x45, 0xb8, 0x00, 0x2e, 0x12, 0x34, 0x40, 0x00, 0x01, protocol, 0xbe, 0xef];
+        quote.extend_from_slice(&[198, 51, 100, 1, 192, 0, 2, 1]);
+        quote.extend_from_slice(transport);
+        return quote;
+    }
+
+    /// Quote of an IPv6 probe of the protocol, with the traffic class 0xb8, flow label
+    /// 0x12345 and hop limit 2, followed by the transport header
+    fn ipv6_quote(protocol: u8, transport: &[u8]) -> Vec<u8> {
+        let mut quote = vec![0x6b, 0x81, 0x23, 0x45, 0x00, 0x1a, protocol, 0x02];
+        quote.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        quote.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        quote.extend_from_slice(transport);
+        return quote;
+    }
+
+    #[test]
+    fn quoted_headers() {
+        let echo = [8, 0, 0x12, 0x34, 0xab, 0xcd, 0x01, 0x05];
+        let (target, id, seq, quote) = parse_icmp(&ipv4_quote(1, &echo), false).unwrap();
+        assert_eq!((target, id, seq), ("192.0.2.1".parse().unwrap(), 0xabcd, 0x0105));
+        assert_eq!(quote, QuotedHeader {
+            ttl: 1,
+            tos: 0xb8,
+            identification: 0x1234,
+            length: 46,
+            checksum: Some(0xbeef),
+        });
+        assert_eq!(quote.to_string(), "quote=1/184/4660/46/48879");
+
+        let echo = [128, 0, 0x12, 0x34, 0xab, 0xcd, 0x01, 0x05];
+        let (target, id, seq, quote) = parse_icmp(&ipv6_quote(58, &echo), false).unwrap();
+        assert_eq!((target, id, seq), ("2001:db8:1::1".parse().unwrap(), 0xabcd, 0x0105));
+        assert_eq!(quote, QuotedHeader {
+            ttl: 2,
+            tos: 0xb8,
+            identification: 0x12345,
+            length: 26,
+            checksum: None,
+        });
+        assert_eq!(quote.to_string(), "quote=2/184/74565/26/");
+    }
+
+    #[test]
+    fn quoted_transport_headers() {
+        // The UDP probes store the identifier in the source port and the sequence in the
+        // destination port, or on Paris mode in the checksum and the length
+        let udp = [0xab, 0xcd, 0x83, 0x9f, 0x00, 0x1a, 0x00, 0x00];
+        let (_, id, seq, _) = parse_icmp(&ipv4_quote(17, &udp), false).unwrap();
+        assert_eq!((id, seq), (0xabcd, 0x0105));
+        let paris = [0x82, 0x9d, 0x82, 0x9a, 0x00, 0x1f, 0x77, 0x77];
+        let (_, id, seq, _) = parse_icmp(&ipv6_quote(17, &paris), true).unwrap();
+        assert_eq!((id, seq), (0x7777, 0x0305));
+        // The TCP probes store them in the sequence number
+        let tcp = [0x00, 0x07, 0x00, 0x50, 0xab, 0xcd, 0x01, 0x05];
+        let (_, id, seq, _) = parse_icmp(&ipv4_quote(6, &tcp), false).unwrap();
+        assert_eq!((id, seq), (0xabcd, 0x0105));
+
+        // Quotes of other protocols, or truncated before the transport header
+        assert!(parse_icmp(&ipv4_quote(47, &tcp), false).is_err());
+        assert!(parse_icmp(&ipv4_quote(6, &tcp[..4]), false).is_err());
+        assert!(parse_icmp(&ipv6_quote(58, &[]), false).is_err());
+        assert!(parse_icmp(&vec![0x45, 0], false).is_err());
+    }
+
+    #[test]
+    fn identifiers_of_the_probes() {
+        let (key, target) = (ProbeKey::new(1, 2), "192.0.2.1".parse().unwrap());
+        let time_ms = 1_500_000_010_000;
+        let (id, seq) = encode_id_seq_key(target, 7, 3, time_ms, &key);
+        assert_eq!(seq, 0x0307);
+        assert_eq!(decode_id_seq_key(target, id, seq, time_ms, &key), Ok((7, 3)));
+        // The replies are matched during the epoch of the probe and the next one
+        assert_eq!(decode_id_seq_key(target, id, seq, time_ms + MAC_EPOCH_MS, &key), Ok((7, 3)));
+        assert_eq!(decode_id_seq_key(target, id, seq, time_ms + 2 * MAC_EPOCH_MS, &key), Err(()));
+        // But not to the probes of other targets, hops or keys
+        assert_eq!(decode_id_seq_key("192.0.2.2".parse().unwrap(), id, seq, time_ms, &key), Err(()));
+        assert_eq!(decode_id_seq_key(target, id, 0x0308, time_ms, &key), Err(()));
+        assert_eq!(decode_id_seq_key(target, id, seq, time_ms, &ProbeKey::new(1, 3)), Err(()));
+    }
+
+    #[test]
+    fn mda_stopping_rule() {
+        // Probes needed with 95% confidence, as on the first values of the table of the MDA paper
+        let expected = [1, 6, 11, 16, 21, 27, 33];
+        for (interfaces, &probes) in expected.iter().enumerate() {
+            assert_eq!(mda_probes(interfaces, 0.95), probes, "{} interfaces", interfaces);
+        }
+        assert_eq!(mda_probes(1, 0.99), 8);
+        assert_eq!(mda_probes(1, 0.5), 2);
+        assert_eq!(mda_probes(100_000, 0.95), u16::max_value());
+    }
+
+    #[test]
+    fn networks_of_the_addresses() {
+        let network = |address: &str, prefix: u8| get_ip_mask(address.parse().unwrap(), prefix).to_string();
+        assert_eq!(network("2001:db8:1234:5678::1", 48), "2001:db8:1234::");
+        assert_eq!(network("2001:db8:1234:ffff:ffff::", 47), "2001:db8:1234::");
+        assert_eq!(network("2001:db8:1235:ffff::", 47), "2001:db8:1234::");
+        assert_eq!(network("2001:db8::1", 128), "2001:db8::1");
+        assert_eq!(network("2001:db8::1", 0), "::");
+        assert_eq!(network("192.0.2.200", 24), "192.0.2.0");
+        assert_eq!(network("192.0.2.200", 25), "192.0.2.128");
+        assert_eq!(network("192.0.2.200", 32), "192.0.2.200");
+        assert_eq!(network("192.0.2.200", 0), "0.0.0.0");
+    }
+}
@@ -4,104 +4,594 @@ extern crate pnet;
 use self::pnet::packet::icmp::destination_unreachable::DestinationUnreachable;
 use self::pnet::packet::icmp::echo_reply::EchoReply;
 use self::pnet::packet::icmp::time_exceeded::TimeExceeded;
+use self::pnet::packet::tcp::Tcp;
 
-pub use self::ping::PingMethod;
-use self::ping::{IcmpResponce, PingHandler, PingHandlerBuilder};
+pub use self::ping::{IcmpExtensions, InterfaceInformation, MemoryNetwork, MemoryTransport,
+                     MplsLabel, PingMethod, ProbeKey, SimulatedTransport, Topology, Transport};
+use self::ping::{IcmpResponce, KernelTimestamp, PingHandler, PingHandlerBuilder,
+                 TCP_PORT, UDP_PORT};
 
-use std;
+use std::cmp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::thread;
 use std::time::{Duration, Instant};
 
 mod helper;
-use self::helper::{decode_id_seq_key, encode_id_seq_key, get_ip_mask, get_max_ttl, parse_icmp,
-                   time_from_epoch_ms, verify_packet_network};
+use self::helper::{decode_id_seq_key, encode_id_seq_key, get_ip_mask, get_max_ttl,
+                   get_unspecified, mda_probes, parse_icmp,
+                   time_from_epoch_ms};
+pub use self::helper::QuotedHeader;
+
+mod sink;
+pub use self::sink::{CsvSink, HopRecord, JsonlSink, ResultSink, TraceRecord};
+
+mod warts;
+pub use self::warts::WartsSink;
+
+mod atlas;
+pub use self::atlas::AtlasSink;
+
+mod prefixes;
+pub use self::prefixes::RoutedPrefixes;
+
+mod seen;
+pub use self::seen::SeenNetworks;
+
+mod checkpoint;
+pub use self::checkpoint::Checkpoint;
+
+mod collector;
+pub use self::collector::{Collector, CollectorSink};
+
+mod control;
+pub use self::control::{Announcement, ControlClient, ControlServer, FollowerStatus, Progress};
+
+/// Maximum of flows used to probe a hop on MDA mode, as the flow is stored in a byte
+const MDA_MAX_FLOWS: u16 = 256;
+
+/// TTL of the probes sent to the targets of the hitlist, checking if they are online
+const HITLIST_TTL: u8 = 64;
+
+/// Delay between the announcement of the measurement and its start, so every follower starts at the same time
+const START_DELAY_MS: u64 = 2000;
+
+/// Interval between the progress reports of the followers
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Maximum time the master waits for the followers to finish their traces after stopping them
+const FOLLOWERS_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time waited for the replies after the last probe is written, before stopping the measurement
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
-struct TraceConfiguration {
-    source: Ipv4Addr,
+pub(crate) struct TraceConfiguration {
+    source: IpAddr,
     max_hop: u8,
     current_ttl: u8,
     traces: Vec<Option<Trace>>,
+    interfaces: Vec<Vec<IpAddr>>,
+    flows: u16,
+    probe_size: Option<u16>,
+    replies: Vec<HopRecord>,
+    /// Time of the last update queued, as the older updates of the queue are ignored
+    next_check: u64,
 }
 
 #[derive(Debug, Clone)]
 struct Trace {
-    router: Ipv4Addr,
+    router: IpAddr,
     hops: u8,
     done: bool,
+    sent_ms: Option<u64>,
+    sent: Option<(Instant, KernelTimestamp)>,
+    /// Times the probes of the other flows were sent on MDA mode, by flow
+    flow_sent: HashMap<u8, (Option<u64>, (Instant, KernelTimestamp))>,
+    /// Reply received before the probe was recorded, written when the probe is recorded
+    pending: Option<(HopRecord, (Instant, KernelTimestamp))>,
+}
+
+impl Trace {
+    /// Store the time the probe of the flow was sent, keeping the first probe of each flow
+    fn record_sent(&mut self, flow: u8, sent_ms: Option<u64>, time: (Instant, KernelTimestamp)) {
+        if flow != 0 {
+            self.flow_sent.entry(flow).or_insert((sent_ms, time));
+        } else if self.sent.is_none() {
+            self.sent_ms = sent_ms;
+            self.sent = Some(time);
+        }
+    }
+
+    /// Get the time the probe of the flow was sent, if it was recorded, completing its
+    /// transmit timestamp if the kernel gave it after the probe was recorded
+    fn sent_time(&mut self, flow: u8, handler: &PingHandler) -> (Option<u64>, Option<(Instant, KernelTimestamp)>) {
+        let (sent_ms, sent) = if flow == 0 {
+            (self.sent_ms, self.sent.as_mut())
+        } else {
+            match self.flow_sent.get_mut(&flow) {
+                Some(&mut (sent_ms, ref mut time)) => (sent_ms, Some(time)),
+                None => (None, None),
+            }
+        };
+        return match sent {
+            Some(sent) => {
+                sent.1 = handler.writer.sent_timestamp(sent.1);
+                (sent_ms, Some(*sent))
+            }
+            None => (sent_ms, None),
+        };
+    }
 }
 
 impl TraceConfiguration {
-    fn new(source: Ipv4Addr, max_hops: u8) -> TraceConfiguration {
+    fn new(source: IpAddr, max_hops: u8) -> TraceConfiguration {
         let opts = vec![Option::None; max_hops as usize];
         return TraceConfiguration {
             source: source,
             max_hop: max_hops,
             traces: opts,
+            interfaces: vec![Vec::new(); max_hops as usize],
             current_ttl: max_hops,
+            flows: 1,
+            probe_size: None,
+            replies: Vec::new(),
+            next_check: 0,
+        };
+    }
+
+    /// Get the amount of flows needed to enumerate the interfaces of the current hop with
+    /// the given confidence, limited to the flows that can be encoded in a probe.
+    fn needed_flows(&self, confidence: f64) -> u16 {
+        let interfaces = match self.interfaces.get(self.current_ttl as usize - 1) {
+            Some(interfaces) => interfaces,
+            None => return self.flows,
+        };
+        // Unresponsive hops and the target itself are not probed again
+        if interfaces.is_empty() || interfaces.iter().all(|interface| *interface == self.source) {
+            return self.flows;
+        }
+        return mda_probes(interfaces.len(), confidence).min(MDA_MAX_FLOWS);
+    }
+
+    /// Finish the trace, writing the pending replies and the probes without reply to the sink.
+    /// Return the record of the trace, with the replies ordered by ttl.
+    fn finish(
+        mut self,
+        sink: &mut dyn ResultSink,
+        source: IpAddr,
+        method: PingMethod,
+        paris: bool,
+    ) -> TraceRecord {
+        for trace in self.traces.iter_mut() {
+            if let Some(measurement) = trace {
+                if let Some((reply, _)) = measurement.pending.take() {
+                    sink.write(&reply);
+                    self.replies.push(reply);
+                } else if !measurement.done {
+                    sink.write(&HopRecord {
+                        target: self.source,
+                        router: get_unspecified(self.source),
+                        ttl: measurement.hops,
+                        reply_ttl: None,
+                        size: self.probe_size.unwrap_or(0),
+                        sent_ms: measurement.sent_ms,
+                        received_ms: None,
+                        rtt_us: None,
+                        icmp_type: None,
+                        icmp_code: None,
+                        method: method.clone(),
+                        quote: None,
+                        extensions: IcmpExtensions::default(),
+                    });
+                }
+            }
+        }
+        self.replies.sort_by_key(|reply| reply.ttl);
+        let probed = self.traces
+            .iter()
+            .enumerate()
+            .filter(|&(_, trace)| trace.is_some())
+            .map(|(index, _)| index as u8 + 1)
+            .collect();
+        return TraceRecord {
+            source: source,
+            target: self.source,
+            method: method,
+            paris: paris,
+            probe_size: self.probe_size,
+            probed: probed,
+            hops: self.replies,
         };
     }
 }
 
-struct Anytrace {
+pub struct Anytrace {
     handler: PingHandler,
-    mapping: HashMap<u32, TraceConfiguration>,
-    check: VecDeque<(u32, u64)>,
-    seen: HashSet<Ipv4Addr>,
-    lines: Option<std::io::Lines<std::io::BufReader<std::fs::File>>>,
-    stdin: Option<std::io::Lines<std::io::BufReader<std::io::Stdin>>>,
+    /// Traces in progress, by the network of the target and its prefix length
+    mapping: HashMap<(IpAddr, u8), TraceConfiguration>,
+    check: VecDeque<((IpAddr, u8), u64)>,
+    seen: SeenNetworks,
+    hitlist: Option<Box<dyn Iterator<Item = io::Result<String>>>>,
+    /// Amount of lines read from the hitlist
+    hitlist_offset: u64,
+    /// Targets of the hitlist whose probes may still be queued, as the count of requests
+    /// queued by the writer with their probe and the line of the target
+    unsent_targets: VecDeque<(u64, u64)>,
+    hitlist_size: Option<u64>,
     pps: u32,
-    key: u16,
+    key: ProbeKey,
+    method: PingMethod,
+    instance: Option<u16>,
+    local: IpAddr,
+    prefix: u8,
+    routed: Option<RoutedPrefixes>,
+    paris: bool,
+    mda: Option<f64>,
+    catchment: bool,
+    /// Networks whose replies were received, on the catchment mode
+    caught: HashSet<(IpAddr, u8)>,
+    sink: Box<dyn ResultSink>,
+    /// File where the state is saved, and the interval between the checkpoints
+    checkpoint: Option<(String, Duration)>,
+    last_checkpoint: Instant,
+    /// Control server announcing the measurement, and the amount of followers to wait for
+    server: Option<(ControlServer, usize)>,
+    client: Option<ControlClient>,
+    last_report: Instant,
+    /// Amount of traces finished
+    finished: u64,
 
     master: bool,
     starttime: Instant,
     runtime: Duration,
+    drain: Duration,
 }
 
-impl Anytrace {
-    pub fn new(
-        hitlist: Option<String>,
-        localip: &str,
-        pps: u32,
-        method: PingMethod,
-        master: bool,
-        runtime: Duration,
-    ) -> Anytrace {
-        let handler = PingHandlerBuilder::new()
-            .localip(localip)
-            .method(method)
-            .rate_limit(pps)
-            .build();
-        let file = match hitlist {
-            Some(hitlist) => Some(BufReader::new(File::open(hitlist).unwrap()).lines()),
-            _ => None,
+pub struct AnytraceBuilder {
+    hitlist: Option<Box<dyn Iterator<Item = io::Result<String>>>>,
+    hitlist_size: Option<u64>,
+    localip: Option<String>,
+    pps: Option<u32>,
+    method: Option<PingMethod>,
+    master: bool,
+    runtime: Option<Duration>,
+    drain: Option<Duration>,
+    prefix: Option<u8>,
+    routed: Option<RoutedPrefixes>,
+    seen: Option<SeenNetworks>,
+    secret: Option<String>,
+    instance: Option<u16>,
+    paris: bool,
+    mda: Option<f64>,
+    catchment: bool,
+    transport: Option<Box<dyn Transport>>,
+    sink: Option<Box<dyn ResultSink>>,
+    checkpoint: Option<(String, Duration)>,
+    resume: Option<Checkpoint>,
+    server: Option<(ControlServer, usize)>,
+    client: Option<ControlClient>,
+}
+
+impl AnytraceBuilder {
+    /// Create a new AnytraceBuilder to build an Anytrace.
+    pub fn new() -> AnytraceBuilder {
+        return AnytraceBuilder {
+            hitlist: None,
+            hitlist_size: None,
+            localip: None,
+            pps: None,
+            method: None,
+            master: false,
+            runtime: None,
+            drain: None,
+            prefix: None,
+            routed: None,
+            seen: None,
+            secret: None,
+            instance: None,
+            paris: false,
+            mda: None,
+            catchment: false,
+            transport: None,
+            sink: None,
+            checkpoint: None,
+            resume: None,
+            server: None,
+            client: None,
+        };
+    }
+
+    /// Read the hitlist from the given file, separated by newline. The stdin is used by default.
+    pub fn hitlist(self, hitlist: &str) -> Self {
+        let size = BufReader::new(File::open(hitlist).unwrap()).lines().count() as u64;
+        let file = BufReader::new(File::open(hitlist).unwrap());
+        let mut builder = self.hitlist_lines(Box::new(file.lines()));
+        builder.hitlist_size = Some(size);
+        return builder;
+    }
+
+    /// Read the hitlist from the given lines.
+    pub fn hitlist_lines(mut self, lines: Box<dyn Iterator<Item = io::Result<String>>>) -> Self {
+        self.hitlist = Some(lines);
+        self.hitlist_size = None;
+        return self;
+    }
+
+    /// Set the local IP address used to send the packets.
+    pub fn localip(mut self, localip: &str) -> Self {
+        self.localip = Some(localip.to_string());
+        return self;
+    }
+
+    /// Set the frequency of packets per seconds to send.
+    pub fn pps(mut self, pps: u32) -> Self {
+        self.pps = Some(pps);
+        return self;
+    }
+
+    /// Set the method used to send the ping packets.
+    pub fn method(mut self, method: PingMethod) -> Self {
+        self.method = Some(method);
+        return self;
+    }
+
+    /// Set the node as a master, sending the requests on the hitlist.
+    pub fn master(mut self, master: bool) -> Self {
+        self.master = master;
+        return self;
+    }
+
+    /// Set the duration of the measurements. Only used on non-master process.
+    pub fn duration(mut self, runtime: Duration) -> Self {
+        self.runtime = Some(runtime);
+        return self;
+    }
+
+    /// Set the time waited for the replies after the last probe is written, 10 seconds by default.
+    pub fn drain(mut self, drain: Duration) -> Self {
+        self.drain = Some(drain);
+        return self;
+    }
+
+    /// Set the prefix length used to aggregate the targets, tracing a single target of
+    /// each network. Defaults to /24 for IPv4 and /48 for IPv6.
+    pub fn prefix(mut self, prefix: u8) -> Self {
+        self.prefix = Some(prefix);
+        return self;
+    }
+
+    /// Aggregate the targets by the most specific routed prefix containing them, using
+    /// the prefix length of the measurement for the targets outside of the table.
+    pub fn routed_prefixes(mut self, routed: RoutedPrefixes) -> Self {
+        self.routed = Some(routed);
+        return self;
+    }
+
+    /// Start with the networks and routers seen by a previous measurement, which are not traced again.
+    pub fn seen(mut self, seen: SeenNetworks) -> Self {
+        self.seen = Some(seen);
+        return self;
+    }
+
+    /// Set the secret used to authenticate the probes, which must be shared between the master
+    /// and the followers. It's required by the followers, and a master without followers uses
+    /// a random secret by default.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_string());
+        return self;
+    }
+
+    /// Set the instance used to mark the probes, isolating the measurements executed at the
+    /// same time on the same address. By default it is derived from the secret and the method.
+    pub fn instance(mut self, instance: u16) -> Self {
+        self.instance = Some(instance);
+        return self;
+    }
+
+    /// Keep the flow identifier constant for every probe of a trace, as in Paris traceroute,
+    /// so the load balancers don't send the probes of different hops through different paths.
+    pub fn paris(mut self, paris: bool) -> Self {
+        self.paris = paris;
+        return self;
+    }
+
+    /// Enumerate the interfaces of the load balanced hops with the Multipath Detection Algorithm,
+    /// probing each hop with new flows until every interface is found with the given confidence.
+    ///
+    /// Implies the Paris mode, and is only supported by the ICMP and UDP methods.
+    pub fn mda(mut self, confidence: f64) -> Self {
+        self.mda = Some(confidence);
+        return self;
+    }
+
+    /// Only measure the catchment of the anycast address, without tracing the networks.
+    ///
+    /// The master pings one target per network, and every node records the networks whose
+    /// replies it receives, as a reply from the target itself. The followers use the mode
+    /// announced by the master.
+    pub fn catchment(mut self, catchment: bool) -> Self {
+        self.catchment = catchment;
+        return self;
+    }
+
+    /// Set the transport used to send and receive the packets. Raw sockets are used by default.
+    pub fn transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        return self;
+    }
+
+    /// Set the sink where the results are written. By default they are written to the stdout as CSV.
+    pub fn sink(mut self, sink: Box<dyn ResultSink>) -> Self {
+        self.sink = Some(sink);
+        return self;
+    }
+
+    /// Save the state of the measurement to the given file periodically, so it can be resumed
+    /// after a crash or a reboot.
+    pub fn checkpoint(mut self, path: &str, interval: Duration) -> Self {
+        self.checkpoint = Some((path.to_string(), interval));
+        return self;
+    }
+
+    /// Resume the measurement saved on the checkpoint, skipping the lines of the hitlist
+    /// already read and the networks already seen, and continuing the traces in progress.
+    ///
+    /// The key of the checkpoint is used instead of the secret, so the replies to the probes
+    /// sent before the checkpoint are still accepted.
+    pub fn resume(mut self, checkpoint: Checkpoint) -> Self {
+        self.resume = Some(checkpoint);
+        return self;
+    }
+
+    /// Announce the measurement to the followers registered on the control server, waiting
+    /// for the given amount of them before starting, and stop them when the probes have drained.
+    pub fn control(mut self, server: ControlServer, followers: usize) -> Self {
+        self.server = Some((server, followers));
+        return self;
+    }
+
+    /// Follow the measurement announced by the master, using its method, key and instance,
+    /// until the master tells the follower to stop.
+    pub fn follow(mut self, client: ControlClient) -> Self {
+        self.client = Some(client);
+        return self;
+    }
+
+    /// Build the Anytrace
+    pub fn build(self) -> Anytrace {
+        let localip = self.localip.unwrap();
+        let local: IpAddr = localip.parse().unwrap();
+        let pps = self.pps.unwrap();
+        let method = match self.client {
+            Some(ref client) => {
+                let announced = client.announcement().method.clone();
+                if self.method.as_ref().map_or(false, |method| *method != announced) {
+                    warn!("Using the method {} announced by the master", announced);
+                }
+                announced
+            }
+            None => self.method.unwrap(),
+        };
+        let instance = match self.client {
+            Some(ref client) => client.announcement().instance,
+            None => self.instance,
         };
+        let catchment = match self.client {
+            Some(ref client) => client.announcement().catchment,
+            None => self.catchment,
+        };
+        let prefix = match local {
+            IpAddr::V4(_) => self.prefix.unwrap_or(24),
+            IpAddr::V6(_) => self.prefix.unwrap_or(48),
+        };
+        if prefix == 0 || prefix > if local.is_ipv4() { 32 } else { 128 } {
+            panic!("Invalid prefix length /{} for {}", prefix, local);
+        }
+
+        // The flow is stored in the ports of the TCP probes, which are used to filter the replies
+        if self.mda.is_some() && method.is_tcp() {
+            panic!("MDA is only supported by the ICMP and UDP methods");
+        }
+        let paris = self.paris || self.mda.is_some();
 
-        let stdin = match &file {
-            Some(_) => None,
-            _ => Some(BufReader::new(io::stdin()).lines()),
+        let key = match self.secret {
+            _ if self.resume.is_some() => self.resume.as_ref().unwrap().key,
+            _ if self.client.is_some() => self.client.as_ref().unwrap().key(),
+            _ if self.server.is_some() => self.server.as_ref().unwrap().0.key(),
+            Some(secret) => ProbeKey::from_secret(&secret),
+            // The followers only receive the replies to the probes of the master
+            None if !self.master => panic!("The followers need the secret of the master to authenticate the replies"),
+            None => {
+                warn!("No secret given, the replies to the probes of other nodes will be discarded");
+                ProbeKey::random()
+            }
+        };
+        // The other nodes authenticated with the key derived from the secret
+        let control = self.client.as_ref().map(|client| client.key())
+            .or(self.server.as_ref().map(|&(ref server, _)| server.key()));
+        if control.map_or(false, |control| control != key) {
+            panic!("The key of the checkpoint doesn't match the secret of the other nodes");
+        }
+
+        let mut handler = PingHandlerBuilder::new()
+            .localip(&localip)
+            .method(method.clone())
+            .rate_limit(pps)
+            .key(key)
+            .paris(paris);
+        if let Some(instance) = instance {
+            handler = handler.instance(instance);
+        }
+        if let Some(transport) = self.transport {
+            handler = handler.transport(transport);
+        }
+
+        let mut hitlist = match self.hitlist {
+            Some(hitlist) => hitlist,
+            None => Box::new(BufReader::new(io::stdin()).lines()),
         };
 
+        let mut mapping = HashMap::new();
+        let mut seen = self.seen.unwrap_or_else(SeenNetworks::new);
+        let mut caught = HashSet::new();
+        let mut hitlist_offset = 0;
+        if let Some(resume) = self.resume {
+            // Skip the targets already sent. The current hop of the traces is probed again
+            // once the measurement starts
+            hitlist_offset = hitlist.by_ref().take(resume.hitlist_offset as usize).count() as u64;
+            for (network, trace) in resume.traces {
+                mapping.insert(network, trace);
+            }
+            seen = resume.seen;
+            caught = resume.caught;
+            info!(
+                "Resuming after {} lines of the hitlist, with {} traces in progress",
+                hitlist_offset,
+                mapping.len()
+            );
+        }
+
         return Anytrace {
-            handler: handler,
-            mapping: HashMap::new(),
+            handler: handler.build(),
+            mapping: mapping,
             check: VecDeque::new(),
-            seen: HashSet::new(),
-            lines: file,
-            stdin: stdin,
+            seen: seen,
+            hitlist: Some(hitlist),
+            hitlist_offset: hitlist_offset,
+            unsent_targets: VecDeque::new(),
+            hitlist_size: self.hitlist_size,
             pps: pps,
-            key: 0xBEEAu16,
+            key: key,
+            method: method,
+            instance: instance,
+            local: local,
+            prefix: prefix,
+            routed: self.routed,
+            paris: paris,
+            mda: self.mda,
+            catchment: catchment,
+            caught: caught,
+            sink: self.sink.unwrap_or_else(|| Box::new(CsvSink::stdout())),
+            checkpoint: self.checkpoint,
+            last_checkpoint: Instant::now(),
+            server: self.server,
+            client: self.client,
+            last_report: Instant::now(),
+            finished: 0,
 
-            master: master,
+            master: self.master,
             starttime: Instant::now(),
-            runtime: runtime,
+            runtime: self.runtime.unwrap_or(Duration::from_secs(u32::max_value() as u64)),
+            drain: self.drain.unwrap_or(DRAIN_TIMEOUT),
         };
     }
+}
 
+impl Anytrace {
     /// Start listening to ICMP packets, and generating a traceroute as new networks  start coming.
     /// Packets can be verified by their identifier, sequence_address and origin
     /// The process is as follow
@@ -120,26 +610,67 @@ impl Anytrace {
     ///         If not on HashMap, goto [A] (This mean the packet is not verified, or came from
     ///             an invalid ip while sending the data)
     ///
-    /// Packet format: id: first 16 bits of the dst ip, seq: (u8 of the dst ip, u8 ttl)
-    /// Output to stdin (csv): original_target, measured_router, hops, ms
+    /// Packet format: id: MAC of the dst ip, seq and 30 s epoch, seq: (u8 flow, u8 ttl)
+    /// The flow is always 0, unless the MDA mode is used to probe the hops with many flows.
+    /// Every reply is written to the result sink with its round trip time, by default to the stdout as csv:
+    ///     original_target, measured_router, hops, ms, rtt_ms, details
     pub fn run(&mut self) {
         match self.master {
             true => info!("Starting in master mode"),
             false => info!("Starting in follower mode"),
         }
+        if self.catchment {
+            info!("Only measuring the catchment, without tracing the networks");
+        }
+        self.synchronize_start();
+        self.resume_traces();
         let start = Instant::now();
         loop {
+            if self.last_report + PROGRESS_INTERVAL <= Instant::now() {
+                self.last_report = Instant::now();
+                self.report_progress();
+            }
+            if let Some((ref path, interval)) = self.checkpoint {
+                if self.last_checkpoint + interval <= Instant::now() {
+                    self.last_checkpoint = Instant::now();
+                    self.save_checkpoint(path);
+                }
+            }
             if self.check.len() < self.pps as usize * 5usize {
                 let mut end = true;
                 if self.master {
+                    // Forget the targets whose probes were already processed by the writer
+                    let done = self.handler.writer.sended_packets() + self.handler.writer.dropped_packets();
+                    while self.unsent_targets.front().map_or(false, |&(request, _)| request <= done) {
+                        self.unsent_targets.pop_front();
+                    }
                     for _ in 0..self.pps {
+                        // Stop reading the hitlist while the writer is behind, leaving
+                        // half of its queue for the trace probes
+                        if self.handler.writer.queue_depth()
+                            >= self.handler.writer.queue_capacity() / 2
+                        {
+                            end = false;
+                            break;
+                        }
                         if let Some(ip) = self.get_nextip() {
                             if let Ok(ip) = ip.parse() {
-                                let ip: Ipv4Addr = ip;
-                                if !self.seen.contains(&Ipv4Addr::from(get_ip_mask(ip) | 0xFF)) {
+                                let ip: IpAddr = ip;
+                                if ip.is_ipv4() != self.local.is_ipv4() {
+                                    debug!("Skipping {}, as its family differs from {}", ip, self.local);
+                                    continue;
+                                }
+                                if !self.seen_network(ip) {
                                     // We don't store the information, as this packet only verifies if
-                                    // the host is online, and not execute the tracerote
-                                    self.handler.writer.send(ip); // try_send or break
+                                    // the host is online, and not execute the tracerote. It's
+                                    // still authenticated, so the replies can't be forged
+                                    self.send_probe(ip, HITLIST_TTL, 0);
+                                    let request = self.handler.writer.queued_packets();
+                                    self.unsent_targets.push_back((request, self.hitlist_offset - 1));
+                                    // On the catchment mode, only one target of each network is pinged
+                                    if self.catchment {
+                                        self.mark_network(ip);
+                                    }
                                     end = false; // move outside
                                 }
                             }
@@ -149,17 +680,20 @@ impl Anytrace {
                     }
                 }
                 if end && self.check.is_empty() {
-                    if start
-                        + Duration::from_secs(
-                            self.handler.writer.sended_packets() / self.pps as u64,
-                        ) + Duration::from_secs(10) > Instant::now()
+                    if self.handler.writer.queue_depth() > 0
+                        || start
+                            + Duration::from_secs(
+                                self.handler.writer.sended_packets() / self.pps as u64,
+                            ) + self.drain > Instant::now()
                     {
                         debug!("Waiting for writting to finish");
-                        use std::thread;
-                        thread::sleep(Duration::from_secs(5));
+                        thread::sleep(cmp::min(Duration::from_secs(5), self.drain));
                     } else {
-                        // Only end if its master, or the slave run time has ended
-                        if self.master || self.starttime + self.runtime < Instant::now() {
+                        // Only end if its master, or the slave run time has ended or the master stopped it
+                        if self.master
+                            || self.starttime + self.runtime < Instant::now()
+                            || self.client.as_ref().map_or(false, |client| client.stopped())
+                        {
                             break;
                         }
                     }
@@ -167,42 +701,63 @@ impl Anytrace {
             }
 
             // Get all ip addresses that we havent received timeout and send the next ttl
-            // only if we havent see the /24
+            // only if we havent see the network
             let current_time = time_from_epoch_ms();
             while !self.check.is_empty() {
                 let (ip, time) = self.check[0];
                 if time < current_time {
                     self.check.pop_front();
 
-                    let ttl = self.mapping.get(&ip).unwrap().current_ttl;
+                    // Only the last update queued for the trace is processed
+                    let ttl = match self.mapping.get(&ip) {
+                        Some(trace) if trace.next_check == time => trace.current_ttl,
+                        _ => continue,
+                    };
                     if ttl == 0 {
-                        trace!("Removing {} from mapping", ip);
-                        self.mapping.remove(&ip);
+                        trace!("Removing {}/{} from mapping", ip.0, ip.1);
+                        if let Some(trace) = self.mapping.remove(&ip) {
+                            let record = trace.finish(
+                                &mut *self.sink,
+                                self.local,
+                                self.method.clone(),
+                                self.paris,
+                            );
+                            self.sink.write_trace(&record);
+                            self.finished += 1;
+                        }
                         continue;
                     }
 
                     if let Some(trace) = self.mapping.get_mut(&ip) {
-                        // Extract next packet metadata and update trace
-                        trace.current_ttl = trace.current_ttl.saturating_sub(1);
-                        if trace.current_ttl >= 1 {
-                            // Send the next packet
-                            let (identifier, sequence) =
-                                encode_id_seq_key(ip, trace.current_ttl, self.key);
-                            self.handler.writer.send_complete(
-                                trace.source,
-                                identifier,
-                                sequence,
-                                trace.current_ttl,
-                                identifier,
-                                sequence,
-                            );
+                        let next_check = time_from_epoch_ms() + 1 * 1000;
+                        trace.next_check = next_check;
+                        // On MDA mode, probe the hop with new flows until the stopping rule is met
+                        let flows = match self.mda {
+                            Some(confidence) => trace.needed_flows(confidence),
+                            None => trace.flows,
+                        };
+                        if flows > trace.flows {
+                            let (source, sent) = (trace.source, trace.flows);
+                            trace.flows = flows;
+                            for flow in sent..flows {
+                                self.send_probe(source, ttl, flow as u8);
+                            }
+                        } else {
+                            // Extract next packet metadata and update trace
+                            trace.current_ttl = trace.current_ttl.saturating_sub(1);
+                            trace.flows = 1;
+                            if trace.current_ttl >= 1 {
+                                // Send the next packet
+                                let (source, ttl) = (trace.source, trace.current_ttl);
+                                self.send_probe(source, ttl, 0);
+                            }
                         }
                         // Queue the next update
-                        self.check.push_back((ip, time_from_epoch_ms() + 1 * 1000));
+                        self.check.push_back((ip, next_check));
                     } else {
                         panic!(
                             "IP Address {:?} in trace queue while not in `mapping`",
-                            Ipv4Addr::from(ip)
+                            ip
                         );
                     }
                 } else {
@@ -220,14 +775,12 @@ impl Anytrace {
                     ping::Responce::Echo(icmp) => self.process_echo_responce(&packet, &icmp),
                     ping::Responce::Timeout(icmp) => self.process_timeout(&packet, &icmp),
                     ping::Responce::Unreachable(icmp) => self.process_unreachable(&packet, &icmp),
-                    ping::Responce::LocalSendedEcho(target) => {
-                        // Receive the locally written packets, and store the timestamp.
-                        self.update_trace_entry(
-                            *target,
-                            Ipv4Addr::new(0, 0, 0, 0),
-                            packet.ttl,
-                            packet.time_ms,
-                        )
+                    ping::Responce::Tcp(tcp) => self.process_tcp_responce(&packet, &tcp),
+                    ping::Responce::LocalSendedEcho(target, _, sequence) => {
+                        // Receive the locally written packets, and store the timestamp of their flow,
+                        // stored in the high byte of the sequence
+                        let record = self.hop_record(&packet, *target, packet.ttl, None);
+                        self.update_trace_entry(record, (*sequence >> 8) as u8, (packet.time, packet.timestamp))
                     }
                 };
 
@@ -244,34 +797,118 @@ impl Anytrace {
                 }
             }
         }
+        self.synchronize_stop();
+        self.sink.flush();
+        if let Some((ref path, _)) = self.checkpoint {
+            self.save_checkpoint(path);
+        }
+        info!(
+            "Finished: {} packets sent, {} failed",
+            self.handler.writer.sended_packets(),
+            self.handler.writer.dropped_packets()
+        );
+    }
+
+    /// Wait for the followers and announce the measurement to them, or wait for the measurement
+    /// announced by the master, so every node starts at the same time.
+    fn synchronize_start(&mut self) {
+        let start_ms = match (&self.server, &self.client) {
+            (&Some((ref server, followers)), _) => {
+                server.wait_followers(followers);
+                let announcement = Announcement {
+                    start_ms: time_from_epoch_ms() + START_DELAY_MS,
+                    method: self.method.clone(),
+                    instance: self.instance,
+                    hitlist_size: self.hitlist_size,
+                    catchment: self.catchment,
+                };
+                server.start(&announcement);
+                announcement.start_ms
+            }
+            (_, &Some(ref client)) => client.announcement().start_ms,
+            _ => return,
+        };
+        let now = time_from_epoch_ms();
+        if start_ms > now {
+            info!("Starting the measurement in {} ms", start_ms - now);
+            thread::sleep(Duration::from_millis(start_ms - now));
+        }
+        self.starttime = Instant::now();
+    }
+
+    /// Stop the followers and wait for them to finish, or tell the master the follower finished
+    fn synchronize_stop(&mut self) {
+        let progress = self.progress();
+        if let Some((ref server, _)) = self.server {
+            server.stop();
+            if !server.wait_done(FOLLOWERS_TIMEOUT) {
+                warn!("Timeout waiting for the followers to finish");
+            }
+            for follower in server.followers() {
+                info!(
+                    "Follower {} ({}): {} packets sent, {} traces finished{}",
+                    follower.name,
+                    follower.address,
+                    follower.progress.sent,
+                    follower.progress.finished,
+                    if follower.done { "" } else { ", not finished" }
+                );
+            }
+        }
+        if let Some(ref mut client) = self.client {
+            client.done(&progress);
+        }
+    }
+
+    /// Report the progress to the master, or log the progress of the followers
+    fn report_progress(&mut self) {
+        let progress = self.progress();
+        if let Some(ref mut client) = self.client {
+            client.report(&progress);
+        }
+        if let Some((ref server, _)) = self.server {
+            for follower in server.followers().iter().filter(|follower| follower.connected) {
+                info!(
+                    "Follower {}: {} packets sent, {} traces in progress, {} finished",
+                    follower.name,
+                    follower.progress.sent,
+                    follower.progress.traces,
+                    follower.progress.finished
+                );
+            }
+        }
+    }
+
+    /// Get the progress of the measurement
+    fn progress(&self) -> Progress {
+        return Progress {
+            sent: self.handler.writer.sended_packets(),
+            traces: self.mapping.len() as u64,
+            finished: self.finished,
+        };
     }
 
     /// Process an ICMP echo responce
     fn process_echo_responce(&mut self, packet: &IcmpResponce, icmp: &EchoReply) -> Result<(), ()> {
-        // Check if this is a new IP Address, only using his /24
-        let ip = get_ip_mask(packet.source);
+        // Check if this is a new IP Address, only using his network
+        let ip = self.network(packet.source);
         if self.mapping.contains_key(&ip) {
             trace!(
-                "Network {}/24 already seen ({}) (ttl: {}, dist: {})",
-                Ipv4Addr::from(ip),
+                "Network {}/{} already seen ({}) (ttl: {}, dist: {})",
+                ip.0,
+                ip.1,
                 packet.source,
                 packet.ttl,
                 get_max_ttl(&packet)
             );
-            if let Ok(_) = PingHandler::verify_signature(&icmp.payload) {
-                let (network, ttl) =
-                    decode_id_seq_key(icmp.identifier, icmp.sequence_number, self.key);
-                if verify_packet_network(packet.source, network) {
+            if let Ok(_) = self.handler.verify_signature(&icmp.payload, packet.source) {
+                let probe = self.decode_probe(packet.source, icmp.identifier, icmp.sequence_number);
+                if let Ok((ttl, flow)) = probe {
                     // Mark the router as measured and update the trace
-                    self.seen.insert(packet.source);
-                    self.seen
-                        .insert(Ipv4Addr::from(get_ip_mask(packet.source) | 0xff));
-                    return self.update_trace_entry(
-                        packet.source,
-                        packet.source,
-                        ttl,
-                        packet.time_ms,
-                    );
+                    self.seen.insert_router(packet.source);
+                    self.mark_network(packet.source);
+                    let record = self.hop_record(&packet, packet.source, ttl, None);
+                    return self.update_trace_entry(record, flow, (packet.time, packet.timestamp));
                 } else {
                     trace!("Error verifying packet from {}", packet.source);
                 }
@@ -280,8 +917,10 @@ impl Anytrace {
             }
         } else {
             // Only process packets generated by our system
-            if let Ok(_) = PingHandler::verify_signature(&icmp.payload) {
-                return self.process_new_entry(&packet);
+            if let Ok(_) = self.handler.verify_signature(&icmp.payload, packet.source) {
+                if self.decode_probe(packet.source, icmp.identifier, icmp.sequence_number).is_ok() {
+                    return self.process_new_entry(&packet, packet.source);
+                }
             }
         }
         return Err(());
@@ -290,32 +929,32 @@ impl Anytrace {
     fn process_timeout(&mut self, packet: &IcmpResponce, icmp: &TimeExceeded) -> Result<(), ()> {
         // The payload contains the EchoRequest packet + 64 bytes of payload if its over UDP or TCP
         trace!("Received timeout from ({})", packet.source);
-        if let Ok((target, id, seq)) = parse_icmp(&icmp.payload) {
+        if let Ok((target, id, seq, quote)) = parse_icmp(&icmp.payload, self.paris) {
             trace!(
                 "Received timeout from (id: {:?}, seq: {:?} => target: {})",
                 id, seq, target
             );
             // Verify the packet
-            let (network, ttl) = decode_id_seq_key(id, seq, self.key);
-            if verify_packet_network(target, network) {
+            if let Ok((ttl, flow)) = self.decode_probe(target, id, seq) {
                 let mut founded = false;
-                if let Some(trace) = self.mapping.get_mut(&get_ip_mask(target)) {
+                let record = self.hop_record(&packet, target, ttl, Some(quote));
+                let network = self.network(target);
+                if let Some(trace) = self.mapping.get_mut(&network) {
                     founded = true;
-                    if let Ok(_) =
-                        update_trace_conf(trace, target, packet.source, ttl, packet.time_ms)
-                    {
+                    if let Ok(_) = update_trace_conf(trace, record, flow, (packet.time, packet.timestamp), &self.handler, &mut *self.sink) {
+                        check_new_flows(trace, network, self.mda, &mut self.check);
                         // If the router is market as done, we don't need to check for skips
                         if trace.current_ttl == 0 {
                             return Ok(());
                         }
 
                         // Check if ip was already seen, and mark as done if the route has already been processed
-                        if self.seen.contains(&packet.source) {
+                        if self.seen.contains_router(packet.source) {
                             // Only skip if the last hop is not the same ip address, as some use the same router for more than one hop
                             let mut skip = true;
                             for i in (ttl as usize + 1 - 1)..trace.traces.len() {
                                 if let Some(Some(upper)) = trace.traces.get(i) {
-                                    if upper.router == Ipv4Addr::new(0, 0, 0, 0) {
+                                    if upper.router.is_unspecified() {
                                         continue;
                                     }
                                     if upper.router == packet.source {
@@ -332,13 +971,13 @@ impl Anytrace {
                         }
 
                         // Add the router to the seen table, so we dont process it again
-                        self.seen.insert(packet.source);
+                        self.seen.insert_router(packet.source);
                     }
                 }
                 if founded {
-                    // Mark the /24 of the router in the table, so we don't start new traces to the target
-                    let netsrc = get_ip_mask(packet.source);
-                    self.seen.insert(Ipv4Addr::from(netsrc | 0xFF));
+                    // Mark the network of the router in the table, so we don't start new traces to the target
+                    let netsrc = self.network(packet.source);
+                    self.mark_network(packet.source);
                     if let Some(trace) = self.mapping.get_mut(&netsrc) {
                         // If its another trace, set the current_ttl to 0 to stop it, as we have a common router
                         if target != packet.source {
@@ -362,176 +1001,406 @@ impl Anytrace {
         trace!(
             "Unreachable from {}, {:?}",
             packet.source,
-            parse_icmp(&icmp.payload)
+            parse_icmp(&icmp.payload, self.paris)
         );
-        let ip = get_ip_mask(packet.source);
+        // The error can be sent by the target, its gateway or a middlebox, so it's verified
+        // against the quoted target, and belongs to its trace
+        let (target, id, seq, quote) = match parse_icmp(&icmp.payload, self.paris) {
+            Ok(probe) => probe,
+            Err(_) => {
+                debug!("Error parsing Unreachable from {}", packet.source);
+                return Err(());
+            }
+        };
+        let (ttl, flow) = match self.decode_probe(target, id, seq) {
+            Ok(probe) => probe,
+            Err(_) => {
+                debug!(
+                    "Error verifying from {} for {}, received id {:04x} and seq {:04x}",
+                    packet.source, target, id, seq
+                );
+                return Err(());
+            }
+        };
+        let ip = self.network(target);
         if self.mapping.contains_key(&ip) {
             trace!(
-                "Network {}/24 already seen ({}) (ttl: {}, dist: {})",
-                Ipv4Addr::from(ip),
+                "Network {}/{} already seen ({}) (ttl: {}, dist: {})",
+                ip.0,
+                ip.1,
                 packet.source,
                 packet.ttl,
                 get_max_ttl(&packet)
             );
+            let record = self.hop_record(&packet, target, ttl, Some(quote));
+            return self.update_trace_entry(record, flow, (packet.time, packet.timestamp));
+        }
+        // Only start the trace if it's not already traced
+        return self.process_new_entry(&packet, target);
+    }
 
-            if let Ok((_, id, seq)) = parse_icmp(&icmp.payload) {
-                let (network, ttl) = decode_id_seq_key(id, seq, self.key);
-                if verify_packet_network(packet.source, network) {
-                    return self.update_trace_entry(
-                        packet.source,
-                        packet.source,
-                        ttl,
-                        packet.time_ms,
-                    );
-                } else {
-                    debug!(
-                        "Error verifying from {}, received {}/24",
-                        packet.source,
-                        Ipv4Addr::from(network)
-                    );
-                }
-            } else {
-                debug!("Error parsing Unreachable from {}", packet.source);
+    /// Process a TCP RST or SYN-ACK, received when a TCP probe reaches the destination.
+    ///
+    /// The reply must come from `TCP_PORT` and acknowledge an authenticated probe, even when
+    /// it starts a new trace, as anyone can send a TCP segment to the measurement address.
+    fn process_tcp_responce(&mut self, packet: &IcmpResponce, tcp: &Tcp) -> Result<(), ()> {
+        let (id, seq) = PingHandler::get_tcp_id_seq(tcp);
+        let (ttl, flow) = match self.decode_probe(packet.source, id, seq) {
+            Ok(probe) if tcp.source == TCP_PORT => probe,
+            _ => {
+                debug!(
+                    "Error verifying TCP reply from {}:{}, received id {:04x} and seq {:04x}",
+                    packet.source, tcp.source, id, seq
+                );
+                return Err(());
             }
-        } else {
-            return self.process_new_entry(&packet);
+        };
+        let ip = self.network(packet.source);
+        if self.mapping.contains_key(&ip) {
+            let record = self.hop_record(&packet, packet.source, ttl, None);
+            return self.update_trace_entry(record, flow, (packet.time, packet.timestamp));
         }
-        return Err(());
+        return self.process_new_entry(&packet, packet.source);
+    }
+
+    /// Send a probe of the flow to the target, storing the authenticated identifier and
+    /// sequence where the method can recover them.
+    ///
+    /// UDP probes store the identifier in the source port and the sequence in the destination
+    /// port above `UDP_PORT`. TCP probes keep the destination port fixed, as the identifier and
+    /// sequence are stored in the TCP sequence number. On Paris mode the ports of the UDP probes
+    /// and the checksum of the ICMP probes only depend on the flow.
+    fn send_probe(&self, target: IpAddr, ttl: u8, flow: u8) {
+        let (identifier, sequence) = encode_id_seq_key(target, ttl, flow, time_from_epoch_ms(), &self.key);
+        let (src_port, dst_port) = match self.method {
+            PingMethod::TcpSyn | PingMethod::TcpAck => (identifier, TCP_PORT),
+            _ if self.paris => (UDP_PORT.wrapping_add(flow as u16), UDP_PORT),
+            _ => (identifier, UDP_PORT.wrapping_add(sequence)),
+        };
+        self.handler
+            .writer
+            .send_complete(target, src_port, dst_port, ttl, identifier, sequence);
     }
 
-    /// Add a new entry to the mapping table and send the first ping packet
+    /// Decode the ttl and flow of a probe sent to the target during the last minute, only
+    /// accepting the flows used by the measurement
+    fn decode_probe(&self, target: IpAddr, id: u16, seq: u16) -> Result<(u8, u8), ()> {
+        let (ttl, flow) = decode_id_seq_key(target, id, seq, time_from_epoch_ms(), &self.key)?;
+        if flow != 0 && self.mda.is_none() {
+            return Err(());
+        }
+        return Ok((ttl, flow));
+    }
+
+    /// Add a new entry to the mapping table and send the first ping packet to the target.
+    /// The packet must be an authenticated reply to a probe sent to the target.
     /// You MUST verify that the ip is not in the mapping before calling this function, or it will override other calls
-    fn process_new_entry(&mut self, packet: &IcmpResponce) -> Result<(), ()> {
-        let ip = get_ip_mask(packet.source);
-        // New network, send the traceroute packets. There is no need to verify as
-        // We dont store the information of this packet.
+    fn process_new_entry(&mut self, packet: &IcmpResponce, target: IpAddr) -> Result<(), ()> {
+        let ip = self.network(target);
+        if self.catchment {
+            return self.process_catchment(packet, target);
+        }
+        // New network, send the traceroute packets
 
         // If we have seen the network, discard it
-        if self.seen.contains(&Ipv4Addr::from(ip | 0xff)) {
+        if self.seen_network(target) {
             debug!(
-                "New network {}/24 already seen, not processing",
-                Ipv4Addr::from(ip)
+                "New network {}/{} already seen, not processing",
+                ip.0,
+                ip.1
             );
             return Err(());
         }
 
         debug!(
-            "New Network {}/24, ttl: {}, starting dist: {}",
-            Ipv4Addr::from(ip),
+            "New Network {}/{}, ttl: {}, starting dist: {}",
+            ip.0,
+            ip.1,
             packet.ttl,
             get_max_ttl(&packet)
         );
-        self.mapping.insert(
-            ip,
-            TraceConfiguration::new(packet.source, get_max_ttl(&packet)),
-        );
+        let mut trace = TraceConfiguration::new(target, get_max_ttl(&packet));
+        trace.next_check = time_from_epoch_ms() + 1 * 1000;
+        self.check.push_back((ip, trace.next_check));
+        self.mapping.insert(ip, trace);
 
-        // Send the max ttl and add it to the queue
+        // Send the max ttl
         let ttl = get_max_ttl(&packet);
-        let (identifier, sequence) = encode_id_seq_key(ip, ttl, self.key);
-        self.handler.writer.send_complete(
-            packet.source,
-            identifier,
-            sequence,
-            ttl,
-            identifier,
-            sequence,
-        );
-        self.check
-            .push_back((get_ip_mask(packet.source), time_from_epoch_ms() + 1 * 1000));
+        self.send_probe(target, ttl, 0);
         return Ok(());
     }
 
-    /// Update the entry with the given information
-    fn update_trace_entry(
-        &mut self,
-        original_target: Ipv4Addr,
-        packet_source: Ipv4Addr,
+    /// Record the network of the target as caught by this node, once per network, instead of tracing it.
+    ///
+    /// The authenticated reply to the probe sent to the target is written to the sink,
+    /// with the distance estimated from its TTL as the hop.
+    fn process_catchment(&mut self, packet: &IcmpResponce, target: IpAddr) -> Result<(), ()> {
+        let ip = self.network(target);
+        if !self.caught.insert(ip) {
+            trace!("Network {}/{} already caught", ip.0, ip.1);
+            return Ok(());
+        }
+        debug!("Network {}/{} caught, from {}", ip.0, ip.1, packet.source);
+        let record = self.hop_record(&packet, target, get_max_ttl(&packet), None);
+        self.sink.write(&record);
+        self.finished += 1;
+        return Ok(());
+    }
+
+    /// Create the record of a packet sent or received for the hop of the trace to the target.
+    ///
+    /// The locally sent packets are recorded with an unspecified router and their sent time.
+    fn hop_record(
+        &self,
+        packet: &IcmpResponce,
+        original_target: IpAddr,
         ttl: u8,
-        time_ms: u64,
-    ) -> Result<(), ()> {
-        let source_net = get_ip_mask(original_target);
+        quote: Option<QuotedHeader>,
+    ) -> HopRecord {
+        let (icmp_type, icmp_code) = match packet.icmp {
+            ping::Responce::Echo(ref icmp) => (Some(icmp.icmp_type.0), Some(icmp.icmp_code.0)),
+            ping::Responce::Timeout(ref icmp) => (Some(icmp.icmp_type.0), Some(icmp.icmp_code.0)),
+            ping::Responce::Unreachable(ref icmp) => (Some(icmp.icmp_type.0), Some(icmp.icmp_code.0)),
+            ping::Responce::Tcp(_) | ping::Responce::LocalSendedEcho(..) => (None, None),
+        };
+        let sent = match packet.icmp {
+            ping::Responce::LocalSendedEcho(..) => true,
+            _ => false,
+        };
+        return HopRecord {
+            target: original_target,
+            router: if sent { get_unspecified(original_target) } else { packet.source },
+            ttl: ttl,
+            reply_ttl: if sent { None } else { Some(packet.ttl) },
+            size: packet.size,
+            sent_ms: if sent { Some(packet.time_ms) } else { None },
+            received_ms: if sent { None } else { Some(packet.time_ms) },
+            rtt_us: None,
+            icmp_type: icmp_type,
+            icmp_code: icmp_code,
+            method: self.method.clone(),
+            quote: quote,
+            extensions: packet.extensions.clone(),
+        };
+    }
+
+    /// Update the entry with the given information of the flow, and the times of the packet
+    fn update_trace_entry(&mut self, record: HopRecord, flow: u8, time: (Instant, KernelTimestamp)) -> Result<(), ()> {
+        let source_net = self.network(record.target);
         if let Some(trace) = self.mapping.get_mut(&source_net) {
-            return update_trace_conf(trace, original_target, packet_source, ttl, time_ms);
+            update_trace_conf(trace, record, flow, time, &self.handler, &mut *self.sink)?;
+            check_new_flows(trace, source_net, self.mda, &mut self.check);
+            return Ok(());
         }
         return Err(());
     }
 
-    /// Get the next line from the different outputs
+    /// Get the networks and routers seen, which can be exported to be skipped by a later measurement
+    pub fn seen(&self) -> &SeenNetworks {
+        return &self.seen;
+    }
+
+    /// Get the network aggregating the address and its prefix length, using the most specific
+    /// routed prefix containing it, or the prefix length of the measurement.
+    fn network(&self, address: IpAddr) -> (IpAddr, u8) {
+        let length = self.routed
+            .as_ref()
+            .and_then(|routed| routed.lookup(address))
+            .unwrap_or(self.prefix);
+        return (get_ip_mask(address, length), length);
+    }
+
+    /// Check if the network of the address was seen
+    fn seen_network(&self, address: IpAddr) -> bool {
+        let (network, length) = self.network(address);
+        return self.seen.contains_network(network, length);
+    }
+
+    /// Mark the network of the address as seen, so no new traces are started to it
+    fn mark_network(&mut self, address: IpAddr) {
+        let (network, length) = self.network(address);
+        self.seen.insert_network(network, length);
+    }
+
+    /// Probe again the current hop of the traces resumed from a checkpoint, unless it
+    /// already replied, and queue their next update
+    fn resume_traces(&mut self) {
+        let networks: Vec<(IpAddr, u8)> = self.mapping.keys().cloned().collect();
+        for network in networks {
+            let (source, ttl, replied) = {
+                let trace = &self.mapping[&network];
+                let hop = trace.traces.get((trace.current_ttl as usize).wrapping_sub(1));
+                let replied = hop.map_or(false, |hop| hop.as_ref().map_or(false, |hop| hop.done));
+                (trace.source, trace.current_ttl, replied)
+            };
+            if ttl >= 1 && !replied {
+                self.send_probe(source, ttl, 0);
+            }
+            let time = time_from_epoch_ms() + 1 * 1000;
+            self.mapping.get_mut(&network).unwrap().next_check = time;
+            self.check.push_back((network, time));
+        }
+    }
+
+    /// Get the amount of lines of the hitlist whose targets were sent, stopping at the first
+    /// target still queued in the writer
+    fn sent_offset(&self) -> u64 {
+        let done = self.handler.writer.sended_packets() + self.handler.writer.dropped_packets();
+        return self.unsent_targets
+            .iter()
+            .find(|&&(request, _)| request > done)
+            .map_or(self.hitlist_offset, |&(_, line)| line);
+    }
+
+    /// Save the state of the measurement on the checkpoint file
+    fn save_checkpoint(&self, path: &str) {
+        let result = checkpoint::save(path, self.sent_offset(), &self.key, &self.mapping, &self.caught, &self.seen);
+        match result {
+            Ok(_) => debug!("Checkpoint saved with {} traces in progress", self.mapping.len()),
+            Err(e) => error!("Unable to save the checkpoint to {}: {}", path, e),
+        }
+    }
+
+    /// Get the next line from the hitlist, closing it when there are no more lines
     fn get_nextip(&mut self) -> Option<String> {
-        if let Some(ref mut lines) = self.lines {
-            if let Some(line) = lines.next() {
-                if let Ok(line) = line {
+        let mut close = false;
+        if let Some(ref mut lines) = self.hitlist {
+            match lines.next() {
+                Some(Ok(line)) => {
+                    self.hitlist_offset += 1;
                     return Some(line);
                 }
-            }
-        } else {
-            let mut close = false;
-            if let Some(ref mut lines) = self.stdin {
-                if let Some(line) = lines.next() {
-                    if let Ok(line) = line {
-                        return Some(line);
-                    }
-                } else {
-                    close = true;
-                }
-            }
-            if close {
-                self.stdin = None;
+                Some(Err(_)) => {}
+                None => close = true,
             }
         }
+        if close {
+            self.hitlist = None;
+        }
         return None;
     }
 }
 
-/// Update the entry with the given information
+/// Queue an update of the trace as soon as possible when MDA needs more flows to probe the
+/// current hop, as the stopping rule changes each time a new interface is found.
+fn check_new_flows(
+    trace: &mut TraceConfiguration,
+    network: (IpAddr, u8),
+    mda: Option<f64>,
+    check: &mut VecDeque<((IpAddr, u8), u64)>,
+) {
+    if let Some(confidence) = mda {
+        if trace.current_ttl >= 1 && trace.needed_flows(confidence) > trace.flows {
+            trace.next_check = time_from_epoch_ms();
+            check.push_front((network, trace.next_check));
+        }
+    }
+}
+
+/// Update the entry with the given information of the flow, writing the replies to the sink.
+///
+/// The replies are paired with the probe of the flow on the hop to measure the round trip
+/// time with the kernel timestamps or the monotonic time of both packets. The transmit
+/// timestamps given late by the kernel are read from the handler when pairing them. A reply
+/// received before its probe was recorded is kept until the probe is recorded, or the trace finishes.
 fn update_trace_conf(
     traceconf: &mut TraceConfiguration,
-    original_target: Ipv4Addr,
-    packet_source: Ipv4Addr,
-    ttl: u8,
-    time_ms: u64,
+    mut record: HopRecord,
+    flow: u8,
+    time: (Instant, KernelTimestamp),
+    handler: &PingHandler,
+    sink: &mut dyn ResultSink,
 ) -> Result<(), ()> {
     // get the index as ttl-1, making sure we dont underflow
-    let index = ttl.saturating_sub(1);
+    let index = record.ttl.saturating_sub(1);
 
     // This should always be set, as we do preallocation
     // Unless it is a router/middlebox, where we dont store the values.
-    if let Some(trace) = traceconf.traces.get_mut(index as usize) {
-        if let Some(measurement) = trace {
-            if !measurement.done {
-                // We have already setted the value before, calculate the time difference
+    let trace = match traceconf.traces.get_mut(index as usize) {
+        Some(trace) => trace,
+        None => return Err(()),
+    };
 
-                if measurement.router.is_unspecified() {
-                    measurement.router = packet_source;
+    if !record.is_reply() {
+        traceconf.probe_size = Some(record.size);
+        match trace {
+            Some(measurement) => {
+                // MDA sends a probe of each flow with the same ttl
+                measurement.record_sent(flow, record.sent_ms, time);
+                if let Some((mut reply, received)) = measurement.pending.take() {
+                    let (sent_ms, sent) = measurement.sent_time(0, handler);
+                    reply.sent_ms = sent_ms;
+                    reply.rtt_us = rtt_us(sent, received);
+                    sink.write(&reply);
+                    traceconf.replies.push(reply);
                 }
-                //println!(
-                //    "{}, {}, {}, {}",
-                //    original_target, measurement.router, , measurement.ms
-                //);
-                println!(
-                    "{}, {}, {}, {}",
-                    original_target, packet_source, measurement.hops, time_ms
-                );
+            }
+            None => {
+                let mut measurement = Trace {
+                    router: record.router,
+                    hops: record.ttl,
+                    done: false,
+                    sent_ms: None,
+                    sent: None,
+                    flow_sent: HashMap::new(),
+                    pending: None,
+                };
+                measurement.record_sent(flow, record.sent_ms, time);
+                *trace = Some(measurement);
+            }
+        }
+        return Ok(());
+    }
+
+    // Store the interfaces answering on the hop, as load balanced hops can have many of them
+    let new_interface = match traceconf.interfaces.get_mut(index as usize) {
+        Some(interfaces) if !interfaces.contains(&record.router) => {
+            interfaces.push(record.router);
+            true
+        }
+        _ => false,
+    };
+
+    match trace {
+        Some(measurement) => {
+            if !measurement.done {
+                let (sent_ms, sent) = measurement.sent_time(flow, handler);
+                measurement.router = record.router;
+                record.ttl = measurement.hops;
+                record.sent_ms = sent_ms;
+                record.rtt_us = rtt_us(sent, time);
+                sink.write(&record);
+                traceconf.replies.push(record);
 
                 // Mark the measurement as done, to prevent duplicated answers.
                 measurement.done = true;
                 return Ok(());
+            } else if new_interface {
+                // Another interface of a load balanced hop, reached with a different flow
+                let (sent_ms, sent) = measurement.sent_time(flow, handler);
+                record.ttl = measurement.hops;
+                record.sent_ms = sent_ms;
+                record.rtt_us = rtt_us(sent, time);
+                sink.write(&record);
+                traceconf.replies.push(record);
+                return Ok(());
             } else {
                 debug!(
                     "Duplicated answer from origin_target: {}, router: {}",
-                    original_target, measurement.router
+                    record.target, measurement.router
                 );
             }
-        } else {
-            println!(
-                "{}, {}, {}, {}",
-                original_target, packet_source, ttl, time_ms
-            );
+        }
+        None => {
+            // The probe is recorded after it's sent, so the reply can be received first
             *trace = Some(Trace {
-                router: packet_source,
-                hops: ttl,
-                done: false,
+                router: record.router,
+                hops: record.ttl,
+                done: true,
+                sent_ms: None,
+                sent: None,
+                flow_sent: HashMap::new(),
+                pending: Some((record, time)),
             });
             return Ok(());
         }
@@ -539,6 +1408,20 @@ fn update_trace_conf(
     return Err(());
 }
 
+/// Get the round trip time in microseconds between the probe and the reply, using their
+/// kernel timestamps if both have them, or the monotonic time when they were processed.
+fn rtt_us(sent: Option<(Instant, KernelTimestamp)>, received: (Instant, KernelTimestamp)) -> Option<u64> {
+    let (sent, sent_kernel) = sent?;
+    let rtt = match received.1.elapsed_since(&sent_kernel) {
+        Some(rtt) => rtt,
+        None if received.0 >= sent => received.0.duration_since(sent),
+        None => return None,
+    };
+    return Some(rtt.as_secs() * 1_000_000 + rtt.subsec_micros() as u64);
+}
+
+/// Run a measurement, aggregating the targets by the default prefix length of their family
+/// unless one is given. The secret is required on the followers.
 pub fn run(
     hitlist: Option<String>,
     localip: &str,
@@ -546,6 +1429,410 @@ pub fn run(
     method: PingMethod,
     master: bool,
     duration: Duration,
+    prefix: Option<u8>,
+    secret: Option<String>,
 ) {
-    Anytrace::new(hitlist, localip, pps, method, master, duration).run();
+    let mut builder = AnytraceBuilder::new()
+        .localip(localip)
+        .pps(pps)
+        .method(method)
+        .master(master)
+        .duration(duration);
+    if let Some(prefix) = prefix {
+        builder = builder.prefix(prefix);
+    }
+    if let Some(hitlist) = hitlist {
+        builder = builder.hitlist(&hitlist);
+    }
+    if let Some(secret) = secret {
+        builder = builder.secret(&secret);
+    }
+    builder.build().run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+
+    /// Sink keeping the records in memory for the test
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        hops: Arc<Mutex<Vec<HopRecord>>>,
+        traces: Arc<Mutex<Vec<TraceRecord>>>,
+    }
+
+    impl ResultSink for MemorySink {
+        fn write(&mut self, record: &HopRecord) {
+            self.hops.lock().unwrap().push(record.clone());
+        }
+
+        fn write_trace(&mut self, trace: &TraceRecord) {
+            self.traces.lock().unwrap().push(trace.clone());
+        }
+    }
+
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = data.chunks(2)
+            .map(|word| (word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32)
+            .fold(0, |sum, word| sum + word);
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        return !sum as u16;
+    }
+
+    /// Build an IPv4 packet with the ICMP message, setting both checksums
+    fn icmp_packet(source: Ipv4Addr, destination: Ipv4Addr, ttl: u8, mut icmp: Vec<u8>) -> Vec<u8> {
+        let icmp_checksum = checksum(&icmp);
+        icmp[2] = (icmp_checksum >> 8) as u8;
+        icmp[3] = icmp_checksum as u8;
+        let length = 20 + icmp.len();
+        let mut packet = vec![0x45, 0, (length >> 8) as u8, length as u8, 0, 0, 0, 0, ttl, 1, 0, 0];
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        let header_checksum = checksum(&packet);
+        packet[10] = (header_checksum >> 8) as u8;
+        packet[11] = header_checksum as u8;
+        packet.extend_from_slice(&icmp);
+        return packet;
+    }
+
+    /// Answer the ICMP probes sent to the network with the given function
+    fn answer_probes(network: MemoryNetwork, answer: fn(&[u8], Ipv4Addr, Ipv4Addr) -> Vec<u8>) {
+        while let Ok(probe) = network.sent().recv() {
+            if probe.len() < 28 || probe[9] != 1 {
+                continue;
+            }
+            let local = Ipv4Addr::new(probe[12], probe[13], probe[14], probe[15]);
+            let target = Ipv4Addr::new(probe[16], probe[17], probe[18], probe[19]);
+            if network.inject(answer(&probe, local, target)).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Build the ICMP error of the type and code quoting the probe
+    fn icmp_error(probe: &[u8], kind: u8, code: u8, source: Ipv4Addr, local: Ipv4Addr, ttl: u8) -> Vec<u8> {
+        let mut icmp = vec![kind, code, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(probe);
+        return icmp_packet(source, local, ttl, icmp);
+    }
+
+    /// Answer as a path of two routers, 10.0.0.1 and 10.0.0.2, before every target
+    fn path_of_two_routers(probe: &[u8], local: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+        let ttl = probe[8];
+        if ttl <= 2 {
+            return icmp_error(probe, 11, 0, Ipv4Addr::new(10, 0, 0, ttl), local, 65 - ttl);
+        }
+        let mut icmp = probe[20..].to_vec();
+        icmp[0] = 0;
+        return icmp_packet(target, local, 62, icmp);
+    }
+
+    /// Answer as a router, 10.0.0.1, before the gateway of the network of the target,
+    /// which is down, so the gateway answers with a Host Unreachable
+    fn host_down(probe: &[u8], local: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+        if probe[8] == 1 {
+            return icmp_error(probe, 11, 0, Ipv4Addr::new(10, 0, 0, 1), local, 64);
+        }
+        let octets = target.octets();
+        let gateway = Ipv4Addr::new(octets[0], octets[1], octets[2], 254);
+        return icmp_error(probe, 3, 1, gateway, local, 63);
+    }
+
+    fn kernel(software_us: Option<u64>, hardware_us: Option<u64>) -> KernelTimestamp {
+        return KernelTimestamp {
+            software: software_us.map(Duration::from_micros),
+            hardware: hardware_us.map(Duration::from_micros),
+            pending: None,
+        };
+    }
+
+    #[test]
+    fn rtt_of_the_replies() {
+        let sent = Instant::now();
+        let received = sent + Duration::from_micros(2_500);
+        // The monotonic time is used without kernel timestamps
+        assert_eq!(rtt_us(Some((sent, kernel(None, None))), (received, kernel(None, None))), Some(2_500));
+        assert_eq!(rtt_us(None, (received, kernel(None, None))), None);
+        assert_eq!(rtt_us(Some((received, kernel(None, None))), (sent, kernel(None, None))), None);
+        // The hardware timestamps are preferred, and the software ones used if both have them
+        let probe = kernel(Some(1_000), Some(5_000));
+        assert_eq!(rtt_us(Some((sent, probe)), (received, kernel(Some(1_900), Some(5_700)))), Some(700));
+        assert_eq!(rtt_us(Some((sent, probe)), (received, kernel(Some(1_900), None))), Some(900));
+        assert_eq!(rtt_us(Some((sent, kernel(None, Some(5_000)))), (received, kernel(Some(1_900), None))), Some(2_500));
+    }
+
+    #[test]
+    fn probes_of_the_flows() {
+        let mut hop = Trace {
+            router: "10.0.0.1".parse().unwrap(),
+            hops: 1,
+            done: false,
+            sent_ms: None,
+            sent: None,
+            flow_sent: HashMap::new(),
+            pending: None,
+        };
+        let first = Instant::now();
+        let later = first + Duration::from_millis(10);
+        // Only the first probe of each flow is kept, as the replies match the first one
+        hop.record_sent(0, Some(1_000), (first, kernel(Some(1), None)));
+        hop.record_sent(0, Some(1_010), (later, kernel(Some(2), None)));
+        hop.record_sent(3, Some(1_010), (later, kernel(Some(3), None)));
+        hop.record_sent(3, Some(1_020), (first, kernel(Some(4), None)));
+        assert_eq!(hop.sent_ms, Some(1_000));
+        assert_eq!(hop.sent, Some((first, kernel(Some(1), None))));
+        assert_eq!(hop.flow_sent.len(), 1);
+        assert_eq!(hop.flow_sent[&3], (Some(1_010), (later, kernel(Some(3), None))));
+    }
+
+    #[test]
+    fn flows_needed_by_mda() {
+        let target: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut trace = TraceConfiguration::new(target, 5);
+        trace.current_ttl = 3;
+        trace.flows = 4;
+        // Unresponsive hops keep their flows
+        assert_eq!(trace.needed_flows(0.95), 4);
+        trace.interfaces[2] = vec!["10.0.0.1".parse().unwrap()];
+        assert_eq!(trace.needed_flows(0.95), 6);
+        trace.interfaces[2].push("10.0.1.1".parse().unwrap());
+        assert_eq!(trace.needed_flows(0.95), 11);
+        assert_eq!(trace.needed_flows(0.99), 15);
+        // Limited to the flows that can be encoded
+        trace.interfaces[2] = (0..100).map(|i| IpAddr::V4(Ipv4Addr::new(10, 0, i, 1))).collect();
+        assert_eq!(trace.needed_flows(0.95), MDA_MAX_FLOWS);
+        // The target is not probed again
+        trace.interfaces[2] = vec![target];
+        assert_eq!(trace.needed_flows(0.95), 4);
+        trace.current_ttl = 6;
+        assert_eq!(trace.needed_flows(0.95), 4);
+    }
+
+    #[test]
+    fn trace_on_memory_transport() {
+        let (transport, network) = MemoryTransport::new();
+        thread::spawn(move || answer_probes(network, path_of_two_routers));
+        let sink = MemorySink::default();
+        let hitlist = vec![Ok("192.0.2.1".to_string()), Ok("192.0.2.2".to_string())];
+        AnytraceBuilder::new()
+            .localip("198.51.100.1")
+            .pps(100)
+            .method(PingMethod::ICMP)
+            .master(true)
+            .secret("secret")
+            .drain(Duration::from_millis(500))
+            .hitlist_lines(Box::new(hitlist.into_iter()))
+            .transport(Box::new(transport))
+            .sink(Box::new(sink.clone()))
+            .build()
+            .run();
+
+        // Only one target of the network is traced, from the hop guessed from the reply TTL
+        let traces = sink.traces.lock().unwrap();
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        assert_eq!(trace.target, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert!(trace.reached());
+        let hops: Vec<(u8, String)> = trace.hops.iter()
+            .map(|hop| (hop.ttl, hop.router.to_string()))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (1, "10.0.0.1".to_string()),
+                (2, "10.0.0.2".to_string()),
+                (3, "192.0.2.1".to_string()),
+                (4, "192.0.2.1".to_string()),
+            ]
+        );
+        assert!(trace.hops.iter().all(|hop| hop.rtt_us.is_some()));
+        assert_eq!(trace.hops[0].icmp_type, Some(11));
+        assert_eq!(sink.hops.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn trace_to_a_host_down() {
+        let (transport, network) = MemoryTransport::new();
+        thread::spawn(move || answer_probes(network, host_down));
+        let sink = MemorySink::default();
+        let hitlist = vec![Ok("192.0.2.1".to_string())];
+        AnytraceBuilder::new()
+            .localip("198.51.100.1")
+            .pps(100)
+            .method(PingMethod::ICMP)
+            .master(true)
+            .secret("secret")
+            .drain(Duration::from_millis(500))
+            .hitlist_lines(Box::new(hitlist.into_iter()))
+            .transport(Box::new(transport))
+            .sink(Box::new(sink.clone()))
+            .build()
+            .run();
+
+        // The Host Unreachable of the gateway is verified against the quoted target
+        let traces = sink.traces.lock().unwrap();
+        assert_eq!(traces.len(), 1);
+        let hops: Vec<(u8, String, Option<u8>)> = traces[0].hops.iter()
+            .map(|hop| (hop.ttl, hop.router.to_string(), hop.icmp_type))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (1, "10.0.0.1".to_string(), Some(11)),
+                (2, "192.0.2.254".to_string(), Some(3)),
+                (3, "192.0.2.254".to_string(), Some(3)),
+            ]
+        );
+        assert!(traces[0].hops.iter().all(|hop| hop.target == "192.0.2.1".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn catchment_of_the_networks() {
+        let (transport, network) = MemoryTransport::new();
+        // Every probe is answered twice, as by the duplicated replies
+        thread::spawn(move || {
+            while let Ok(probe) = network.sent().recv() {
+                let local = Ipv4Addr::new(probe[12], probe[13], probe[14], probe[15]);
+                let target = Ipv4Addr::new(probe[16], probe[17], probe[18], probe[19]);
+                let reply = path_of_two_routers(&probe, local, target);
+                if network.inject(reply.clone()).is_err() || network.inject(reply).is_err() {
+                    return;
+                }
+            }
+        });
+        let sink = MemorySink::default();
+        let hitlist = vec![
+            Ok("192.0.2.1".to_string()),
+            Ok("192.0.2.2".to_string()),
+            Ok("203.0.113.7".to_string()),
+        ];
+        AnytraceBuilder::new()
+            .localip("198.51.100.1")
+            .pps(100)
+            .method(PingMethod::ICMP)
+            .master(true)
+            .secret("secret")
+            .drain(Duration::from_millis(500))
+            .catchment(true)
+            .hitlist_lines(Box::new(hitlist.into_iter()))
+            .transport(Box::new(transport))
+            .sink(Box::new(sink.clone()))
+            .build()
+            .run();
+
+        // One record for each network, with the distance estimated from the reply TTL
+        assert!(sink.traces.lock().unwrap().is_empty());
+        let hops = sink.hops.lock().unwrap();
+        let records: Vec<(String, String, u8)> = hops.iter()
+            .map(|hop| (hop.target.to_string(), hop.router.to_string(), hop.ttl))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                ("192.0.2.1".to_string(), "192.0.2.1".to_string(), 4),
+                ("203.0.113.7".to_string(), "203.0.113.7".to_string(), 4),
+            ]
+        );
+        assert!(hops.iter().all(|hop| hop.is_reply() && hop.icmp_type == Some(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "need the secret of the master")]
+    fn follower_without_secret() {
+        let (transport, _network) = MemoryTransport::new();
+        AnytraceBuilder::new()
+            .localip("198.51.100.1")
+            .pps(100)
+            .method(PingMethod::ICMP)
+            .master(false)
+            .transport(Box::new(transport))
+            .build();
+    }
+
+    const CHAIN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data/fixtures/chain.txt");
+
+    /// Trace the targets on the simulated chain of routers, from the site ams
+    fn trace_chain(targets: &[&str]) -> Vec<TraceRecord> {
+        let topology = Topology::load(CHAIN).unwrap();
+        let transport = SimulatedTransport::new(topology, Some("ams")).unwrap();
+        let sink = MemorySink::default();
+        let hitlist: Vec<io::Result<String>> = targets.iter().map(|target| Ok(target.to_string())).collect();
+        AnytraceBuilder::new()
+            .localip("192.0.2.1")
+            .pps(100)
+            .method(PingMethod::ICMP)
+            .master(true)
+            .secret("secret")
+            .drain(Duration::from_millis(500))
+            .hitlist_lines(Box::new(hitlist.into_iter()))
+            .transport(Box::new(transport))
+            .sink(Box::new(sink.clone()))
+            .build()
+            .run();
+        let traces = sink.traces.lock().unwrap();
+        return traces.clone();
+    }
+
+    #[test]
+    fn trace_follows_the_ground_truth() {
+        let target: IpAddr = "198.51.100.1".parse().unwrap();
+        let traces = trace_chain(&["198.51.100.1"]);
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        assert!(trace.reached());
+
+        // The reply TTL is 128 - 5, so the trace starts 5 + 2 hops away
+        assert_eq!(trace.hops.iter().map(|hop| hop.ttl).max(), Some(7));
+
+        let truth = Topology::load(CHAIN).unwrap().ground_truth("ams", target).unwrap();
+        for hop in trace.hops.iter() {
+            let expected = truth.get(hop.ttl as usize - 1).cloned().unwrap_or(Some(target));
+            assert_eq!(Some(hop.router), expected, "hop {}", hop.ttl);
+        }
+        for (index, router) in truth.iter().enumerate() {
+            if let Some(router) = *router {
+                assert!(trace.hops.iter().any(|hop| hop.ttl as usize == index + 1 && hop.router == router));
+            }
+        }
+    }
+
+    #[test]
+    fn traces_skip_the_routers_seen() {
+        let mut traces = trace_chain(&["198.51.100.1", "203.0.113.1"]);
+        assert_eq!(traces.len(), 2);
+        traces.sort_by_key(|trace| trace.hops.iter().map(|hop| hop.ttl).min());
+
+        // The first trace reaching the edge router continues, the other one stops there
+        let edge: IpAddr = "10.0.4.1".parse().unwrap();
+        assert_eq!(traces[0].hops.iter().map(|hop| hop.ttl).min(), Some(1));
+        let skipped: Vec<(u8, bool)> = traces[1].hops.iter()
+            .map(|hop| (hop.ttl, hop.router == edge))
+            .collect();
+        assert_eq!(skipped, vec![(4, true), (5, false), (6, false), (7, false)]);
+        assert!(traces.iter().all(|trace| trace.reached()));
+    }
+
+    #[test]
+    fn trace_through_a_firewall() {
+        // The firewall answers the hitlist probe and every later probe going past it
+        let traces = trace_chain(&["100.64.0.1"]);
+        assert_eq!(traces.len(), 1);
+        let hops: Vec<(u8, String, Option<u8>)> = traces[0].hops.iter()
+            .map(|hop| (hop.ttl, hop.router.to_string(), hop.icmp_type))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (1, "10.0.0.1".to_string(), Some(11)),
+                (2, "10.0.1.1".to_string(), Some(11)),
+                (3, "10.0.5.1".to_string(), Some(11)),
+                (4, "10.0.5.1".to_string(), Some(3)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,166 @@
+use super::helper::get_ip_mask;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+
+/// Table of routed prefixes, used to aggregate the targets by the prefix announced on BGP
+/// instead of a fixed prefix length.
+///
+/// The table is loaded from a text file, with one prefix per line, either in CIDR notation
+/// or as the address and the length separated by whitespace, as the pfx2as files of CAIDA.
+/// Any other field of the line, such as the origin AS, is ignored:
+///
+/// ```text
+/// # Comments start with '#'
+/// 192.0.2.0/24
+/// 198.51.100.0    22    64500
+/// 2001:db8::/32
+/// ```
+#[derive(Debug, Clone)]
+pub struct RoutedPrefixes {
+    /// Networks of each prefix length
+    networks: HashMap<u8, HashSet<IpAddr>>,
+    /// Prefix lengths of the table, from the longest to the shortest
+    lengths: Vec<u8>,
+}
+
+impl RoutedPrefixes {
+    /// Create an empty table
+    pub fn new() -> RoutedPrefixes {
+        return RoutedPrefixes {
+            networks: HashMap::new(),
+            lengths: Vec::new(),
+        };
+    }
+
+    /// Load the table from the given file
+    pub fn load(path: &str) -> Result<RoutedPrefixes, String> {
+        let mut content = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+            return Err(format!("Unable to read {}: {}", path, e));
+        }
+        return Self::parse(&content);
+    }
+
+    /// Parse the list of prefixes
+    pub fn parse(content: &str) -> Result<RoutedPrefixes, String> {
+        let mut table = RoutedPrefixes::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let mut prefix = fields[0].split('/');
+            let address = prefix.next().unwrap();
+            let length = match prefix.next() {
+                Some(length) => Some(length),
+                None => fields.get(1).map(|length| *length),
+            };
+            let result = match (address.parse::<IpAddr>(), length.map(|l| l.parse::<u8>())) {
+                (Ok(address), Some(Ok(length))) => table.insert(address, length),
+                _ => Err(format!("Invalid prefix '{}'", line)),
+            };
+            if let Err(e) = result {
+                return Err(format!("Line {}: {}", number + 1, e));
+            }
+        }
+        return Ok(table);
+    }
+
+    /// Add the prefix to the table
+    pub fn insert(&mut self, address: IpAddr, length: u8) -> Result<(), String> {
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        if length == 0 || length > bits {
+            return Err(format!("Invalid prefix length {} for {}", length, address));
+        }
+        self.networks
+            .entry(length)
+            .or_insert_with(HashSet::new)
+            .insert(get_ip_mask(address, length));
+        if !self.lengths.contains(&length) {
+            self.lengths.push(length);
+            self.lengths.sort_by(|a, b| b.cmp(a));
+        }
+        return Ok(());
+    }
+
+    /// Get the length of the most specific prefix containing the address, if it's routed
+    pub fn lookup(&self, address: IpAddr) -> Option<u8> {
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        for &length in self.lengths.iter().filter(|&&length| length <= bits) {
+            if self.networks[&length].contains(&get_ip_mask(address, length)) {
+                return Some(length);
+            }
+        }
+        return None;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.networks.values().map(|networks| networks.len()).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let table = RoutedPrefixes::parse(
+            "# Routed prefixes\n\
+             192.0.2.0/24\n\
+             192.0.0.0/16 # covering\n\
+             192.0.2.128/25\n\
+             \n\
+             2001:db8::/32\n\
+             2001:db8:1::/48\n",
+        ).unwrap();
+        assert_eq!(table.len(), 5);
+        assert_eq!(table.lookup(ip("192.0.2.1")), Some(24));
+        assert_eq!(table.lookup(ip("192.0.2.200")), Some(25));
+        assert_eq!(table.lookup(ip("192.0.3.1")), Some(16));
+        assert_eq!(table.lookup(ip("192.1.0.1")), None);
+        assert_eq!(table.lookup(ip("2001:db8:1:2::1")), Some(48));
+        assert_eq!(table.lookup(ip("2001:db8:2::1")), Some(32));
+        assert_eq!(table.lookup(ip("2001:db9::1")), None);
+        // The IPv4 address with the bits of 2001:db8:: is not on the IPv6 prefix
+        assert_eq!(table.lookup(ip("32.1.13.184")), None);
+        assert!(RoutedPrefixes::new().is_empty());
+    }
+
+    #[test]
+    fn pfx2as_format() {
+        let table = RoutedPrefixes::parse(
+            "198.51.100.0\t22\t64500\n\
+             203.0.113.0\t24\t64501_64502\n\
+             2001:db8::\t32\t64503,64504\n",
+        ).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.lookup(ip("198.51.103.1")), Some(22));
+        assert_eq!(table.lookup(ip("203.0.113.1")), Some(24));
+        assert_eq!(table.lookup(ip("2001:db8::1")), Some(32));
+    }
+
+    #[test]
+    fn bad_lines() {
+        let error = |content: &str| RoutedPrefixes::parse(content).unwrap_err();
+        assert_eq!(error("192.0.2.0/24\n192.0.2.0\n"), "Line 2: Invalid prefix '192.0.2.0'");
+        assert_eq!(error("192.0.2/24"), "Line 1: Invalid prefix '192.0.2/24'");
+        assert_eq!(error("192.0.2.0/x"), "Line 1: Invalid prefix '192.0.2.0/x'");
+        assert_eq!(error("192.0.2.0 300 64500"), "Line 1: Invalid prefix '192.0.2.0 300 64500'");
+        assert_eq!(error("192.0.2.0/33"), "Line 1: Invalid prefix length 33 for 192.0.2.0");
+        assert_eq!(error("0.0.0.0/0"), "Line 1: Invalid prefix length 0 for 0.0.0.0");
+        assert_eq!(error("2001:db8::/129"), "Line 1: Invalid prefix length 129 for 2001:db8::");
+    }
+}
@@ -0,0 +1,249 @@
+use super::helper::get_ip_mask;
+
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Magic number starting the exported tables, with the version of the format
+const SEEN_MAGIC: &[u8; 8] = b"ANYSEEN\x02";
+
+/// Size in bytes of the bitmap of every IPv4 /24
+const BITMAP_BYTES: usize = 1 << 21;
+
+/// Networks and routers already measured, which are not traced again.
+///
+/// The IPv4 networks of /24 or shorter are stored on a bitmap of every /24, marking each
+/// /24 of the network, so it takes 2 MiB regardless of the amount of networks seen. The
+/// longer IPv4 networks and the IPv6 networks are stored on a set, as the routers.
+#[derive(Debug, Clone)]
+pub struct SeenNetworks {
+    /// Bitmap of the IPv4 /24 seen, allocated when the first one is inserted
+    bitmap: Vec<u8>,
+    networks: HashSet<(IpAddr, u8)>,
+    routers: HashSet<IpAddr>,
+}
+
+impl SeenNetworks {
+    /// Create an empty table
+    pub fn new() -> SeenNetworks {
+        return SeenNetworks {
+            bitmap: Vec::new(),
+            networks: HashSet::new(),
+            routers: HashSet::new(),
+        };
+    }
+
+    /// Mark the network of the address with the given prefix length as seen
+    pub fn insert_network(&mut self, address: IpAddr, length: u8) {
+        match address {
+            IpAddr::V4(address) if length <= 24 => {
+                if self.bitmap.is_empty() {
+                    self.bitmap = vec![0; BITMAP_BYTES];
+                }
+                let mask = (!0u32).checked_shl(32 - length as u32).unwrap_or(0);
+                let first = (u32::from(address) & mask) >> 8;
+                for block in first..first + (1 << (24 - length as u32)) {
+                    self.bitmap[block as usize / 8] |= 1 << (block % 8);
+                }
+            }
+            _ => {
+                self.networks.insert((get_ip_mask(address, length), length));
+            }
+        }
+    }
+
+    /// Check if the network of the address with the given prefix length was seen
+    pub fn contains_network(&self, address: IpAddr, length: u8) -> bool {
+        return match address {
+            IpAddr::V4(address) if length <= 24 => {
+                let block = u32::from(address) >> 8;
+                !self.bitmap.is_empty() && self.bitmap[block as usize / 8] & 1 << (block % 8) != 0
+            }
+            _ => self.networks.contains(&(get_ip_mask(address, length), length)),
+        };
+    }
+
+    /// Mark the router as measured
+    pub fn insert_router(&mut self, router: IpAddr) {
+        self.routers.insert(router);
+    }
+
+    /// Check if the router was measured
+    pub fn contains_router(&self, router: IpAddr) -> bool {
+        return self.routers.contains(&router);
+    }
+
+    /// Get the amount of routers measured
+    pub fn routers(&self) -> usize {
+        return self.routers.len();
+    }
+
+    /// Write the table to the writer, to be imported on a later measurement.
+    ///
+    /// The format is the magic number, a byte telling if the bitmap of the IPv4 /24 follows,
+    /// as it's left out when no /24 was seen, and the networks and the routers, each list
+    /// preceded by its length.
+    pub fn export<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(self.bitmap.len() + 32);
+        buffer.extend_from_slice(SEEN_MAGIC);
+        buffer.push(!self.bitmap.is_empty() as u8);
+        buffer.extend_from_slice(&self.bitmap);
+        put_u32(&mut buffer, self.networks.len() as u32);
+        for &(network, length) in &self.networks {
+            put_address(&mut buffer, network);
+            buffer.push(length);
+        }
+        put_u32(&mut buffer, self.routers.len() as u32);
+        for &router in &self.routers {
+            put_address(&mut buffer, router);
+        }
+        return writer.write_all(&buffer);
+    }
+
+    /// Read a table written by `export`
+    pub fn import<R: Read>(reader: &mut R) -> io::Result<SeenNetworks> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SEEN_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid table of seen networks"));
+        }
+        let mut seen = SeenNetworks::new();
+        let mut flag = [0u8; 1];
+        reader.read_exact(&mut flag)?;
+        match flag[0] {
+            0 => {}
+            1 => {
+                seen.bitmap = vec![0; BITMAP_BYTES];
+                reader.read_exact(&mut seen.bitmap)?;
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid table of seen networks")),
+        }
+        for _ in 0..read_u32(reader)? {
+            let network = read_address(reader)?;
+            let mut length = [0u8; 1];
+            reader.read_exact(&mut length)?;
+            seen.networks.insert((network, length[0]));
+        }
+        for _ in 0..read_u32(reader)? {
+            seen.routers.insert(read_address(reader)?);
+        }
+        return Ok(seen);
+    }
+}
+
+pub(crate) fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+/// Write the address preceded by its family, 4 or 6
+pub(crate) fn put_address(buffer: &mut Vec<u8>, address: IpAddr) {
+    match address {
+        IpAddr::V4(address) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&address.octets());
+        }
+        IpAddr::V6(address) => {
+            buffer.push(6);
+            buffer.extend_from_slice(&address.octets());
+        }
+    }
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    return Ok((buffer[0] as u32) << 24 | (buffer[1] as u32) << 16 | (buffer[2] as u32) << 8 | buffer[3] as u32);
+}
+
+pub(crate) fn read_address<R: Read>(reader: &mut R) -> io::Result<IpAddr> {
+    let mut family = [0u8; 1];
+    reader.read_exact(&mut family)?;
+    return match family[0] {
+        4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets)?;
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets)?;
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid address family")),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    #[test]
+    fn networks_of_the_bitmap() {
+        let mut seen = SeenNetworks::new();
+        assert!(!seen.contains_network(ip("192.0.2.1"), 24));
+        seen.insert_network(ip("198.51.101.7"), 22);
+        for block in &["198.51.100.1", "198.51.101.1", "198.51.102.1", "198.51.103.255"] {
+            assert!(seen.contains_network(ip(block), 24));
+        }
+        assert!(!seen.contains_network(ip("198.51.99.1"), 24));
+        assert!(!seen.contains_network(ip("198.51.104.1"), 24));
+        assert_eq!(seen.bitmap.len(), BITMAP_BYTES);
+        assert!(seen.networks.is_empty());
+
+        // The whole address space is marked by a /0
+        let mut seen = SeenNetworks::new();
+        seen.insert_network(ip("192.0.2.1"), 0);
+        assert!(seen.contains_network(ip("0.0.0.1"), 24));
+        assert!(seen.contains_network(ip("255.255.255.255"), 24));
+    }
+
+    #[test]
+    fn networks_of_the_set() {
+        let mut seen = SeenNetworks::new();
+        seen.insert_network(ip("192.0.2.17"), 28);
+        seen.insert_network(ip("2001:db8:1:2::1"), 48);
+        assert!(seen.bitmap.is_empty());
+        assert_eq!(seen.networks.len(), 2);
+        assert!(seen.contains_network(ip("192.0.2.30"), 28));
+        assert!(!seen.contains_network(ip("192.0.2.33"), 28));
+        assert!(!seen.contains_network(ip("192.0.2.17"), 24));
+        assert!(seen.contains_network(ip("2001:db8:1:ffff::1"), 48));
+        assert!(!seen.contains_network(ip("2001:db8:2::1"), 48));
+        assert!(!seen.contains_network(ip("2001:db8:1:2::1"), 64));
+    }
+
+    #[test]
+    fn export_and_import() {
+        let mut seen = SeenNetworks::new();
+        seen.insert_router(ip("10.0.0.1"));
+        seen.insert_network(ip("2001:db8::"), 32);
+        let mut buffer = Vec::new();
+        seen.export(&mut buffer).unwrap();
+        // The empty bitmap is left out
+        assert_eq!(buffer.len(), 8 + 1 + 4 + 18 + 4 + 5);
+        let imported = SeenNetworks::import(&mut &buffer[..]).unwrap();
+        assert!(imported.bitmap.is_empty());
+        assert!(imported.contains_network(ip("2001:db8::1"), 32));
+        assert!(imported.contains_router(ip("10.0.0.1")));
+        assert_eq!(imported.routers(), 1);
+
+        seen.insert_network(ip("192.0.2.1"), 24);
+        let mut buffer = Vec::new();
+        seen.export(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 8 + 1 + BITMAP_BYTES + 4 + 18 + 4 + 5);
+        let imported = SeenNetworks::import(&mut &buffer[..]).unwrap();
+        assert!(imported.contains_network(ip("192.0.2.1"), 24));
+        assert!(!imported.contains_network(ip("192.0.3.1"), 24));
+        assert!(imported.contains_network(ip("2001:db8::1"), 32));
+
+        // Truncated bitmap and invalid flag
+        assert!(SeenNetworks::import(&mut &buffer[..100]).is_err());
+        buffer[8] = 2;
+        assert!(SeenNetworks::import(&mut &buffer[..]).is_err());
+    }
+}
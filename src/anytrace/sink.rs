@@ -0,0 +1,421 @@
+use super::ping::{IcmpExtensions, PingMethod};
+use super::helper::QuotedHeader;
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+
+/// Reply received for a hop of a trace, or probe that received no reply.
+///
+/// Replies include the sent timestamp and the round trip time of the probe of the hop, if
+/// it was sent by this node. Probes without a reply are recorded with an unspecified router
+/// and only the sent timestamp, when the trace finishes.
+#[derive(Debug, Clone)]
+pub struct HopRecord {
+    /// Original target of the trace
+    pub target: IpAddr,
+    /// Router (or target) that replied, unspecified for the sent probes
+    pub router: IpAddr,
+    /// TTL of the probe, which is the distance in hops to the router
+    pub ttl: u8,
+    /// TTL of the reply when it was received
+    pub reply_ttl: Option<u8>,
+    /// Length of the probe or reply, without the IP header
+    pub size: u16,
+    /// Time in milliseconds since the epoch when the probe was sent
+    pub sent_ms: Option<u64>,
+    /// Time in milliseconds since the epoch when the reply was received
+    pub received_ms: Option<u64>,
+    /// Round trip time in microseconds, measured with the kernel timestamps or a monotonic clock
+    pub rtt_us: Option<u64>,
+    /// Type and code of the ICMP or ICMPv6 reply, missing for the probes and TCP replies
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// Method used to send the probe
+    pub method: PingMethod,
+    /// IP header of the probe quoted by the ICMP errors
+    pub quote: Option<QuotedHeader>,
+    /// Extensions of the ICMP errors
+    pub extensions: IcmpExtensions,
+}
+
+impl HopRecord {
+    /// Check if the record is a reply, and not a sent probe
+    pub fn is_reply(&self) -> bool {
+        return self.received_ms.is_some();
+    }
+}
+
+/// Trace finished, with the replies received for each hop ordered by ttl
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// Local address sending the probes
+    pub source: IpAddr,
+    pub target: IpAddr,
+    pub method: PingMethod,
+    /// If the flow identifier was kept constant for every probe
+    pub paris: bool,
+    /// Length of the probes without the IP header, if they were sent by this node
+    pub probe_size: Option<u16>,
+    /// TTLs probed by the trace in increasing order, as the traces start at the estimated
+    /// distance of the target and stop at the first router already seen
+    pub probed: Vec<u8>,
+    pub hops: Vec<HopRecord>,
+}
+
+impl TraceRecord {
+    /// Check if the target replied to the trace
+    pub fn reached(&self) -> bool {
+        return self.hops.iter().any(|hop| hop.router == self.target);
+    }
+}
+
+/// Destination of the results of the traces
+pub trait ResultSink {
+    /// Write the record of a reply received, or of a probe without reply
+    fn write(&mut self, record: &HopRecord);
+
+    /// Write the record of a finished trace, ignored by default
+    fn write_trace(&mut self, _trace: &TraceRecord) {}
+
+    /// Flush the records written, called when the measurement finishes
+    fn flush(&mut self) {}
+}
+
+/// Write the records as CSV lines of `target, router, hops, time_ms, rtt_ms, details`.
+///
+/// The time is the time the reply was received, or the time the probe was sent for the
+/// probes without reply, and the round trip time is empty if it's unknown. The details
+/// are the quoted header and the extensions of the ICMP errors.
+pub struct CsvSink<W: Write> {
+    writer: W,
+}
+
+impl CsvSink<io::Stdout> {
+    /// Create a sink writing to the stdout, used by default
+    pub fn stdout() -> CsvSink<io::Stdout> {
+        return CsvSink::new(io::stdout());
+    }
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Create a sink writing to the given writer
+    pub fn new(writer: W) -> CsvSink<W> {
+        return CsvSink { writer: writer };
+    }
+}
+
+impl<W: Write> ResultSink for CsvSink<W> {
+    fn write(&mut self, record: &HopRecord) {
+        let time_ms = record.received_ms.or(record.sent_ms).unwrap_or(0);
+        let mut details = match record.quote {
+            Some(ref quote) => quote.to_string(),
+            None => String::new(),
+        };
+        if !details.is_empty() && !record.extensions.is_empty() {
+            details.push(' ');
+        }
+        details.push_str(&record.extensions.to_string());
+        let rtt_ms = match record.rtt_us {
+            Some(rtt) => format!("{}.{:03}", rtt / 1000, rtt % 1000),
+            None => String::new(),
+        };
+        let result = writeln!(
+            self.writer,
+            "{}, {}, {}, {}, {}, {}",
+            record.target, record.router, record.ttl, time_ms, rtt_ms, details
+        );
+        if let Err(e) = result {
+            error!("Unable to write the result: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Unable to flush the results: {}", e);
+        }
+    }
+}
+
+/// Write the records as JSON Lines, with a self-describing object for each record.
+///
+/// Probes without reply are written with `"type": "probe"` and replies with `"type": "hop"`, and the
+/// finished traces with `"type": "trace"` if enabled. Times are in milliseconds since the
+/// epoch, the round trip time in microseconds, and missing values are written as null.
+pub struct JsonlSink<W: Write> {
+    writer: W,
+    traces: bool,
+}
+
+impl JsonlSink<io::Stdout> {
+    /// Create a sink writing to the stdout
+    pub fn stdout(traces: bool) -> JsonlSink<io::Stdout> {
+        return JsonlSink::new(io::stdout(), traces);
+    }
+}
+
+impl<W: Write> JsonlSink<W> {
+    /// Create a sink writing to the given writer, also writing the finished traces if `traces` is set
+    pub fn new(writer: W, traces: bool) -> JsonlSink<W> {
+        return JsonlSink {
+            writer: writer,
+            traces: traces,
+        };
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            error!("Unable to write the result: {}", e);
+        }
+    }
+}
+
+impl<W: Write> ResultSink for JsonlSink<W> {
+    fn write(&mut self, record: &HopRecord) {
+        let line = hop_json(record);
+        self.write_line(&line);
+    }
+
+    fn write_trace(&mut self, trace: &TraceRecord) {
+        if !self.traces {
+            return;
+        }
+        let line = trace_json(trace);
+        self.write_line(&line);
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Unable to flush the results: {}", e);
+        }
+    }
+}
+
+/// Format the finished trace as a JSON object, with its hops
+pub(crate) fn trace_json(trace: &TraceRecord) -> String {
+    let hops = trace.hops.iter().map(hop_json).collect::<Vec<_>>();
+    return format!(
+        "{{\"type\": \"trace\", \"source\": {}, \"target\": {}, \"method\": {}, \"paris\": {}, \"reached\": {}, \"hops\": [{}]}}",
+        json_string(&trace.source),
+        json_string(&trace.target),
+        json_string(&trace.method),
+        trace.paris,
+        trace.reached(),
+        hops.join(", ")
+    );
+}
+
+/// Format the record as a JSON object
+pub(crate) fn hop_json(record: &HopRecord) -> String {
+    let router = match record.is_reply() {
+        true => json_string(&record.router),
+        false => "null".to_string(),
+    };
+    let quote = match record.quote {
+        Some(ref quote) => format!(
+            "{{\"ttl\": {}, \"tos\": {}, \"identification\": {}, \"length\": {}, \"checksum\": {}}}",
+            quote.ttl,
+            quote.tos,
+            quote.identification,
+            quote.length,
+            json_option(&quote.checksum)
+        ),
+        None => "null".to_string(),
+    };
+    let mpls = record.extensions.mpls.iter().map(|label| {
+        format!(
+            "{{\"label\": {}, \"traffic_class\": {}, \"bottom\": {}, \"ttl\": {}}}",
+            label.label, label.traffic_class, label.bottom, label.ttl
+        )
+    });
+    let interfaces = record.extensions.interfaces.iter().map(|interface| {
+        format!(
+            "{{\"role\": {}, \"index\": {}, \"address\": {}, \"name\": {}, \"mtu\": {}}}",
+            interface.role,
+            json_option(&interface.index),
+            interface.address.as_ref().map_or("null".to_string(), json_string),
+            interface.name.as_ref().map_or("null".to_string(), json_string),
+            json_option(&interface.mtu)
+        )
+    });
+    return format!(
+        "{{\"type\": \"{}\", \"target\": {}, \"router\": {}, \"ttl\": {}, \"reply_ttl\": {}, \"size\": {}, \"sent_ms\": {}, \"received_ms\": {}, \"rtt_us\": {}, \"icmp_type\": {}, \"icmp_code\": {}, \"method\": {}, \"quote\": {}, \"mpls\": [{}], \"interfaces\": [{}]}}",
+        if record.is_reply() { "hop" } else { "probe" },
+        json_string(&record.target),
+        router,
+        record.ttl,
+        json_option(&record.reply_ttl),
+        record.size,
+        json_option(&record.sent_ms),
+        json_option(&record.received_ms),
+        json_option(&record.rtt_us),
+        json_option(&record.icmp_type),
+        json_option(&record.icmp_code),
+        json_string(&record.method),
+        quote,
+        mpls.collect::<Vec<_>>().join(", "),
+        interfaces.collect::<Vec<_>>().join(", ")
+    );
+}
+
+/// Format the value as a JSON string, escaping the quotes and control characters
+pub(crate) fn json_string<T: fmt::Display>(value: &T) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+/// Format the number, or null if it's missing
+pub(crate) fn json_option<T: fmt::Display>(value: &Option<T>) -> String {
+    return match *value {
+        Some(ref value) => value.to_string(),
+        None => "null".to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ping::{InterfaceInformation, MplsLabel};
+
+    fn reply() -> HopRecord {
+        let mut extensions = IcmpExtensions::default();
+        extensions.mpls.push(MplsLabel {
+            label: 16002,
+            traffic_class: 0,
+            bottom: true,
+            ttl: 1,
+        });
+        extensions.interfaces.push(InterfaceInformation {
+            role: 0,
+            index: Some(7),
+            address: None,
+            name: Some("ge-0/0/1, uplink".to_string()),
+            mtu: Some(1500),
+        });
+        return HopRecord {
+            target: "192.0.2.1".parse().unwrap(),
+            router: "10.0.0.2".parse().unwrap(),
+            ttl: 2,
+            reply_ttl: Some(253),
+            size: 36,
+            sent_ms: Some(1_500_000_000_000),
+            received_ms: Some(1_500_000_000_012),
+            rtt_us: Some(12_034),
+            icmp_type: Some(11),
+            icmp_code: Some(0),
+            method: PingMethod::ICMP,
+            quote: Some(QuotedHeader {
+                ttl: 1,
+                tos: 0,
+                identification: 7,
+                length: 46,
+                checksum: Some(0xbeef),
+            }),
+            extensions: extensions,
+        };
+    }
+
+    fn probe() -> HopRecord {
+        return HopRecord {
+            target: "192.0.2.1".parse().unwrap(),
+            router: "0.0.0.0".parse().unwrap(),
+            ttl: 3,
+            reply_ttl: None,
+            size: 26,
+            sent_ms: Some(1_500_000_000_500),
+            received_ms: None,
+            rtt_us: None,
+            icmp_type: None,
+            icmp_code: None,
+            method: PingMethod::ICMP,
+            quote: None,
+            extensions: IcmpExtensions::default(),
+        };
+    }
+
+    #[test]
+    fn csv_lines() {
+        let mut sink = CsvSink::new(Vec::new());
+        sink.write(&reply());
+        sink.write(&probe());
+        let mut record = reply();
+        record.quote = None;
+        record.extensions = IcmpExtensions::default();
+        sink.write(&record);
+        // The separators of the names of the interfaces are replaced, so the details stay one field
+        assert_eq!(
+            String::from_utf8(sink.writer).unwrap(),
+            "192.0.2.1, 10.0.0.2, 2, 1500000000012, 12.034, quote=1/0/7/46/48879 mpls=16002/0/1/1 if=incoming/7//ge-0_0_1__uplink/1500\n\
+             192.0.2.1, 0.0.0.0, 3, 1500000000500, , \n\
+             192.0.2.1, 10.0.0.2, 2, 1500000000012, 12.034, \n"
+        );
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string(&"ge-0/0/1"), "\"ge-0/0/1\"");
+        assert_eq!(json_string(&"a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
+        assert_eq!(json_string(&"tab\tnew\nline\u{1}"), "\"tab\\u0009new\\u000aline\\u0001\"");
+        assert_eq!(json_string(&"é \u{7f}"), "\"é \u{7f}\"");
+        assert_eq!(json_option(&Some(5)), "5");
+        assert_eq!(json_option::<u8>(&None), "null");
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut record = reply();
+        record.extensions.interfaces[0].name = Some("xe-\"0\"\n".to_string());
+        let mut sink = JsonlSink::new(Vec::new(), false);
+        sink.write(&record);
+        sink.write(&probe());
+        sink.write_trace(&TraceRecord {
+            source: "198.51.100.1".parse().unwrap(),
+            target: "192.0.2.1".parse().unwrap(),
+            method: PingMethod::ICMP,
+            paris: false,
+            probe_size: Some(26),
+            probed: vec![2, 3],
+            hops: vec![record.clone()],
+        });
+        let hop = "{\"type\": \"hop\", \"target\": \"192.0.2.1\", \"router\": \"10.0.0.2\", \"ttl\": 2, \"reply_ttl\": 253, \
+                   \"size\": 36, \"sent_ms\": 1500000000000, \"received_ms\": 1500000000012, \"rtt_us\": 12034, \
+                   \"icmp_type\": 11, \"icmp_code\": 0, \"method\": \"ICMP\", \
+                   \"quote\": {\"ttl\": 1, \"tos\": 0, \"identification\": 7, \"length\": 46, \"checksum\": 48879}, \
+                   \"mpls\": [{\"label\": 16002, \"traffic_class\": 0, \"bottom\": true, \"ttl\": 1}], \
+                   \"interfaces\": [{\"role\": 0, \"index\": 7, \"address\": null, \"name\": \"xe-\\\"0\\\"\\u000a\", \"mtu\": 1500}]}";
+        let probe = "{\"type\": \"probe\", \"target\": \"192.0.2.1\", \"router\": null, \"ttl\": 3, \"reply_ttl\": null, \
+                     \"size\": 26, \"sent_ms\": 1500000000500, \"received_ms\": null, \"rtt_us\": null, \
+                     \"icmp_type\": null, \"icmp_code\": null, \"method\": \"ICMP\", \"quote\": null, \"mpls\": [], \"interfaces\": []}";
+        // The traces are only written if enabled
+        assert_eq!(String::from_utf8(sink.writer).unwrap(), format!("{}\n{}\n", hop, probe));
+
+        let mut sink = JsonlSink::new(Vec::new(), true);
+        sink.write_trace(&TraceRecord {
+            source: "198.51.100.1".parse().unwrap(),
+            target: "192.0.2.1".parse().unwrap(),
+            method: PingMethod::UDP,
+            paris: true,
+            probe_size: None,
+            probed: vec![2],
+            hops: vec![record],
+        });
+        assert_eq!(
+            String::from_utf8(sink.writer).unwrap(),
+            format!(
+                "{{\"type\": \"trace\", \"source\": \"198.51.100.1\", \"target\": \"192.0.2.1\", \"method\": \"UDP\", \
+                 \"paris\": true, \"reached\": false, \"hops\": [{}]}}\n",
+                hop
+            )
+        );
+    }
+}
@@ -0,0 +1,478 @@
+use super::ping::PingMethod;
+use super::sink::{HopRecord, ResultSink, TraceRecord};
+use super::helper::time_from_epoch_ms;
+
+use std::io::Write;
+use std::net::IpAddr;
+
+/// Magic number starting the header of every warts object
+const WARTS_MAGIC: u16 = 0x1205;
+
+/// Types of the warts objects written
+const TYPE_LIST: u16 = 0x0001;
+const TYPE_CYCLE_START: u16 = 0x0002;
+const TYPE_CYCLE_STOP: u16 = 0x0004;
+const TYPE_TRACE: u16 = 0x0006;
+
+/// Parameters of the trace objects
+const TRACE_LIST_ID: u8 = 1;
+const TRACE_CYCLE_ID: u8 = 2;
+const TRACE_START: u8 = 5;
+const TRACE_STOP_REASON: u8 = 6;
+const TRACE_ATTEMPTS: u8 = 9;
+const TRACE_TYPE: u8 = 11;
+const TRACE_FIRST_HOP: u8 = 15;
+const TRACE_WAIT: u8 = 17;
+const TRACE_HOP_COUNT: u8 = 19;
+const TRACE_ADDR_SRC: u8 = 26;
+const TRACE_ADDR_DST: u8 = 27;
+
+/// Parameters of the hop records
+const HOP_PROBE_TTL: u8 = 2;
+const HOP_REPLY_TTL: u8 = 3;
+const HOP_FLAGS: u8 = 4;
+const HOP_RTT: u8 = 6;
+const HOP_ICMP_TYPE_CODE: u8 = 7;
+const HOP_QUOTE_LENGTH: u8 = 13;
+const HOP_QUOTE_TTL: u8 = 14;
+const HOP_QUOTE_TOS: u8 = 16;
+const HOP_ICMP_EXTENSIONS: u8 = 17;
+const HOP_ADDR: u8 = 18;
+const HOP_TX: u8 = 19;
+
+/// Flags of the hop records
+const HOP_FLAG_REPLY_TTL: u8 = 0x10;
+const HOP_FLAG_TCP: u8 = 0x20;
+
+/// Stop reasons of the traces
+const STOP_NONE: u8 = 0;
+const STOP_COMPLETED: u8 = 1;
+
+/// Write the traces as scamper warts objects, to be processed by the scamper tools.
+///
+/// The file starts with a list and a cycle, referenced by every trace, and the cycle is
+/// stopped when the sink is flushed at the end of the measurement. Only the finished
+/// traces are written, ignoring the individual records of the probes and replies.
+pub struct WartsSink<W: Write> {
+    writer: W,
+    stopped: bool,
+}
+
+impl<W: Write> WartsSink<W> {
+    /// Create a sink writing to the given writer, starting the list and cycle with the given
+    /// names and identifiers.
+    pub fn new(writer: W, list_name: &str, list_id: u32, cycle_id: u32) -> WartsSink<W> {
+        let mut sink = WartsSink {
+            writer: writer,
+            stopped: false,
+        };
+        // The list and the cycle have the identifier 1 on the file, referenced by the traces
+        let mut list = Vec::new();
+        put_u32(&mut list, 1);
+        put_u32(&mut list, list_id);
+        put_string(&mut list, list_name);
+        Params::new().encode(&mut list);
+        sink.write_object(TYPE_LIST, &list);
+
+        let mut cycle = Vec::new();
+        put_u32(&mut cycle, 1);
+        put_u32(&mut cycle, 1);
+        put_u32(&mut cycle, cycle_id);
+        put_u32(&mut cycle, (time_from_epoch_ms() / 1000) as u32);
+        Params::new().encode(&mut cycle);
+        sink.write_object(TYPE_CYCLE_START, &cycle);
+        return sink;
+    }
+
+    /// Write the object with its header
+    fn write_object(&mut self, kind: u16, data: &[u8]) {
+        let mut object = Vec::with_capacity(data.len() + 8);
+        put_u16(&mut object, WARTS_MAGIC);
+        put_u16(&mut object, kind);
+        put_u32(&mut object, data.len() as u32);
+        object.extend_from_slice(data);
+        if let Err(e) = self.writer.write_all(&object) {
+            error!("Unable to write the result: {}", e);
+        }
+    }
+}
+
+impl<W: Write> ResultSink for WartsSink<W> {
+    fn write(&mut self, _record: &HopRecord) {}
+
+    fn write_trace(&mut self, trace: &TraceRecord) {
+        // The trace starts with the first probe, or the first reply if the probes were sent by another node
+        let start_ms = trace.hops.iter().filter_map(|hop| hop.sent_ms).min()
+            .or(trace.hops.iter().filter_map(|hop| hop.received_ms).min())
+            .unwrap_or_else(time_from_epoch_ms);
+        let hop_count = trace.hops.iter().map(|hop| hop.ttl as u16).max().unwrap_or(0);
+
+        let mut params = Params::new();
+        params.put(TRACE_LIST_ID, &be32(1));
+        params.put(TRACE_CYCLE_ID, &be32(1));
+        params.put(TRACE_START, &timeval(start_ms));
+        let stop = if trace.reached() { STOP_COMPLETED } else { STOP_NONE };
+        params.put(TRACE_STOP_REASON, &[stop]);
+        params.put(TRACE_ATTEMPTS, &[1]);
+        params.put(TRACE_TYPE, &[trace_type(&trace.method, trace.paris)]);
+        params.put(TRACE_FIRST_HOP, &[1]);
+        params.put(TRACE_WAIT, &[1]);
+        params.put(TRACE_HOP_COUNT, &be16(hop_count));
+        params.put(TRACE_ADDR_SRC, &address(trace.source));
+        params.put(TRACE_ADDR_DST, &address(trace.target));
+
+        let mut data = Vec::new();
+        params.encode(&mut data);
+        put_u16(&mut data, trace.hops.len() as u16);
+        for hop in &trace.hops {
+            hop_params(hop).encode(&mut data);
+        }
+        // End of the optional attributes of the trace
+        put_u16(&mut data, 0);
+        self.write_object(TYPE_TRACE, &data);
+    }
+
+    fn flush(&mut self) {
+        if !self.stopped {
+            self.stopped = true;
+            let mut cycle = Vec::new();
+            put_u32(&mut cycle, 1);
+            put_u32(&mut cycle, (time_from_epoch_ms() / 1000) as u32);
+            Params::new().encode(&mut cycle);
+            self.write_object(TYPE_CYCLE_STOP, &cycle);
+        }
+        if let Err(e) = self.writer.flush() {
+            error!("Unable to flush the results: {}", e);
+        }
+    }
+}
+
+/// Optional parameters of a warts object, preceded by the flags of the parameters present.
+///
+/// The flags are numbered from 1, seven on each byte, where the high bit marks that
+/// another byte follows. The parameters must be added in the order of their flags.
+struct Params {
+    flags: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Params {
+    fn new() -> Params {
+        return Params {
+            flags: Vec::new(),
+            data: Vec::new(),
+        };
+    }
+
+    fn put(&mut self, id: u8, value: &[u8]) {
+        let (byte, bit) = ((id as usize - 1) / 7, (id - 1) % 7);
+        if self.flags.len() <= byte {
+            self.flags.resize(byte + 1, 0);
+        }
+        self.flags[byte] |= 1 << bit;
+        self.data.extend_from_slice(value);
+    }
+
+    /// Encode the flags and the parameters, or a single empty flag byte if there are none
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        if self.flags.is_empty() {
+            buffer.push(0);
+            return;
+        }
+        let last = self.flags.len() - 1;
+        for (i, flag) in self.flags.iter().enumerate() {
+            buffer.push(if i < last { flag | 0x80 } else { *flag });
+        }
+        put_u16(buffer, self.data.len() as u16);
+        buffer.extend_from_slice(&self.data);
+    }
+}
+
+/// Get the parameters of the hop record of a reply
+fn hop_params(hop: &HopRecord) -> Params {
+    let mut flags = 0;
+    if hop.reply_ttl.is_some() {
+        flags |= HOP_FLAG_REPLY_TTL;
+    }
+    if hop.icmp_type.is_none() && hop.method.is_tcp() {
+        flags |= HOP_FLAG_TCP;
+    }
+
+    let mut params = Params::new();
+    params.put(HOP_PROBE_TTL, &[hop.ttl]);
+    if let Some(ttl) = hop.reply_ttl {
+        params.put(HOP_REPLY_TTL, &[ttl]);
+    }
+    params.put(HOP_FLAGS, &[flags]);
+    if let Some(rtt) = hop.rtt_us {
+        params.put(HOP_RTT, &be32(rtt.min(u32::max_value() as u64) as u32));
+    }
+    if let (Some(kind), Some(code)) = (hop.icmp_type, hop.icmp_code) {
+        params.put(HOP_ICMP_TYPE_CODE, &[kind, code]);
+    }
+    if let Some(ref quote) = hop.quote {
+        // The quoted length is only stored for IPv4, as scamper stores the total length of IPv6 packets
+        if quote.checksum.is_some() {
+            params.put(HOP_QUOTE_LENGTH, &be16(quote.length));
+        }
+        params.put(HOP_QUOTE_TTL, &[quote.ttl]);
+        params.put(HOP_QUOTE_TOS, &[quote.tos]);
+    }
+    if !hop.extensions.mpls.is_empty() {
+        // Only the MPLS label stack is encoded again as an extension object
+        let mut stack = Vec::new();
+        for label in &hop.extensions.mpls {
+            let entry = label.label << 12 | (label.traffic_class as u32 & 0x07) << 9
+                | (label.bottom as u32) << 8 | label.ttl as u32;
+            put_u32(&mut stack, entry);
+        }
+        let mut extensions = Vec::new();
+        put_u16(&mut extensions, stack.len() as u16 + 4);
+        put_u16(&mut extensions, stack.len() as u16);
+        extensions.extend_from_slice(&[1, 1]);
+        extensions.extend_from_slice(&stack);
+        params.put(HOP_ICMP_EXTENSIONS, &extensions);
+    }
+    params.put(HOP_ADDR, &address(hop.router));
+    if let Some(sent) = hop.sent_ms {
+        params.put(HOP_TX, &timeval(sent));
+    }
+    return params;
+}
+
+/// Get the type of trace of scamper for the method
+fn trace_type(method: &PingMethod, paris: bool) -> u8 {
+    return match (method, paris) {
+        (PingMethod::ICMP, false) => 0x01,
+        (PingMethod::UDP, false) => 0x02,
+        (PingMethod::TcpSyn, _) => 0x03,
+        (PingMethod::ICMP, true) => 0x04,
+        (PingMethod::UDP, true) => 0x05,
+        (PingMethod::TcpAck, _) => 0x06,
+    };
+}
+
+/// Encode the address with its length and type, without referencing the previous addresses
+fn address(address: IpAddr) -> Vec<u8> {
+    return match address {
+        IpAddr::V4(address) => {
+            let mut buffer = vec![4, 0x01];
+            buffer.extend_from_slice(&address.octets());
+            buffer
+        }
+        IpAddr::V6(address) => {
+            let mut buffer = vec![16, 0x02];
+            buffer.extend_from_slice(&address.octets());
+            buffer
+        }
+    };
+}
+
+/// Encode the time in milliseconds as a timeval of seconds and microseconds
+fn timeval(time_ms: u64) -> [u8; 8] {
+    let mut buffer = [0u8; 8];
+    buffer[..4].copy_from_slice(&be32((time_ms / 1000) as u32));
+    buffer[4..].copy_from_slice(&be32((time_ms % 1000 * 1000) as u32));
+    return buffer;
+}
+
+fn be16(value: u16) -> [u8; 2] {
+    return [(value >> 8) as u8, value as u8];
+}
+
+fn be32(value: u32) -> [u8; 4] {
+    return [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8];
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&be16(value));
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&be32(value));
+}
+
+/// Write the string terminated with a null byte
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::helper::QuotedHeader;
+    use super::super::ping::{IcmpExtensions, MplsLabel};
+
+    fn reply(ttl: u8, router: &str, reply_ttl: u8, rtt_us: u64, sent_ms: u64) -> HopRecord {
+        return HopRecord {
+            target: "192.0.2.1".parse().unwrap(),
+            router: router.parse().unwrap(),
+            ttl: ttl,
+            reply_ttl: Some(reply_ttl),
+            size: 36,
+            sent_ms: Some(sent_ms),
+            received_ms: Some(sent_ms + rtt_us / 1000),
+            rtt_us: Some(rtt_us),
+            icmp_type: Some(0),
+            icmp_code: Some(0),
+            method: PingMethod::ICMP,
+            quote: None,
+            extensions: IcmpExtensions::default(),
+        };
+    }
+
+    #[test]
+    fn flags_of_the_params() {
+        let mut buffer = Vec::new();
+        Params::new().encode(&mut buffer);
+        assert_eq!(buffer, vec![0x00]);
+
+        // The flags 1, 8 and 15 are the first bit of three bytes, continued by the high bit
+        let mut params = Params::new();
+        params.put(1, &[0xaa]);
+        params.put(8, &[0xbb, 0xcc]);
+        params.put(15, &[0xdd]);
+        let mut buffer = Vec::new();
+        params.encode(&mut buffer);
+        assert_eq!(buffer, vec![0x81, 0x81, 0x01, 0x00, 0x04, 0xaa, 0xbb, 0xcc, 0xdd]);
+
+        // The bytes without flags are still written when a later flag is set
+        let mut params = Params::new();
+        params.put(16, &[0xee]);
+        let mut buffer = Vec::new();
+        params.encode(&mut buffer);
+        assert_eq!(buffer, vec![0x80, 0x80, 0x02, 0x00, 0x01, 0xee]);
+    }
+
+    #[test]
+    fn params_of_a_hop() {
+        let mut hop = reply(3, "10.0.0.3", 250, 12_345, 1_500_000_000_250);
+        hop.icmp_type = Some(11);
+        hop.quote = Some(QuotedHeader {
+            ttl: 1,
+            tos: 0,
+            identification: 1,
+            length: 46,
+            checksum: Some(0),
+        });
+        hop.extensions.mpls.push(MplsLabel {
+            label: 16002,
+            traffic_class: 0,
+            bottom: true,
+            ttl: 1,
+        });
+        let mut buffer = Vec::new();
+        hop_params(&hop).encode(&mut buffer);
+        assert_eq!(buffer, vec![
+            // Flags 2, 3, 4, 6, 7, then 13, 14, then 16, 17, 18, 19, and the length of the params
+            0xee, 0xe0, 0x1e, 0x00, 0x25,
+            // Probe TTL, reply TTL and the flag of the reply TTL
+            0x03, 0xfa, 0x10,
+            // RTT in microseconds
+            0x00, 0x00, 0x30, 0x39,
+            // ICMP type and code
+            0x0b, 0x00,
+            // Quoted length, TTL and ToS
+            0x00, 0x2e, 0x01, 0x00,
+            // Extensions of 8 bytes, with an object of 4 bytes of class 1 and type 1: the label
+            // 16002, traffic class 0, bottom of the stack and TTL 1
+            0x00, 0x08, 0x00, 0x04, 0x01, 0x01, 0x03, 0xe8, 0x21, 0x01,
+            // Address of the router
+            0x04, 0x01, 0x0a, 0x00, 0x00, 0x03,
+            // Time sent as seconds and microseconds
+            0x59, 0x68, 0x2f, 0x00, 0x00, 0x03, 0xd0, 0x90,
+        ]);
+
+        // The quoted length of IPv6 is not stored, and TCP replies without ICMP are flagged
+        let mut hop = reply(2, "2001:db8::2", 60, 1_000, 1_500_000_000_000);
+        hop.method = PingMethod::TcpSyn;
+        hop.icmp_type = None;
+        hop.icmp_code = None;
+        hop.reply_ttl = None;
+        hop.sent_ms = None;
+        hop.quote = Some(QuotedHeader {
+            ttl: 1,
+            tos: 0x20,
+            identification: 0,
+            length: 20,
+            checksum: None,
+        });
+        let mut buffer = Vec::new();
+        hop_params(&hop).encode(&mut buffer);
+        let mut expected = vec![0xaa, 0xc0, 0x0a, 0x00, 0x1a, 0x02, 0x20, 0x00, 0x00, 0x03, 0xe8, 0x01, 0x20, 0x10, 0x02];
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn list_and_cycle() {
+        let before = (time_from_epoch_ms() / 1000) as u32;
+        let mut sink = WartsSink::new(Vec::new(), "hitlist", 7, 9);
+        let after = (time_from_epoch_ms() / 1000) as u32;
+        assert_eq!(sink.writer[..25].to_vec(), vec![
+            0x12, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11,
+            // Identifier on the file and of the list, its name and no params
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x07,
+            b'h', b'i', b't', b'l', b'i', b's', b't', 0x00, 0x00,
+        ]);
+        assert_eq!(sink.writer[25..45].to_vec(), vec![
+            0x12, 0x05, 0x00, 0x02, 0x00, 0x00, 0x00, 0x11,
+            // Identifier on the file, of the list on the file and of the cycle
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x09,
+        ]);
+        let start = read_u32(&sink.writer[45..49]);
+        assert!(before <= start && start <= after);
+        assert_eq!(sink.writer[49..].to_vec(), vec![0x00]);
+
+        // The cycle is stopped once
+        sink.writer.clear();
+        sink.flush();
+        sink.flush();
+        assert_eq!(sink.writer.len(), 17);
+        assert_eq!(sink.writer[..12].to_vec(), vec![
+            0x12, 0x05, 0x00, 0x04, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x01,
+        ]);
+        assert!(read_u32(&sink.writer[12..16]) >= start);
+        assert_eq!(sink.writer[16], 0x00);
+    }
+
+    #[test]
+    fn trace_reaching_the_target() {
+        let mut sink = WartsSink::new(Vec::new(), "hitlist", 1, 1);
+        sink.writer.clear();
+        sink.write_trace(&TraceRecord {
+            source: "198.51.100.1".parse().unwrap(),
+            target: "192.0.2.1".parse().unwrap(),
+            method: PingMethod::ICMP,
+            paris: true,
+            probe_size: Some(26),
+            probed: vec![4],
+            hops: vec![reply(4, "192.0.2.1", 60, 20_000, 1_500_000_000_500)],
+        });
+        assert_eq!(sink.writer, vec![
+            0x12, 0x05, 0x00, 0x06, 0x00, 0x00, 0x00, 0x49,
+            // Flags 1, 2, 5, 6, then 9, 11, then 15, 17, 19, then 26, 27, and the length of the params
+            0xb3, 0x8a, 0x95, 0x30, 0x00, 0x23,
+            // Identifiers of the list and cycle on the file
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+            // Start time of the first probe
+            0x59, 0x68, 0x2f, 0x00, 0x00, 0x07, 0xa1, 0x20,
+            // Completed, one attempt, Paris ICMP, first hop 1 and wait of 1 second
+            0x01, 0x01, 0x04, 0x01, 0x01,
+            // Hop count, source and destination
+            0x00, 0x04, 0x04, 0x01, 0xc6, 0x33, 0x64, 0x01, 0x04, 0x01, 0xc0, 0x00, 0x02, 0x01,
+            // A single hop, with the flags 2, 3, 4, 6, 7, then none, then 18, 19
+            0x00, 0x01, 0xee, 0x80, 0x18, 0x00, 0x17,
+            0x04, 0x3c, 0x10, 0x00, 0x00, 0x4e, 0x20, 0x00, 0x00,
+            0x04, 0x01, 0xc0, 0x00, 0x02, 0x01,
+            0x59, 0x68, 0x2f, 0x00, 0x00, 0x07, 0xa1, 0x20,
+            // No optional attributes
+            0x00, 0x00,
+        ]);
+    }
+
+    fn read_u32(buffer: &[u8]) -> u32 {
+        return (buffer[0] as u32) << 24 | (buffer[1] as u32) << 16 | (buffer[2] as u32) << 8 | buffer[3] as u32;
+    }
+}
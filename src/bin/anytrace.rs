@@ -2,8 +2,10 @@ extern crate anytrace;
 extern crate env_logger;
 extern crate getopts;
 
-use anytrace::anytrace::PingMethod;
-use anytrace::anytrace::run;
+use anytrace::anytrace::{AnytraceBuilder, AtlasSink, Checkpoint, CollectorSink, ControlClient,
+                         ControlServer, JsonlSink, PingMethod, ProbeKey, RoutedPrefixes,
+                         SimulatedTransport, Topology, WartsSink};
+use std::io;
 use getopts::{Matches, Options};
 use std::env;
 use std::ops::BitXor;
@@ -34,10 +36,10 @@ fn get_options() -> Result<Matches, ()> {
         "stdin",
         "Use the stdin to receive the hitlist, separated by newline. Can't be used with --hitlist",
     );
-    opts.reqopt(
+    opts.optopt(
         "m",
         "method",
-        "Method used to send the ping requests. Options: ICMP, UDP",
+        "Method used to send the ping requests. Options: ICMP, UDP, TCP-SYN, TCP-ACK. Followers joining a master use its method.",
         "ICMP",
     );
     opts.optflag(
@@ -51,6 +53,131 @@ fn get_options() -> Result<Matches, ()> {
         "Set the duration in seconds of the measurements. Only works on non-master process.",
         "600",
     );
+    opts.optopt(
+        "",
+        "prefix",
+        "Prefix length used to aggregate the networks, tracing a single target of each one. Defaults to 24 on IPv4 and 48 on IPv6.",
+        "24",
+    );
+    opts.optopt(
+        "",
+        "routed-prefixes",
+        "File with the routed prefixes, one per line as CIDR or pfx2as. The targets are aggregated by the most specific prefix containing them, or by --prefix if none does.",
+        "data/pfx2as.txt",
+    );
+    opts.optopt(
+        "",
+        "secret",
+        "Secret used to authenticate the probes, shared between the master and the followers. Required unless the node is a master measuring alone",
+        "SECRET",
+    );
+    opts.optopt(
+        "",
+        "instance",
+        "Identifier of the measurement, used to run many measurements at the same time on the same address. Defaults to a value derived from the secret and the method.",
+        "1",
+    );
+    opts.optflag(
+        "",
+        "paris",
+        "Keep the flow identifier constant for every probe of a trace, as Paris traceroute, so the load balancers don't split the trace",
+    );
+    opts.optflag(
+        "",
+        "mda",
+        "Enumerate every interface of the load balanced hops with the Multipath Detection Algorithm. Implies --paris, and only works with ICMP and UDP.",
+    );
+    opts.optopt(
+        "",
+        "mda-confidence",
+        "Confidence of having found every interface of a hop on MDA mode",
+        "0.95",
+    );
+    opts.optflag(
+        "",
+        "catchment",
+        "Only ping one target per network and record the networks whose replies reach this site, without tracing them. The followers use the mode of the master.",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "Format of the results written to the stdout. Options: csv, jsonl, warts, atlas",
+        "csv",
+    );
+    opts.optflag(
+        "",
+        "traces",
+        "On jsonl format, also write an object with the hops of each finished trace",
+    );
+    opts.optopt(
+        "",
+        "list-name",
+        "Name of the list of targets written on warts format",
+        "anytrace",
+    );
+    opts.optopt("", "list-id", "Identifier of the list written on warts format", "1");
+    opts.optopt("", "cycle-id", "Identifier of the cycle written on warts format", "1");
+    opts.optopt("", "msm-id", "Measurement identifier written on atlas format", "0");
+    opts.optopt("", "prb-id", "Probe identifier written on atlas format", "0");
+    opts.optopt(
+        "",
+        "checkpoint",
+        "File where the state of the measurement is saved periodically, to be resumed with --resume",
+        "anytrace.checkpoint",
+    );
+    opts.optopt(
+        "",
+        "checkpoint-interval",
+        "Interval in seconds between the checkpoints",
+        "60",
+    );
+    opts.optflag(
+        "",
+        "resume",
+        "Resume the measurement saved on the --checkpoint file, skipping the hitlist lines and networks already processed",
+    );
+    opts.optopt(
+        "",
+        "control",
+        "On the master, listen for the followers on the address, announcing the start of the measurement and stopping them when it finishes",
+        "0.0.0.0:7777",
+    );
+    opts.optopt(
+        "",
+        "followers",
+        "Amount of followers the master waits for before starting the measurement",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "join",
+        "On a follower, join the measurement of the master listening on the address, using its method. The --secret must be the one of the master",
+        "192.168.0.1:7777",
+    );
+    opts.optopt(
+        "",
+        "name",
+        "Name of the node, reported to the master and the collector. Defaults to the --ip",
+        "site1",
+    );
+    opts.optopt(
+        "",
+        "collector",
+        "Send the results to the collector listening on the address, instead of writing them to the stdout. --traces also sends the finished traces.",
+        "192.168.0.1:7778",
+    );
+    opts.optopt(
+        "",
+        "simulate",
+        "Send the probes to a simulated network described in the topology file, instead of using raw sockets",
+        "data/topology.txt",
+    );
+    opts.optopt(
+        "",
+        "site",
+        "Anycast site of the simulated topology used as local node. Defaults to the first site.",
+        "site1",
+    );
     opts.optflag("h", "help", "Print this help menu");
 
     let args: Vec<String> = env::args().collect();
@@ -83,24 +210,140 @@ fn main() {
             panic!("When using master, you must set either --hitlist or --stdin, and not both.");
         }
 
-        run(
-            opts.opt_str("hitlist"),
-            &opts.opt_str("ip").unwrap(),
-            opts.opt_get("pps")
-                .unwrap_or_else(|_| panic!("--pps must be a u32"))
-                .unwrap(),
-            match opts.opt_str("method").unwrap().to_uppercase().as_ref() {
-                "ICMP" => PingMethod::ICMP,
-                "UDP" => PingMethod::UDP,
-                _ => panic!("--method must be ICMP or UDP"),
-            },
-            opts.opt_present("master"),
-            Duration::from_secs(
+        let mut builder = AnytraceBuilder::new()
+            .localip(&opts.opt_str("ip").unwrap())
+            .pps(
+                opts.opt_get("pps")
+                    .unwrap_or_else(|_| panic!("--pps must be a u32"))
+                    .unwrap(),
+            )
+            .master(opts.opt_present("master"))
+            .duration(Duration::from_secs(
                 opts.opt_str("duration")
                     .unwrap_or(format!("{}", u32::max_value()))
                     .parse::<u64>()
                     .unwrap(),
-            ),
-        );
+            ))
+            .paris(opts.opt_present("paris"));
+        if let Some(prefix) = opts.opt_get::<u8>("prefix")
+            .unwrap_or_else(|_| panic!("--prefix must be a u8"))
+        {
+            if prefix == 0 || prefix > 128 {
+                panic!("--prefix must be between 1 and 128");
+            }
+            builder = builder.prefix(prefix);
+        }
+        if let Some(path) = opts.opt_str("routed-prefixes") {
+            let routed = RoutedPrefixes::load(&path).unwrap_or_else(|e| panic!("{}", e));
+            builder = builder.routed_prefixes(routed);
+        }
+        match opts.opt_str("method") {
+            Some(method) => {
+                let method: PingMethod = method.parse()
+                    .unwrap_or_else(|_| panic!("--method must be ICMP, UDP, TCP-SYN or TCP-ACK"));
+                builder = builder.method(method);
+            }
+            None if opts.opt_present("join") => {}
+            None => panic!("--method is required, unless joining a master with --join"),
+        }
+        if let Some(hitlist) = opts.opt_str("hitlist") {
+            builder = builder.hitlist(&hitlist);
+        }
+        match opts.opt_str("secret") {
+            Some(ref secret) if secret.is_empty() => panic!("--secret can't be empty"),
+            Some(secret) => builder = builder.secret(&secret),
+            None if !opts.opt_present("master") => {
+                panic!("--secret is required, as the probes are sent by the master with its secret")
+            }
+            None => {}
+        }
+        if let Some(instance) = opts.opt_get("instance")
+            .unwrap_or_else(|_| panic!("--instance must be a u16"))
+        {
+            if instance == 0 {
+                panic!("--instance must be greater than 0");
+            }
+            builder = builder.instance(instance);
+        }
+        if opts.opt_present("mda") {
+            let confidence: f64 = opts.opt_get_default("mda-confidence", 0.95)
+                .unwrap_or_else(|_| panic!("--mda-confidence must be a number"));
+            if confidence <= 0.0 || confidence >= 1.0 {
+                panic!("--mda-confidence must be between 0 and 1");
+            }
+            builder = builder.mda(confidence);
+        }
+        builder = builder.catchment(opts.opt_present("catchment"));
+        match opts.opt_str("format").unwrap_or("csv".to_string()).to_lowercase().as_ref() {
+            "csv" => {}
+            "jsonl" => builder = builder.sink(Box::new(JsonlSink::stdout(opts.opt_present("traces")))),
+            "warts" => {
+                let list_id = opts.opt_get_default("list-id", 1)
+                    .unwrap_or_else(|_| panic!("--list-id must be a u32"));
+                let cycle_id = opts.opt_get_default("cycle-id", 1)
+                    .unwrap_or_else(|_| panic!("--cycle-id must be a u32"));
+                let list_name = opts.opt_str("list-name").unwrap_or("anytrace".to_string());
+                let sink = WartsSink::new(io::stdout(), &list_name, list_id, cycle_id);
+                builder = builder.sink(Box::new(sink));
+            }
+            "atlas" => {
+                let msm_id = opts.opt_get_default("msm-id", 0)
+                    .unwrap_or_else(|_| panic!("--msm-id must be a u32"));
+                let prb_id = opts.opt_get_default("prb-id", 0)
+                    .unwrap_or_else(|_| panic!("--prb-id must be a u32"));
+                builder = builder.sink(Box::new(AtlasSink::new(io::stdout(), msm_id, prb_id)));
+            }
+            _ => panic!("--format must be csv, jsonl, warts or atlas"),
+        }
+        if let Some(address) = opts.opt_str("collector") {
+            let name = opts.opt_str("name").unwrap_or(opts.opt_str("ip").unwrap());
+            let secret = opts.opt_str("secret")
+                .unwrap_or_else(|| panic!("--collector needs the --secret shared with the collector"));
+            let key = ProbeKey::from_secret(&secret);
+            builder = builder.sink(Box::new(CollectorSink::new(&address, &name, key, opts.opt_present("traces"))));
+        }
+        if let Some(path) = opts.opt_str("checkpoint") {
+            let interval = opts.opt_get_default("checkpoint-interval", 60)
+                .unwrap_or_else(|_| panic!("--checkpoint-interval must be a u64"));
+            if opts.opt_present("resume") {
+                let checkpoint = Checkpoint::load(&path)
+                    .unwrap_or_else(|e| panic!("Unable to load the checkpoint {}: {}", path, e));
+                builder = builder.resume(checkpoint);
+            }
+            builder = builder.checkpoint(&path, Duration::from_secs(interval));
+        } else if opts.opt_present("resume") {
+            panic!("--resume needs the --checkpoint file to resume");
+        }
+        if let Some(address) = opts.opt_str("control") {
+            if !opts.opt_present("master") {
+                panic!("--control can only be used by the master");
+            }
+            let followers = opts.opt_get_default("followers", 0)
+                .unwrap_or_else(|_| panic!("--followers must be a usize"));
+            let secret = opts.opt_str("secret")
+                .unwrap_or_else(|| panic!("--control needs the --secret shared with the followers"));
+            let server = ControlServer::bind(address.as_str(), ProbeKey::from_secret(&secret))
+                .unwrap_or_else(|e| panic!("Unable to listen on {}: {}", address, e));
+            builder = builder.control(server, followers);
+        }
+        if let Some(address) = opts.opt_str("join") {
+            if opts.opt_present("master") {
+                panic!("--join can only be used by the followers");
+            }
+            let name = opts.opt_str("name").unwrap_or(opts.opt_str("ip").unwrap());
+            let secret = opts.opt_str("secret")
+                .unwrap_or_else(|| panic!("--join needs the --secret shared with the master"));
+            let client = ControlClient::join(address.as_str(), &name, ProbeKey::from_secret(&secret))
+                .unwrap_or_else(|e| panic!("Unable to join the master on {}: {}", address, e));
+            builder = builder.follow(client);
+        }
+        if let Some(path) = opts.opt_str("simulate") {
+            let topology = Topology::load(&path).unwrap_or_else(|e| panic!("{}", e));
+            let site = opts.opt_str("site");
+            let transport = SimulatedTransport::new(topology, site.as_ref().map(|s| s.as_str()))
+                .unwrap_or_else(|e| panic!("{}", e));
+            builder = builder.transport(Box::new(transport));
+        }
+        builder.build().run();
     }
 }
@@ -0,0 +1,114 @@
+extern crate anytrace;
+extern crate env_logger;
+extern crate getopts;
+
+use anytrace::anytrace::RoutedPrefixes;
+use anytrace::catchment::Catchment;
+use getopts::Options;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!(
+        "Usage: {} [options] [SITE=]FILE...\n\n\
+         Each FILE holds the CSV results of a site, or JSON lines if it ends with .jsonl, as the\n\
+         output of the collector. The site is named after the file if SITE is not given, unless\n\
+         the JSON lines have a site field.",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    env_logger::init();
+    let mut opts = Options::new();
+    opts.optopt("p", "prefix", "Prefix length aggregating the IPv4 targets", "24");
+    opts.optopt("", "prefix6", "Prefix length aggregating the IPv6 targets", "48");
+    opts.optopt(
+        "",
+        "routed-prefixes",
+        "File with the routed prefixes, one per line as CIDR or pfx2as. The targets are aggregated by the most specific prefix containing them, or by --prefix if none does.",
+        "routed.txt",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "File where the catchment table is written. The stdout is used by default.",
+        "catchment.csv",
+    );
+    opts.optopt(
+        "s",
+        "summary",
+        "File where the networks caught by each site are written. The stderr is used by default.",
+        "sites.csv",
+    );
+    opts.optflag("h", "help", "Print this help menu");
+
+    let args: Vec<String> = env::args().collect();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            println!("{}", f.to_string());
+            print_usage(&args[0], opts);
+            return;
+        }
+    };
+    if matches.opt_present("help") || matches.free.is_empty() {
+        print_usage(&args[0], opts);
+        return;
+    }
+
+    let prefix = matches.opt_get_default::<u8>("prefix", 24)
+        .unwrap_or_else(|_| panic!("--prefix must be a u8"));
+    let prefix6 = matches.opt_get_default::<u8>("prefix6", 48)
+        .unwrap_or_else(|_| panic!("--prefix6 must be a u8"));
+    if prefix == 0 || prefix > 32 || prefix6 == 0 || prefix6 > 128 {
+        panic!("--prefix must be between 1 and 32, and --prefix6 between 1 and 128");
+    }
+    let mut catchment = Catchment::new(prefix, prefix6);
+    if let Some(path) = matches.opt_str("routed-prefixes") {
+        let routed = RoutedPrefixes::load(&path).unwrap_or_else(|e| panic!("{}", e));
+        catchment = catchment.routed_prefixes(routed);
+    }
+
+    for argument in &matches.free {
+        let (site, path) = match argument.find('=') {
+            Some(index) => (argument[..index].to_string(), &argument[index + 1..]),
+            None => {
+                let stem = Path::new(argument).file_stem().and_then(|stem| stem.to_str());
+                (stem.unwrap_or(argument).to_string(), argument.as_str())
+            }
+        };
+        let reader = BufReader::new(File::open(path).unwrap_or_else(|e| panic!("Unable to open {}: {}", path, e)));
+        let result = match path.ends_with(".jsonl") {
+            true => catchment.read_jsonl(Some(&site), reader),
+            false => catchment.read_csv(&site, reader),
+        };
+        match result {
+            Ok(count) => eprintln!("Read {} records from {}", count, path),
+            Err(e) => panic!("Unable to read {}: {}", path, e),
+        }
+    }
+
+    let mut table: Box<dyn Write> = match matches.opt_str("output") {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(&path).unwrap_or_else(|e| panic!("Unable to create {}: {}", path, e)),
+        )),
+        None => Box::new(io::stdout()),
+    };
+    let mut summary: Box<dyn Write> = match matches.opt_str("summary") {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(&path).unwrap_or_else(|e| panic!("Unable to create {}: {}", path, e)),
+        )),
+        None => Box::new(io::stderr()),
+    };
+    catchment.write_table(&mut table)
+        .and_then(|_| table.flush())
+        .unwrap_or_else(|e| panic!("Unable to write the catchment table: {}", e));
+    catchment.write_summary(&mut summary)
+        .and_then(|_| summary.flush())
+        .unwrap_or_else(|e| panic!("Unable to write the summary: {}", e));
+}
@@ -0,0 +1,70 @@
+extern crate anytrace;
+extern crate env_logger;
+extern crate getopts;
+
+use anytrace::anytrace::{Collector, ProbeKey};
+use getopts::Options;
+use std::env;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufWriter, Write};
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    env_logger::init();
+    let mut opts = Options::new();
+    opts.reqopt(
+        "l",
+        "listen",
+        "Address where the results of the nodes are received",
+        "0.0.0.0:7778",
+    );
+    opts.reqopt(
+        "",
+        "secret",
+        "Secret shared with the nodes, which must authenticate with it",
+        "xxxxxxxx",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "File where the merged results are appended as JSON lines. The stdout is used by default.",
+        "results.jsonl",
+    );
+    opts.optflag("h", "help", "Print this help menu");
+
+    let args: Vec<String> = env::args().collect();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            println!("{}", f.to_string());
+            print_usage(&args[0], opts);
+            return;
+        }
+    };
+    if matches.opt_present("help") {
+        print_usage(&args[0], opts);
+        return;
+    }
+
+    let address = matches.opt_str("listen").unwrap();
+    let key = ProbeKey::from_secret(&matches.opt_str("secret").unwrap());
+    let collector = Collector::bind(address.as_str(), key)
+        .unwrap_or_else(|e| panic!("Unable to listen on {}: {}", address, e));
+    let writer: Box<dyn Write + Send> = match matches.opt_str("output") {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap_or_else(|e| panic!("Unable to open {}: {}", path, e));
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(io::stdout()),
+    };
+    collector.run(writer);
+}
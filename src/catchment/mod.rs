@@ -0,0 +1,528 @@
+use anytrace::RoutedPrefixes;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::iter::Peekable;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::Chars;
+
+/// Replies of a site from the targets of a network
+#[derive(Debug, Clone, Default)]
+pub struct SiteReplies {
+    /// Targets of the network that replied to the site
+    pub targets: usize,
+    /// Replies received from the targets
+    pub replies: u64,
+    /// Time in milliseconds since the epoch of the first reply
+    pub first_ms: u64,
+}
+
+/// How the site catching a network was decided
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchStatus {
+    /// Only one site received replies from the network
+    Single,
+    /// Many sites received replies, and the site with the most targets and replies was chosen
+    Majority,
+    /// Many sites received the same amount of targets and replies, and the first one was chosen
+    Tie,
+}
+
+impl fmt::Display for CatchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            CatchStatus::Single => "single",
+            CatchStatus::Majority => "majority",
+            CatchStatus::Tie => "tie",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+/// Site catching a network, with the replies of every site that received them
+#[derive(Debug, Clone)]
+pub struct CatchmentEntry {
+    pub network: IpAddr,
+    pub length: u8,
+    pub site: String,
+    pub status: CatchStatus,
+    /// Replies of each site, from the site catching the network to the one with fewer replies
+    pub sites: Vec<(String, SiteReplies)>,
+}
+
+/// Networks caught by a site
+#[derive(Debug, Clone, Default)]
+pub struct SiteSummary {
+    /// Networks whose catching site is the site
+    pub networks: usize,
+    /// Networks caught by the site, where other sites also received replies
+    pub shared: usize,
+    /// Networks where the site received replies, but it was not chosen as the catching site
+    pub lost: usize,
+    /// Replies received by the site
+    pub replies: u64,
+}
+
+/// Catchment of an anycast service, built from the results of every site.
+///
+/// A site catches a network when it receives the replies of its targets, as the replies
+/// sent to the anycast address are routed to the closest site. These are the records whose
+/// router is the target itself, as the echo replies. When many sites receive replies from
+/// the same network, as with load balancing or route changes during the measurement, the
+/// site with the most targets is chosen, then the one with the most replies, and then the one
+/// receiving the first reply.
+pub struct Catchment {
+    prefix: u8,
+    prefix6: u8,
+    routed: Option<RoutedPrefixes>,
+    networks: HashMap<(IpAddr, u8), BTreeMap<String, (HashSet<IpAddr>, SiteReplies)>>,
+}
+
+impl Catchment {
+    /// Create an empty catchment, aggregating the IPv4 and IPv6 targets with the given prefix lengths
+    pub fn new(prefix: u8, prefix6: u8) -> Catchment {
+        return Catchment {
+            prefix: prefix,
+            prefix6: prefix6,
+            routed: None,
+            networks: HashMap::new(),
+        };
+    }
+
+    /// Aggregate the targets by the most specific routed prefix containing them, as the measurement
+    pub fn routed_prefixes(mut self, routed: RoutedPrefixes) -> Self {
+        self.routed = Some(routed);
+        return self;
+    }
+
+    /// Add a record of the results of the site, ignoring it if it's not a reply from the target
+    pub fn add(&mut self, site: &str, target: IpAddr, router: IpAddr, time_ms: u64) {
+        if target != router {
+            return;
+        }
+        let length = match self.routed.as_ref().and_then(|routed| routed.lookup(target)) {
+            Some(length) => length,
+            None if target.is_ipv4() => self.prefix,
+            None => self.prefix6,
+        };
+        let network = (mask(target, length), length);
+        let sites = self.networks.entry(network).or_insert_with(BTreeMap::new);
+        let &mut (ref mut targets, ref mut replies) = sites
+            .entry(site.to_string())
+            .or_insert_with(|| (HashSet::new(), SiteReplies::default()));
+        targets.insert(target);
+        replies.targets = targets.len();
+        replies.replies += 1;
+        if replies.first_ms == 0 || time_ms < replies.first_ms {
+            replies.first_ms = time_ms;
+        }
+    }
+
+    /// Read the CSV results of the site, as `target, router, hops, time_ms, ...`.
+    /// Return the amount of lines read.
+    pub fn read_csv<R: BufRead>(&mut self, site: &str, reader: R) -> io::Result<usize> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() < 4 {
+                continue;
+            }
+            if let (Ok(target), Ok(router), Ok(time_ms)) = (fields[0].parse(), fields[1].parse(), fields[3].parse()) {
+                self.add(site, target, router, time_ms);
+                count += 1;
+            }
+        }
+        return Ok(count);
+    }
+
+    /// Read the JSON Lines results, using the `site` field of the records written by the
+    /// collector, or the given site for the results of a single site. Return the amount of
+    /// hop records read.
+    pub fn read_jsonl<R: BufRead>(&mut self, site: Option<&str>, reader: R) -> io::Result<usize> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let fields = match json_object(&line) {
+                Ok(fields) => fields,
+                Err(_) => continue,
+            };
+            if fields.get("type").map(|kind| kind.as_str()) != Some("hop") {
+                continue;
+            }
+            let record_site = match fields.get("site").map(|site| site.as_str()).or(site) {
+                Some(site) => site.to_string(),
+                None => continue,
+            };
+            let target = fields.get("target").and_then(|target| target.parse().ok());
+            let router = fields.get("router").and_then(|router| router.parse().ok());
+            let time_ms = fields.get("received_ms").and_then(|time| time.parse().ok());
+            if let (Some(target), Some(router), Some(time_ms)) = (target, router, time_ms) {
+                self.add(&record_site, target, router, time_ms);
+                count += 1;
+            }
+        }
+        return Ok(count);
+    }
+
+    /// Get the site catching each network, ordered by network
+    pub fn entries(&self) -> Vec<CatchmentEntry> {
+        let mut entries: Vec<CatchmentEntry> = self.networks.iter().map(|(&(network, length), sites)| {
+            let mut sites: Vec<(String, SiteReplies)> = sites.iter()
+                .map(|(site, &(_, ref replies))| (site.clone(), replies.clone()))
+                .collect();
+            sites.sort_by(|a, b| {
+                b.1.targets.cmp(&a.1.targets)
+                    .then(b.1.replies.cmp(&a.1.replies))
+                    .then(a.1.first_ms.cmp(&b.1.first_ms))
+                    .then(a.0.cmp(&b.0))
+            });
+            let status = match sites.get(1) {
+                None => CatchStatus::Single,
+                Some(second) if second.1.targets == sites[0].1.targets && second.1.replies == sites[0].1.replies => {
+                    CatchStatus::Tie
+                }
+                Some(_) => CatchStatus::Majority,
+            };
+            CatchmentEntry {
+                network: network,
+                length: length,
+                site: sites[0].0.clone(),
+                status: status,
+                sites: sites,
+            }
+        }).collect();
+        entries.sort_by(|a, b| (a.network, a.length).cmp(&(b.network, b.length)));
+        return entries;
+    }
+
+    /// Get the networks caught by each site, ordered by site
+    pub fn summary(&self) -> BTreeMap<String, SiteSummary> {
+        let mut summary: BTreeMap<String, SiteSummary> = BTreeMap::new();
+        for entry in self.entries() {
+            for (i, &(ref site, ref replies)) in entry.sites.iter().enumerate() {
+                let site = summary.entry(site.clone()).or_insert_with(SiteSummary::default);
+                site.replies += replies.replies;
+                match (i, entry.status) {
+                    (0, CatchStatus::Single) => site.networks += 1,
+                    (0, _) => {
+                        site.networks += 1;
+                        site.shared += 1;
+                    }
+                    _ => site.lost += 1,
+                }
+            }
+        }
+        return summary;
+    }
+
+    /// Write the catchment table as CSV lines of `network, site, status, sites`, where the
+    /// sites are the `site:targets:replies` of every site receiving replies from the network.
+    pub fn write_table<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "network, site, status, sites")?;
+        for entry in self.entries() {
+            let sites = entry.sites.iter()
+                .map(|&(ref site, ref replies)| format!("{}:{}:{}", site, replies.targets, replies.replies))
+                .collect::<Vec<_>>();
+            writeln!(
+                writer,
+                "{}/{}, {}, {}, {}",
+                entry.network, entry.length, entry.site, entry.status, sites.join(" ")
+            )?;
+        }
+        return Ok(());
+    }
+
+    /// Write the networks caught by each site as CSV lines of `site, networks, shared, lost, replies`
+    pub fn write_summary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "site, networks, shared, lost, replies")?;
+        for (site, summary) in self.summary() {
+            writeln!(
+                writer,
+                "{}, {}, {}, {}, {}",
+                site, summary.networks, summary.shared, summary.lost, summary.replies
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+/// Get the network of the address, using the first `length` bits
+fn mask(address: IpAddr, length: u8) -> IpAddr {
+    return match address {
+        IpAddr::V4(address) => {
+            let mask = (!0u32).checked_shl(32 - length as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = (!0u128).checked_shl(128 - length as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    };
+}
+
+/// Get the top level fields of a JSON object, with the strings unescaped and the numbers and
+/// booleans as written. The null fields and the nested objects and arrays are left out.
+fn json_object(line: &str) -> Result<HashMap<String, String>, ()> {
+    let mut parser = JsonParser {
+        chars: line.chars().peekable(),
+    };
+    let fields = parser.object()?;
+    parser.whitespace();
+    if parser.chars.next().is_some() {
+        return Err(());
+    }
+    return Ok(fields);
+}
+
+/// Parser of the JSON lines, which only keeps the top level fields of the objects
+struct JsonParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+    fn whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ()> {
+        self.whitespace();
+        return match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(()),
+        };
+    }
+
+    /// Parse an object, keeping the first value of each field
+    fn object(&mut self) -> Result<HashMap<String, String>, ()> {
+        let mut fields = HashMap::new();
+        self.expect('{')?;
+        self.whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(fields);
+        }
+        loop {
+            self.whitespace();
+            let name = self.string()?;
+            self.expect(':')?;
+            if let Some(value) = self.value()? {
+                fields.entry(name).or_insert(value);
+            }
+            self.whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(fields),
+                _ => return Err(()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<(), ()> {
+        self.expect('[')?;
+        self.whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(());
+        }
+        loop {
+            self.value()?;
+            self.whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(()),
+                _ => return Err(()),
+            }
+        }
+    }
+
+    /// Parse a value, returning it if it's a string, a number or a boolean
+    fn value(&mut self) -> Result<Option<String>, ()> {
+        self.whitespace();
+        match self.chars.peek() {
+            Some(&'"') => return self.string().map(Some),
+            Some(&'{') => return self.object().map(|_| None),
+            Some(&'[') => return self.array().map(|_| None),
+            _ => {}
+        }
+        let mut scalar = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == ',' || c == '}' || c == ']' || c.is_whitespace() {
+                break;
+            }
+            scalar.push(c);
+            self.chars.next();
+        }
+        return match scalar.as_str() {
+            "" => Err(()),
+            "null" => Ok(None),
+            _ => Ok(Some(scalar)),
+        };
+    }
+
+    fn string(&mut self) -> Result<String, ()> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next().ok_or(())? {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped = match self.chars.next().ok_or(())? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode()?,
+                        _ => return Err(()),
+                    };
+                    string.push(escaped);
+                }
+                c if (c as u32) < 0x20 => return Err(()),
+                c => string.push(c),
+            }
+        }
+    }
+
+    /// Parse the `\u` escape of a character, which can be a surrogate pair
+    fn unicode(&mut self) -> Result<char, ()> {
+        let high = self.hex4()?;
+        if high < 0xD800 || high >= 0xE000 {
+            return ::std::char::from_u32(high).ok_or(());
+        }
+        if high >= 0xDC00 || self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+            return Err(());
+        }
+        let low = self.hex4()?;
+        if low < 0xDC00 || low >= 0xE000 {
+            return Err(());
+        }
+        return ::std::char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)).ok_or(());
+    }
+
+    fn hex4(&mut self) -> Result<u32, ()> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next().and_then(|c| c.to_digit(16)).ok_or(())?;
+            value = value << 4 | digit;
+        }
+        return Ok(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    fn sites(entry: &CatchmentEntry) -> Vec<&str> {
+        return entry.sites.iter().map(|&(ref site, _)| site.as_str()).collect();
+    }
+
+    #[test]
+    fn sites_ordered_by_targets_replies_and_first_reply() {
+        let mut catchment = Catchment::new(24, 48);
+        // Most targets wins over most replies
+        catchment.add("ams", ip("192.0.2.1"), ip("192.0.2.1"), 30);
+        catchment.add("ams", ip("192.0.2.2"), ip("192.0.2.2"), 30);
+        catchment.add("scl", ip("192.0.2.3"), ip("192.0.2.3"), 10);
+        catchment.add("scl", ip("192.0.2.3"), ip("192.0.2.3"), 10);
+        catchment.add("scl", ip("192.0.2.3"), ip("192.0.2.3"), 10);
+        // Then most replies, then the first reply
+        catchment.add("ams", ip("198.51.100.1"), ip("198.51.100.1"), 20);
+        catchment.add("scl", ip("198.51.100.1"), ip("198.51.100.1"), 30);
+        catchment.add("scl", ip("198.51.100.1"), ip("198.51.100.1"), 40);
+        catchment.add("syd", ip("198.51.100.2"), ip("198.51.100.2"), 10);
+        catchment.add("syd", ip("198.51.100.2"), ip("198.51.100.2"), 50);
+        // The same replies at the same time are ordered by name
+        catchment.add("scl", ip("203.0.113.1"), ip("203.0.113.1"), 10);
+        catchment.add("ams", ip("203.0.113.2"), ip("203.0.113.2"), 10);
+        catchment.add("syd", ip("2001:db8::1"), ip("2001:db8::1"), 10);
+        // Replies from routers other than the target are ignored
+        catchment.add("ams", ip("2001:db8::1"), ip("2001:db8:ff::1"), 10);
+
+        let entries = catchment.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].network, ip("192.0.2.0"));
+        assert_eq!(sites(&entries[0]), vec!["ams", "scl"]);
+        assert_eq!(entries[0].status, CatchStatus::Majority);
+        assert_eq!(sites(&entries[1]), vec!["syd", "scl", "ams"]);
+        assert_eq!(entries[1].status, CatchStatus::Tie);
+        assert_eq!(sites(&entries[2]), vec!["ams", "scl"]);
+        assert_eq!(entries[2].status, CatchStatus::Tie);
+        assert_eq!((entries[3].network, entries[3].length), (ip("2001:db8::"), 48));
+        assert_eq!(entries[3].status, CatchStatus::Single);
+
+        let summary = catchment.summary();
+        assert_eq!(summary["ams"].networks, 2);
+        assert_eq!(summary["ams"].lost, 1);
+        assert_eq!(summary["scl"].lost, 3);
+        assert_eq!(summary["syd"].networks, 2);
+        assert_eq!(summary["syd"].shared, 1);
+    }
+
+    #[test]
+    fn read_csv_results() {
+        let mut catchment = Catchment::new(24, 48);
+        let csv = "target, router, hops, time_ms, rtt_ms, details\n\
+                   192.0.2.1, 192.0.2.1, 12, 1000, 10.000, \n\
+                   192.0.2.2, 10.0.0.1, 3, 1000, , \n\
+                   invalid line\n\
+                   192.0.2.3, 192.0.2.3, 12, 900\n";
+        assert_eq!(catchment.read_csv("ams", csv.as_bytes()).unwrap(), 3);
+        let entries = catchment.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].sites[0].1.targets, 2);
+        assert_eq!(entries[0].sites[0].1.first_ms, 900);
+    }
+
+    #[test]
+    fn read_jsonl_results() {
+        let mut catchment = Catchment::new(24, 48);
+        let jsonl = concat!(
+            // The nested fields, as the quote, are not the fields of the record
+            r#"{"site": "scl", "quote": {"target": "192.0.2.9", "router": "192.0.2.9"}, "type": "hop", "#,
+            r#""target": "192.0.2.1", "router": "192.0.2.1", "received_ms": 1000, "extensions": [1, {"a": []}]}"#,
+            "\n",
+            r#"{"type": "hop", "site": "a \"quoted\" site", "target": "192.0.2.2", "router": "192.0.2.2", "received_ms": 900}"#,
+            "\n",
+            r#"{"type": "hop", "target": "198.51.100.1", "router": "198.51.100.1", "received_ms": 800}"#,
+            "\n",
+            r#"{"type": "hop", "target": "203.0.113.1", "router": null, "received_ms": null, "sent_ms": 700}"#,
+            "\n",
+            r#"{"type": "trace", "site": "scl", "target": "203.0.113.1", "router": "203.0.113.1"}"#,
+            "\n",
+            r#"{"type": "hop", "target": "203.0.113.2", "router": "203.0.113.2", "received_ms": 1} trailing"#,
+            "\n"
+        );
+        assert_eq!(catchment.read_jsonl(Some("ams"), jsonl.as_bytes()).unwrap(), 3);
+        let entries = catchment.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(sites(&entries[0]), vec!["a \"quoted\" site", "scl"]);
+        assert_eq!(sites(&entries[1]), vec!["ams"]);
+    }
+
+    #[test]
+    fn json_strings() {
+        let fields = json_object(r#"{"a": "\\\/\b\f\n\r\té😀", "b": true, "b": 2, "c" : -1.5e3 }"#).unwrap();
+        assert_eq!(fields["a"], "\\/\u{8}\u{c}\n\r\t\u{e9}\u{1f600}");
+        assert_eq!(fields["b"], "true");
+        assert_eq!(fields["c"], "-1.5e3");
+        assert!(json_object("{}").unwrap().is_empty());
+        assert!(json_object(r#"{"a": "\ud83d"}"#).is_err());
+        assert!(json_object(r#"{"a": "unterminated}"#).is_err());
+        assert!(json_object(r#"{"a": }"#).is_err());
+        assert!(json_object(r#"{"a": [1, 2}"#).is_err());
+        assert!(json_object(r#"["a"]"#).is_err());
+    }
+}
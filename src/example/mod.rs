@@ -2,7 +2,7 @@ extern crate ping;
 extern crate pnet;
 
 use self::ping::{PingHandler, PingHandlerBuilder, PingMethod};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn run(localip: &str) {
@@ -11,7 +11,7 @@ pub fn run(localip: &str) {
         .method(PingMethod::ICMP)
         .build();
 
-    let target: Ipv4Addr = "1.1.1.1".parse().unwrap();
+    let target: IpAddr = "1.1.1.1".parse().unwrap();
     for _ in 0..10 {
         handler.writer.send(target);
     }
@@ -35,7 +35,10 @@ pub fn run(localip: &str) {
             ping::Responce::Unreachable(_packet) => {
                 println!("Received unreachable");
             }
-            ping::Responce::LocalSendedEcho(_) => {}
+            ping::Responce::Tcp(_packet) => {
+                println!("Received TCP reply");
+            }
+            ping::Responce::LocalSendedEcho(..) => {}
         }
     }
 }
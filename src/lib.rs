@@ -2,5 +2,6 @@
 extern crate log;
 
 pub mod anytrace;
+pub mod catchment;
 pub mod example;
 pub mod hitlist;